@@ -0,0 +1,393 @@
+//! Derive macro for the `serde_binprot::HasShape` trait.
+//!
+//! The generated shapes follow what `[@@deriving bin_io]` produces on the
+//! OCaml side: structs with named fields are records, enums are variants,
+//! struct variants use inline records. The names used are the field and
+//! variant names, taking `#[serde(rename = "...")]`, `rename_all` and
+//! `rename_all_fields` into account. Skipped variants are only allowed after
+//! the other variants as they would shift the constructor tags.
+//!
+//! The following container attributes are supported:
+//! - `#[bin_shape(uuid = "...")]` annotates the shape with the given uuid,
+//!   similar to `[@@deriving bin_shape ~annotation_provisional]`.
+//! - `#[bin_shape(basetype = "...")]` uses a base type with the given uuid,
+//!   similar to `[@@deriving bin_shape ~basetype]`.
+extern crate proc_macro;
+
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::visit_mut::VisitMut;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Ident, LitStr, Type};
+
+#[proc_macro_derive(BinShape, attributes(bin_shape))]
+pub fn derive_bin_shape(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct ContainerAttrs {
+    uuid: Option<LitStr>,
+    basetype: Option<LitStr>,
+}
+
+fn container_attrs(input: &DeriveInput) -> syn::Result<ContainerAttrs> {
+    let mut attrs = ContainerAttrs::default();
+    for attr in input.attrs.iter() {
+        if !attr.path().is_ident("bin_shape") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("uuid") || meta.path.is_ident("annotate") {
+                attrs.uuid = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("basetype") {
+                attrs.basetype = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("unsupported bin_shape attribute"))
+            }
+        })?;
+    }
+    Ok(attrs)
+}
+
+#[derive(Default)]
+struct SerdeAttrs {
+    rename: Option<String>,
+    skip: bool,
+    rename_all: Option<RenameRule>,
+    rename_all_fields: Option<RenameRule>,
+}
+
+// The case conversions of `#[serde(rename_all = "...")]`.
+#[derive(Clone, Copy)]
+enum RenameRule {
+    Lower,
+    Upper,
+    Pascal,
+    Camel,
+    Snake,
+    ScreamingSnake,
+    Kebab,
+    ScreamingKebab,
+}
+
+impl RenameRule {
+    fn parse(name: &LitStr) -> syn::Result<Self> {
+        let rule = match name.value().as_str() {
+            "lowercase" => RenameRule::Lower,
+            "UPPERCASE" => RenameRule::Upper,
+            "PascalCase" => RenameRule::Pascal,
+            "camelCase" => RenameRule::Camel,
+            "snake_case" => RenameRule::Snake,
+            "SCREAMING_SNAKE_CASE" => RenameRule::ScreamingSnake,
+            "kebab-case" => RenameRule::Kebab,
+            "SCREAMING-KEBAB-CASE" => RenameRule::ScreamingKebab,
+            _ => return Err(syn::Error::new(name.span(), "unknown rename rule")),
+        };
+        Ok(rule)
+    }
+
+    // Variant names are in PascalCase, as serde does.
+    fn apply_to_variant(self, name: &str) -> String {
+        let snake = || {
+            let mut snake = String::new();
+            for (index, c) in name.char_indices() {
+                if c.is_uppercase() && index > 0 {
+                    snake.push('_')
+                }
+                snake.push(c.to_ascii_lowercase())
+            }
+            snake
+        };
+        match self {
+            RenameRule::Lower => name.to_ascii_lowercase(),
+            RenameRule::Upper => name.to_ascii_uppercase(),
+            RenameRule::Pascal => name.to_string(),
+            RenameRule::Camel => lower_first(name),
+            RenameRule::Snake => snake(),
+            RenameRule::ScreamingSnake => snake().to_ascii_uppercase(),
+            RenameRule::Kebab => snake().replace('_', "-"),
+            RenameRule::ScreamingKebab => snake().replace('_', "-").to_ascii_uppercase(),
+        }
+    }
+
+    // Field names are in snake_case, as serde does.
+    fn apply_to_field(self, name: &str) -> String {
+        let pascal = || {
+            let mut pascal = String::new();
+            let mut capitalize = true;
+            for c in name.chars() {
+                if c == '_' {
+                    capitalize = true
+                } else if capitalize {
+                    pascal.push(c.to_ascii_uppercase());
+                    capitalize = false
+                } else {
+                    pascal.push(c)
+                }
+            }
+            pascal
+        };
+        match self {
+            RenameRule::Lower | RenameRule::Snake => name.to_string(),
+            RenameRule::Upper | RenameRule::ScreamingSnake => name.to_ascii_uppercase(),
+            RenameRule::Pascal => pascal(),
+            RenameRule::Camel => lower_first(&pascal()),
+            RenameRule::Kebab => name.replace('_', "-"),
+            RenameRule::ScreamingKebab => name.replace('_', "-").to_ascii_uppercase(),
+        }
+    }
+}
+
+fn lower_first(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) => c.to_ascii_lowercase().to_string() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+// Parses `name = "..."` or `name(serialize = "...")`, only the serialized
+// name matters here.
+fn serialized_name(meta: &syn::meta::ParseNestedMeta) -> syn::Result<Option<LitStr>> {
+    if meta.input.peek(syn::Token![=]) {
+        return Ok(Some(meta.value()?.parse()?));
+    }
+    let mut serialized = None;
+    meta.parse_nested_meta(|meta| {
+        let name: LitStr = meta.value()?.parse()?;
+        if meta.path.is_ident("serialize") {
+            serialized = Some(name)
+        }
+        Ok(())
+    })?;
+    Ok(serialized)
+}
+
+fn serde_attrs(attrs: &[syn::Attribute]) -> syn::Result<SerdeAttrs> {
+    let mut serde_attrs = SerdeAttrs::default();
+    for attr in attrs.iter() {
+        if !attr.path().is_ident("serde") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                if let Some(name) = serialized_name(&meta)? {
+                    serde_attrs.rename = Some(name.value())
+                }
+            } else if meta.path.is_ident("rename_all") {
+                if let Some(name) = serialized_name(&meta)? {
+                    serde_attrs.rename_all = Some(RenameRule::parse(&name)?)
+                }
+            } else if meta.path.is_ident("rename_all_fields") {
+                if let Some(name) = serialized_name(&meta)? {
+                    serde_attrs.rename_all_fields = Some(RenameRule::parse(&name)?)
+                }
+            } else if meta.path.is_ident("skip") || meta.path.is_ident("skip_serializing") {
+                serde_attrs.skip = true
+            } else if meta.input.peek(syn::Token![=]) {
+                // Ignore the other serde attributes.
+                let _: syn::Expr = meta.value()?.parse()?;
+            } else if meta.input.peek(syn::token::Paren) {
+                let _ = meta.parse_nested_meta(|_| Ok(()));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(serde_attrs)
+}
+
+// Replaces the type parameters with the matching variable shapes, and the
+// references to the type being defined with recursive applications.
+struct Substitute<'a> {
+    ident: &'a Ident,
+    params: Vec<Ident>,
+}
+
+impl VisitMut for Substitute<'_> {
+    fn visit_type_mut(&mut self, ty: &mut Type) {
+        if let Type::Path(type_path) = ty {
+            if type_path.qself.is_none() && type_path.path.segments.len() == 1 {
+                let segment = &mut type_path.path.segments[0];
+                if let Some(index) = self.params.iter().position(|p| p == &segment.ident) {
+                    *ty = parse_quote!(::serde_binprot::shape::ShapeVar<#index>);
+                    return;
+                }
+                if &segment.ident == self.ident || segment.ident == "Self" {
+                    let mut args: Vec<Type> = vec![];
+                    if let syn::PathArguments::AngleBracketed(generics) = &mut segment.arguments {
+                        for arg in generics.args.iter_mut() {
+                            if let syn::GenericArgument::Type(arg) = arg {
+                                self.visit_type_mut(arg);
+                                args.push(arg.clone());
+                            }
+                        }
+                    } else if segment.ident == "Self" {
+                        args = (0..self.params.len())
+                            .map(|index| parse_quote!(::serde_binprot::shape::ShapeVar<#index>))
+                            .collect();
+                    }
+                    *ty = parse_quote!(::serde_binprot::shape::ShapeRecApp<(#(#args,)*)>);
+                    return;
+                }
+            }
+        }
+        syn::visit_mut::visit_type_mut(self, ty)
+    }
+}
+
+fn field_shapes(
+    fields: &Fields,
+    rename_all: Option<RenameRule>,
+    subst: &mut Substitute,
+) -> syn::Result<Vec<(String, TokenStream)>> {
+    let mut shapes = vec![];
+    for (index, field) in fields.iter().enumerate() {
+        let attrs = serde_attrs(&field.attrs)?;
+        if attrs.skip {
+            continue;
+        }
+        let name = match (attrs.rename, &field.ident) {
+            (Some(name), _) => name,
+            (None, Some(ident)) => {
+                let name = ident.to_string().trim_start_matches("r#").to_string();
+                match rename_all {
+                    Some(rule) => rule.apply_to_field(&name),
+                    None => name,
+                }
+            }
+            (None, None) => index.to_string(),
+        };
+        let mut ty = field.ty.clone();
+        subst.visit_type_mut(&mut ty);
+        shapes.push((name, quote!(<#ty as ::serde_binprot::HasShape>::shape())));
+    }
+    Ok(shapes)
+}
+
+fn record(fields: Vec<(String, TokenStream)>) -> TokenStream {
+    let fields = fields
+        .into_iter()
+        .map(|(name, shape)| quote!((#name.to_string(), #shape)));
+    quote!(::serde_binprot::Shape::Record(vec![#(#fields),*]))
+}
+
+// The shape of the arguments of a struct or of an enum variant, this follows
+// the serialization of serde derived types.
+fn args_shapes(
+    fields: &Fields,
+    rename_all: Option<RenameRule>,
+    subst: &mut Substitute,
+) -> syn::Result<Vec<TokenStream>> {
+    let shapes = field_shapes(fields, rename_all, subst)?;
+    let shapes = match fields {
+        Fields::Named(_) => vec![record(shapes)],
+        Fields::Unnamed(_) | Fields::Unit => shapes.into_iter().map(|(_, shape)| shape).collect(),
+    };
+    Ok(shapes)
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let attrs = container_attrs(&input)?;
+    let container_serde = serde_attrs(&input.attrs)?;
+    let ident = &input.ident;
+    let params: Vec<Ident> = input
+        .generics
+        .type_params()
+        .map(|p| p.ident.clone())
+        .collect();
+    let mut subst = Substitute {
+        ident,
+        params: params.clone(),
+    };
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let mut shapes = args_shapes(&data.fields, container_serde.rename_all, &mut subst)?;
+            match &data.fields {
+                Fields::Unit => quote!(::serde_binprot::Shape::unit()),
+                Fields::Unnamed(_) if shapes.len() == 1 => shapes.remove(0),
+                Fields::Named(_) => shapes.remove(0),
+                Fields::Unnamed(_) => quote!(::serde_binprot::Shape::Tuple(vec![#(#shapes),*])),
+            }
+        }
+        Data::Enum(data) => {
+            if data.variants.len() > 256 {
+                return Err(syn::Error::new(
+                    Span::call_site(),
+                    "only enums with at most 256 variants are supported",
+                ));
+            }
+            let mut variants = vec![];
+            let mut skipped = None;
+            for variant in data.variants.iter() {
+                let variant_attrs = serde_attrs(&variant.attrs)?;
+                // The constructor tags are the variant indexes, a skipped
+                // variant can only be dropped if no other variant follows.
+                if variant_attrs.skip {
+                    skipped.get_or_insert(&variant.ident);
+                    continue;
+                }
+                if let Some(skipped) = skipped {
+                    return Err(syn::Error::new(
+                        skipped.span(),
+                        "skipped variants have to come after the other variants",
+                    ));
+                }
+                let name = match (variant_attrs.rename, container_serde.rename_all) {
+                    (Some(name), _) => name,
+                    (None, Some(rule)) => rule.apply_to_variant(&variant.ident.to_string()),
+                    (None, None) => variant.ident.to_string(),
+                };
+                let rename_all = variant_attrs
+                    .rename_all
+                    .or(container_serde.rename_all_fields);
+                let shapes = args_shapes(&variant.fields, rename_all, &mut subst)?;
+                variants.push(quote!((#name.to_string(), vec![#(#shapes),*])));
+            }
+            quote!(::serde_binprot::Shape::Variant(vec![#(#variants),*]))
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "unions are not supported",
+            ));
+        }
+    };
+
+    let args = params
+        .iter()
+        .map(|p| quote!(<#p as ::serde_binprot::HasShape>::shape()));
+    let shape = match (attrs.basetype, attrs.uuid) {
+        (Some(uuid), _) => {
+            quote!(::serde_binprot::Shape::Base(#uuid.to_string(), vec![#(#args),*]))
+        }
+        (None, uuid) => {
+            let body = match uuid {
+                None => body,
+                Some(uuid) => {
+                    quote!(::serde_binprot::Shape::Annotate(#uuid.to_string(), Box::new(#body)))
+                }
+            };
+            quote!(::serde_binprot::Shape::Application(Box::new(#body), vec![#(#args),*]))
+        }
+    };
+
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::serde_binprot::HasShape));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::serde_binprot::HasShape for #ident #ty_generics #where_clause {
+            fn shape() -> ::serde_binprot::Shape {
+                #shape
+            }
+        }
+    })
+}
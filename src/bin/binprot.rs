@@ -0,0 +1,18 @@
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let stdout = std::io::stdout();
+    if let Err(err) = serde_binprot::cli::run(&args, &mut stdout.lock()) {
+        match err {
+            // The output was closed early, e.g. when piped to `head`.
+            serde_binprot::Error::IoError(err) if err.kind() == std::io::ErrorKind::BrokenPipe => {
+                return;
+            }
+            serde_binprot::Error::Message(msg) => eprintln!("binprot: {}", msg),
+            serde_binprot::Error::AtPath { path, offset, msg } => {
+                eprintln!("binprot: {} at byte {} ({})", msg, offset, path)
+            }
+            err => eprintln!("binprot: {}", err),
+        }
+        std::process::exit(1)
+    }
+}
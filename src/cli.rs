@@ -0,0 +1,571 @@
+//! The `binprot` command line tool.
+use crate::codegen::{self, RustOptions};
+use crate::error::{Error, Result};
+use crate::framing::{self, FrameReader};
+use crate::ocaml::Declarations;
+use crate::query::{Format, Path, Predicate, Query};
+use crate::registry::TypeRegistry;
+use crate::sexp::Sexp;
+use crate::shape::Shape;
+use crate::value::{self, Value};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+const USAGE: &str = "usage: binprot <command> [options]
+
+commands:
+  gen-rust FILE [--bin-shape] [-o OUTPUT]
+      generates Rust types from the OCaml type declarations in FILE
+  gen-ocaml SHAPE_FILE [--name NAME] [-o OUTPUT]
+      generates OCaml type declarations from a shape s-expression
+  diff SHAPE_FILE LEFT RIGHT [--hex]
+      compares two messages of the given shape field by field, the
+      messages are raw bin_prot files or hexadecimal with --hex
+  explain SHAPE_FILE DATA [--hex]
+      prints an annotated hexdump of a message of the given shape, and
+      the number of bytes used by each field
+  layout SHAPE_FILE [--markdown]
+      describes the wire format of a shape with the size of each field
+  decode SHAPE [INPUT] [--framed] [--hex] [--sexp] [-o OUTPUT]
+      prints a bin_prot message as JSON, or as a s-expression with --sexp,
+      with --framed the input is a stream of messages each preceded by a
+      8 bytes size header and one message is printed per line
+  encode SHAPE [INPUT] [--framed] [--hex] [--sexp] [-o OUTPUT]
+      encodes a JSON value, or a s-expression with --sexp, as bin_prot,
+      with --framed the input can hold several values that are written
+      with a size header, --hex writes the bytes in hexadecimal
+  query SHAPE [INPUT] [--filter PREDICATE] [--select PATHS] [--csv] [-o OUTPUT]
+      streams a file of messages each preceded by a 8 bytes size header and
+      prints the messages satisfying PREDICATE, e.g.
+      '.symbol == \"AAPL\" and .qty > 100', as JSON lines or CSV, --select
+      takes a comma separated list of paths to print, e.g. '.symbol,.qty'
+
+The input is read from the standard input when not given or when `-`, the
+input of decode is hexadecimal with --hex. The SHAPE of decode, encode and
+query is one of:
+  --shape SHAPE_FILE          a shape s-expression
+  --ocaml FILE --type NAME    a type from some OCaml type declarations
+  --type NAME                 a type registered by the program
+";
+
+fn usage_error(msg: &str) -> Error {
+    Error::Message(format!("{}\n\n{}", msg, USAGE))
+}
+
+// The command line arguments, split between positional arguments and
+// `--flag` or `--flag VALUE` options.
+struct Args {
+    positional: Vec<String>,
+    options: BTreeMap<String, Option<String>>,
+}
+
+impl Args {
+    fn parse(args: &[String], with_value: &[&str], without_value: &[&str]) -> Result<Self> {
+        let mut positional = vec![];
+        let mut options = BTreeMap::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if with_value.contains(&arg.as_str()) {
+                match args.next() {
+                    Some(value) => options.insert(arg.clone(), Some(value.clone())),
+                    None => return Err(usage_error(&format!("missing value for {}", arg))),
+                };
+            } else if without_value.contains(&arg.as_str()) {
+                options.insert(arg.clone(), None);
+            } else if arg.starts_with('-') && arg != "-" {
+                return Err(usage_error(&format!("unknown option {}", arg)));
+            } else {
+                positional.push(arg.clone())
+            }
+        }
+        Ok(Args {
+            positional,
+            options,
+        })
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.options.get(name).and_then(|v| v.as_deref())
+    }
+
+    fn single_positional(&self, what: &str) -> Result<&str> {
+        match self.positional.as_slice() {
+            [arg] => Ok(arg),
+            [] => Err(usage_error(&format!("missing {}", what))),
+            _ => Err(usage_error("too many arguments")),
+        }
+    }
+}
+
+// Reads a file, `-` being the standard input.
+fn read_file(path: &str) -> Result<String> {
+    if path == "-" {
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content)?;
+        Ok(content)
+    } else {
+        Ok(std::fs::read_to_string(path)?)
+    }
+}
+
+// Reads a binary file, `-` being the standard input. With `hex`, the file
+// contains the bytes in hexadecimal, whitespace being ignored.
+fn read_bytes(path: &str, hex: bool) -> Result<Vec<u8>> {
+    let mut content = vec![];
+    if path == "-" {
+        std::io::stdin().read_to_end(&mut content)?;
+    } else {
+        content = std::fs::read(path)?
+    };
+    if !hex {
+        return Ok(content);
+    }
+    let digits: Vec<u8> = content
+        .into_iter()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect();
+    let invalid = || Error::Message(format!("{}: invalid hexadecimal data", path));
+    if !digits.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    digits
+        .chunks(2)
+        .map(|c| {
+            let c = std::str::from_utf8(c).map_err(|_| invalid())?;
+            u8::from_str_radix(c, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+// Adds the file name to the parse errors.
+fn with_path<T>(path: &str, res: Result<T>) -> Result<T> {
+    res.map_err(|err| match err {
+        Error::ParseError { line, column, msg } => {
+            Error::Message(format!("{}:{}:{}: {}", path, line, column, msg))
+        }
+        err => err,
+    })
+}
+
+fn write_output(path: Option<&str>, content: &str, out: &mut dyn Write) -> Result<()> {
+    with_output(path, out, |out| Ok(out.write_all(content.as_bytes())?))
+}
+
+// Runs `f` on the output file, `-` or no file being `out`.
+fn with_output<F>(path: Option<&str>, out: &mut dyn Write, f: F) -> Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> Result<()>,
+{
+    match path {
+        None | Some("-") => f(out),
+        Some(path) => {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            f(&mut file)?;
+            Ok(file.flush()?)
+        }
+    }
+}
+
+// The options selecting the shape of the decode and encode commands.
+const SHAPE_OPTIONS: [&str; 3] = ["--shape", "--ocaml", "--type"];
+
+fn shape_of_args<R>(args: &Args, registry: &TypeRegistry<R>) -> Result<Shape> {
+    match (
+        args.value("--shape"),
+        args.value("--ocaml"),
+        args.value("--type"),
+    ) {
+        (Some(path), None, None) => with_path(path, Shape::from_sexp_str(&read_file(path)?)),
+        (None, Some(path), Some(name)) => {
+            let decls = with_path(path, Declarations::parse(&read_file(path)?))?;
+            decls.shape(name)
+        }
+        (None, None, Some(name)) => registered_shape(registry, name),
+        (None, Some(_), None) => Err(usage_error("--ocaml requires --type")),
+        (None, None, None) => Err(usage_error("missing --shape, --ocaml or --type")),
+        _ => Err(usage_error("--shape cannot be used with --ocaml or --type")),
+    }
+}
+
+// Finds a registered type by its full name, the last component of its
+// name, or its key.
+fn registered_shape<R>(registry: &TypeRegistry<R>, name: &str) -> Result<Shape> {
+    let suffix = format!("::{}", name);
+    let mut found = registry.entries().filter(|(key, type_name, _)| {
+        *type_name == name || type_name.ends_with(&suffix) || key.to_string() == name
+    });
+    match (found.next(), found.next()) {
+        (Some((_, _, shape)), None) => Ok(shape.clone()),
+        (Some(_), Some(_)) => Err(Error::Message(format!("type {} is ambiguous", name))),
+        (None, _) => {
+            let known: Vec<_> = registry.entries().map(|(_, name, _)| name).collect();
+            let known = if known.is_empty() {
+                "no types are registered".to_string()
+            } else {
+                format!("registered types: {}", known.join(", "))
+            };
+            Err(Error::Message(format!("unknown type {}, {}", name, known)))
+        }
+    }
+}
+
+fn input_path(args: &Args) -> Result<&str> {
+    match args.positional.as_slice() {
+        [] => Ok("-"),
+        [path] => Ok(path),
+        _ => Err(usage_error("too many arguments")),
+    }
+}
+
+fn decode<R>(args: &[String], registry: &TypeRegistry<R>, out: &mut dyn Write) -> Result<()> {
+    let with_value = [&SHAPE_OPTIONS[..], &["-o", "--output"]].concat();
+    let args = Args::parse(args, &with_value, &["--framed", "--hex", "--sexp"])?;
+    let shape = shape_of_args(&args, registry)?;
+    let path = input_path(&args)?;
+    let sexp = args.flag("--sexp");
+    let to_string = |data: &[u8]| {
+        if sexp {
+            Ok(value::decode_value(data, &shape)?
+                .to_sexp()
+                .to_string_mach())
+        } else {
+            crate::json::to_string(data, &shape)
+        }
+    };
+    let output = args.value("-o").or_else(|| args.value("--output"));
+    with_output(output, out, |out| {
+        if !args.flag("--framed") {
+            let data = read_bytes(path, args.flag("--hex"))?;
+            return Ok(writeln!(out, "{}", to_string(&data)?)?);
+        }
+        let input: Box<dyn Read> = if args.flag("--hex") {
+            Box::new(std::io::Cursor::new(read_bytes(path, true)?))
+        } else if path == "-" {
+            Box::new(std::io::stdin())
+        } else {
+            Box::new(std::io::BufReader::new(std::fs::File::open(path)?))
+        };
+        for (index, frame) in FrameReader::new(input).enumerate() {
+            let (offset, payload) = frame?;
+            let line = to_string(&payload).map_err(|err| framing::in_frame(err, index, offset))?;
+            writeln!(out, "{}", line)?
+        }
+        Ok(())
+    })
+}
+
+fn encode<R>(args: &[String], registry: &TypeRegistry<R>, out: &mut dyn Write) -> Result<()> {
+    let with_value = [&SHAPE_OPTIONS[..], &["-o", "--output"]].concat();
+    let args = Args::parse(args, &with_value, &["--framed", "--hex", "--sexp"])?;
+    let shape = shape_of_args(&args, registry)?;
+    let path = input_path(&args)?;
+    let input = read_file(path)?;
+    let payloads = if args.flag("--sexp") {
+        let sexps = with_path(path, Sexp::parse_many(&input))?;
+        let payloads = sexps.iter().enumerate().map(|(index, sexp)| {
+            let value = Value::from_sexp(sexp, &shape)
+                .map_err(|err| Error::Message(format!("{}: value {}: {}", path, index, err)))?;
+            value::encode_value(&value, &shape)
+        });
+        payloads.collect::<Result<Vec<_>>>()?
+    } else {
+        with_path(path, crate::json::from_str_many(&input, &shape))?
+    };
+    let mut data = vec![];
+    if args.flag("--framed") {
+        for payload in payloads.iter() {
+            framing::write_frame(&mut data, payload)?
+        }
+    } else {
+        match payloads.as_slice() {
+            [payload] => data.extend_from_slice(payload),
+            [] => return Err(Error::Message(format!("{}: no value to encode", path))),
+            _ => {
+                let msg = format!("{}: several values, use --framed to encode them", path);
+                return Err(Error::Message(msg));
+            }
+        }
+    }
+    let output = args.value("-o").or_else(|| args.value("--output"));
+    with_output(output, out, |out| {
+        if args.flag("--hex") {
+            let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "{}", hex)?
+        } else {
+            out.write_all(&data)?
+        }
+        Ok(())
+    })
+}
+
+fn gen_rust(args: &[String], out: &mut dyn Write) -> Result<()> {
+    let args = Args::parse(args, &["-o", "--output"], &["--bin-shape"])?;
+    let path = args.single_positional("OCaml file")?;
+    let options = RustOptions {
+        bin_shape: args.flag("--bin-shape"),
+    };
+    let code = with_path(path, codegen::rust_of_ocaml(&read_file(path)?, &options))?;
+    let output = args.value("-o").or_else(|| args.value("--output"));
+    write_output(output, &code, out)
+}
+
+fn gen_ocaml(args: &[String], out: &mut dyn Write) -> Result<()> {
+    let args = Args::parse(args, &["-o", "--output", "--name"], &[])?;
+    let path = args.single_positional("shape file")?;
+    let shape = with_path(path, Shape::from_sexp_str(&read_file(path)?))?;
+    let name = args.value("--name").unwrap_or("t");
+    let code = codegen::ocaml_of_shape(name, &shape)?;
+    let output = args.value("-o").or_else(|| args.value("--output"));
+    write_output(output, &code, out)
+}
+
+fn diff(args: &[String], out: &mut dyn Write) -> Result<()> {
+    let args = Args::parse(args, &[], &["--hex"])?;
+    let (shape, left, right) = match args.positional.as_slice() {
+        [shape, left, right] => (shape, left, right),
+        [] | [_] | [_, _] => return Err(usage_error("missing arguments")),
+        _ => return Err(usage_error("too many arguments")),
+    };
+    let shape = with_path(shape, Shape::from_sexp_str(&read_file(shape)?))?;
+    let hex = args.flag("--hex");
+    let (left, right) = (read_bytes(left, hex)?, read_bytes(right, hex)?);
+    for diff in crate::diff::diff(&left, &right, &shape) {
+        writeln!(out, "{}", diff)?
+    }
+    Ok(())
+}
+
+fn explain(args: &[String], out: &mut dyn Write) -> Result<()> {
+    let args = Args::parse(args, &[], &["--hex"])?;
+    let (shape, data) = match args.positional.as_slice() {
+        [shape, data] => (shape, data),
+        [] | [_] => return Err(usage_error("missing arguments")),
+        _ => return Err(usage_error("too many arguments")),
+    };
+    let shape = with_path(shape, Shape::from_sexp_str(&read_file(shape)?))?;
+    let data = read_bytes(data, args.flag("--hex"))?;
+    write!(out, "{}", crate::explain::explain(&data, &shape))?;
+    Ok(())
+}
+
+fn layout(args: &[String], out: &mut dyn Write) -> Result<()> {
+    let args = Args::parse(args, &[], &["--markdown"])?;
+    let path = args.single_positional("shape file")?;
+    let shape = with_path(path, Shape::from_sexp_str(&read_file(path)?))?;
+    let layout = crate::layout::layout(&shape);
+    if args.flag("--markdown") {
+        out.write_all(layout.to_markdown().as_bytes())?
+    } else {
+        write!(out, "{}", layout)?
+    }
+    Ok(())
+}
+
+fn query<R>(args: &[String], registry: &TypeRegistry<R>, out: &mut dyn Write) -> Result<()> {
+    let with_value = [
+        &SHAPE_OPTIONS[..],
+        &["--filter", "--select", "-o", "--output"],
+    ]
+    .concat();
+    let args = Args::parse(args, &with_value, &["--csv"])?;
+    let mut query = Query::new(shape_of_args(&args, registry)?);
+    if let Some(filter) = args.value("--filter") {
+        query = query.filter(Predicate::parse(filter)?)
+    }
+    if let Some(select) = args.value("--select") {
+        query = query.select(Path::parse_list(select)?)
+    }
+    if args.flag("--csv") {
+        query = query.format(Format::Csv)
+    }
+    let path = input_path(&args)?;
+    let output = args.value("-o").or_else(|| args.value("--output"));
+    with_output(output, out, |out| {
+        if path == "-" {
+            query.run(std::io::stdin().lock(), out)?;
+        } else {
+            let input = std::io::BufReader::new(std::fs::File::open(path)?);
+            query.run(input, out)?;
+        }
+        Ok(())
+    })
+}
+
+/// Runs the command line tool with the given arguments, the program name
+/// excluded. The output of the command is written to `out`.
+pub fn run(args: &[String], out: &mut dyn Write) -> Result<()> {
+    run_with_registry(args, &TypeRegistry::<()>::new(), out)
+}
+
+/// Same as [`run`], the types of `registry` can be selected by name with
+/// `--type` in the decode, encode and query commands.
+pub fn run_with_registry<R>(
+    args: &[String],
+    registry: &TypeRegistry<R>,
+    out: &mut dyn Write,
+) -> Result<()> {
+    match args.first().map(|s| s.as_str()) {
+        Some("gen-rust") => gen_rust(&args[1..], out),
+        Some("gen-ocaml") => gen_ocaml(&args[1..], out),
+        Some("diff") => diff(&args[1..], out),
+        Some("explain") => explain(&args[1..], out),
+        Some("layout") => layout(&args[1..], out),
+        Some("decode") => decode(&args[1..], registry, out),
+        Some("encode") => encode(&args[1..], registry, out),
+        Some("query") => query(&args[1..], registry, out),
+        Some("help" | "--help" | "-h") => {
+            out.write_all(USAGE.as_bytes())?;
+            Ok(())
+        }
+        Some(command) => Err(usage_error(&format!("unknown command {}", command))),
+        None => Err(usage_error("missing command")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, run_with_registry};
+    use crate::registry::{TypeKey, TypeRegistry};
+    use crate::{Error, HasShape};
+
+    // A temporary file, removed when dropped so that failing tests do not
+    // leave it behind.
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> TempFile {
+            let path =
+                std::env::temp_dir().join(format!("binprot-cli-{}-{}", std::process::id(), name));
+            std::fs::write(&path, contents).unwrap();
+            TempFile(path.display().to_string())
+        }
+    }
+
+    impl std::ops::Deref for TempFile {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn run_args(args: &[&str]) -> crate::Result<Vec<u8>> {
+        let args: Vec<_> = args.iter().map(|s| s.to_string()).collect();
+        let mut out = vec![];
+        run(&args, &mut out).map(|()| out)
+    }
+
+    #[test]
+    fn test_gen_rust() {
+        let ml = TempFile::new("gen.ml", "type t = { a : int; b : string list }\n");
+        let out = String::from_utf8(run_args(&["gen-rust", &ml]).unwrap()).unwrap();
+        assert!(out.contains("pub struct T {\n    pub a: i64,\n    pub b: Vec<String>,\n}"));
+
+        std::fs::write(&*ml, "type t = { a : int;\n b : int -> int }\n").unwrap();
+        let err = run_args(&["gen-rust", &ml]).unwrap_err().to_string();
+        assert!(err.contains(&format!("{}:2:", &*ml)), "{}", err);
+
+        assert!(run_args(&["frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let sexp = <(i64, Vec<String>)>::shape().to_sexp();
+        let shape = TempFile::new("diff-shape", &sexp.to_string_hum());
+        let left = TempFile::new("diff-left", "0a 01 03 66 6f 6f\n");
+        let right = TempFile::new("diff-right", "0b01036261 72");
+        let out = run_args(&["diff", &shape, &left, &right, "--hex"]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, ".0: 10 vs 11\n.1[0]: foo vs bar\n");
+    }
+
+    #[test]
+    fn test_decode_encode() {
+        let ml = TempFile::new("t.ml", "type t = A | B of { x : int option }\n");
+        let json = TempFile::new("json", "\"A\"\n{\"B\": {\"x\": 300}}\n");
+        let data = TempFile::new("data", "");
+        let shape = ["--ocaml", &ml, "--type", "t"];
+        let encode = [&["encode", &json, "--framed", "-o", &data], &shape[..]].concat();
+        assert!(run_args(&encode).unwrap().is_empty());
+        let out = run_args(&[&["decode", &data, "--framed", "--sexp"], &shape[..]].concat());
+        let out = String::from_utf8(out.unwrap()).unwrap();
+        assert_eq!(out, "A\n(B(x(300)))\n");
+
+        // The s-expressions printed by decode are read back by encode.
+        let sexp = TempFile::new("sexp", &out);
+        let copy = TempFile::new("copy", "");
+        let encode = [
+            &["encode", &sexp, "--framed", "--sexp", "-o", &copy],
+            &shape[..],
+        ];
+        assert!(run_args(&encode.concat()).unwrap().is_empty());
+        assert_eq!(
+            std::fs::read(&*copy).unwrap(),
+            std::fs::read(&*data).unwrap()
+        );
+        let encode = [&["encode", &json, "--framed", "--hex"], &shape[..]].concat();
+        let out = run_args(&encode).unwrap();
+        assert_eq!(out, b"01000000000000000005000000000000000101fe2c01\n");
+
+        let query = [
+            "query",
+            &data,
+            "--filter",
+            ".B.x > 10",
+            "--select",
+            ".B.x",
+            "--csv",
+        ];
+        let out = run_args(&[&query[..], &shape[..]].concat()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "B.x\n300\n");
+
+        // The error offsets are relative to the whole stream.
+        let mut bytes = std::fs::read(&*data).unwrap();
+        bytes[18] = 2;
+        std::fs::write(&*data, bytes).unwrap();
+        match run_args(&[&["decode", &data, "--framed"], &shape[..]].concat()) {
+            Err(Error::AtPath { path, offset, msg }) => {
+                assert_eq!((path.as_str(), offset), (".B.x", 18));
+                assert_eq!(msg, "message 1: ExpectedOption");
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(run_args(&[&["encode", &json], &shape[..]].concat()).is_err());
+
+        let mut registry = TypeRegistry::new();
+        registry
+            .register(TypeKey::of::<Vec<i64>>(), |_: Vec<i64>| ())
+            .unwrap();
+        std::fs::write(&*data, "02 01 ff ff").unwrap();
+        let args = ["decode", "--type", "Vec<i64>", "--hex", &data];
+        let args: Vec<_> = args.iter().map(|s| s.to_string()).collect();
+        let mut out = vec![];
+        run_with_registry(&args, &registry, &mut out).unwrap();
+        assert_eq!(out, b"[1,-1]\n");
+        let err = run_args(&["decode", "--type", "Vec<i64>", &data]).unwrap_err();
+        assert!(err.to_string().contains("no types are registered"));
+    }
+
+    #[test]
+    fn test_sexp_bytes() {
+        let ml = TempFile::new("u.ml", "type u = { b : bytes; c : char }\n");
+        let data = TempFile::new("bytes", "03 ff 00 61 e9");
+        let shape = ["--ocaml", &ml, "--type", "u"];
+        let out = run_args(&[&["decode", &data, "--hex", "--sexp"], &shape[..]].concat());
+        let out = String::from_utf8(out.unwrap()).unwrap();
+        assert_eq!(out, "((b\"\\255\\000a\")(c\"\\233\"))\n");
+        let sexp = TempFile::new("bytes-sexp", &out);
+        let out = run_args(&[&["encode", &sexp, "--hex", "--sexp"], &shape[..]].concat());
+        assert_eq!(out.unwrap(), b"03ff0061e9\n");
+    }
+}
@@ -0,0 +1,1393 @@
+//! Code generation between OCaml type declarations and Rust types.
+//!
+//! In the OCaml to Rust direction, the generated types use the serde derives
+//! and the wrapper types from this crate so that `to_vec` and `from_slice`
+//! are byte-compatible with the `[@@deriving bin_io]` functions on the OCaml
+//! side. The code can be generated from a `build.rs` script:
+//!
+//! ```no_run
+//! let out_dir = std::env::var("OUT_DIR").unwrap();
+//! let options = serde_binprot::codegen::RustOptions::default();
+//! let code = serde_binprot::codegen::rust_of_ocaml_file("src/order.ml", &options).unwrap();
+//! std::fs::write(format!("{}/order.rs", out_dir), code).unwrap();
+//! ```
+//!
+//! and then included with `include!(concat!(env!("OUT_DIR"), "/order.rs"));`.
+//!
+//! Polymorphic variants are supported when they are the body of a type
+//! declaration, without type parameters: the generated enum gets its own
+//! serde implementations, and its `HasShape` implementation when
+//! [`RustOptions::bin_shape`] is set.
+//!
+//! In the other direction, [`ocaml_of_type`] generates OCaml declarations
+//! from the shape of a Rust type.
+use crate::error::{Error, Result};
+use crate::ocaml::{
+    Constructor, ConstructorArgs, Declarations, Field, PolyTag, TypeDecl, TypeExpr, TypeKind,
+};
+use crate::shape::{map_args, uuid, Shape};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Default)]
+pub struct RustOptions {
+    /// Also derive `serde_binprot::BinShape` for the generated types.
+    pub bin_shape: bool,
+}
+
+const RUST_KEYWORDS: [&str; 48] = [
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
+// `snake_case` or `Snake_case` to `CamelCase`.
+fn camel_case(s: &str) -> String {
+    s.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                None => String::new(),
+                Some(c) => c.to_ascii_uppercase().to_string() + chars.as_str(),
+            }
+        })
+        .collect::<String>()
+        .replace('\'', "_")
+}
+
+fn field_name(name: &str) -> (String, Option<&str>) {
+    if ["self", "super", "crate", "Self"].contains(&name) {
+        (format!("{}_", name), Some(name))
+    } else if RUST_KEYWORDS.contains(&name) {
+        (format!("r#{}", name), None)
+    } else if name.contains('\'') {
+        (name.replace('\'', "_"), Some(name))
+    } else {
+        (name.to_string(), None)
+    }
+}
+
+fn type_param(param: &str) -> String {
+    camel_case(param)
+}
+
+fn unsupported(line: usize, column: usize, msg: &str) -> Error {
+    Error::ParseError {
+        line,
+        column,
+        msg: msg.to_string(),
+    }
+}
+
+struct Generator<'a> {
+    decls: &'a Declarations,
+    names: Vec<Option<String>>,
+    options: &'a RustOptions,
+    // Whether the references of a declaration to itself are written as the
+    // recursive occurrence used by `HasShape`.
+    rec_app: std::cell::Cell<bool>,
+}
+
+impl Generator<'_> {
+    fn type_expr(&self, ty: &TypeExpr, from: usize, boxed: bool) -> Result<String> {
+        let decl = &self.decls.decls[from];
+        match ty {
+            TypeExpr::Var(v) => {
+                if !decl.params.contains(v) {
+                    return Err(unsupported(
+                        decl.line,
+                        decl.column,
+                        &format!("unbound type variable '{}", v),
+                    ));
+                }
+                Ok(type_param(v))
+            }
+            TypeExpr::Tuple(tys) => {
+                let tys = tys
+                    .iter()
+                    .map(|ty| self.type_expr(ty, from, boxed))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(format!("({})", tys.join(", ")))
+            }
+            TypeExpr::PolyVariant(tags) => {
+                let (line, column) = tags
+                    .iter()
+                    .find_map(|tag| match tag {
+                        PolyTag::Tag(_, Some(TypeExpr::Constr { line, column, .. })) => {
+                            Some((*line, *column))
+                        }
+                        _ => None,
+                    })
+                    .unwrap_or((decl.line, decl.column));
+                Err(unsupported(
+                    line,
+                    column,
+                    "polymorphic variants are only supported as the body of a declaration",
+                ))
+            }
+            TypeExpr::Constr {
+                name,
+                args,
+                line,
+                column,
+            } => {
+                if let Some(target) = self.decls.resolve(name, from) {
+                    if target == from && self.rec_app.get() {
+                        return Ok("serde_binprot::shape::ShapeRecApp<()>".to_string());
+                    }
+                    let target_decl = &self.decls.decls[target];
+                    let rust_name = match &self.names[target] {
+                        Some(rust_name) => rust_name,
+                        None => {
+                            return Err(unsupported(
+                                *line,
+                                *column,
+                                &format!("type {} is abstract", name),
+                            ))
+                        }
+                    };
+                    if target_decl.params.len() != args.len() {
+                        return Err(unsupported(
+                            *line,
+                            *column,
+                            &format!(
+                                "type {} expects {} argument(s) but got {}",
+                                name,
+                                target_decl.params.len(),
+                                args.len()
+                            ),
+                        ));
+                    }
+                    let args = args
+                        .iter()
+                        .map(|ty| self.type_expr(ty, from, false))
+                        .collect::<Result<Vec<_>>>()?;
+                    let mut rust_type = rust_name.clone();
+                    if !args.is_empty() {
+                        rust_type = format!("{}<{}>", rust_type, args.join(", "))
+                    }
+                    // References to the same recursive group are boxed,
+                    // unless already behind a container.
+                    let recursive = !decl.nonrec && target_decl.group == decl.group;
+                    if recursive && boxed {
+                        rust_type = format!("Box<{}>", rust_type)
+                    }
+                    return Ok(rust_type);
+                }
+                // The standard types are mapped using their shapes, the
+                // arguments are represented by variables.
+                let vars = (0..args.len()).map(Shape::Var).collect();
+                let shape = match crate::ocaml::core_type(name, vars) {
+                    Some(shape) => shape.map_err(|msg| unsupported(*line, *column, &msg))?,
+                    None => {
+                        return Err(unsupported(
+                            *line,
+                            *column,
+                            &format!("unknown type {}", name),
+                        ))
+                    }
+                };
+                let indirect = match &shape {
+                    Shape::Base(uuid, _) => {
+                        uuid != uuid::OPTION && uuid != uuid::REF && uuid != uuid::LAZY
+                    }
+                    _ => true,
+                };
+                let args = args
+                    .iter()
+                    .map(|ty| self.type_expr(ty, from, boxed && !indirect))
+                    .collect::<Result<Vec<_>>>()?;
+                let stripped = crate::ocaml::strip_stdlib_prefix(name);
+                if stripped == "Nonempty_list.t" {
+                    return Ok(format!(
+                        "serde_binprot::containers::NonemptyList<{}>",
+                        args[0]
+                    ));
+                }
+                // The variants of `Result` are written with the same tags.
+                if stripped == "Result.t" {
+                    return Ok(format!("std::result::Result<{}, {}>", args[0], args[1]));
+                }
+                rust_of_shape(&shape, &args).map_err(|msg| unsupported(*line, *column, &msg))
+            }
+        }
+    }
+
+    fn fields(&self, fields: &[Field], from: usize, visibility: &str) -> Result<Vec<String>> {
+        let mut lines = vec![];
+        for field in fields.iter() {
+            let (name, rename) = field_name(&field.name);
+            if let Some(rename) = rename {
+                lines.push(format!("#[serde(rename = \"{}\")]", rename))
+            }
+            let ty = self.type_expr(&field.ty, from, true)?;
+            lines.push(format!("{}{}: {},", visibility, name, ty))
+        }
+        Ok(lines)
+    }
+
+    fn decl(&self, index: usize, out: &mut String) -> Result<()> {
+        let decl = &self.decls.decls[index];
+        let name = match &self.names[index] {
+            None => return Ok(()),
+            Some(name) => name,
+        };
+        let mut generics = String::new();
+        if !decl.params.is_empty() {
+            let params: Vec<_> = decl.params.iter().map(|p| type_param(p)).collect();
+            generics = format!("<{}>", params.join(", "))
+        }
+        if decl.params.iter().any(|p| p == "_") {
+            return Err(unsupported(
+                decl.line,
+                decl.column,
+                "anonymous type parameters are not supported",
+            ));
+        }
+        let mut derives = vec![
+            "Debug",
+            "Clone",
+            "PartialEq",
+            "serde::Serialize",
+            "serde::Deserialize",
+        ];
+        if self.options.bin_shape {
+            derives.push("serde_binprot::BinShape")
+        }
+        let derives = format!("#[derive({})]", derives.join(", "));
+        match &decl.kind {
+            TypeKind::Abstract => {}
+            TypeKind::Alias(TypeExpr::PolyVariant(tags)) => {
+                if !decl.params.is_empty() {
+                    return Err(unsupported(
+                        decl.line,
+                        decl.column,
+                        "polymorphic variants with type parameters are not supported",
+                    ));
+                }
+                self.poly_variant(index, name, tags, out)?
+            }
+            TypeKind::Alias(ty) => {
+                let ty = self.type_expr(ty, index, true)?;
+                writeln!(out, "pub type {}{} = {};", name, generics, ty).unwrap();
+            }
+            TypeKind::Record(fields) => {
+                check_params_used(decl)?;
+                writeln!(out, "{}", derives).unwrap();
+                writeln!(out, "pub struct {}{} {{", name, generics).unwrap();
+                for line in self.fields(fields, index, "pub ")? {
+                    writeln!(out, "    {}", line).unwrap();
+                }
+                writeln!(out, "}}").unwrap();
+            }
+            TypeKind::Variant(constructors) => {
+                check_params_used(decl)?;
+                if constructors.len() > 256 {
+                    return Err(unsupported(
+                        decl.line,
+                        decl.column,
+                        "only variants with at most 256 constructors are supported",
+                    ));
+                }
+                writeln!(out, "{}", derives).unwrap();
+                writeln!(out, "pub enum {}{} {{", name, generics).unwrap();
+                for constructor in constructors.iter() {
+                    let mut rust_name = camel_case(&constructor.name);
+                    if rust_name == "[]" || rust_name == "()" || rust_name.is_empty() {
+                        rust_name = "Nil".to_string()
+                    }
+                    if rust_name != constructor.name {
+                        writeln!(out, "    #[serde(rename = \"{}\")]", constructor.name).unwrap();
+                    }
+                    match &constructor.args {
+                        ConstructorArgs::Tuple(args) if args.is_empty() => {
+                            writeln!(out, "    {},", rust_name).unwrap();
+                        }
+                        ConstructorArgs::Tuple(args) => {
+                            let args = args
+                                .iter()
+                                .map(|ty| self.type_expr(ty, index, true))
+                                .collect::<Result<Vec<_>>>()?;
+                            writeln!(out, "    {}({}),", rust_name, args.join(", ")).unwrap();
+                        }
+                        ConstructorArgs::Record(fields) => {
+                            writeln!(out, "    {} {{", rust_name).unwrap();
+                            for line in self.fields(fields, index, "")? {
+                                writeln!(out, "        {}", line).unwrap();
+                            }
+                            writeln!(out, "    }},").unwrap();
+                        }
+                    }
+                }
+                writeln!(out, "}}").unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    // Polymorphic variants are generated as enums with serde implementations
+    // writing the hash of the constructor names as tags. The arguments of a
+    // constructor follow its tag.
+    fn poly_variant(
+        &self,
+        index: usize,
+        name: &str,
+        tags: &[PolyTag],
+        out: &mut String,
+    ) -> Result<()> {
+        let decl = &self.decls.decls[index];
+        let mut constructors = vec![];
+        for tag in tags.iter() {
+            match tag {
+                PolyTag::Tag(tag, arg) => {
+                    let args = match arg {
+                        None => vec![],
+                        Some(TypeExpr::Tuple(tys)) => tys.iter().collect(),
+                        Some(ty) => vec![ty],
+                    };
+                    let args = args
+                        .into_iter()
+                        .map(|ty| self.type_expr(ty, index, true))
+                        .collect::<Result<Vec<_>>>()?;
+                    constructors.push((tag, camel_case(tag), args))
+                }
+                PolyTag::Inherit(_) => {
+                    return Err(unsupported(
+                        decl.line,
+                        decl.column,
+                        "inherited polymorphic variants are not supported",
+                    ))
+                }
+            }
+        }
+        let len = 1 + constructors
+            .iter()
+            .map(|(_, _, args)| args.len())
+            .max()
+            .unwrap_or(0);
+        writeln!(out, "#[derive(Debug, Clone, PartialEq)]").unwrap();
+        writeln!(out, "pub enum {} {{", name).unwrap();
+        for (_, rust_name, args) in constructors.iter() {
+            if args.is_empty() {
+                writeln!(out, "    {},", rust_name).unwrap();
+            } else {
+                writeln!(out, "    {}({}),", rust_name, args.join(", ")).unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        writeln!(out).unwrap();
+
+        writeln!(out, "impl serde::Serialize for {} {{", name).unwrap();
+        writeln!(out, "    fn serialize<__S: serde::Serializer>(&self, serializer: __S) -> std::result::Result<__S::Ok, __S::Error> {{").unwrap();
+        writeln!(out, "        use serde::ser::SerializeTuple;").unwrap();
+        writeln!(out, "        match self {{").unwrap();
+        for (tag, rust_name, args) in constructors.iter() {
+            let vars: Vec<_> = (0..args.len()).map(|i| format!("a{}", i)).collect();
+            if vars.is_empty() {
+                writeln!(out, "            {}::{} => {{", name, rust_name).unwrap();
+            } else {
+                writeln!(
+                    out,
+                    "            {}::{}({}) => {{",
+                    name,
+                    rust_name,
+                    vars.join(", ")
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "                let mut tuple = serializer.serialize_tuple({})?;",
+                1 + vars.len()
+            )
+            .unwrap();
+            writeln!(out, "                tuple.serialize_element(&serde_binprot::PolyVariantTag::new(\"{}\"))?;", tag).unwrap();
+            for var in vars.iter() {
+                writeln!(out, "                tuple.serialize_element({})?;", var).unwrap();
+            }
+            writeln!(out, "                tuple.end()").unwrap();
+            writeln!(out, "            }}").unwrap();
+        }
+        writeln!(out, "        }}").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}").unwrap();
+        writeln!(out).unwrap();
+
+        writeln!(out, "impl<'de> serde::Deserialize<'de> for {} {{", name).unwrap();
+        writeln!(out, "    fn deserialize<__D: serde::Deserializer<'de>>(deserializer: __D) -> std::result::Result<Self, __D::Error> {{").unwrap();
+        writeln!(out, "        struct __Visitor;").unwrap();
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "        impl<'de> serde::de::Visitor<'de> for __Visitor {{"
+        )
+        .unwrap();
+        writeln!(out, "            type Value = {};", name).unwrap();
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {{"
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "                f.write_str(\"a polymorphic variant\")"
+        )
+        .unwrap();
+        writeln!(out, "            }}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "            fn visit_seq<__A: serde::de::SeqAccess<'de>>(self, mut seq: __A) -> std::result::Result<{}, __A::Error> {{", name).unwrap();
+        writeln!(out, "                let missing = || <__A::Error as serde::de::Error>::custom(\"missing polymorphic variant argument\");").unwrap();
+        writeln!(out, "                let tag: serde_binprot::PolyVariantTag = seq.next_element()?.ok_or_else(missing)?;").unwrap();
+        for (i, (tag, rust_name, args)) in constructors.iter().enumerate() {
+            let keyword = if i == 0 { "if" } else { "} else if" };
+            writeln!(
+                out,
+                "                {} tag == serde_binprot::PolyVariantTag::new(\"{}\") {{",
+                keyword, tag
+            )
+            .unwrap();
+            if args.is_empty() {
+                writeln!(out, "                    Ok({}::{})", name, rust_name).unwrap();
+            } else {
+                writeln!(out, "                    Ok({}::{}(", name, rust_name).unwrap();
+                for _ in args.iter() {
+                    writeln!(
+                        out,
+                        "                        seq.next_element()?.ok_or_else(missing)?,"
+                    )
+                    .unwrap();
+                }
+                writeln!(out, "                    ))").unwrap();
+            }
+        }
+        writeln!(out, "                }} else {{").unwrap();
+        writeln!(out, "                    Err(serde::de::Error::custom(format!(\"unknown polymorphic variant hash {{}}\", tag.0)))").unwrap();
+        writeln!(out, "                }}").unwrap();
+        writeln!(out, "            }}").unwrap();
+        writeln!(out, "        }}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "        deserializer.deserialize_tuple({}, __Visitor)",
+            len
+        )
+        .unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}").unwrap();
+
+        if self.options.bin_shape {
+            writeln!(out).unwrap();
+            writeln!(out, "impl serde_binprot::HasShape for {} {{", name).unwrap();
+            writeln!(out, "    fn shape() -> serde_binprot::Shape {{").unwrap();
+            writeln!(
+                out,
+                "        let mut tags = std::collections::BTreeMap::new();"
+            )
+            .unwrap();
+            self.rec_app.set(true);
+            for tag in tags.iter() {
+                if let PolyTag::Tag(tag, arg) = tag {
+                    let arg = match arg {
+                        None => "None".to_string(),
+                        Some(ty) => {
+                            let ty = self.type_expr(ty, index, false);
+                            format!("Some(<{} as serde_binprot::HasShape>::shape())", ty?)
+                        }
+                    };
+                    writeln!(
+                        out,
+                        "        tags.insert(\"{}\".to_string(), {});",
+                        tag, arg
+                    )
+                    .unwrap();
+                }
+            }
+            self.rec_app.set(false);
+            writeln!(
+                out,
+                "        let body = serde_binprot::Shape::PolyVariant(tags);"
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "        serde_binprot::Shape::Application(Box::new(body), vec![])"
+            )
+            .unwrap();
+            writeln!(out, "    }}").unwrap();
+            writeln!(out, "}}").unwrap();
+        }
+        Ok(())
+    }
+}
+
+// Rust rejects the type parameters that are not used in a struct or enum.
+fn check_params_used(decl: &TypeDecl) -> Result<()> {
+    fn uses(ty: &TypeExpr, param: &str) -> bool {
+        match ty {
+            TypeExpr::Var(v) => v == param,
+            TypeExpr::Tuple(tys) => tys.iter().any(|ty| uses(ty, param)),
+            TypeExpr::Constr { args, .. } => args.iter().any(|ty| uses(ty, param)),
+            TypeExpr::PolyVariant(_) => false,
+        }
+    }
+    let fields_use = |fields: &[Field], param: &str| fields.iter().any(|f| uses(&f.ty, param));
+    for param in decl.params.iter() {
+        let used = match &decl.kind {
+            TypeKind::Abstract | TypeKind::Alias(_) => true,
+            TypeKind::Record(fields) => fields_use(fields, param),
+            TypeKind::Variant(constructors) => constructors.iter().any(|c| match &c.args {
+                ConstructorArgs::Tuple(args) => args.iter().any(|ty| uses(ty, param)),
+                ConstructorArgs::Record(fields) => fields_use(fields, param),
+            }),
+        };
+        if !used {
+            return Err(unsupported(
+                decl.line,
+                decl.column,
+                &format!("type parameter '{} of {} is not used", param, decl.name),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// The Rust type for the shape of a standard type, [Var i] being replaced by
+// the i-th argument.
+fn rust_of_shape(shape: &Shape, args: &[String]) -> std::result::Result<String, String> {
+    let rust = |shape: &Shape| rust_of_shape(shape, args);
+    match shape {
+        Shape::Var(index) => Ok(args[*index].clone()),
+        Shape::Tuple(shapes) => {
+            let shapes = shapes
+                .iter()
+                .map(rust)
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            Ok(format!("({})", shapes.join(", ")))
+        }
+        Shape::Base(uuid, shapes) => {
+            let shapes = map_args(uuid, shapes)
+                .iter()
+                .map(rust)
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            let rust_type = match uuid.as_str() {
+                uuid::UNIT => "()",
+                uuid::BOOL => "bool",
+                uuid::STRING => "String",
+                uuid::BYTES | uuid::BIGSTRING => "serde_binprot::Bigstring",
+                uuid::CHAR => "char",
+                uuid::FLOAT => "f64",
+                uuid::INT | uuid::INT63 | uuid::INT64 | uuid::NATIVEINT => "i64",
+                uuid::INT32 => "i32",
+                uuid::NAT0 => "u64",
+                uuid::REF | uuid::LAZY => return Ok(shapes[0].clone()),
+                uuid::OPTION => "Option",
+                uuid::LIST | uuid::ARRAY => "Vec",
+                uuid::DEQUE => "serde_binprot::containers::Deque",
+                uuid::MAP => "serde_binprot::containers::Map",
+                uuid::SET => "serde_binprot::containers::Set",
+                uuid::HASHTBL => "std::collections::HashMap",
+                uuid::HASH_SET => "std::collections::HashSet",
+                uuid => return Err(format!("no Rust type for {}", uuid)),
+            };
+            if shapes.is_empty() {
+                Ok(rust_type.to_string())
+            } else {
+                Ok(format!("{}<{}>", rust_type, shapes.join(", ")))
+            }
+        }
+        shape => Err(format!("no Rust type for {:?}", shape)),
+    }
+}
+
+// The Rust names for the declarations, `None` for the abstract ones, and
+// whether a Rust type is generated for each declaration. A type `t` is
+// named after its module, and the other types are qualified with their
+// module path when names collide.
+fn rust_names(decls: &Declarations) -> Result<(Vec<Option<String>>, Vec<bool>)> {
+    let simple_name = |decl: &TypeDecl| match decl.module_path.last() {
+        Some(module) if decl.name == "t" => camel_case(module),
+        _ => camel_case(&decl.name),
+    };
+    let qualified_name = |decl: &TypeDecl| {
+        let mut name: String = decl.module_path.iter().map(|m| camel_case(m)).collect();
+        if decl.name != "t" || decl.module_path.is_empty() {
+            name.push_str(&camel_case(&decl.name))
+        }
+        name
+    };
+    let mut names: Vec<Option<String>> = decls
+        .decls
+        .iter()
+        .map(|decl| match decl.kind {
+            TypeKind::Abstract => None,
+            _ => Some(simple_name(decl)),
+        })
+        .collect();
+    // Aliases to a type with the same Rust name, e.g. `type side = Side.t`,
+    // are the same type on the Rust side.
+    let mut same_as = vec![None; names.len()];
+    for (index, decl) in decls.decls.iter().enumerate() {
+        if let TypeKind::Alias(TypeExpr::Constr { name, .. }) = &decl.kind {
+            if let Some(target) = decls.resolve(name, index) {
+                if target != index && names[target] == names[index] {
+                    same_as[index] = Some(target)
+                }
+            }
+        }
+    }
+    let generated: Vec<bool> = (0..names.len())
+        .map(|index| names[index].is_some() && same_as[index].is_none())
+        .collect();
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for (index, name) in names.iter().enumerate() {
+        if let (Some(name), true) = (name, generated[index]) {
+            *counts.entry(name.clone()).or_default() += 1
+        }
+    }
+    for (index, decl) in decls.decls.iter().enumerate() {
+        if let (Some(name), true) = (&names[index], generated[index]) {
+            if counts[name] > 1 {
+                names[index] = Some(qualified_name(decl))
+            }
+        }
+    }
+    for index in 0..names.len() {
+        if let Some(target) = same_as[index] {
+            names[index] = names[target].clone()
+        }
+    }
+    let mut seen: BTreeMap<&str, usize> = BTreeMap::new();
+    for (index, name) in names.iter().enumerate() {
+        if let (Some(name), true) = (name, generated[index]) {
+            if let Some(previous) = seen.insert(name, index) {
+                return Err(unsupported(
+                    decls.decls[index].line,
+                    decls.decls[index].column,
+                    &format!(
+                        "type {} is also defined on line {}",
+                        name, decls.decls[previous].line
+                    ),
+                ));
+            }
+        }
+    }
+    Ok((names, generated))
+}
+
+/// Generates Rust types for all the type declarations.
+pub fn rust_of_declarations(decls: &Declarations, options: &RustOptions) -> Result<String> {
+    let (names, generated) = rust_names(decls)?;
+    let generator = Generator {
+        decls,
+        names,
+        options,
+        rec_app: std::cell::Cell::new(false),
+    };
+    let mut out = String::new();
+    out.push_str("// Generated by serde-binprot from OCaml type declarations, do not edit.\n");
+    for (index, &generated) in generated.iter().enumerate() {
+        if generated {
+            out.push('\n');
+            generator.decl(index, &mut out)?;
+        }
+    }
+    Ok(out)
+}
+
+/// Generates Rust types from the content of an OCaml source file.
+pub fn rust_of_ocaml(src: &str, options: &RustOptions) -> Result<String> {
+    rust_of_declarations(&Declarations::parse(src)?, options)
+}
+
+/// Generates Rust types from an OCaml source file.
+pub fn rust_of_ocaml_file<P: AsRef<std::path::Path>>(
+    path: P,
+    options: &RustOptions,
+) -> Result<String> {
+    let src = std::fs::read_to_string(path)?;
+    rust_of_ocaml(&src, options)
+}
+
+const OCAML_KEYWORDS: [&str; 56] = [
+    "and",
+    "as",
+    "assert",
+    "asr",
+    "begin",
+    "class",
+    "constraint",
+    "do",
+    "done",
+    "downto",
+    "else",
+    "end",
+    "exception",
+    "external",
+    "false",
+    "for",
+    "fun",
+    "function",
+    "functor",
+    "if",
+    "in",
+    "include",
+    "inherit",
+    "initializer",
+    "land",
+    "lazy",
+    "let",
+    "lor",
+    "lsl",
+    "lsr",
+    "lxor",
+    "match",
+    "method",
+    "mod",
+    "module",
+    "mutable",
+    "new",
+    "nonrec",
+    "object",
+    "of",
+    "open",
+    "or",
+    "private",
+    "rec",
+    "sig",
+    "struct",
+    "then",
+    "to",
+    "true",
+    "try",
+    "type",
+    "val",
+    "virtual",
+    "when",
+    "while",
+    "with",
+];
+
+// `CamelCase` to `snake_case`.
+fn snake_case(s: &str) -> String {
+    let mut res = String::new();
+    for (i, c) in s.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 && !res.ends_with('_') {
+                res.push('_')
+            }
+            res.push(c.to_ascii_lowercase())
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '\'' {
+            res.push(c)
+        } else {
+            res.push('_')
+        }
+    }
+    if !res.starts_with(|c: char| c.is_ascii_lowercase() || c == '_') {
+        res.insert(0, '_')
+    }
+    if OCAML_KEYWORDS.contains(&res.as_str()) {
+        res.push('_')
+    }
+    res
+}
+
+fn ocaml_constructor(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() => c.to_ascii_uppercase().to_string() + chars.as_str(),
+        _ => format!("C{}", s),
+    }
+}
+
+fn param_name(index: usize) -> String {
+    let letter = (b'a' + (index % 26) as u8) as char;
+    if index < 26 {
+        letter.to_string()
+    } else {
+        format!("{}{}", letter, index / 26)
+    }
+}
+
+// A type declaration being generated. Applications introduce a new scope
+// for type variables and recursive references, the records and variants
+// that are not at the top of a declaration get their own declaration too.
+struct Frame {
+    name: String,
+    params: Vec<String>,
+    is_application: bool,
+    // The lowest frame index referred to from this frame or from its
+    // children, the frames in between have to be in the same recursive group.
+    low: usize,
+    group: Vec<TypeDecl>,
+}
+
+#[derive(Default)]
+struct OcamlGenerator {
+    decls: Vec<TypeDecl>,
+    groups: usize,
+    used_names: std::collections::BTreeSet<String>,
+    frames: Vec<Frame>,
+    // The declarations already generated for non-recursive applications.
+    known: BTreeMap<Shape, String>,
+    // Whether some declaration refers to the `Nat0` module below.
+    uses_nat0: bool,
+}
+
+// `Bin_prot.Nat0` has no sexp converters so the generated files use this
+// module in its place.
+const NAT0_MODULE: &str = "module Nat0 = struct
+  include Bin_prot.Nat0
+
+  let sexp_of_t t = Int.sexp_of_t (t :> int)
+  let t_of_sexp sexp = of_int (Int.t_of_sexp sexp)
+end
+";
+
+impl OcamlGenerator {
+    fn fresh_name(&mut self, hint: &str) -> String {
+        let hint = snake_case(hint);
+        let mut name = hint.clone();
+        let mut index = 1;
+        while self.used_names.contains(&name) {
+            index += 1;
+            name = format!("{}{}", hint, index)
+        }
+        self.used_names.insert(name.clone());
+        name
+    }
+
+    fn application_frames(&self) -> impl Iterator<Item = (usize, &Frame)> {
+        self.frames
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, frame)| frame.is_application)
+    }
+
+    fn expr(&mut self, shape: &Shape, context: &str) -> Result<TypeExpr> {
+        let constr = |name: &str, args: Vec<TypeExpr>| TypeExpr::Constr {
+            name: name.to_string(),
+            args,
+            line: 0,
+            column: 0,
+        };
+        let expr = match shape {
+            Shape::Annotate(_, shape) => self.expr(shape, context)?,
+            Shape::Base(uuid, args) => {
+                let mut args = map_args(uuid, args)
+                    .iter()
+                    .map(|arg| self.expr(arg, context))
+                    .collect::<Result<Vec<_>>>()?;
+                let key_module = |key: &TypeExpr| match key {
+                    TypeExpr::Constr { name, args, .. } if args.is_empty() => match name.as_str() {
+                        "string" | "int" | "char" | "bool" | "float" | "int32" | "int64" => {
+                            Ok(ocaml_constructor(name))
+                        }
+                        _ => Err(Error::Message(format!(
+                            "unsupported key type {} in {}",
+                            name, context
+                        ))),
+                    },
+                    key => Err(Error::Message(format!(
+                        "unsupported key type {} in {}",
+                        key, context
+                    ))),
+                };
+                let name = match uuid.as_str() {
+                    uuid::UNIT => "unit",
+                    uuid::BOOL => "bool",
+                    uuid::STRING => "string",
+                    uuid::BYTES => "bytes",
+                    uuid::CHAR => "char",
+                    uuid::FLOAT => "float",
+                    uuid::INT => "int",
+                    uuid::INT32 => "int32",
+                    uuid::INT63 => "Int63.t",
+                    uuid::INT64 => "int64",
+                    uuid::NATIVEINT => "nativeint",
+                    uuid::NAT0 => {
+                        self.uses_nat0 = true;
+                        "Nat0.t"
+                    }
+                    uuid::BIGSTRING => "Bigstring.t",
+                    uuid::REF => "ref",
+                    uuid::LAZY => "lazy_t",
+                    uuid::OPTION => "option",
+                    uuid::LIST => "list",
+                    uuid::ARRAY => "array",
+                    uuid::DEQUE => "Deque.t",
+                    uuid::MAP | uuid::SET | uuid::HASHTBL | uuid::HASH_SET if !args.is_empty() => {
+                        let key = key_module(&args.remove(0))?;
+                        let container = match uuid.as_str() {
+                            uuid::MAP => "Map",
+                            uuid::SET => "Set",
+                            uuid::HASHTBL => "Hashtbl",
+                            _ => "Hash_set",
+                        };
+                        return Ok(constr(&format!("{}.M({}).t", container, key), args));
+                    }
+                    uuid => {
+                        return Err(Error::Message(format!(
+                            "no OCaml type for {} in {}",
+                            uuid, context
+                        )))
+                    }
+                };
+                constr(name, args)
+            }
+            Shape::Tuple(shapes) => match shapes.as_slice() {
+                [] => {
+                    return Err(Error::Message(format!(
+                        "empty tuples have no OCaml equivalent in {}",
+                        context
+                    )))
+                }
+                [shape] => self.expr(shape, context)?,
+                shapes => TypeExpr::Tuple(
+                    shapes
+                        .iter()
+                        .map(|shape| self.expr(shape, context))
+                        .collect::<Result<Vec<_>>>()?,
+                ),
+            },
+            Shape::Record(_) | Shape::Variant(_) => {
+                let params = match self.application_frames().next() {
+                    Some((_, frame)) => frame.params.clone(),
+                    None => vec![],
+                };
+                let name = self.decl(context, shape, false, params.clone())?;
+                constr(&name, params.into_iter().map(TypeExpr::Var).collect())
+            }
+            Shape::PolyVariant(variants) => {
+                let mut tags = vec![];
+                for (name, arg) in variants.iter() {
+                    let arg = match arg {
+                        None => None,
+                        Some(arg) => Some(self.expr(arg, context)?),
+                    };
+                    tags.push(PolyTag::Tag(name.clone(), arg))
+                }
+                TypeExpr::PolyVariant(tags)
+            }
+            Shape::Application(body, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.expr(arg, context))
+                    .collect::<Result<Vec<_>>>()?;
+                let name = match self.known.get(body.as_ref()) {
+                    Some(name) => name.clone(),
+                    None => {
+                        let params = (0..args.len()).map(param_name).collect();
+                        self.decl(context, body, true, params)?
+                    }
+                };
+                constr(&name, args)
+            }
+            Shape::RecApp(index, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.expr(arg, context))
+                    .collect::<Result<Vec<_>>>()?;
+                let (frame_index, name) = match self.application_frames().nth(*index) {
+                    Some((frame_index, frame)) => (frame_index, frame.name.clone()),
+                    None => {
+                        return Err(Error::Message(format!(
+                            "unbound recursive reference in {}",
+                            context
+                        )))
+                    }
+                };
+                let frame = self.frames.last_mut().unwrap();
+                frame.low = usize::min(frame.low, frame_index);
+                constr(&name, args)
+            }
+            Shape::Var(index) => {
+                let param = self
+                    .application_frames()
+                    .next()
+                    .and_then(|(_, frame)| frame.params.get(*index).cloned());
+                match param {
+                    Some(param) => TypeExpr::Var(param),
+                    None => {
+                        return Err(Error::Message(format!(
+                            "unbound type variable in {}",
+                            context
+                        )))
+                    }
+                }
+            }
+        };
+        Ok(expr)
+    }
+
+    fn fields(&mut self, fields: &[(String, Shape)], context: &str) -> Result<Vec<Field>> {
+        fields
+            .iter()
+            .map(|(name, shape)| {
+                let context = format!("{}_{}", context, name);
+                Ok(Field {
+                    name: snake_case(name),
+                    ty: self.expr(shape, &context)?,
+                    mutable: false,
+                })
+            })
+            .collect()
+    }
+
+    fn kind(&mut self, shape: &Shape, context: &str) -> Result<TypeKind> {
+        let kind = match shape {
+            Shape::Annotate(_, shape) => self.kind(shape, context)?,
+            Shape::Record(fields) => TypeKind::Record(self.fields(fields, context)?),
+            Shape::Variant(variants) => {
+                if variants.len() > 256 {
+                    return Err(Error::Message(format!(
+                        "only variants with at most 256 constructors are supported in {}",
+                        context
+                    )));
+                }
+                let mut constructors = vec![];
+                for (name, args) in variants.iter() {
+                    let context = format!("{}_{}", context, name);
+                    let args = match args.as_slice() {
+                        [Shape::Record(fields)] => {
+                            ConstructorArgs::Record(self.fields(fields, &context)?)
+                        }
+                        args => ConstructorArgs::Tuple(
+                            args.iter()
+                                .map(|arg| self.expr(arg, &context))
+                                .collect::<Result<Vec<_>>>()?,
+                        ),
+                    };
+                    constructors.push(Constructor {
+                        name: ocaml_constructor(name),
+                        args,
+                    })
+                }
+                TypeKind::Variant(constructors)
+            }
+            shape => TypeKind::Alias(self.expr(shape, context)?),
+        };
+        Ok(kind)
+    }
+
+    fn decl(
+        &mut self,
+        hint: &str,
+        body: &Shape,
+        is_application: bool,
+        params: Vec<String>,
+    ) -> Result<String> {
+        let name = self.fresh_name(hint);
+        let index = self.frames.len();
+        self.frames.push(Frame {
+            name: name.clone(),
+            params: params.clone(),
+            is_application,
+            low: index,
+            group: vec![],
+        });
+        let kind = self.kind(body, &name);
+        let frame = self.frames.pop().unwrap();
+        let decl = TypeDecl {
+            name: name.clone(),
+            params,
+            kind: kind?,
+            line: 0,
+            column: 0,
+            group: 0,
+            nonrec: false,
+            module_path: vec![],
+        };
+        if frame.low < index {
+            let parent = self.frames.last_mut().unwrap();
+            parent.low = usize::min(parent.low, frame.low);
+            parent.group.push(decl);
+            parent.group.extend(frame.group);
+        } else {
+            if is_application {
+                self.known.insert(body.clone(), name.clone());
+            }
+            let group = self.groups;
+            self.groups += 1;
+            for mut decl in std::iter::once(decl).chain(frame.group) {
+                decl.group = group;
+                self.decls.push(decl)
+            }
+        }
+        Ok(name)
+    }
+}
+
+fn generate(types: &[(&str, &Shape)]) -> Result<OcamlGenerator> {
+    let mut generator = OcamlGenerator::default();
+    for (name, shape) in types.iter() {
+        match shape {
+            Shape::Application(body, args) if args.is_empty() => {
+                generator.decl(name, body, true, vec![])?;
+            }
+            Shape::Application(body, args)
+                if args.iter().enumerate().all(|(i, a)| a == &Shape::Var(i)) =>
+            {
+                let params = (0..args.len()).map(param_name).collect();
+                generator.decl(name, body, true, params)?;
+            }
+            shape => {
+                generator.decl(name, shape, false, vec![])?;
+            }
+        }
+    }
+    Ok(generator)
+}
+
+/// Generates OCaml type declarations for the given shapes, with the
+/// `[@@deriving bin_io, sexp]` attributes. The layout of the OCaml types
+/// matches the one of this crate `Serializer` for the types with these
+/// shapes. Nested records, variants and type applications get their own
+/// declarations, named after the path leading to them. Unsigned integers
+/// are declared as `Nat0.t`, a module with sexp converters added to
+/// `Bin_prot.Nat0` that [`ocaml_of_shape`] defines.
+pub fn ocaml_of_shapes(types: &[(&str, &Shape)]) -> Result<Declarations> {
+    let generator = generate(types)?;
+    Ok(Declarations {
+        decls: generator.decls,
+    })
+}
+
+/// Generates the content of an OCaml file declaring a type `name` with the
+/// given shape.
+pub fn ocaml_of_shape(name: &str, shape: &Shape) -> Result<String> {
+    let generator = generate(&[(name, shape)])?;
+    let nat0 = if generator.uses_nat0 {
+        format!("{}\n", NAT0_MODULE)
+    } else {
+        String::new()
+    };
+    let decls = Declarations {
+        decls: generator.decls,
+    };
+    Ok(format!(
+        "(* Generated by serde-binprot, do not edit. *)\nopen! Core\n\n{}{}",
+        nat0, decls
+    ))
+}
+
+/// Generates the content of an OCaml file declaring a type `name` with the
+/// same bin_prot layout as `T`.
+pub fn ocaml_of_type<T: crate::HasShape + ?Sized>(name: &str) -> Result<String> {
+    ocaml_of_shape(name, &T::shape())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ocaml_of_type, rust_of_ocaml, RustOptions};
+    use crate::ocaml::Declarations;
+    use crate::{Error, HasShape};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::Serialize;
+
+    #[test]
+    fn test_codegen() {
+        let src = r#"
+module Side = struct
+  type t = Buy | Sell | Short_sell [@@deriving bin_io]
+end
+
+type side = Side.t [@@deriving bin_io]
+
+type 'a tree =
+  | Leaf
+  | Node of 'a tree * 'a * 'a tree
+  | Nodes of { children : 'a tree list; label : 'a tree option }
+[@@deriving bin_io]
+
+type order =
+  { qty : Nat0.t
+  ; side : side
+  ; loop : string
+  ; tags : (string * float) array
+  ; by_id : int String.Map.t
+  ; digest : Md5.t option
+  ; status : (unit, string) Result.t
+  } [@@deriving bin_io]
+"#;
+        let code = rust_of_ocaml(src, &RustOptions::default()).unwrap();
+        let expected = r#"// Generated by serde-binprot from OCaml type declarations, do not edit.
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Side {
+    Buy,
+    Sell,
+    #[serde(rename = "Short_sell")]
+    ShortSell,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Tree<A> {
+    Leaf,
+    Node(Box<Tree<A>>, A, Box<Tree<A>>),
+    Nodes {
+        children: Vec<Tree<A>>,
+        label: Option<Box<Tree<A>>>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Order {
+    pub qty: u64,
+    pub side: Side,
+    pub r#loop: String,
+    pub tags: Vec<(String, f64)>,
+    pub by_id: serde_binprot::containers::Map<String, i64>,
+    pub digest: Option<String>,
+    pub status: std::result::Result<(), String>,
+}
+"#;
+        assert_eq!(code, expected);
+
+        // Polymorphic variants get their own serde implementations.
+        let code = rust_of_ocaml("type t = [ `A | `B of int ]", &RustOptions::default()).unwrap();
+        let expected = r#"// Generated by serde-binprot from OCaml type declarations, do not edit.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum T {
+    A,
+    B(i64),
+}
+
+impl serde::Serialize for T {
+    fn serialize<__S: serde::Serializer>(&self, serializer: __S) -> std::result::Result<__S::Ok, __S::Error> {
+        use serde::ser::SerializeTuple;
+        match self {
+            T::A => {
+                let mut tuple = serializer.serialize_tuple(1)?;
+                tuple.serialize_element(&serde_binprot::PolyVariantTag::new("A"))?;
+                tuple.end()
+            }
+            T::B(a0) => {
+                let mut tuple = serializer.serialize_tuple(2)?;
+                tuple.serialize_element(&serde_binprot::PolyVariantTag::new("B"))?;
+                tuple.serialize_element(a0)?;
+                tuple.end()
+            }
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for T {
+    fn deserialize<__D: serde::Deserializer<'de>>(deserializer: __D) -> std::result::Result<Self, __D::Error> {
+        struct __Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for __Visitor {
+            type Value = T;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a polymorphic variant")
+            }
+
+            fn visit_seq<__A: serde::de::SeqAccess<'de>>(self, mut seq: __A) -> std::result::Result<T, __A::Error> {
+                let missing = || <__A::Error as serde::de::Error>::custom("missing polymorphic variant argument");
+                let tag: serde_binprot::PolyVariantTag = seq.next_element()?.ok_or_else(missing)?;
+                if tag == serde_binprot::PolyVariantTag::new("A") {
+                    Ok(T::A)
+                } else if tag == serde_binprot::PolyVariantTag::new("B") {
+                    Ok(T::B(
+                        seq.next_element()?.ok_or_else(missing)?,
+                    ))
+                } else {
+                    Err(serde::de::Error::custom(format!("unknown polymorphic variant hash {}", tag.0)))
+                }
+            }
+        }
+
+        deserializer.deserialize_tuple(2, __Visitor)
+    }
+}
+"#;
+        assert_eq!(code, expected);
+        let src = "\ntype t = { c : [ `A | `B of int ] }";
+        let err = rust_of_ocaml(src, &RustOptions::default());
+        assert!(matches!(err, Err(Error::ParseError { line: 2, .. })));
+    }
+
+    #[test]
+    fn test_ocaml_codegen() {
+        #[derive(BinShape, Serialize)]
+        #[allow(dead_code)]
+        enum Side {
+            Buy,
+            Sell,
+        }
+
+        #[derive(BinShape, Serialize)]
+        #[allow(dead_code)]
+        enum Expr {
+            Const(f64),
+            Add(Box<Expr>, Box<Expr>),
+            Let { name: String, body: Vec<Expr> },
+        }
+
+        #[derive(BinShape, Serialize)]
+        #[allow(dead_code)]
+        struct Order {
+            qty: u64,
+            price: i32,
+            side: Side,
+            #[serde(rename = "type")]
+            ty: Option<String>,
+            levels: [f64; 2],
+            expr: Expr,
+            fill: Option<(i64, Vec<u8>)>,
+        }
+
+        let code = ocaml_of_type::<Order>("t").unwrap();
+        let expected = r#"(* Generated by serde-binprot, do not edit. *)
+open! Core
+
+module Nat0 = struct
+  include Bin_prot.Nat0
+
+  let sexp_of_t t = Int.sexp_of_t (t :> int)
+  let t_of_sexp sexp = of_int (Int.t_of_sexp sexp)
+end
+
+type t_side =
+  | Buy
+  | Sell
+[@@deriving bin_io, sexp]
+
+type t_expr =
+  | Const of float
+  | Add of t_expr * t_expr
+  | Let of
+      { name : string
+      ; body : t_expr list
+      }
+[@@deriving bin_io, sexp]
+
+type t =
+  { qty : Nat0.t
+  ; price : int
+  ; side : t_side
+  ; type_ : string option
+  ; levels : float * float
+  ; expr : t_expr
+  ; fill : (int * Nat0.t list) option
+  }
+[@@deriving bin_io, sexp]
+"#;
+        assert_eq!(code, expected);
+
+        // The declarations can be parsed back, with the same layout.
+        let decls = Declarations::parse(&code).unwrap();
+        let shape = decls.shape("t_expr").unwrap();
+        assert_eq!(shape, Expr::shape());
+        assert!(ocaml_of_type::<()>("t").unwrap().contains("type t = unit"));
+
+        // The Nat0 module is only defined when used.
+        assert!(!ocaml_of_type::<i64>("t").unwrap().contains("Nat0"));
+    }
+}
@@ -0,0 +1,600 @@
+//! Compatibility checks between two versions of a shape.
+//!
+//! As bin_prot is positional and not self-describing, most changes to a type
+//! make the data written with one version unreadable, or worse silently
+//! misread, with the other version. [`compare`] lists these changes, each
+//! with the path of the value that changed. Some changes only break one
+//! direction, e.g. a constructor added at the end of a variant can be read
+//! by new readers but not by old ones.
+//!
+//! ```
+//! use serde_binprot::compat::{compare, Severity};
+//! use serde_binprot::Shape;
+//!
+//! let record = |fields: &[(&str, Shape)]| {
+//!     Shape::Record(fields.iter().map(|(n, s)| (n.to_string(), s.clone())).collect())
+//! };
+//! let old = record(&[("qty", Shape::int()), ("price", Shape::float())]);
+//! let new = record(&[("qty", Shape::int()), ("px", Shape::float())]);
+//! let changes = compare(&old, &new);
+//! assert_eq!(changes.len(), 1);
+//! assert_eq!(changes[0].severity, Severity::NameOnly);
+//! ```
+use crate::shape::{encoding_class, is_sequence, uuid, Shape};
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// The binary layout is the same, only the names or the digest differ.
+    NameOnly,
+    /// Some values written with one version cannot be read with the other.
+    WireBreaking,
+}
+
+/// The readers affected by a change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Direction {
+    /// Data written with the old version may not be read with the new one.
+    OldToNew,
+    /// Data written with the new version may not be read with the old one.
+    NewToOld,
+    Both,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Incompatibility {
+    /// The path of the value that changed, e.g. `.orders[].side`.
+    pub path: String,
+    pub severity: Severity,
+    pub direction: Direction,
+    pub message: String,
+}
+
+impl Incompatibility {
+    /// Whether the change prevents data written with the old version from
+    /// being read with the new one, or the opposite when `old_to_new` is
+    /// false.
+    pub fn breaks(&self, old_to_new: bool) -> bool {
+        let direction = if old_to_new {
+            Direction::OldToNew
+        } else {
+            Direction::NewToOld
+        };
+        self.severity == Severity::WireBreaking
+            && (self.direction == direction || self.direction == Direction::Both)
+    }
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = if self.path.is_empty() {
+            "."
+        } else {
+            &self.path
+        };
+        let severity = match (self.severity, self.direction) {
+            (Severity::NameOnly, _) => "name only",
+            (Severity::WireBreaking, Direction::Both) => "wire breaking",
+            (Severity::WireBreaking, Direction::OldToNew) => {
+                "wire breaking for old writers and new readers"
+            }
+            (Severity::WireBreaking, Direction::NewToOld) => {
+                "wire breaking for new writers and old readers"
+            }
+        };
+        write!(f, "{}: {} ({})", path, self.message, severity)
+    }
+}
+
+fn describe(shape: &Shape) -> String {
+    match shape {
+        Shape::Annotate(uuid, _) => format!("annotated type {}", uuid),
+        Shape::Base(uuid, _) if uuid == uuid::NAT0 => "Nat0.t".to_string(),
+        Shape::Base(uuid, _) => uuid.to_string(),
+        Shape::Tuple(shapes) => format!("{}-tuple", shapes.len()),
+        Shape::Record(_) => "record".to_string(),
+        Shape::Variant(_) => "variant".to_string(),
+        Shape::PolyVariant(_) => "polymorphic variant".to_string(),
+        Shape::Application(_, _) => "type application".to_string(),
+        Shape::RecApp(_, _) => "recursive type".to_string(),
+        Shape::Var(index) => format!("type variable {}", index),
+    }
+}
+
+// The number of bits of the integer types, on a 64 bits platform.
+fn int_bits(uuid: &str) -> Option<u32> {
+    match uuid {
+        uuid::INT | uuid::INT63 => Some(63),
+        uuid::INT32 => Some(32),
+        uuid::INT64 | uuid::NATIVEINT => Some(64),
+        _ => None,
+    }
+}
+
+// The maximum nesting of the comparison, this limits the unfolding of
+// non-regular recursive types.
+const MAX_DEPTH: usize = 256;
+
+struct Comparer {
+    changes: Vec<Incompatibility>,
+    // The pairs of applications already compared, or being compared, so
+    // that recursive types are only compared once.
+    visited: BTreeSet<(Shape, Shape)>,
+    depth: usize,
+}
+
+impl Comparer {
+    fn report(&mut self, path: &str, severity: Severity, message: String) {
+        self.changes.push(Incompatibility {
+            path: path.to_string(),
+            severity,
+            direction: Direction::Both,
+            message,
+        })
+    }
+
+    // Reports a change that only breaks one direction.
+    fn report_one_way(&mut self, path: &str, direction: Direction, message: String) {
+        self.changes.push(Incompatibility {
+            path: path.to_string(),
+            severity: Severity::WireBreaking,
+            direction,
+            message,
+        })
+    }
+
+    fn compare(&mut self, old: &Shape, new: &Shape, path: &str) {
+        if old == new {
+            return;
+        }
+        let is_application =
+            |s: &Shape| matches!(s, Shape::Application(_, _) | Shape::Annotate(_, _));
+        if is_application(old) || is_application(new) {
+            if !self.visited.insert((old.clone(), new.clone())) {
+                return;
+            }
+            if self.depth >= MAX_DEPTH {
+                self.report(
+                    path,
+                    Severity::WireBreaking,
+                    "recursion too deep to compare".to_string(),
+                );
+                return;
+            }
+        }
+        self.depth += 1;
+        self.compare_unfolded(&old.unfold(), &new.unfold(), path);
+        self.depth -= 1;
+    }
+
+    fn compare_unfolded(&mut self, old: &Shape, new: &Shape, path: &str) {
+        match (old, new) {
+            (old_shape, Shape::Base(uuid, args))
+                if uuid == uuid::OPTION
+                    && args.len() == 1
+                    && !matches!(old_shape, Shape::Base(u, _) if u == uuid::OPTION) =>
+            {
+                self.report(path, Severity::WireBreaking, "option added".to_string())
+            }
+            (Shape::Base(uuid, args), new_shape)
+                if uuid == uuid::OPTION
+                    && args.len() == 1
+                    && !matches!(new_shape, Shape::Base(u, _) if u == uuid::OPTION) =>
+            {
+                self.report(path, Severity::WireBreaking, "option removed".to_string())
+            }
+            (Shape::Base(uuid1, args1), Shape::Base(uuid2, args2)) => {
+                if uuid1 != uuid2 {
+                    if encoding_class(uuid1) != encoding_class(uuid2) || args1.len() != args2.len()
+                    {
+                        return self.changed(old, new, path);
+                    }
+                    match (int_bits(uuid1), int_bits(uuid2)) {
+                        // Both integer types share the encoding but the
+                        // readers reject the values that do not fit.
+                        (Some(bits1), Some(bits2)) if bits1 != bits2 => {
+                            let msg = format!(
+                                "type changed from {} to {}, with a different range",
+                                describe(old),
+                                describe(new)
+                            );
+                            let direction = if bits1 > bits2 {
+                                Direction::OldToNew
+                            } else {
+                                Direction::NewToOld
+                            };
+                            self.report_one_way(path, direction, msg)
+                        }
+                        _ => {
+                            let msg = format!(
+                                "type changed from {} to {}, with the same encoding",
+                                describe(old),
+                                describe(new)
+                            );
+                            self.report(path, Severity::NameOnly, msg)
+                        }
+                    }
+                }
+                if args1.len() != args2.len() {
+                    return self.changed(old, new, path);
+                }
+                for (index, (arg1, arg2)) in args1.iter().zip(args2.iter()).enumerate() {
+                    let path = match encoding_class(uuid2) {
+                        uuid::OPTION => format!("{}?", path),
+                        class if is_sequence(class) => format!("{}[]", path),
+                        _ => format!("{}<{}>", path, index),
+                    };
+                    self.compare(arg1, arg2, &path)
+                }
+            }
+            (Shape::Tuple(shapes1), Shape::Tuple(shapes2)) => {
+                if shapes1.len() != shapes2.len() {
+                    let msg = format!(
+                        "tuple length changed from {} to {}",
+                        shapes1.len(),
+                        shapes2.len()
+                    );
+                    return self.report(path, Severity::WireBreaking, msg);
+                }
+                for (index, (shape1, shape2)) in shapes1.iter().zip(shapes2.iter()).enumerate() {
+                    self.compare(shape1, shape2, &format!("{}.{}", path, index))
+                }
+            }
+            (Shape::Record(fields1), Shape::Record(fields2)) => {
+                self.compare_records(fields1, fields2, path)
+            }
+            // Records and tuples with the same components have the same
+            // layout.
+            (Shape::Record(fields), Shape::Tuple(shapes))
+            | (Shape::Tuple(shapes), Shape::Record(fields))
+                if fields.len() == shapes.len() =>
+            {
+                let msg = format!("changed from {} to {}", describe(old), describe(new));
+                self.report(path, Severity::NameOnly, msg);
+                for (index, ((_, shape1), shape2)) in fields.iter().zip(shapes.iter()).enumerate() {
+                    let (shape1, shape2) = match old {
+                        Shape::Record(_) => (shape1, shape2),
+                        _ => (shape2, shape1),
+                    };
+                    self.compare(shape1, shape2, &format!("{}.{}", path, index))
+                }
+            }
+            (Shape::Variant(variants1), Shape::Variant(variants2)) => {
+                self.compare_variants(variants1, variants2, path)
+            }
+            (Shape::PolyVariant(variants1), Shape::PolyVariant(variants2)) => {
+                for (name, arg1) in variants1.iter() {
+                    let path = format!("{}.`{}", path, name);
+                    match (arg1, variants2.get(name)) {
+                        (_, None) => self.report_one_way(
+                            &path,
+                            Direction::OldToNew,
+                            format!("tag `{} removed", name),
+                        ),
+                        (Some(arg1), Some(Some(arg2))) => self.compare(arg1, arg2, &path),
+                        (None, Some(None)) => {}
+                        (_, Some(_)) => self.report(
+                            &path,
+                            Severity::WireBreaking,
+                            format!("argument of tag `{} changed", name),
+                        ),
+                    }
+                }
+                for name in variants2.keys() {
+                    if !variants1.contains_key(name) {
+                        let msg = format!("tag `{} added", name);
+                        let path = format!("{}.`{}", path, name);
+                        self.report_one_way(&path, Direction::NewToOld, msg)
+                    }
+                }
+            }
+            _ => self.changed(old, new, path),
+        }
+    }
+
+    fn changed(&mut self, old: &Shape, new: &Shape, path: &str) {
+        let msg = format!("type changed from {} to {}", describe(old), describe(new));
+        self.report(path, Severity::WireBreaking, msg)
+    }
+
+    fn compare_records(
+        &mut self,
+        fields1: &[(String, Shape)],
+        fields2: &[(String, Shape)],
+        path: &str,
+    ) {
+        let position =
+            |fields: &[(String, Shape)], name: &str| fields.iter().position(|(n, _)| n == name);
+        let names1: BTreeSet<_> = fields1.iter().map(|(n, _)| n).collect();
+        let names2: BTreeSet<_> = fields2.iter().map(|(n, _)| n).collect();
+        if fields1.len() == fields2.len()
+            && names1 != names2
+            && names1
+                .intersection(&names2)
+                .all(|n| position(fields1, n) == position(fields2, n))
+        {
+            // Same number of fields, the common ones at the same position:
+            // the other ones have been renamed.
+            for ((name1, shape1), (name2, shape2)) in fields1.iter().zip(fields2.iter()) {
+                let field_path = format!("{}.{}", path, name2);
+                if name1 != name2 {
+                    let msg = format!("field {} renamed to {}", name1, name2);
+                    self.report(&field_path, Severity::NameOnly, msg)
+                }
+                self.compare(shape1, shape2, &field_path)
+            }
+            return;
+        }
+        for (index, (name, _)) in fields2.iter().enumerate() {
+            if position(fields1, name).is_none() {
+                let place = if index + 1 == fields2.len() {
+                    "at the end"
+                } else {
+                    "in the middle"
+                };
+                let msg = format!("field {} added {} (position {})", name, place, index);
+                self.report(&format!("{}.{}", path, name), Severity::WireBreaking, msg)
+            }
+        }
+        for (index, (name, _)) in fields1.iter().enumerate() {
+            if position(fields2, name).is_none() {
+                let msg = format!("field {} removed (position {})", name, index);
+                self.report(&format!("{}.{}", path, name), Severity::WireBreaking, msg)
+            }
+        }
+        let common1: Vec<_> = fields1.iter().filter(|(n, _)| names2.contains(n)).collect();
+        let common2: Vec<_> = fields2.iter().filter(|(n, _)| names1.contains(n)).collect();
+        for (name, shape2) in common2.iter() {
+            let field_path = format!("{}.{}", path, name);
+            let index1 = common1.iter().position(|(n, _)| n == name).unwrap();
+            let index2 = common2.iter().position(|(n, _)| n == name).unwrap();
+            if index1 != index2 {
+                let msg = format!(
+                    "field {} moved from position {} to {}",
+                    name,
+                    position(fields1, name).unwrap(),
+                    position(fields2, name).unwrap()
+                );
+                self.report(&field_path, Severity::WireBreaking, msg)
+            }
+            let (_, shape1) = common1[index1];
+            self.compare(shape1, shape2, &field_path)
+        }
+    }
+
+    fn compare_variants(
+        &mut self,
+        variants1: &[(String, Vec<Shape>)],
+        variants2: &[(String, Vec<Shape>)],
+        path: &str,
+    ) {
+        // Bin_prot writes the tags on two bytes past 256 constructors.
+        if (variants1.len() > 256) != (variants2.len() > 256) {
+            let msg = format!(
+                "number of constructors changed from {} to {}, with a different tag size",
+                variants1.len(),
+                variants2.len()
+            );
+            return self.report(path, Severity::WireBreaking, msg);
+        }
+        let position = |variants: &[(String, Vec<Shape>)], name: &str| {
+            variants.iter().position(|(n, _)| n == name)
+        };
+        let compare_args = |c: &mut Comparer, args1: &[Shape], args2: &[Shape], path: &str| {
+            if args1.len() != args2.len() {
+                let msg = format!(
+                    "number of arguments changed from {} to {}",
+                    args1.len(),
+                    args2.len()
+                );
+                return c.report(path, Severity::WireBreaking, msg);
+            }
+            match (args1, args2) {
+                ([arg1], [arg2]) => c.compare(arg1, arg2, path),
+                _ => {
+                    for (index, (arg1, arg2)) in args1.iter().zip(args2.iter()).enumerate() {
+                        c.compare(arg1, arg2, &format!("{}.{}", path, index))
+                    }
+                }
+            }
+        };
+        for (index2, (name2, args2)) in variants2.iter().enumerate() {
+            let ctor_path = format!("{}.{}", path, name2);
+            match position(variants1, name2) {
+                Some(index1) => {
+                    if index1 != index2 {
+                        let msg = format!(
+                            "constructor {} moved from tag {} to {}",
+                            name2, index1, index2
+                        );
+                        self.report(&ctor_path, Severity::WireBreaking, msg)
+                    }
+                    compare_args(self, &variants1[index1].1, args2, &ctor_path)
+                }
+                None => match variants1.get(index2) {
+                    // A constructor at the same position that does not exist
+                    // anymore in the new version is considered renamed.
+                    Some((name1, args1)) if position(variants2, name1).is_none() => {
+                        let msg = format!("constructor {} renamed to {}", name1, name2);
+                        self.report(&ctor_path, Severity::NameOnly, msg);
+                        compare_args(self, args1, args2, &ctor_path)
+                    }
+                    _ => {
+                        // The constructors shifted by the addition are
+                        // reported as moved.
+                        let msg = if index2 >= variants1.len() {
+                            format!("constructor {} added at the end", name2)
+                        } else {
+                            format!("constructor {} added at tag {}", name2, index2)
+                        };
+                        self.report_one_way(&ctor_path, Direction::NewToOld, msg)
+                    }
+                },
+            }
+        }
+        for (index1, (name1, _)) in variants1.iter().enumerate() {
+            let renamed = variants2
+                .get(index1)
+                .is_some_and(|(name2, _)| position(variants1, name2).is_none());
+            if position(variants2, name1).is_none() && !renamed {
+                let msg = format!("constructor {} removed (tag {})", name1, index1);
+                let path = format!("{}.{}", path, name1);
+                self.report_one_way(&path, Direction::OldToNew, msg)
+            }
+        }
+    }
+}
+
+/// Compares an old and a new version of a shape, and returns the list of
+/// changes between the two. An empty list means that the two versions have
+/// the same layout and names.
+pub fn compare(old: &Shape, new: &Shape) -> Vec<Incompatibility> {
+    let mut comparer = Comparer {
+        changes: vec![],
+        visited: BTreeSet::new(),
+        depth: 0,
+    };
+    comparer.compare(old, new, "");
+    comparer.changes
+}
+
+/// Whether data written with one version of a shape can be read with the
+/// other one and the other way around, i.e. whether all the changes are
+/// name-only.
+pub fn is_wire_compatible(old: &Shape, new: &Shape) -> bool {
+    compare(old, new)
+        .iter()
+        .all(|change| change.severity == Severity::NameOnly)
+}
+
+/// Whether all the data written with the `writer` shape can be read with the
+/// `reader` one.
+pub fn can_read(writer: &Shape, reader: &Shape) -> bool {
+    compare(writer, reader)
+        .iter()
+        .all(|change| !change.breaks(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{can_read, compare, is_wire_compatible, Direction, Severity};
+    use crate::{HasShape, Shape};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::Serialize;
+
+    #[allow(dead_code)]
+    mod v1 {
+        use super::*;
+
+        #[derive(BinShape, Serialize)]
+        pub enum Side {
+            Buy,
+            Sell,
+        }
+
+        #[derive(BinShape, Serialize)]
+        pub struct Order {
+            pub qty: i64,
+            pub price: f64,
+            pub side: Side,
+            pub tags: Vec<String>,
+        }
+
+        #[derive(BinShape, Serialize)]
+        pub enum List {
+            Nil,
+            Cons(i64, Box<List>),
+        }
+    }
+
+    #[allow(dead_code)]
+    mod v2 {
+        use super::*;
+
+        #[derive(BinShape, Serialize)]
+        pub enum Side {
+            Sell,
+            Buy,
+            Short,
+        }
+
+        #[derive(BinShape, Serialize)]
+        pub struct Order {
+            pub qty: f64,
+            pub venue: String,
+            pub price: f64,
+            pub side: Side,
+            pub labels: Vec<Option<String>>,
+        }
+
+        #[derive(BinShape, Serialize)]
+        pub enum List {
+            Empty,
+            Cons(i64, Box<List>),
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let changes = compare(&v1::Order::shape(), &v2::Order::shape());
+        let changes: Vec<_> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            changes,
+            [
+                ".venue: field venue added in the middle (position 1) (wire breaking)",
+                ".labels: field labels added at the end (position 4) (wire breaking)",
+                ".tags: field tags removed (position 3) (wire breaking)",
+                ".qty: type changed from int to float (wire breaking)",
+                ".side.Sell: constructor Sell moved from tag 1 to 0 (wire breaking)",
+                ".side.Buy: constructor Buy moved from tag 0 to 1 (wire breaking)",
+                ".side.Short: constructor Short added at the end (wire breaking for new writers and old readers)",
+            ]
+        );
+
+        let old = Shape::Variant(vec![("A".to_string(), vec![])]);
+        let new = Shape::Variant(vec![("A".to_string(), vec![]), ("B".to_string(), vec![])]);
+        assert!(can_read(&old, &new));
+        assert!(!can_read(&new, &old));
+        assert!(!is_wire_compatible(&old, &new));
+        let changes = compare(&new, &old);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].direction, Direction::OldToNew);
+        assert!(changes[0].breaks(true) && !changes[0].breaks(false));
+
+        let changes = compare(&v1::List::shape(), &v2::List::shape());
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, ".Empty");
+        assert_eq!(changes[0].severity, Severity::NameOnly);
+        assert!(is_wire_compatible(&v1::List::shape(), &v2::List::shape()));
+
+        assert!(compare(&v1::Order::shape(), &v1::Order::shape()).is_empty());
+        assert!(is_wire_compatible(
+            &<Vec<i64>>::shape(),
+            &Shape::array(Shape::base("int63"))
+        ));
+        let int32 = Shape::list(Shape::base("int32"));
+        let int64 = Shape::array(Shape::base("int64"));
+        assert!(!is_wire_compatible(&int32, &int64));
+        assert!(can_read(&int32, &int64) && !can_read(&int64, &int32));
+        let changes = compare(&Shape::int(), &Shape::base("int32"));
+        assert_eq!(
+            changes[0].to_string(),
+            ".: type changed from int to int32, with a different range (wire breaking for old writers and new readers)"
+        );
+        let variant =
+            |n: usize| Shape::Variant((0..n).map(|i| (format!("C{}", i), vec![])).collect());
+        let changes = compare(&variant(256), &variant(257));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].direction, Direction::Both);
+        let changes = compare(&<Vec<i64>>::shape(), &<Vec<Option<i64>>>::shape());
+        assert_eq!(changes[0].to_string(), "[]: option added (wire breaking)");
+        let changes = compare(&<Option<i64>>::shape(), &<Option<f64>>::shape());
+        assert_eq!(
+            changes[0].to_string(),
+            "?: type changed from int to float (wire breaking)"
+        );
+        let changes = compare(&<(i64, String)>::shape(), &<(i64, String, bool)>::shape());
+        assert_eq!(changes[0].severity, Severity::WireBreaking);
+    }
+}
@@ -0,0 +1,411 @@
+//! Types reproducing the bin_prot encodings of some Core containers.
+//!
+//! Most of these containers use the same encoding as a list: the number of
+//! elements as a `Nat0.t` followed by the elements. `Map` and `Set` enforce
+//! that the keys are sorted in increasing order and duplicate-free on decode,
+//! as the OCaml side expects.
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, SerializeTuple, Serializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+/// A non-empty list, `Nonempty_list.t` in Core. This is encoded as the head
+/// element followed by the list of remaining elements.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NonemptyList<T> {
+    pub head: T,
+    pub tail: Vec<T>,
+}
+
+impl<T> NonemptyList<T> {
+    pub fn new(head: T, tail: Vec<T>) -> Self {
+        NonemptyList { head, tail }
+    }
+
+    pub fn singleton(head: T) -> Self {
+        NonemptyList { head, tail: vec![] }
+    }
+
+    /// Returns `None` if `v` is empty.
+    pub fn from_vec(mut v: Vec<T>) -> Option<Self> {
+        if v.is_empty() {
+            None
+        } else {
+            let head = v.remove(0);
+            Some(NonemptyList { head, tail: v })
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        let mut v = Vec::with_capacity(1 + self.tail.len());
+        v.push(self.head);
+        v.extend(self.tail);
+        v
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        1 + self.tail.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        std::iter::once(&self.head).chain(self.tail.iter())
+    }
+}
+
+impl<T: Serialize> Serialize for NonemptyList<T> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&self.head)?;
+        tuple.serialize_element(&self.tail)?;
+        tuple.end()
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for NonemptyList<T> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (head, tail) = <(T, Vec<T>)>::deserialize(deserializer)?;
+        Ok(NonemptyList { head, tail })
+    }
+}
+
+/// A functional queue, `Fqueue.t` in Core. The elements are encoded from the
+/// front of the queue to its back.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Fqueue<T>(pub VecDeque<T>);
+
+/// A double ended queue, `Deque.t` in Core. The elements are encoded from the
+/// front of the queue to its back.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Deque<T>(pub VecDeque<T>);
+
+/// A map with sorted keys, `Map.t` in Core. The bindings are encoded in
+/// increasing key order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Map<K, V>(pub BTreeMap<K, V>);
+
+/// A hash table, `Hashtbl.t` in Core. This has the same encoding as a plain
+/// `HashMap`, the wrapper only lets shape tracing tell it apart from a `Map`.
+#[derive(Debug, Clone)]
+pub struct Hashtbl<K, V>(pub HashMap<K, V>);
+
+// The name passed to `deserialize_newtype_struct` by `Hashtbl`.
+pub(crate) const HASHTBL_MARKER: &str = "$serde_binprot::Hashtbl";
+
+/// A set with sorted elements, `Set.t` in Core. The elements are encoded in
+/// increasing order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Set<T>(pub BTreeSet<T>);
+
+impl<K: Ord, V> Default for Map<K, V> {
+    fn default() -> Self {
+        Map(BTreeMap::new())
+    }
+}
+
+impl<K, V> Default for Hashtbl<K, V> {
+    fn default() -> Self {
+        Hashtbl(HashMap::new())
+    }
+}
+
+impl<K: Eq + Hash, V: PartialEq> PartialEq for Hashtbl<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<K: Eq + Hash, V: Eq> Eq for Hashtbl<K, V> {}
+
+impl<T: Ord> Default for Set<T> {
+    fn default() -> Self {
+        Set(BTreeSet::new())
+    }
+}
+
+macro_rules! impl_wrapper {
+    ($name:ident<$($param:ident),*>, $inner:ty) => {
+        impl<$($param),*> std::ops::Deref for $name<$($param),*> {
+            type Target = $inner;
+
+            fn deref(&self) -> &$inner {
+                &self.0
+            }
+        }
+
+        impl<$($param),*> std::ops::DerefMut for $name<$($param),*> {
+            fn deref_mut(&mut self) -> &mut $inner {
+                &mut self.0
+            }
+        }
+
+        impl<$($param),*> From<$inner> for $name<$($param),*> {
+            fn from(v: $inner) -> Self {
+                $name(v)
+            }
+        }
+    };
+}
+
+impl_wrapper!(Fqueue<T>, VecDeque<T>);
+impl_wrapper!(Deque<T>, VecDeque<T>);
+impl_wrapper!(Map<K, V>, BTreeMap<K, V>);
+impl_wrapper!(Hashtbl<K, V>, HashMap<K, V>);
+impl_wrapper!(Set<T>, BTreeSet<T>);
+
+fn serialize_iter<S, I>(serializer: S, iter: I) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    I: ExactSizeIterator,
+    I::Item: Serialize,
+{
+    let mut seq = serializer.serialize_seq(Some(iter.len()))?;
+    for elem in iter {
+        seq.serialize_element(&elem)?;
+    }
+    seq.end()
+}
+
+impl<T: Serialize> Serialize for Fqueue<T> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serialize_iter(serializer, self.0.iter())
+    }
+}
+
+impl<T: Serialize> Serialize for Deque<T> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serialize_iter(serializer, self.0.iter())
+    }
+}
+
+impl<T: Serialize> Serialize for Set<T> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serialize_iter(serializer, self.0.iter())
+    }
+}
+
+impl<K: Serialize, V: Serialize> Serialize for Map<K, V> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in self.0.iter() {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<K: Serialize, V: Serialize> Serialize for Hashtbl<K, V> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Fqueue<T> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        VecDeque::deserialize(deserializer).map(Fqueue)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Deque<T> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        VecDeque::deserialize(deserializer).map(Deque)
+    }
+}
+
+struct SetVisitor<T>(PhantomData<T>);
+
+impl<'de, T> Visitor<'de> for SetVisitor<T>
+where
+    T: Deserialize<'de> + Ord,
+{
+    type Value = Set<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a sequence of sorted and distinct elements")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Set<T>, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut set = BTreeSet::new();
+        while let Some(elem) = seq.next_element::<T>()? {
+            if let Some(last) = set.last() {
+                if &elem == last {
+                    return Err(de::Error::custom("duplicate element in set"));
+                }
+                if &elem < last {
+                    return Err(de::Error::custom("set elements are not sorted"));
+                }
+            }
+            set.insert(elem);
+        }
+        Ok(Set(set))
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Set<T>
+where
+    T: Deserialize<'de> + Ord,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(SetVisitor(PhantomData))
+    }
+}
+
+struct MapVisitor<K, V>(PhantomData<(K, V)>);
+
+impl<'de, K, V> Visitor<'de> for MapVisitor<K, V>
+where
+    K: Deserialize<'de> + Ord,
+    V: Deserialize<'de>,
+{
+    type Value = Map<K, V>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a map with sorted and distinct keys")
+    }
+
+    fn visit_map<A>(self, mut access: A) -> std::result::Result<Map<K, V>, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut map = BTreeMap::new();
+        while let Some((key, value)) = access.next_entry::<K, V>()? {
+            if let Some((last, _)) = map.last_key_value() {
+                if &key == last {
+                    return Err(de::Error::custom("duplicate key in map"));
+                }
+                if &key < last {
+                    return Err(de::Error::custom("map keys are not sorted"));
+                }
+            }
+            map.insert(key, value);
+        }
+        Ok(Map(map))
+    }
+}
+
+impl<'de, K, V> Deserialize<'de> for Map<K, V>
+where
+    K: Deserialize<'de> + Ord,
+    V: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(MapVisitor(PhantomData))
+    }
+}
+
+struct HashtblVisitor<K, V>(PhantomData<(K, V)>);
+
+impl<'de, K, V> Visitor<'de> for HashtblVisitor<K, V>
+where
+    K: Deserialize<'de> + Eq + Hash,
+    V: Deserialize<'de>,
+{
+    type Value = Hashtbl<K, V>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a hash table")
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> std::result::Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        HashMap::deserialize(deserializer).map(Hashtbl)
+    }
+}
+
+impl<'de, K, V> Deserialize<'de> for Hashtbl<K, V>
+where
+    K: Deserialize<'de> + Eq + Hash,
+    V: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_newtype_struct(HASHTBL_MARKER, HashtblVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Deque, Fqueue, Map, NonemptyList, Set};
+    use crate::{from_slice, to_vec};
+
+    #[test]
+    fn test_list_like() {
+        let l = NonemptyList::new(1i64, vec![2, 3]);
+        let ser = to_vec(&l).unwrap();
+        assert_eq!(ser, [1, 2, 2, 3]);
+        assert_eq!(from_slice::<NonemptyList<i64>>(&ser).unwrap(), l);
+        assert_eq!(l.iter().copied().collect::<Vec<_>>(), l.clone().into_vec());
+        assert_eq!(NonemptyList::from_vec(vec![1i64, 2, 3]), Some(l));
+        assert_eq!(NonemptyList::<i64>::from_vec(vec![]), None);
+
+        let q = Fqueue(vec![1i64, -1].into());
+        let ser = to_vec(&q).unwrap();
+        assert_eq!(ser, [2, 1, 0xff, 0xff]);
+        assert_eq!(from_slice::<Fqueue<i64>>(&ser).unwrap(), q);
+        assert_eq!(from_slice::<Deque<i64>>(&ser).unwrap().0, q.0);
+    }
+
+    #[test]
+    fn test_sorted() {
+        let mut m = Map::default();
+        m.insert("b".to_string(), 2i64);
+        m.insert("a".to_string(), 1i64);
+        let ser = to_vec(&m).unwrap();
+        assert_eq!(ser, [2, 1, b'a', 1, 1, b'b', 2]);
+        assert_eq!(from_slice::<Map<String, i64>>(&ser).unwrap(), m);
+
+        let unsorted = to_vec(&vec![("b", 2i64), ("a", 1)]).unwrap();
+        assert!(from_slice::<Map<String, i64>>(&unsorted).is_err());
+        let duplicate = to_vec(&vec![("a", 2i64), ("a", 1)]).unwrap();
+        assert!(from_slice::<Map<String, i64>>(&duplicate).is_err());
+
+        let s: Set<i64> = Set(vec![3, 1, 2].into_iter().collect());
+        let ser = to_vec(&s).unwrap();
+        assert_eq!(ser, [3, 1, 2, 3]);
+        assert_eq!(from_slice::<Set<i64>>(&ser).unwrap(), s);
+        assert!(from_slice::<Set<i64>>(&[3, 1, 3, 2]).is_err());
+        assert!(from_slice::<Set<i64>>(&[2, 1, 1]).is_err());
+    }
+}
@@ -1,14 +1,18 @@
 //! Deserialize bin_prot data to a Rust data structure.
 
 use crate::error::{Error, Result};
+use crate::shape::{uuid, Kind, Shape};
+use crate::walk::{read_constructor, read_poly_tag};
 use crate::{CODE_INT16, CODE_INT32, CODE_INT64, CODE_NEG_INT8};
 use byteorder::{LittleEndian, ReadBytesExt};
-use serde::de::{self, Visitor};
+use serde::de::{self, IntoDeserializer, Visitor};
 use std::convert::TryInto;
 use std::io;
 
 pub struct Deserializer<R> {
     read: R,
+    // The shape of the next value to be deserialized, if known.
+    shape: Option<Shape>,
 }
 
 impl<R> Deserializer<R>
@@ -16,7 +20,27 @@ where
     R: io::Read,
 {
     pub fn new(read: R) -> Self {
-        Deserializer { read }
+        Deserializer { read, shape: None }
+    }
+
+    /// Creates a deserializer for data encoded with a type of the given
+    /// shape. The shape tells what is on the wire at every position, which
+    /// makes `deserialize_any` and `deserialize_ignored_any` available.
+    pub fn with_shape(read: R, shape: &Shape) -> Self {
+        Deserializer {
+            read,
+            shape: Some(shape.clone()),
+        }
+    }
+
+    // Takes the shape of the next value, with the applications, references
+    // and lazy values removed.
+    fn take_shape(&mut self) -> Option<Shape> {
+        self.shape.take().as_ref().map(Shape::resolve)
+    }
+
+    pub(crate) fn get_ref(&self) -> &R {
+        &self.read
     }
 }
 
@@ -24,7 +48,7 @@ impl<R> Deserializer<R>
 where
     R: io::Read,
 {
-    fn read_signed(&mut self) -> Result<i64> {
+    pub(crate) fn read_signed(&mut self) -> Result<i64> {
         let c = self.read.read_u8()?;
         let v = match c {
             CODE_NEG_INT8 => self.read.read_i8()? as i64,
@@ -36,7 +60,7 @@ where
         Ok(v)
     }
 
-    fn read_nat0(&mut self) -> Result<u64> {
+    pub(crate) fn read_nat0(&mut self) -> Result<u64> {
         let c = self.read.read_u8()?;
         let v = match c {
             CODE_INT16 => self.read.read_u16::<LittleEndian>()? as u64,
@@ -47,25 +71,123 @@ where
         Ok(v)
     }
 
-    fn read_float(&mut self) -> Result<f64> {
+    pub(crate) fn read_float(&mut self) -> Result<f64> {
         let f = self.read.read_f64::<LittleEndian>()?;
         Ok(f)
     }
+
+    pub(crate) fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read.read_u8()?)
+    }
+
+    pub(crate) fn read_i32(&mut self) -> Result<i32> {
+        Ok(self.read.read_i32::<LittleEndian>()?)
+    }
+
+    // Unlike a `read_exact` on a pre-allocated buffer, this does not
+    // allocate more than what is available for corrupted lengths.
+    pub(crate) fn read_bytes(&mut self, len: u64) -> Result<Vec<u8>> {
+        let mut vec = vec![];
+        io::Read::read_to_end(&mut io::Read::take(&mut self.read, len), &mut vec)?;
+        if (vec.len() as u64) < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(vec)
+    }
+}
+
+/// A reader over a byte slice that tracks the number of bytes consumed so
+/// far, used to report offsets in errors.
+pub(crate) struct SliceReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        SliceReader { data, offset: 0 }
+    }
+
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len() - self.offset
+    }
+
+    // The data that has not been read yet.
+    #[cfg(feature = "json")]
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        &self.data[self.offset..]
+    }
+}
+
+impl<'a> io::Read for SliceReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len(), self.remaining());
+        buf[..len].copy_from_slice(&self.data[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
 }
 
+#[allow(clippy::needless_lifetimes)]
 impl<'de, 'a, R> de::Deserializer<'de> for &'a mut Deserializer<R>
 where
     R: io::Read,
 {
     type Error = Error;
 
-    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value>
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        // The bin_prot format is not self describing so return an error
-        // here.
-        Err(Error::CannotDeserializeAny)
+        // The bin_prot format is not self describing so without a shape
+        // return an error here.
+        let shape = match self.take_shape() {
+            None => return Err(Error::CannotDeserializeAny),
+            Some(shape) => shape,
+        };
+        let kind = shape.kind()?;
+        // Only the collections read the shape of their elements from here, it
+        // would otherwise be left for the next value.
+        if matches!(
+            kind,
+            Kind::Option(_) | Kind::Map(_, _) | Kind::Sequence(_, _) | Kind::Tuple(_)
+        ) {
+            self.shape = Some(shape.clone());
+        }
+        match kind {
+            Kind::Unit => self.deserialize_unit(visitor),
+            Kind::Bool => self.deserialize_bool(visitor),
+            Kind::Char => self.deserialize_char(visitor),
+            Kind::Float => self.deserialize_f64(visitor),
+            Kind::Int(_) => self.deserialize_i64(visitor),
+            Kind::Nat0 => self.deserialize_u64(visitor),
+            Kind::String(uuid::STRING) => self.deserialize_string(visitor),
+            Kind::String(_) => self.deserialize_byte_buf(visitor),
+            Kind::Option(_) => self.deserialize_option(visitor),
+            Kind::Map(_, _) => self.deserialize_map(visitor),
+            Kind::Sequence(_, _) => self.deserialize_seq(visitor),
+            Kind::Tuple(shapes) => self.deserialize_tuple(shapes.len(), visitor),
+            // Records are presented as maps from field names, variants as
+            // the constructor name when there are no arguments and as a map
+            // with a single entry otherwise, as self-describing formats do.
+            Kind::Record(fields) => visitor.visit_map(RecordAccess {
+                de: self,
+                fields: Vec::from(fields).into_iter(),
+            }),
+            Kind::Variant(variants) => {
+                let (name, args) = read_constructor(self, variants)?.clone();
+                self.visit_constructor(name, args, visitor)
+            }
+            Kind::PolyVariant(variants) => {
+                let (name, arg) = read_poly_tag(self, variants)?;
+                let args = arg.iter().cloned().collect();
+                self.visit_constructor(name.clone(), args, visitor)
+            }
+        }
     }
 
     fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
@@ -205,6 +327,12 @@ where
             _ => return Err(Error::ExpectedOption),
         };
         if is_some {
+            match self.take_shape() {
+                Some(Shape::Base(uuid, mut args)) if uuid == uuid::OPTION && args.len() == 1 => {
+                    self.shape = args.pop()
+                }
+                _ => {}
+            }
             visitor.visit_some(self)
         } else {
             visitor.visit_none()
@@ -241,15 +369,24 @@ where
     where
         V: Visitor<'de>,
     {
+        let shape = self.take_shape();
+        let shapes = match shape.as_ref().map(Shape::kind) {
+            Some(Ok(Kind::Sequence(_, elt))) => Shapes::Repeat(elt.clone()),
+            Some(Ok(Kind::Map(key, value))) => {
+                Shapes::Repeat(Shape::Tuple(vec![key.clone(), value.clone()]))
+            }
+            _ => Shapes::None,
+        };
         let len = self.read_nat0()?;
-        visitor.visit_seq(SeqWithLen::new(self, len as usize))
+        visitor.visit_seq(SeqWithLen::with_shapes(self, len as usize, shapes))
     }
 
     fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        visitor.visit_seq(SeqWithLen::new(self, len))
+        let shapes = Shapes::components(self.take_shape());
+        visitor.visit_seq(SeqWithLen::with_shapes(self, len, shapes))
     }
 
     fn deserialize_tuple_struct<V>(
@@ -261,15 +398,26 @@ where
     where
         V: Visitor<'de>,
     {
-        visitor.visit_seq(SeqWithLen::new(self, len))
+        self.deserialize_tuple(len, visitor)
     }
 
     fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
+        let shape = self.take_shape();
+        let shapes = match shape.as_ref().map(Shape::kind) {
+            Some(Ok(Kind::Map(key, value))) => Shapes::Pairs(key.clone(), value.clone()),
+            Some(Ok(Kind::Record(fields))) => {
+                return visitor.visit_map(RecordAccess {
+                    de: self,
+                    fields: Vec::from(fields).into_iter(),
+                })
+            }
+            _ => Shapes::None,
+        };
         let len = self.read_nat0()?;
-        visitor.visit_map(SeqWithLen::new(self, len as usize))
+        visitor.visit_map(SeqWithLen::with_shapes(self, len as usize, shapes))
     }
 
     fn deserialize_struct<V>(
@@ -281,7 +429,7 @@ where
     where
         V: Visitor<'de>,
     {
-        visitor.visit_seq(SeqWithLen::new(self, fields.len()))
+        self.deserialize_tuple(fields.len(), visitor)
     }
 
     fn deserialize_enum<V>(
@@ -293,7 +441,15 @@ where
     where
         V: Visitor<'de>,
     {
-        visitor.visit_enum(VariantAccess::new(self))
+        let variants = match self.take_shape() {
+            Some(Shape::Variant(variants)) => Some(variants),
+            _ => None,
+        };
+        visitor.visit_enum(VariantAccess {
+            de: self,
+            variants,
+            args: None,
+        })
     }
 
     fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
@@ -309,18 +465,143 @@ where
     where
         V: Visitor<'de>,
     {
-        self.deserialize_any(visitor)
+        if self.shape.is_none() {
+            return self.deserialize_any(visitor);
+        }
+        self.deserialize_any(de::IgnoredAny)?;
+        visitor.visit_unit()
+    }
+}
+
+impl<R: io::Read> Deserializer<R> {
+    fn visit_constructor<'de, V>(
+        &mut self,
+        name: String,
+        args: Vec<Shape>,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if args.is_empty() {
+            return visitor.visit_string(name);
+        }
+        let shape = if args.len() == 1 {
+            args.into_iter().next().unwrap()
+        } else {
+            Shape::Tuple(args)
+        };
+        visitor.visit_map(ConstructorAccess {
+            de: self,
+            name: Some(name),
+            shape,
+        })
+    }
+}
+
+// The shapes of the components of a compound value.
+enum Shapes {
+    None,
+    Repeat(Shape),
+    Each(std::vec::IntoIter<Shape>),
+    Pairs(Shape, Shape),
+}
+
+impl Shapes {
+    fn components(shape: Option<Shape>) -> Self {
+        match shape {
+            Some(Shape::Tuple(shapes)) => Shapes::Each(shapes.into_iter()),
+            Some(Shape::Record(fields)) => {
+                let shapes: Vec<_> = fields.into_iter().map(|(_, shape)| shape).collect();
+                Shapes::Each(shapes.into_iter())
+            }
+            _ => Shapes::None,
+        }
+    }
+
+    fn next(&mut self) -> Option<Shape> {
+        match self {
+            Shapes::None => None,
+            Shapes::Repeat(shape) => Some(shape.clone()),
+            Shapes::Each(shapes) => shapes.next(),
+            Shapes::Pairs(key, _) => Some(key.clone()),
+        }
+    }
+
+    fn next_value(&mut self) -> Option<Shape> {
+        match self {
+            Shapes::Pairs(_, value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+}
+
+struct RecordAccess<'a, R: 'a> {
+    de: &'a mut Deserializer<R>,
+    fields: std::vec::IntoIter<(String, Shape)>,
+}
+
+impl<'de, 'a, R: io::Read + 'a> de::MapAccess<'de> for RecordAccess<'a, R> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        match self.fields.next() {
+            None => Ok(None),
+            Some((name, shape)) => {
+                self.de.shape = Some(shape);
+                seed.deserialize(name.into_deserializer()).map(Some)
+            }
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+struct ConstructorAccess<'a, R: 'a> {
+    de: &'a mut Deserializer<R>,
+    name: Option<String>,
+    shape: Shape,
+}
+
+impl<'de, 'a, R: io::Read + 'a> de::MapAccess<'de> for ConstructorAccess<'a, R> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        match self.name.take() {
+            None => Ok(None),
+            Some(name) => seed.deserialize(name.into_deserializer()).map(Some),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        self.de.shape = Some(self.shape.clone());
+        seed.deserialize(&mut *self.de)
     }
 }
 
 struct SeqWithLen<'a, R: 'a> {
     de: &'a mut Deserializer<R>,
     len: usize,
+    shapes: Shapes,
 }
 
 impl<'a, R: 'a> SeqWithLen<'a, R> {
-    fn new(de: &'a mut Deserializer<R>, len: usize) -> Self {
-        SeqWithLen { de, len }
+    fn with_shapes(de: &'a mut Deserializer<R>, len: usize, shapes: Shapes) -> Self {
+        SeqWithLen { de, len, shapes }
     }
 }
 
@@ -335,6 +616,7 @@ impl<'de, 'a, R: io::Read + 'a> de::SeqAccess<'de> for SeqWithLen<'a, R> {
             return Ok(None);
         }
         self.len -= 1;
+        self.de.shape = self.shapes.next();
         seed.deserialize(&mut *self.de).map(Some)
     }
 }
@@ -350,6 +632,7 @@ impl<'de, 'a, R: io::Read + 'a> de::MapAccess<'de> for SeqWithLen<'a, R> {
             return Ok(None);
         }
         self.len -= 1;
+        self.de.shape = self.shapes.next();
         seed.deserialize(&mut *self.de).map(Some)
     }
 
@@ -357,17 +640,27 @@ impl<'de, 'a, R: io::Read + 'a> de::MapAccess<'de> for SeqWithLen<'a, R> {
     where
         V: de::DeserializeSeed<'de>,
     {
+        self.de.shape = self.shapes.next_value();
         seed.deserialize(&mut *self.de)
     }
 }
 
 struct VariantAccess<'a, R: 'a> {
     de: &'a mut Deserializer<R>,
+    variants: Option<Vec<(String, Vec<Shape>)>>,
+    // The shapes of the arguments of the constructor, once known.
+    args: Option<Vec<Shape>>,
 }
 
-impl<'a, R: 'a> VariantAccess<'a, R> {
-    fn new(de: &'a mut Deserializer<R>) -> Self {
-        VariantAccess { de }
+impl<'a, R: io::Read + 'a> VariantAccess<'a, R> {
+    fn args_shapes(&mut self) -> Shapes {
+        match self.args.take() {
+            Some(args) => match args.as_slice() {
+                [Shape::Record(_)] => Shapes::components(args.into_iter().next()),
+                _ => Shapes::Each(args.into_iter()),
+            },
+            None => Shapes::None,
+        }
     }
 }
 
@@ -375,11 +668,22 @@ impl<'de, 'a, R: io::Read + 'a> de::EnumAccess<'de> for VariantAccess<'a, R> {
     type Error = Error;
     type Variant = Self;
 
-    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self)>
+    fn variant_seed<V>(mut self, seed: V) -> Result<(V::Value, Self)>
     where
         V: de::DeserializeSeed<'de>,
     {
-        let val = seed.deserialize(&mut *self.de)?;
+        let variant_index = self.de.read.read_u8()?;
+        if let Some(variants) = self.variants.take() {
+            match variants.into_iter().nth(variant_index as usize) {
+                Some((_, args)) => self.args = Some(args),
+                None => {
+                    let msg = format!("invalid constructor index {}", variant_index);
+                    return Err(Error::Message(msg));
+                }
+            }
+        }
+        let index: de::value::U32Deserializer<Error> = (variant_index as u32).into_deserializer();
+        let val = seed.deserialize(index)?;
         Ok((val, self))
     }
 }
@@ -388,28 +692,44 @@ impl<'de, 'a, R: io::Read + 'a> de::VariantAccess<'de> for VariantAccess<'a, R>
     type Error = Error;
 
     fn unit_variant(self) -> Result<()> {
-        Ok(())
+        // With a shape, the arguments of the actual constructor are skipped,
+        // e.g. when it is mapped to a `#[serde(other)]` variant.
+        match self.args {
+            Some(args) if !args.is_empty() => {
+                self.de.shape = Some(Shape::Tuple(args));
+                de::Deserializer::deserialize_ignored_any(self.de, de::IgnoredAny)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
     }
 
     fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
     where
         T: de::DeserializeSeed<'de>,
     {
+        self.de.shape = match self.args {
+            Some(mut args) if args.len() == 1 => args.pop(),
+            Some(args) => Some(Shape::Tuple(args)),
+            None => None,
+        };
         seed.deserialize(self.de)
     }
 
-    fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    fn tuple_variant<V>(mut self, len: usize, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        visitor.visit_seq(SeqWithLen::new(self.de, len))
+        let shapes = self.args_shapes();
+        visitor.visit_seq(SeqWithLen::with_shapes(self.de, len, shapes))
     }
 
-    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    fn struct_variant<V>(mut self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        visitor.visit_seq(SeqWithLen::new(self.de, fields.len()))
+        let shapes = self.args_shapes();
+        visitor.visit_seq(SeqWithLen::with_shapes(self.de, fields.len(), shapes))
     }
 }
 
@@ -418,7 +738,23 @@ where
     R: io::Read,
     T: de::Deserialize<'a>,
 {
-    let mut de = Deserializer::new(rdr);
+    deserialize_all(Deserializer::new(rdr))
+}
+
+/// Deserializes some data encoded with a type of the given shape, the
+/// target type can use `deserialize_any`, e.g. untagged enums.
+pub fn from_slice_with_shape<'a, T>(v: &'a [u8], shape: &Shape) -> Result<T>
+where
+    T: de::Deserialize<'a>,
+{
+    deserialize_all(Deserializer::with_shape(v, shape))
+}
+
+fn deserialize_all<'a, R, T>(mut de: Deserializer<R>) -> Result<T>
+where
+    R: io::Read,
+    T: de::Deserialize<'a>,
+{
     let value = de::Deserialize::deserialize(&mut de)?;
     match de.read.read_u8() {
         Ok(_) => Err(Error::TrailingCharacters),
@@ -442,3 +778,83 @@ where
 {
     from_reader(s.as_bytes())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{from_slice, from_slice_with_shape, to_vec, HasShape};
+    use serde::de::IgnoredAny;
+    use serde_binprot_derive::BinShape;
+    use serde_derive::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, BinShape)]
+    enum Kind {
+        Start,
+        Stop { code: i64 },
+    }
+
+    #[derive(Serialize, BinShape)]
+    struct Event {
+        id: i64,
+        kind: Kind,
+        tags: Vec<String>,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(untagged)]
+    enum Key {
+        Id(i64),
+        Name(String),
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum KindV0 {
+        Start,
+        #[serde(other)]
+        Unknown,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct EventV0 {
+        id: Key,
+        kind: KindV0,
+        tags: IgnoredAny,
+    }
+
+    #[test]
+    fn test_with_shape() {
+        let event = Event {
+            id: 7,
+            kind: Kind::Stop { code: 3 },
+            tags: vec!["a".to_string()],
+        };
+        let data = to_vec(&event).unwrap();
+        let shape = Event::shape();
+        let v0: EventV0 = from_slice_with_shape(&data, &shape).unwrap();
+        assert_eq!((v0.id, v0.kind), (Key::Id(7), KindV0::Unknown));
+        assert!(from_slice::<EventV0>(&data).is_err());
+        let start = to_vec(&Kind::Start).unwrap();
+        assert_eq!(from_slice::<KindV0>(&start).unwrap(), KindV0::Start);
+        from_slice_with_shape::<IgnoredAny>(&data, &shape).unwrap();
+
+        let data = to_vec(&"foo").unwrap();
+        let key: Key = from_slice_with_shape(&data, &String::shape()).unwrap();
+        assert_eq!(key, Key::Name("foo".to_string()));
+        let mut de = super::Deserializer::with_shape(&data[..], &String::shape());
+        serde::Deserialize::deserialize(&mut de)
+            .map(|_: IgnoredAny| ())
+            .unwrap();
+        assert!(de.shape.is_none());
+
+        let data = to_vec(&(1i64, 2i64)).unwrap();
+        let shape = crate::Shape::Record(vec![
+            ("x".to_string(), i64::shape()),
+            ("y".to_string(), i64::shape()),
+        ]);
+        let map: BTreeMap<String, i64> = from_slice_with_shape(&data, &shape).unwrap();
+        assert_eq!(
+            map.into_iter().collect::<Vec<_>>(),
+            [("x".to_string(), 1), ("y".to_string(), 2)]
+        );
+    }
+}
@@ -0,0 +1,433 @@
+//! Structural comparison of two messages encoded with the same shape.
+//!
+//! Both messages are walked in parallel following the shape and the
+//! differences are reported by path, e.g. `.legs[1].qty: 100 vs 1000`.
+//! Elements present on one side only are reported as missing on the other
+//! side. When one of the messages cannot be decoded, typically because it
+//! was encoded with a different layout, the position where decoding fails
+//! is reported and the comparison stops there.
+//!
+//! ```
+//! use serde_binprot::HasShape;
+//!
+//! let left = serde_binprot::to_vec(&(1i64, vec![2i64, 3])).unwrap();
+//! let right = serde_binprot::to_vec(&(1i64, vec![2i64, 4, 5])).unwrap();
+//! let diffs = serde_binprot::diff::diff(&left, &right, &<(i64, Vec<i64>)>::shape());
+//! let diffs: Vec<_> = diffs.iter().map(|d| d.to_string()).collect();
+//! assert_eq!(diffs, [".1[1]: 3 vs 4", ".1[2]: missing vs 5"]);
+//! ```
+use crate::error::Error;
+use crate::shape::{HasShape, Kind, Shape};
+use crate::value::{decode_value_prefix, Value};
+use crate::walk::MAX_DEPTH;
+use std::fmt;
+
+/// One side of a difference.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    Value(Value),
+    /// The value is not present on this side.
+    Missing,
+    /// The value cannot be decoded, `offset` is where decoding failed.
+    Invalid {
+        offset: usize,
+        msg: String,
+    },
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Entry::Value(value) => write!(f, "{}", value),
+            Entry::Missing => write!(f, "missing"),
+            Entry::Invalid { offset, msg } => write!(f, "<invalid at byte {}: {}>", offset, msg),
+        }
+    }
+}
+
+/// A difference between the two messages at some path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    pub path: String,
+    pub left: Entry,
+    pub right: Entry,
+}
+
+impl Difference {
+    /// Whether decoding failed on one side, the following data could not be
+    /// compared.
+    pub fn is_divergence(&self) -> bool {
+        matches!(self.left, Entry::Invalid { .. }) || matches!(self.right, Entry::Invalid { .. })
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = if self.path.is_empty() {
+            "."
+        } else {
+            &self.path
+        };
+        write!(f, "{}: {} vs {}", path, self.left, self.right)
+    }
+}
+
+/// Compares two messages encoded with a type of the given shape. An empty
+/// result means that the two messages are identical.
+pub fn diff(left: &[u8], right: &[u8], shape: &Shape) -> Vec<Difference> {
+    let mut differ = Differ {
+        data: [left, right],
+        offsets: [0, 0],
+        diffs: vec![],
+        depth: 0,
+    };
+    if differ.value(shape, "") {
+        let trailing = |side: usize| {
+            let offset = differ.offsets[side];
+            if offset < differ.data[side].len() {
+                let msg = "trailing bytes".to_string();
+                Entry::Invalid { offset, msg }
+            } else {
+                Entry::Missing
+            }
+        };
+        let (left, right) = (trailing(0), trailing(1));
+        if left != Entry::Missing || right != Entry::Missing {
+            let path = String::new();
+            differ.diffs.push(Difference { path, left, right })
+        }
+    }
+    differ.diffs
+}
+
+/// Compares two messages encoded with type `T`.
+pub fn diff_as<T: HasShape + ?Sized>(left: &[u8], right: &[u8]) -> Vec<Difference> {
+    diff(left, right, &T::shape())
+}
+
+struct Differ<'a> {
+    data: [&'a [u8]; 2],
+    offsets: [usize; 2],
+    diffs: Vec<Difference>,
+    depth: usize,
+}
+
+impl<'a> Differ<'a> {
+    // Decodes the value of the given shape on one side.
+    fn entry(&mut self, side: usize, shape: &Shape) -> Entry {
+        let offset = self.offsets[side];
+        match decode_value_prefix(&self.data[side][offset..], shape) {
+            Ok((value, len)) => {
+                self.offsets[side] += len;
+                Entry::Value(value)
+            }
+            Err(Error::AtPath {
+                path,
+                offset: o,
+                msg,
+            }) => {
+                let msg = if path == "." {
+                    msg
+                } else {
+                    format!("{} at {}", msg, path)
+                };
+                Entry::Invalid {
+                    offset: offset + o,
+                    msg,
+                }
+            }
+            Err(err) => Entry::Invalid {
+                offset,
+                msg: err.to_string(),
+            },
+        }
+    }
+
+    // Records a difference, returns false when the comparison cannot go on.
+    fn push(&mut self, path: &str, left: Entry, right: Entry) -> bool {
+        let diff = Difference {
+            path: path.to_string(),
+            left,
+            right,
+        };
+        let ok = !diff.is_divergence();
+        self.diffs.push(diff);
+        ok
+    }
+
+    // Decodes the whole values starting at `start` on both sides and records
+    // them as a difference.
+    fn changed(&mut self, start: [usize; 2], shape: &Shape, path: &str) -> bool {
+        self.offsets = start;
+        let left = self.entry(0, shape);
+        let right = self.entry(1, shape);
+        self.push(path, left, right)
+    }
+
+    // Compares a leaf on both sides, using the raw bytes so that e.g. nans
+    // with the same payload are equal.
+    fn leaf(&mut self, shape: &Shape, path: &str) -> bool {
+        let start = self.offsets;
+        let left = self.entry(0, shape);
+        let right = self.entry(1, shape);
+        let same = |differ: &Self| {
+            let bytes = |side: usize| &differ.data[side][start[side]..differ.offsets[side]];
+            bytes(0) == bytes(1)
+        };
+        match (&left, &right) {
+            (Entry::Value(_), Entry::Value(_)) if same(self) => true,
+            _ => self.push(path, left, right),
+        }
+    }
+
+    // Reads a tag on both sides, i.e. the byte of an option or of a variant
+    // constructor, or the hash of a polymorphic variant.
+    fn tags(&mut self, len: usize) -> Option<[&'a [u8]; 2]> {
+        let tag = |side: usize| {
+            let offset = self.offsets[side];
+            self.data[side].get(offset..offset + len)
+        };
+        let tags = [tag(0)?, tag(1)?];
+        for side in 0..2 {
+            self.offsets[side] += len
+        }
+        Some(tags)
+    }
+
+    fn value(&mut self, shape: &Shape, path: &str) -> bool {
+        if self.depth >= MAX_DEPTH {
+            let msg = "value nested too deeply".to_string();
+            let invalid = |side: usize| Entry::Invalid {
+                offset: self.offsets[side],
+                msg: msg.clone(),
+            };
+            let (left, right) = (invalid(0), invalid(1));
+            return self.push(path, left, right);
+        }
+        self.depth += 1;
+        let res = self.resolved(&shape.resolve(), path);
+        self.depth -= 1;
+        res
+    }
+
+    fn resolved(&mut self, shape: &Shape, path: &str) -> bool {
+        let start = self.offsets;
+        let kind = match shape.kind() {
+            Ok(kind) => kind,
+            // The error is reported when decoding the value.
+            Err(_) => return self.leaf(shape, path),
+        };
+        match kind {
+            Kind::Option(arg) => match self.tags(1) {
+                Some([[1], [1]]) => self.value(arg, &format!("{}?", path)),
+                Some([[0], [0]]) => true,
+                _ => self.changed(start, shape, path),
+            },
+            Kind::Map(key, value) => {
+                let elt = Shape::Tuple(vec![key.clone(), value.clone()]);
+                self.elements(shape, &elt, path, |differ, path| {
+                    differ.value(key, &format!("{}<0>", path))
+                        && differ.value(value, &format!("{}<1>", path))
+                })
+            }
+            Kind::Sequence(_, elt) => {
+                self.elements(shape, elt, path, |differ, path| differ.value(elt, path))
+            }
+            Kind::Tuple(shapes) => shapes
+                .iter()
+                .enumerate()
+                .all(|(index, shape)| self.value(shape, &format!("{}.{}", path, index))),
+            Kind::Record(fields) => fields
+                .iter()
+                .all(|(name, shape)| self.value(shape, &format!("{}.{}", path, name))),
+            Kind::Variant(variants) => match self.tags(1) {
+                Some([[i1], [i2]]) if i1 == i2 && (*i1 as usize) < variants.len() => {
+                    let (name, args) = &variants[*i1 as usize];
+                    let path = format!("{}.{}", path, name);
+                    if args.len() == 1 {
+                        return self.value(&args[0], &path);
+                    }
+                    args.iter()
+                        .enumerate()
+                        .all(|(index, arg)| self.value(arg, &format!("{}.{}", path, index)))
+                }
+                _ => self.changed(start, shape, path),
+            },
+            Kind::PolyVariant(variants) => match self.tags(4) {
+                Some([t1, t2]) if t1 == t2 => {
+                    let hash = i32::from_le_bytes([t1[0], t1[1], t1[2], t1[3]]) >> 1;
+                    let variant = variants
+                        .iter()
+                        .find(|(name, _)| crate::ser::hash_variant(name) == hash);
+                    match variant {
+                        Some((name, Some(arg))) => self.value(arg, &format!("{}.`{}", path, name)),
+                        Some((_, None)) => true,
+                        None => self.changed(start, shape, path),
+                    }
+                }
+                _ => self.changed(start, shape, path),
+            },
+            _ => self.leaf(shape, path),
+        }
+    }
+
+    // Compares the elements of two collections, the common elements are
+    // compared with `f` and the extra ones reported as missing on the other
+    // side.
+    fn elements<F>(&mut self, shape: &Shape, elt: &Shape, path: &str, mut f: F) -> bool
+    where
+        F: FnMut(&mut Self, &str) -> bool,
+    {
+        let start = self.offsets;
+        let len = |entry: &Entry| match entry {
+            Entry::Value(Value::Nat0(len)) => Some(*len),
+            _ => None,
+        };
+        let left = self.entry(0, &Shape::nat0());
+        let right = self.entry(1, &Shape::nat0());
+        let (l1, l2) = match (len(&left), len(&right)) {
+            (Some(l1), Some(l2)) => (l1, l2),
+            _ => return self.changed(start, shape, path),
+        };
+        for index in 0..std::cmp::min(l1, l2) {
+            if !f(self, &format!("{}[{}]", path, index)) {
+                return false;
+            }
+        }
+        for index in std::cmp::min(l1, l2)..std::cmp::max(l1, l2) {
+            let path = format!("{}[{}]", path, index);
+            let ok = if l1 > l2 {
+                let left = self.entry(0, elt);
+                self.push(&path, left, Entry::Missing)
+            } else {
+                let right = self.entry(1, elt);
+                self.push(&path, Entry::Missing, right)
+            };
+            if !ok {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diff_as;
+    use serde_binprot_derive::BinShape;
+    use serde_derive::Serialize;
+
+    #[derive(BinShape, Serialize)]
+    enum Side {
+        Buy,
+        Sell,
+    }
+
+    #[derive(BinShape, Serialize)]
+    struct Leg {
+        qty: i64,
+        side: Side,
+        note: Option<String>,
+    }
+
+    #[derive(BinShape, Serialize)]
+    struct Order {
+        id: String,
+        legs: Vec<Leg>,
+        price: f64,
+    }
+
+    #[test]
+    fn test_diff() {
+        let leg = |qty, side, note: Option<&str>| Leg {
+            qty,
+            side,
+            note: note.map(|s| s.to_string()),
+        };
+        let left = Order {
+            id: "abc".to_string(),
+            legs: vec![leg(1, Side::Buy, None), leg(100, Side::Buy, Some("x"))],
+            price: 2.5,
+        };
+        let right = Order {
+            id: "abc".to_string(),
+            legs: vec![
+                leg(1, Side::Buy, Some("y")),
+                leg(1000, Side::Sell, Some("z")),
+                leg(3, Side::Buy, None),
+            ],
+            price: 2.5,
+        };
+        let left = crate::to_vec(&left).unwrap();
+        let right = crate::to_vec(&right).unwrap();
+        let diffs = diff_as::<Order>(&left, &right);
+        let diffs: Vec<_> = diffs.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diffs,
+            [
+                ".legs[0].note: () vs (y)",
+                ".legs[1].qty: 100 vs 1000",
+                ".legs[1].side: Buy vs Sell",
+                ".legs[1].note?: x vs z",
+                ".legs[2]: missing vs ((qty 3)(side Buy)(note()))",
+            ]
+        );
+        assert!(diff_as::<Order>(&left, &left).is_empty());
+
+        // A payload with a different layout, the qty is a float.
+        let other = crate::to_vec(&("abc", vec![(1.5f64, 0u8, None::<String>)], 2.5)).unwrap();
+        let diffs = diff_as::<Order>(&left, &other);
+        let last = diffs.last().unwrap();
+        assert!(last.is_divergence());
+        assert_eq!(
+            last.to_string(),
+            ".: missing vs <invalid at byte 16: trailing bytes>"
+        );
+
+        // A truncated payload.
+        let diffs = diff_as::<Order>(&left, &left[..left.len() - 3]);
+        let msg = format!(
+            ".price: 2.5 vs <invalid at byte {}: unexpected end of data>",
+            left.len() - 8
+        );
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].to_string(), msg);
+    }
+
+    #[test]
+    fn test_diff_poly_variant_hashtbl() {
+        use crate::containers::Hashtbl;
+        use crate::{HasShape, PolyVariantTag, Shape};
+
+        let mut price = std::collections::BTreeMap::new();
+        price.insert("Market".to_string(), None);
+        price.insert("Limit".to_string(), Some(Shape::float()));
+        let shape = Shape::Tuple(vec![
+            Shape::PolyVariant(price),
+            <Hashtbl<String, i64>>::shape(),
+        ]);
+        // The tables are encoded as lists of pairs so that the order of the
+        // entries is deterministic, the layout is the same.
+        let limit = |price: f64| (PolyVariantTag::new("Limit"), price);
+        let left = crate::to_vec(&(limit(2.5), vec![("a", 1i64), ("b", 2)])).unwrap();
+        let right = crate::to_vec(&(limit(3.5), vec![("a", 1i64), ("b", 5), ("c", 3)])).unwrap();
+        let diffs = super::diff(&left, &right, &shape);
+        let diffs: Vec<_> = diffs.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diffs,
+            [
+                ".0.`Limit: 2.5 vs 3.5",
+                ".1[1]<1>: 2 vs 5",
+                ".1[2]: missing vs (c 3)"
+            ]
+        );
+
+        let market = crate::to_vec(&(PolyVariantTag::new("Market"), vec![("a", 1i64)])).unwrap();
+        let diffs = super::diff(&left, &market, &shape);
+        let diffs: Vec<_> = diffs.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diffs,
+            [".0: (Limit 2.5) vs Market", ".1[1]: (b 2) vs missing"]
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! A self-describing envelope embedding the shape ahead of the payload.
+//!
+//! The envelope starts with a magic number, followed by the bin_prot
+//! encoding of the shape digest and of the shape s-expression. The payload
+//! comes last and is byte-identical to the output of [`crate::to_vec`], so
+//! it can be extracted and read as plain bin_prot.
+//!
+//! ```
+//! use serde_binprot::envelope::{self, Envelope};
+//!
+//! let data = envelope::to_vec(&(42i64, "foo".to_string())).unwrap();
+//! let env = Envelope::parse(&data).unwrap();
+//! assert_eq!(env.payload(), serde_binprot::to_vec(&(42i64, "foo")).unwrap());
+//! let (i, s): (i64, String) = env.deserialize().unwrap();
+//! assert_eq!((i, s.as_str()), (42, "foo"));
+//! ```
+use crate::de::{Deserializer, SliceReader};
+use crate::error::{Error, Result};
+use crate::shape::{HasShape, Shape};
+use crate::types::Md5Digest;
+use crate::value::Value;
+use serde::de::{Deserialize, DeserializeOwned};
+use serde::ser::Serialize;
+
+/// The bytes that envelopes start with, the last one is the format version.
+pub const MAGIC: &[u8; 4] = b"BPS\x01";
+
+// Limits on the untrusted shape s-expression, checked before parsing it.
+const MAX_HEADER_LEN: u64 = 1 << 20;
+const MAX_HEADER_DEPTH: usize = 256;
+
+// The maximal nesting of the lists in a s-expression, without parsing it.
+fn nesting(sexp: &[u8]) -> usize {
+    let (mut depth, mut max_depth) = (0usize, 0);
+    let (mut quoted, mut escaped) = (false, false);
+    for &c in sexp {
+        match c {
+            _ if escaped => escaped = false,
+            b'\\' if quoted => escaped = true,
+            b'"' => quoted = !quoted,
+            b'(' if !quoted => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            b')' if !quoted => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max_depth
+}
+
+/// A parsed envelope, borrowing its payload from the original data.
+#[derive(Debug, Clone)]
+pub struct Envelope<'a> {
+    shape: Shape,
+    digest: Md5Digest,
+    payload: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    /// Parses the envelope header and checks that the embedded digest
+    /// matches the embedded shape.
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let data = data
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| Error::Message("missing envelope magic number".to_string()))?;
+        let mut de = Deserializer::new(SliceReader::new(data));
+        let digest = Md5Digest::deserialize(&mut de)?;
+        let len = de.read_nat0()?;
+        if len > MAX_HEADER_LEN {
+            return Err(Error::Message(format!("envelope shape of {} bytes", len)));
+        }
+        let shape = de.read_bytes(len)?;
+        if nesting(&shape) > MAX_HEADER_DEPTH {
+            return Err(Error::Message(
+                "envelope shape nested too deeply".to_string(),
+            ));
+        }
+        let payload = &data[de.get_ref().offset()..];
+        let shape = std::str::from_utf8(&shape)
+            .map_err(|_| Error::Message("envelope shape is not UTF-8".to_string()))?;
+        let shape = Shape::from_sexp_str(shape)?;
+        let actual = shape.eval_md5();
+        if actual != digest {
+            return Err(Error::ShapeMismatch {
+                expected: digest.to_hex(),
+                actual: actual.to_hex(),
+            });
+        }
+        Ok(Envelope {
+            shape,
+            digest,
+            payload,
+        })
+    }
+
+    pub fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    pub fn digest(&self) -> Md5Digest {
+        self.digest
+    }
+
+    /// The plain bin_prot payload.
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Checks that the payload can be read as a `T`, i.e. that the shape of
+    /// `T` is the embedded one up to changes that do not break readers of
+    /// the embedded shape, e.g. name-only changes.
+    pub fn check<T: HasShape + ?Sized>(&self) -> Result<()> {
+        let shape = T::shape();
+        if shape.eval_md5() == self.digest || crate::compat::can_read(&self.shape, &shape) {
+            return Ok(());
+        }
+        Err(Error::ShapeMismatch {
+            expected: self.digest.to_hex(),
+            actual: shape.eval_digest(),
+        })
+    }
+
+    /// Deserializes the payload as a `T` after checking its shape.
+    pub fn deserialize<T: DeserializeOwned + HasShape>(&self) -> Result<T> {
+        self.check::<T>()?;
+        crate::from_slice(self.payload)
+    }
+
+    /// Decodes the payload as a dynamic value using the embedded shape.
+    pub fn decode_value(&self) -> Result<Value> {
+        crate::decode_value(self.payload, &self.shape)
+    }
+}
+
+/// Wraps some bin_prot payload of the given shape in an envelope.
+pub fn encode(shape: &Shape, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut out = MAGIC.to_vec();
+    let header = (shape.eval_md5(), shape.to_sexp().to_string_mach());
+    crate::to_writer(&mut out, &header)?;
+    out.extend_from_slice(payload);
+    Ok(out)
+}
+
+/// Serializes a value and wraps it in an envelope with the shape of `T`.
+pub fn to_vec<T: Serialize + HasShape + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    encode(&T::shape(), &crate::to_vec(value)?)
+}
+
+/// Deserializes a value from an envelope, checking the embedded shape
+/// against the shape of `T` first.
+pub fn from_slice<T: DeserializeOwned + HasShape>(data: &[u8]) -> Result<T> {
+    Envelope::parse(data)?.deserialize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_slice, to_vec, Envelope};
+    use crate::{Error, HasShape, Value};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(BinShape, Serialize, Deserialize, Debug, PartialEq)]
+    struct Order {
+        qty: i64,
+        tags: Vec<String>,
+    }
+
+    #[derive(BinShape, Deserialize, Debug, PartialEq)]
+    struct Renamed {
+        quantity: i64,
+        labels: Vec<String>,
+    }
+
+    #[derive(BinShape, Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Other {
+        qty: f64,
+    }
+
+    #[test]
+    fn test_envelope() {
+        let order = Order {
+            qty: 3,
+            tags: vec!["a".to_string()],
+        };
+        let data = to_vec(&order).unwrap();
+        let env = Envelope::parse(&data).unwrap();
+        assert_eq!(env.shape(), &Order::shape());
+        assert_eq!(env.payload(), crate::to_vec(&order).unwrap());
+        assert_eq!(
+            env.decode_value().unwrap(),
+            Value::Record(vec![
+                ("qty".to_string(), Value::Int(3)),
+                (
+                    "tags".to_string(),
+                    Value::List(vec![Value::String("a".to_string())])
+                ),
+            ])
+        );
+        assert_eq!(from_slice::<Order>(&data).unwrap(), order);
+        let renamed: Renamed = from_slice(&data).unwrap();
+        assert_eq!(renamed.quantity, 3);
+        assert!(matches!(
+            from_slice::<Other>(&data),
+            Err(Error::ShapeMismatch { .. })
+        ));
+        assert!(Envelope::parse(&data[1..]).is_err());
+        let mut corrupted = data.clone();
+        corrupted[6] ^= 1;
+        assert!(Envelope::parse(&corrupted).is_err());
+
+        let deep = format!("{}{}", "(".repeat(100_000), ")".repeat(100_000));
+        let mut malicious = super::MAGIC.to_vec();
+        crate::to_writer(&mut malicious, &(Order::shape().eval_md5(), deep)).unwrap();
+        assert!(matches!(
+            Envelope::parse(&malicious),
+            Err(Error::Message(_))
+        ));
+        let mut malicious = super::MAGIC.to_vec();
+        malicious.extend_from_slice(&data[4..21]);
+        malicious.extend_from_slice(&[0xfd, 0xff, 0xff, 0xff, 0x7f]);
+        assert!(matches!(
+            Envelope::parse(&malicious),
+            Err(Error::Message(_))
+        ));
+    }
+}
@@ -16,12 +16,54 @@ pub enum Error {
     TrailingCharacters,
     CannotDeserializeAny,
     UnknownSeqLength,
+    UnknownVersion(u64),
+    UnknownTypeKey {
+        key: String,
+        known: Vec<String>,
+    },
+    ParseError {
+        line: usize,
+        column: usize,
+        msg: String,
+    },
+    ShapeMismatch {
+        expected: String,
+        actual: String,
+    },
+    // An error when processing some data according to a shape, `path`
+    // locates the value within the shape and `offset` within the data.
+    AtPath {
+        path: String,
+        offset: usize,
+        msg: String,
+    },
 
     IoError(std::io::Error),
     TryFromIntError(std::num::TryFromIntError),
     FromUtf8Error(std::string::FromUtf8Error),
 }
 
+impl Error {
+    // Locates an error that happened when processing the value at `path`,
+    // starting at `offset`, errors that are already located are kept as is.
+    pub(crate) fn at_path(self, path: &str, offset: usize) -> Error {
+        let msg = match self {
+            err @ Error::AtPath { .. } => return err,
+            Error::IoError(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                "unexpected end of data".to_string()
+            }
+            Error::Message(msg) => msg,
+            err => err.to_string(),
+        };
+        let path = if path.is_empty() { "." } else { path };
+        Error::AtPath {
+            path: path.to_string(),
+            offset,
+            msg,
+        }
+    }
+}
+
 impl ser::Error for Error {
     fn custom<T: Display>(msg: T) -> Self {
         Error::Message(msg.to_string())
@@ -0,0 +1,435 @@
+//! Annotated hexdumps of encoded messages.
+//!
+//! Each byte range of a message is labelled with the path of the value it
+//! encodes, the integer code used for variable-length integers, and the
+//! decoded value. The bytes used by each field and sub-tree are summed up,
+//! the elements of collections being grouped together, which shows what
+//! dominates the size of a message.
+//!
+//! ```
+//! use serde_binprot::HasShape;
+//!
+//! let data = serde_binprot::to_vec(&(1000i64, "foo")).unwrap();
+//! let explanation = serde_binprot::explain::explain(&data, &<(i64, String)>::shape());
+//! let span = &explanation.spans[0];
+//! assert_eq!((span.path.as_str(), span.code, span.len), (".0", Some("CODE_INT16"), 3));
+//! ```
+use crate::de::{Deserializer, SliceReader};
+use crate::error::{Error, Result};
+use crate::shape::{HasShape, Shape};
+use crate::value::Value;
+use crate::walk::{Node, Walker};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A byte range of the message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+    pub path: String,
+    /// What the bytes encode: `int`, `string`, `option`, `length`...
+    pub kind: &'static str,
+    /// The code prefixing a variable-length integer or length, if any.
+    pub code: Option<&'static str>,
+    pub value: String,
+}
+
+/// The bytes used by the values at a path, the indexes of the elements of
+/// collections are replaced with `[]` so that all the elements are counted
+/// together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Size {
+    pub path: String,
+    pub bytes: usize,
+    pub count: usize,
+}
+
+#[derive(Debug)]
+pub struct Explanation<'a> {
+    data: &'a [u8],
+    pub spans: Vec<Span>,
+    /// The sizes in the order in which the paths first appear.
+    pub sizes: Vec<Size>,
+    /// Why the message could not be decoded entirely, the spans and sizes
+    /// cover the data before the error.
+    pub error: Option<Error>,
+}
+
+/// Explains some data encoded with a type of the given shape.
+pub fn explain<'a>(data: &'a [u8], shape: &Shape) -> Explanation<'a> {
+    let mut explainer = Explainer {
+        data,
+        de: Deserializer::new(SliceReader::new(data)),
+        spans: vec![],
+        sizes: vec![],
+        size_index: HashMap::new(),
+        depth: 0,
+    };
+    let mut error = explainer.entry(shape, "").err();
+    if error.is_none() && explainer.offset() < data.len() {
+        let err = Error::Message("trailing bytes".to_string());
+        error = Some(err.at_path("", explainer.offset()))
+    }
+    Explanation {
+        data,
+        spans: explainer.spans,
+        sizes: explainer.sizes,
+        error,
+    }
+}
+
+/// Explains some data encoded with type `T`.
+pub fn explain_as<T: HasShape + ?Sized>(data: &[u8]) -> Explanation<'_> {
+    explain(data, &T::shape())
+}
+
+fn code(byte: u8) -> Option<&'static str> {
+    match byte {
+        crate::CODE_NEG_INT8 => Some("CODE_NEG_INT8"),
+        crate::CODE_INT16 => Some("CODE_INT16"),
+        crate::CODE_INT32 => Some("CODE_INT32"),
+        crate::CODE_INT64 => Some("CODE_INT64"),
+        _ => None,
+    }
+}
+
+fn generic_path(path: &str) -> String {
+    let mut generic = String::new();
+    let mut in_index = false;
+    for c in path.chars() {
+        match c {
+            '[' => {
+                in_index = true;
+                generic.push_str("[]")
+            }
+            ']' if in_index => in_index = false,
+            _ if in_index => {}
+            c => generic.push(c),
+        }
+    }
+    generic
+}
+
+struct Explainer<'a> {
+    data: &'a [u8],
+    de: Deserializer<SliceReader<'a>>,
+    spans: Vec<Span>,
+    sizes: Vec<Size>,
+    size_index: HashMap<String, usize>,
+    depth: usize,
+}
+
+impl<'a> Explainer<'a> {
+    fn push(
+        &mut self,
+        offset: usize,
+        path: &str,
+        kind: &'static str,
+        code: Option<&'static str>,
+        value: String,
+    ) {
+        let len = self.offset() - offset;
+        self.spans.push(Span {
+            offset,
+            len,
+            path: path.to_string(),
+            kind,
+            code,
+            value,
+        })
+    }
+
+    fn length(&mut self, offset: usize, len: u64, path: &str) {
+        let code = code(self.data[offset]);
+        self.push(offset, path, "length", code, len.to_string())
+    }
+
+    // Explains a value and accounts for its size.
+    fn entry(&mut self, shape: &Shape, path: &str) -> Result<()> {
+        let generic = generic_path(path);
+        let index = match self.size_index.get(&generic) {
+            Some(index) => *index,
+            None => {
+                let index = self.sizes.len();
+                self.sizes.push(Size {
+                    path: if path.is_empty() {
+                        ".".to_string()
+                    } else {
+                        generic.clone()
+                    },
+                    bytes: 0,
+                    count: 0,
+                });
+                self.size_index.insert(generic, index);
+                index
+            }
+        };
+        let start = self.offset();
+        let res = self.value(shape, path);
+        // The bytes of values that could not be decoded entirely are still
+        // accounted for up to the error, but the values are not counted.
+        let end = match &res {
+            Err(Error::AtPath { offset, .. }) => *offset,
+            _ => self.offset(),
+        };
+        let size = &mut self.sizes[index];
+        size.bytes += end - start;
+        if res.is_ok() {
+            size.count += 1
+        }
+        res
+    }
+}
+
+impl<'a> Walker<'a> for Explainer<'a> {
+    type Output = ();
+
+    fn de(&mut self) -> &mut Deserializer<SliceReader<'a>> {
+        &mut self.de
+    }
+
+    fn depth(&mut self) -> &mut usize {
+        &mut self.depth
+    }
+
+    fn node(&mut self, node: Node, offset: usize, path: &str) -> Result<()> {
+        match node {
+            Node::Scalar(value) => {
+                let kind = match value.kind() {
+                    "bytes" => "string",
+                    kind => kind,
+                };
+                let code = match kind {
+                    "int" | "nat0" | "string" => code(self.data[offset]),
+                    _ => None,
+                };
+                let description = match &value {
+                    Value::String(s) => format!("{:?}", s),
+                    Value::Bytes(b) => format!("{} bytes", b.len()),
+                    value => value.to_string(),
+                };
+                self.push(offset, path, kind, code, description);
+                Ok(())
+            }
+            Node::Option(arg) => {
+                let value = if arg.is_some() { "Some" } else { "None" };
+                self.push(offset, path, "option", None, value.to_string());
+                match arg {
+                    Some(arg) => self.entry(arg, &format!("{}?", path)),
+                    None => Ok(()),
+                }
+            }
+            Node::Sequence(len, elt) => {
+                self.length(offset, len, path);
+                for index in 0..len {
+                    self.entry(elt, &format!("{}[{}]", path, index))?
+                }
+                Ok(())
+            }
+            Node::Map(len, key, value) => {
+                self.length(offset, len, path);
+                for index in 0..len {
+                    let path = format!("{}[{}]", path, index);
+                    self.entry(key, &format!("{}<0>", path))?;
+                    self.entry(value, &format!("{}<1>", path))?
+                }
+                Ok(())
+            }
+            Node::Tuple(shapes) => {
+                for (index, shape) in shapes.iter().enumerate() {
+                    self.entry(shape, &format!("{}.{}", path, index))?
+                }
+                Ok(())
+            }
+            Node::Record(fields) => {
+                for (name, shape) in fields.iter() {
+                    self.entry(shape, &format!("{}.{}", path, name))?
+                }
+                Ok(())
+            }
+            Node::Constructor { name, args, poly } => {
+                let name = if poly {
+                    format!("`{}", name)
+                } else {
+                    name.to_string()
+                };
+                let ctor_path = format!("{}.{}", path, name);
+                self.push(offset, path, "constructor", None, name);
+                if args.len() == 1 {
+                    return self.entry(&args[0], &ctor_path);
+                }
+                for (index, arg) in args.iter().enumerate() {
+                    self.entry(arg, &format!("{}.{}", ctor_path, index))?
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// The number of bytes displayed on each line of the hexdump.
+const BYTES_PER_LINE: usize = 16;
+
+impl<'a> fmt::Display for Explanation<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = 3 * BYTES_PER_LINE - 1;
+        for span in self.spans.iter() {
+            let path = if span.path.is_empty() {
+                "."
+            } else {
+                &span.path
+            };
+            let code = span.code.map_or(String::new(), |code| format!(" {}", code));
+            let bytes = &self.data[span.offset..span.offset + span.len];
+            let mut lines = bytes.chunks(BYTES_PER_LINE).map(|chunk| {
+                let hex: Vec<_> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+                hex.join(" ")
+            });
+            let first = lines.next().unwrap_or_default();
+            writeln!(
+                f,
+                "{:08x}  {:<width$}  {} {}{}: {}",
+                span.offset,
+                first,
+                path,
+                span.kind,
+                code,
+                span.value,
+                width = width
+            )?;
+            for line in lines {
+                writeln!(f, "{:8}  {}", "", line)?
+            }
+        }
+        writeln!(f)?;
+        writeln!(f, "{:>8}  {:>6}  path", "bytes", "count")?;
+        for size in self.sizes.iter() {
+            writeln!(f, "{:>8}  {:>6}  {}", size.bytes, size.count, size.path)?
+        }
+        match &self.error {
+            None => {}
+            Some(Error::AtPath { path, offset, msg }) => {
+                writeln!(f, "\nerror at byte {} ({}): {}", offset, path, msg)?
+            }
+            Some(err) => writeln!(f, "\nerror: {}", err)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::explain_as;
+    use serde_binprot_derive::BinShape;
+    use serde_derive::Serialize;
+
+    #[derive(BinShape, Serialize)]
+    enum Side {
+        Buy,
+        Sell(f64),
+    }
+
+    #[derive(BinShape, Serialize)]
+    struct Leg {
+        qty: i64,
+        side: Side,
+    }
+
+    #[derive(BinShape, Serialize)]
+    struct Order {
+        id: u64,
+        symbol: String,
+        legs: Vec<Leg>,
+        note: Option<String>,
+    }
+
+    #[test]
+    fn test_explain() {
+        let order = Order {
+            id: 70000,
+            symbol: "a long enough symbol".to_string(),
+            legs: vec![
+                Leg {
+                    qty: -1,
+                    side: Side::Buy,
+                },
+                Leg {
+                    qty: -200,
+                    side: Side::Sell(1.5),
+                },
+            ],
+            note: None,
+        };
+        let data = crate::to_vec(&order).unwrap();
+        let explanation = explain_as::<Order>(&data);
+        let expected = [
+            "00000000  fd 70 11 01 00                                   .id nat0 CODE_INT32: 70000",
+            "00000005  14 61 20 6c 6f 6e 67 20 65 6e 6f 75 67 68 20 73  .symbol string: \"a long enough symbol\"",
+            "          79 6d 62 6f 6c",
+            "0000001a  02                                               .legs length: 2",
+            "0000001b  ff ff                                            .legs[0].qty int CODE_NEG_INT8: -1",
+            "0000001d  00                                               .legs[0].side constructor: Buy",
+            "0000001e  fe 38 ff                                         .legs[1].qty int CODE_INT16: -200",
+            "00000021  01                                               .legs[1].side constructor: Sell",
+            "00000022  00 00 00 00 00 00 f8 3f                          .legs[1].side.Sell float: 1.5",
+            "0000002a  00                                               .note option: None",
+            "",
+            "   bytes   count  path",
+            "      43       1  .",
+            "       5       1  .id",
+            "      21       1  .symbol",
+            "      16       1  .legs",
+            "      15       2  .legs[]",
+            "       5       2  .legs[].qty",
+            "      10       2  .legs[].side",
+            "       8       1  .legs[].side.Sell",
+            "       1       1  .note",
+        ];
+        assert_eq!(explanation.to_string(), expected.join("\n") + "\n");
+        assert!(explanation.error.is_none());
+
+        let explanation = explain_as::<Order>(&data[..data.len() - 3]);
+        assert_eq!(explanation.spans.len(), 7);
+        assert_eq!(explanation.sizes[0].bytes, 34);
+        let explanation = explanation.to_string();
+        let error = "error at byte 34 (.legs[1].side.Sell): unexpected end of data\n";
+        assert!(explanation.ends_with(error), "{}", explanation);
+    }
+
+    #[test]
+    fn test_explain_poly_variant_hashtbl() {
+        use crate::containers::Hashtbl;
+        use crate::{HasShape, PolyVariantTag, Shape};
+
+        let mut price = std::collections::BTreeMap::new();
+        price.insert("Market".to_string(), None);
+        price.insert("Limit".to_string(), Some(Shape::float()));
+        let shape = Shape::Tuple(vec![
+            Shape::PolyVariant(price),
+            <Hashtbl<String, i64>>::shape(),
+        ]);
+        let mut table = Hashtbl::default();
+        table.0.insert("a".to_string(), 300i64);
+        let limit = (PolyVariantTag::new("Limit"), 2.5f64);
+        let data = crate::to_vec(&(limit, table)).unwrap();
+        let explanation = super::explain(&data, &shape);
+        let expected = [
+            "00000000  37 1e 5d 10                                      .0 constructor: `Limit",
+            "00000004  00 00 00 00 00 00 04 40                          .0.`Limit float: 2.5",
+            "0000000c  01                                               .1 length: 1",
+            "0000000d  01 61                                            .1[0]<0> string: \"a\"",
+            "0000000f  fe 2c 01                                         .1[0]<1> int CODE_INT16: 300",
+            "",
+            "   bytes   count  path",
+            "      18       1  .",
+            "      12       1  .0",
+            "       8       1  .0.`Limit",
+            "       6       1  .1",
+            "       2       1  .1[]<0>",
+            "       3       1  .1[]<1>",
+        ];
+        assert_eq!(explanation.to_string(), expected.join("\n") + "\n");
+        assert!(explanation.error.is_none());
+    }
+}
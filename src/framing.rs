@@ -0,0 +1,154 @@
+//! Streams of messages framed with a size header.
+//!
+//! Each message is preceded by its length written as a 8 bytes little-endian
+//! integer, as done by `Bin_prot.Utils.bin_dump ~header:true`. The frames
+//! are read one at a time so that large streams are not loaded in memory.
+//!
+//! ```
+//! use serde_binprot::framing::{self, FrameReader};
+//!
+//! let mut data = vec![];
+//! framing::write_frame(&mut data, b"foo").unwrap();
+//! framing::write_frame(&mut data, b"").unwrap();
+//! let frames: Vec<_> = FrameReader::new(data.as_slice()).collect::<Result<_, _>>().unwrap();
+//! assert_eq!(frames, [(8, b"foo".to_vec()), (19, vec![])]);
+//! ```
+use crate::error::{Error, Result};
+use std::io::{Read, Write};
+
+/// The length of the size header.
+pub const HEADER_LENGTH: usize = 8;
+
+/// Writes a message preceded by its size header.
+pub fn write_frame<W: Write + ?Sized>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+// Locates an error raised when processing the message number `index` of a
+// stream, starting at `offset`.
+#[cfg(feature = "json")]
+pub(crate) fn in_frame(err: Error, index: usize, offset: usize) -> Error {
+    match err.at_path("", 0) {
+        Error::AtPath {
+            path,
+            offset: offset_,
+            msg,
+        } => Error::AtPath {
+            path,
+            offset: offset + offset_,
+            msg: format!("message {}: {}", index, msg),
+        },
+        err => err,
+    }
+}
+
+/// An iterator over the frames of a stream, each item is the offset of the
+/// payload in the stream together with the payload.
+pub struct FrameReader<R> {
+    reader: R,
+    offset: usize,
+    done: bool,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        FrameReader {
+            reader,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    // Reads as many bytes as possible in `buf`, returns the number of bytes
+    // read which is only smaller than the buffer at the end of the stream.
+    fn read_full(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut len = 0;
+        while len < buf.len() {
+            match self.reader.read(&mut buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(len)
+    }
+
+    fn next_frame(&mut self) -> Result<Option<(usize, Vec<u8>)>> {
+        let mut header = [0u8; HEADER_LENGTH];
+        match self.read_full(&mut header)? {
+            0 => return Ok(None),
+            HEADER_LENGTH => {}
+            _ => {
+                let msg = "truncated size header".to_string();
+                return Err(Error::Message(msg).at_path("", self.offset));
+            }
+        }
+        let len = u64::from_le_bytes(header);
+        let offset = self.offset + HEADER_LENGTH;
+        // The payload is read in chunks so that a corrupted header does not
+        // trigger a huge allocation.
+        let mut payload = vec![];
+        let mut chunk = [0u8; 8192];
+        while (payload.len() as u64) < len {
+            let want = usize::min(chunk.len(), (len - payload.len() as u64) as usize);
+            let read = self.read_full(&mut chunk[..want])?;
+            payload.extend_from_slice(&chunk[..read]);
+            if read < want {
+                let msg = format!(
+                    "truncated frame, expected {} bytes, got {}",
+                    len,
+                    payload.len()
+                );
+                return Err(Error::Message(msg).at_path("", self.offset));
+            }
+        }
+        self.offset = offset + payload.len();
+        Ok(Some((offset, payload)))
+    }
+}
+
+impl<R: Read> Iterator for FrameReader<R> {
+    type Item = Result<(usize, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let frame = self.next_frame().transpose();
+        if !matches!(frame, Some(Ok(_))) {
+            self.done = true
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_frame, FrameReader};
+    use crate::Error;
+
+    #[test]
+    fn test_truncated() {
+        let mut data = vec![];
+        write_frame(&mut data, &[1; 10000]).unwrap();
+        write_frame(&mut data, b"abc").unwrap();
+        let frames: Vec<_> = FrameReader::new(&data[..data.len() - 1]).collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].as_ref().unwrap().1.len(), 10000);
+        match &frames[1] {
+            Err(Error::AtPath { offset, msg, .. }) => {
+                assert_eq!(*offset, 10008);
+                assert_eq!(msg, "truncated frame, expected 3 bytes, got 2");
+            }
+            _ => panic!("expected a truncated frame"),
+        }
+        let frames: Vec<_> = FrameReader::new(&data[..10011]).collect();
+        assert!(matches!(
+            &frames[1],
+            Err(Error::AtPath { offset: 10008, .. })
+        ));
+    }
+}
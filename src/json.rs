@@ -0,0 +1,827 @@
+//! Conversion between bin_prot data and JSON given a shape.
+//!
+//! The conversion is streamed, no Rust type or intermediate tree is built.
+//! Values are mapped as follows:
+//! - records are objects keyed by field names,
+//! - constructors without arguments are strings, the other ones objects
+//!   with a single entry from the constructor name to the argument, or to
+//!   the list of arguments when there are more than one,
+//! - options are `null` or their content, the content is wrapped in a
+//!   single element list when it can be `null` itself, e.g. nested options,
+//! - integers are written exactly, floats with the shortest representation
+//!   that round-trips, non-finite floats as the strings `"nan"`, `"inf"`
+//!   and `"-inf"`,
+//! - strings and bytes are strings, or lists of bytes when not valid UTF-8,
+//! - maps are objects when the keys are strings that are all valid UTF-8,
+//!   lists of `[key, value]` pairs otherwise.
+//!
+//! Converting the resulting JSON back gives the original bytes.
+//!
+//! ```
+//! use serde_binprot::HasShape;
+//!
+//! let shape = <(i64, Option<String>)>::shape();
+//! let data = serde_binprot::to_vec(&(-1i64, Some("foo"))).unwrap();
+//! let json = serde_binprot::json::to_string(&data, &shape).unwrap();
+//! assert_eq!(json, r#"[-1,"foo"]"#);
+//! assert_eq!(serde_binprot::json::from_str(&json, &shape).unwrap(), data);
+//! ```
+use crate::de::{Deserializer, SliceReader};
+use crate::error::{Error, Result};
+use crate::ser::{poly_variant_tag, Serializer};
+use crate::shape::{encoding_class, is_sequence, map_args, uuid, Shape};
+use crate::value::{decode_value_prefix, Value};
+use crate::walk::{Node, Walker, MAX_DEPTH};
+use serde::de::{self, DeserializeSeed, Visitor};
+use serde::ser::Serializer as _;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+
+/// Writes some bin_prot data, encoded with a type of the given shape, as
+/// JSON.
+pub fn to_writer<W: io::Write>(data: &[u8], shape: &Shape, writer: W) -> Result<()> {
+    let mut encoder = JsonEncoder {
+        de: Deserializer::new(SliceReader::new(data)),
+        out: writer,
+        depth: 0,
+    };
+    encoder.value(shape, "")?;
+    if encoder.de.get_ref().remaining() > 0 {
+        return Err(Error::TrailingCharacters);
+    }
+    Ok(())
+}
+
+/// Same as [`to_writer`], returning the JSON as a string.
+pub fn to_string(data: &[u8], shape: &Shape) -> Result<String> {
+    let mut out = vec![];
+    to_writer(data, shape, &mut out)?;
+    Ok(String::from_utf8(out)?)
+}
+
+/// Encodes some JSON, as produced by [`to_writer`], with a type of the given
+/// shape.
+pub fn from_reader<R: io::Read>(reader: R, shape: &Shape) -> Result<Vec<u8>> {
+    from_json(serde_json::Deserializer::from_reader(reader), shape)
+}
+
+/// Same as [`from_reader`] on a string.
+pub fn from_str(json: &str, shape: &Shape) -> Result<Vec<u8>> {
+    from_json(serde_json::Deserializer::from_str(json), shape)
+}
+
+fn from_json<'de, R: serde_json::de::Read<'de>>(
+    mut de: serde_json::Deserializer<R>,
+    shape: &Shape,
+) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    let seed = ShapeSeed {
+        shape,
+        path: String::new(),
+        out: &mut out,
+        depth: 0,
+    };
+    seed.deserialize(&mut de)
+        .and_then(|()| de.end())
+        .map_err(parse_error)?;
+    Ok(out)
+}
+
+/// Encodes a sequence of JSON values separated by whitespace, e.g. one value
+/// per line, each with a type of the given shape.
+pub fn from_str_many(json: &str, shape: &Shape) -> Result<Vec<Vec<u8>>> {
+    let mut de = serde_json::Deserializer::from_str(json);
+    let mut values = vec![];
+    // `end` only fails when there is something other than whitespace left.
+    while de.end().is_err() {
+        let mut out = vec![];
+        let seed = ShapeSeed {
+            shape,
+            path: String::new(),
+            out: &mut out,
+            depth: 0,
+        };
+        seed.deserialize(&mut de).map_err(parse_error)?;
+        values.push(out)
+    }
+    Ok(values)
+}
+
+fn parse_error(err: serde_json::Error) -> Error {
+    Error::ParseError {
+        line: err.line(),
+        column: err.column(),
+        msg: err.to_string(),
+    }
+}
+
+fn is_option(shape: &Shape) -> bool {
+    matches!(shape, Shape::Base(uuid, args) if uuid == uuid::OPTION && args.len() == 1)
+}
+
+// The values that can be `null`, an option containing them has its content
+// wrapped in a list.
+fn is_nullable(shape: &Shape) -> bool {
+    is_option(shape) || matches!(shape, Shape::Base(uuid, _) if uuid == uuid::UNIT)
+}
+
+fn is_string(shape: &Shape) -> bool {
+    matches!(shape, Shape::Base(uuid, _) if uuid == uuid::STRING)
+}
+
+// Whether the keys of the next `len` map entries are all valid UTF-8
+// strings, so that the map can be written as an object. Errors are left to
+// the encoding of the entries, which knows their location.
+fn utf8_keys(mut data: &[u8], len: u64, key: &Shape, value: &Shape) -> bool {
+    if !is_string(&key.resolve()) {
+        return false;
+    }
+    for _ in 0..len {
+        let key_len = match decode_value_prefix(data, key) {
+            Ok((Value::String(_), key_len)) => key_len,
+            _ => return false,
+        };
+        match decode_value_prefix(&data[key_len..], value) {
+            Ok((_, value_len)) => data = &data[key_len + value_len..],
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+struct JsonEncoder<'a, W> {
+    de: Deserializer<SliceReader<'a>>,
+    out: W,
+    depth: usize,
+}
+
+impl<'a, W: io::Write> JsonEncoder<'a, W> {
+    fn write(&mut self, s: &str) -> Result<()> {
+        self.out.write_all(s.as_bytes())?;
+        Ok(())
+    }
+
+    fn write_json<T: serde::Serialize + ?Sized>(&mut self, v: &T) -> Result<()> {
+        serde_json::to_writer(&mut self.out, v).map_err(|err| Error::Message(err.to_string()))
+    }
+
+    // Writes the arguments of a constructor, the constructor name has
+    // already been written.
+    fn constructor(&mut self, name: &str, args: &[Shape], path: &str) -> Result<()> {
+        if args.is_empty() {
+            return self.write_json(name);
+        }
+        self.write("{")?;
+        self.write_json(name)?;
+        self.write(":")?;
+        let path = format!("{}.{}", path, name);
+        if args.len() == 1 {
+            self.value(&args[0], &path)?
+        } else {
+            self.write("[")?;
+            for (index, arg) in args.iter().enumerate() {
+                if index > 0 {
+                    self.write(",")?
+                }
+                self.value(arg, &format!("{}.{}", path, index))?
+            }
+            self.write("]")?
+        }
+        self.write("}")
+    }
+
+    fn elements(&mut self, len: u64, shape: &Shape, path: &str) -> Result<()> {
+        self.write("[")?;
+        for index in 0..len {
+            if index > 0 {
+                self.write(",")?
+            }
+            self.value(shape, path)?
+        }
+        self.write("]")
+    }
+}
+
+impl<'a, W: io::Write> Walker<'a> for JsonEncoder<'a, W> {
+    type Output = ();
+
+    fn de(&mut self) -> &mut Deserializer<SliceReader<'a>> {
+        &mut self.de
+    }
+
+    fn depth(&mut self) -> &mut usize {
+        &mut self.depth
+    }
+
+    fn node(&mut self, node: Node, _offset: usize, path: &str) -> Result<()> {
+        match node {
+            Node::Scalar(Value::Unit) => self.write("null"),
+            Node::Scalar(Value::Bool(b)) => self.write(if b { "true" } else { "false" }),
+            Node::Scalar(Value::Float(f)) if f.is_nan() => self.write("\"nan\""),
+            Node::Scalar(Value::Float(f)) if f == f64::INFINITY => self.write("\"inf\""),
+            Node::Scalar(Value::Float(f)) if f == f64::NEG_INFINITY => self.write("\"-inf\""),
+            Node::Scalar(Value::Float(f)) => self.write_json(&f),
+            Node::Scalar(Value::Char(c)) => self.write_json(&c),
+            Node::Scalar(Value::Int(v)) => self.write_json(&v),
+            Node::Scalar(Value::Nat0(v)) => self.write_json(&v),
+            Node::Scalar(Value::String(s)) => self.write_json(&s),
+            Node::Scalar(Value::Bytes(bytes)) => match std::str::from_utf8(&bytes) {
+                Ok(s) => self.write_json(s),
+                Err(_) => self.write_json(&bytes),
+            },
+            Node::Scalar(value) => Err(Error::Message(format!("unexpected {}", value.kind()))),
+            Node::Option(None) => self.write("null"),
+            Node::Option(Some(arg)) => {
+                let path = format!("{}?", path);
+                if is_nullable(&arg.resolve()) {
+                    self.write("[")?;
+                    self.value(arg, &path)?;
+                    self.write("]")
+                } else {
+                    self.value(arg, &path)
+                }
+            }
+            Node::Sequence(len, elt) => self.elements(len, elt, &format!("{}[]", path)),
+            Node::Map(len, key, value) => {
+                let string_keys = utf8_keys(self.de.get_ref().rest(), len, key, value);
+                self.write(if string_keys { "{" } else { "[" })?;
+                for index in 0..len {
+                    if index > 0 {
+                        self.write(",")?
+                    }
+                    if string_keys {
+                        self.value(key, &format!("{}<0>", path))?;
+                        self.write(":")?;
+                        self.value(value, &format!("{}<1>", path))?
+                    } else {
+                        self.write("[")?;
+                        self.value(key, &format!("{}<0>", path))?;
+                        self.write(",")?;
+                        self.value(value, &format!("{}<1>", path))?;
+                        self.write("]")?
+                    }
+                }
+                self.write(if string_keys { "}" } else { "]" })
+            }
+            Node::Tuple(shapes) => {
+                self.write("[")?;
+                for (index, shape) in shapes.iter().enumerate() {
+                    if index > 0 {
+                        self.write(",")?
+                    }
+                    self.value(shape, &format!("{}.{}", path, index))?
+                }
+                self.write("]")
+            }
+            Node::Record(fields) => {
+                self.write("{")?;
+                for (index, (name, shape)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        self.write(",")?
+                    }
+                    self.write_json(name)?;
+                    self.write(":")?;
+                    self.value(shape, &format!("{}.{}", path, name))?
+                }
+                self.write("}")
+            }
+            Node::Constructor { name, args, .. } => self.constructor(name, args, path),
+        }
+    }
+}
+
+// Encodes the next JSON value with the given shape.
+struct ShapeSeed<'a> {
+    shape: &'a Shape,
+    path: String,
+    out: &'a mut Vec<u8>,
+    depth: usize,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for ShapeSeed<'a> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> std::result::Result<(), D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        if self.depth >= MAX_DEPTH {
+            return Err(de::Error::custom(format!(
+                "{}: value nested too deeply",
+                self.path
+            )));
+        }
+        deserializer.deserialize_any(ShapeVisitor {
+            shape: self.shape.resolve(),
+            path: self.path,
+            out: self.out,
+            depth: self.depth + 1,
+        })
+    }
+}
+
+struct ShapeVisitor<'a> {
+    shape: Shape,
+    path: String,
+    out: &'a mut Vec<u8>,
+    depth: usize,
+}
+
+impl<'a> ShapeVisitor<'a> {
+    fn error<E: de::Error>(&self, msg: &str) -> E {
+        let path = if self.path.is_empty() {
+            "."
+        } else {
+            &self.path
+        };
+        E::custom(format!("{}: {}", path, msg))
+    }
+
+    fn mismatch<E: de::Error>(&self, got: &str) -> E {
+        self.error(&format!("expected {}, got {}", describe(&self.shape), got))
+    }
+
+    fn seed<'b>(&'b mut self, shape: &'b Shape, path: String) -> ShapeSeed<'b> {
+        ShapeSeed {
+            shape,
+            path,
+            out: self.out,
+            depth: self.depth,
+        }
+    }
+
+    fn serializer(&mut self) -> Serializer<&mut Vec<u8>> {
+        Serializer::new(&mut *self.out)
+    }
+
+    // The options of a non-null value are all present, the content of
+    // nested options is given in a list.
+    fn unwrap_options(&mut self) {
+        while let Shape::Base(uuid, args) = &self.shape {
+            if uuid != uuid::OPTION || args.len() != 1 {
+                break;
+            }
+            let arg = args[0].resolve();
+            if is_nullable(&arg) {
+                break;
+            }
+            self.out.push(1);
+            self.shape = arg;
+            self.path.push('?')
+        }
+    }
+
+    fn int<E: de::Error>(mut self, v: i128) -> std::result::Result<(), E> {
+        self.unwrap_options();
+        let res = match &self.shape {
+            Shape::Base(uuid, _) if encoding_class(uuid) == uuid::INT => {
+                let range = if uuid == uuid::INT32 {
+                    i32::MIN as i128..=i32::MAX as i128
+                } else {
+                    i64::MIN as i128..=i64::MAX as i128
+                };
+                if !range.contains(&v) {
+                    return Err(self.error(&format!("{} out of range for {}", v, uuid)));
+                }
+                (&mut self.serializer()).serialize_i64(v as i64)
+            }
+            Shape::Base(uuid, _) if uuid == uuid::NAT0 => match u64::try_from(v) {
+                Ok(v) => self.serializer().serialize_nat0(v),
+                Err(_) => return Err(self.error(&format!("{} out of range for Nat0.t", v))),
+            },
+            Shape::Base(uuid, _) if uuid == uuid::FLOAT => {
+                self.out.extend_from_slice(&(v as f64).to_le_bytes());
+                Ok(())
+            }
+            _ => return Err(self.mismatch("an integer")),
+        };
+        res.map_err(|err| self.error(&err.to_string()))
+    }
+
+    fn bytes<E: de::Error>(&mut self, bytes: &[u8]) -> std::result::Result<(), E> {
+        let res = self.serializer().serialize_bytes(bytes);
+        res.map_err(|err| self.error(&err.to_string()))
+    }
+
+    // Encodes the arguments of a constructor given as a map entry value.
+    fn constructor_args<'de, A: de::MapAccess<'de>>(
+        &mut self,
+        name: &str,
+        args: &[Shape],
+        map: &mut A,
+    ) -> std::result::Result<(), A::Error> {
+        let path = format!("{}.{}", self.path, name);
+        match args {
+            [] => map.next_value::<()>(),
+            [arg] => map.next_value_seed(self.seed(arg, path)),
+            args => {
+                let tuple = Shape::Tuple(args.to_vec());
+                map.next_value_seed(self.seed(&tuple, path))
+            }
+        }
+    }
+
+    fn elements<'de, A: de::SeqAccess<'de>>(
+        &mut self,
+        shape: &Shape,
+        mut seq: A,
+    ) -> std::result::Result<(), A::Error> {
+        let mut elements = vec![];
+        let mut len = 0u64;
+        let path = format!("{}[]", self.path);
+        loop {
+            let seed = ShapeSeed {
+                shape,
+                path: path.clone(),
+                out: &mut elements,
+                depth: self.depth,
+            };
+            match seq.next_element_seed(seed)? {
+                Some(()) => len += 1,
+                None => break,
+            }
+        }
+        self.serializer()
+            .serialize_nat0(len)
+            .map_err(|err| self.error::<A::Error>(&err.to_string()))?;
+        self.out.extend_from_slice(&elements);
+        Ok(())
+    }
+}
+
+fn describe(shape: &Shape) -> String {
+    match shape {
+        Shape::Base(uuid, _) if uuid == uuid::NAT0 => "Nat0.t".to_string(),
+        Shape::Base(uuid, _) => uuid.to_string(),
+        Shape::Tuple(shapes) => format!("{}-tuple", shapes.len()),
+        Shape::Record(_) => "record".to_string(),
+        Shape::Variant(_) | Shape::PolyVariant(_) => "variant".to_string(),
+        shape => format!("{:?}", shape),
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for ShapeVisitor<'a> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a value of shape {}", describe(&self.shape))
+    }
+
+    fn visit_bool<E: de::Error>(mut self, v: bool) -> std::result::Result<(), E> {
+        self.unwrap_options();
+        match &self.shape {
+            Shape::Base(uuid, _) if uuid == uuid::BOOL => {
+                self.out.push(v as u8);
+                Ok(())
+            }
+            _ => Err(self.mismatch("a boolean")),
+        }
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<(), E> {
+        self.int(v as i128)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<(), E> {
+        self.int(v as i128)
+    }
+
+    fn visit_f64<E: de::Error>(mut self, v: f64) -> std::result::Result<(), E> {
+        self.unwrap_options();
+        match &self.shape {
+            Shape::Base(uuid, _) if uuid == uuid::FLOAT => {
+                self.out.extend_from_slice(&v.to_le_bytes());
+                Ok(())
+            }
+            _ => Err(self.mismatch("a float")),
+        }
+    }
+
+    fn visit_str<E: de::Error>(mut self, v: &str) -> std::result::Result<(), E> {
+        self.unwrap_options();
+        match &self.shape {
+            Shape::Base(uuid, _) if encoding_class(uuid) == uuid::STRING => {
+                self.bytes(v.as_bytes())
+            }
+            Shape::Base(uuid, _) if uuid == uuid::CHAR => {
+                let mut chars = v.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if (c as u32) < 256 => {
+                        self.out.push(c as u32 as u8);
+                        Ok(())
+                    }
+                    _ => Err(self.error(&format!("invalid char {:?}", v))),
+                }
+            }
+            Shape::Base(uuid, _) if uuid == uuid::FLOAT => {
+                let f = match v {
+                    "nan" => f64::NAN,
+                    "inf" => f64::INFINITY,
+                    "-inf" => f64::NEG_INFINITY,
+                    _ => return Err(self.error(&format!("invalid float {:?}", v))),
+                };
+                self.out.extend_from_slice(&f.to_le_bytes());
+                Ok(())
+            }
+            Shape::Variant(variants) => match variants.iter().position(|(n, _)| n == v) {
+                Some(index) if variants[index].1.is_empty() => {
+                    self.out.push(index as u8);
+                    Ok(())
+                }
+                Some(_) => Err(self.error(&format!("missing arguments for {}", v))),
+                None => Err(self.error(&format!("unknown constructor {}", v))),
+            },
+            Shape::PolyVariant(variants) => match variants.get(v) {
+                Some(None) => {
+                    self.out.extend_from_slice(&poly_variant_tag(v));
+                    Ok(())
+                }
+                Some(Some(_)) => Err(self.error(&format!("missing argument for `{}", v))),
+                None => Err(self.error(&format!("unknown tag `{}", v))),
+            },
+            _ => Err(self.mismatch("a string")),
+        }
+    }
+
+    fn visit_unit<E: de::Error>(self) -> std::result::Result<(), E> {
+        match &self.shape {
+            Shape::Base(uuid, _) if uuid == uuid::UNIT || uuid == uuid::OPTION => {
+                self.out.push(0);
+                Ok(())
+            }
+            _ => Err(self.mismatch("null")),
+        }
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(mut self, mut seq: A) -> std::result::Result<(), A::Error> {
+        self.unwrap_options();
+        let shape = self.shape.clone();
+        match &shape {
+            Shape::Base(uuid, args) if uuid == uuid::OPTION => {
+                // The content of a nested option.
+                self.out.push(1);
+                let path = format!("{}?", self.path);
+                if seq.next_element_seed(self.seed(&args[0], path))?.is_none() {
+                    return Err(self.error("expected a single element list"));
+                }
+                if seq.next_element::<de::IgnoredAny>()?.is_some() {
+                    return Err(self.error("expected a single element list"));
+                }
+                Ok(())
+            }
+            Shape::Base(uuid, _) if encoding_class(uuid) == uuid::STRING => {
+                let mut bytes = vec![];
+                while let Some(byte) = seq.next_element::<u8>()? {
+                    bytes.push(byte)
+                }
+                self.bytes(&bytes)
+            }
+            Shape::Base(uuid, _) if uuid == uuid::FLOAT_ARRAY => {
+                self.elements(&Shape::float(), seq)
+            }
+            Shape::Base(uuid, args) if is_sequence(uuid) && args.len() == 1 => {
+                self.elements(&args[0], seq)
+            }
+            Shape::Base(uuid, args)
+                if (uuid == uuid::MAP || uuid == uuid::HASHTBL)
+                    && map_args(uuid, args).len() == 2 =>
+            {
+                self.elements(&Shape::Tuple(map_args(uuid, args).to_vec()), seq)
+            }
+            Shape::Tuple(shapes) => {
+                for (index, shape) in shapes.iter().enumerate() {
+                    let path = format!("{}.{}", self.path, index);
+                    if seq.next_element_seed(self.seed(shape, path))?.is_none() {
+                        return Err(self.error(&format!("expected {} elements", shapes.len())));
+                    }
+                }
+                if seq.next_element::<de::IgnoredAny>()?.is_some() {
+                    return Err(self.error(&format!("expected {} elements", shapes.len())));
+                }
+                Ok(())
+            }
+            _ => Err(self.mismatch("a list")),
+        }
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(mut self, mut map: A) -> std::result::Result<(), A::Error> {
+        self.unwrap_options();
+        let shape = self.shape.clone();
+        match &shape {
+            Shape::Record(fields) => {
+                // The fields may come in any order, they are encoded first and
+                // written in the shape order afterwards.
+                let mut values = BTreeMap::new();
+                while let Some(name) = map.next_key::<String>()? {
+                    let shape = match fields.iter().find(|(n, _)| *n == name) {
+                        Some((_, shape)) => shape,
+                        None => return Err(self.error(&format!("unknown field {}", name))),
+                    };
+                    let mut value = vec![];
+                    map.next_value_seed(ShapeSeed {
+                        shape,
+                        path: format!("{}.{}", self.path, name),
+                        out: &mut value,
+                        depth: self.depth,
+                    })?;
+                    if values.insert(name.clone(), value).is_some() {
+                        return Err(self.error(&format!("duplicate field {}", name)));
+                    }
+                }
+                for (name, shape) in fields.iter() {
+                    match values.remove(name) {
+                        Some(value) => self.out.extend_from_slice(&value),
+                        None if is_option(&shape.resolve()) => self.out.push(0),
+                        None => return Err(self.error(&format!("missing field {}", name))),
+                    }
+                }
+                Ok(())
+            }
+            Shape::Variant(variants) => {
+                let name = match map.next_key::<String>()? {
+                    Some(name) => name,
+                    None => return Err(self.error("expected a constructor")),
+                };
+                let index = match variants.iter().position(|(n, _)| *n == name) {
+                    Some(index) => index,
+                    None => return Err(self.error(&format!("unknown constructor {}", name))),
+                };
+                self.out.push(index as u8);
+                self.constructor_args(&name, &variants[index].1, &mut map)?;
+                if map.next_key::<de::IgnoredAny>()?.is_some() {
+                    return Err(self.error("expected a single constructor"));
+                }
+                Ok(())
+            }
+            Shape::PolyVariant(variants) => {
+                let name = match map.next_key::<String>()? {
+                    Some(name) => name,
+                    None => return Err(self.error("expected a tag")),
+                };
+                let args: Vec<_> = match variants.get(&name) {
+                    Some(arg) => arg.iter().cloned().collect(),
+                    None => return Err(self.error(&format!("unknown tag `{}", name))),
+                };
+                self.out.extend_from_slice(&poly_variant_tag(&name));
+                self.constructor_args(&format!("`{}", name), &args, &mut map)?;
+                if map.next_key::<de::IgnoredAny>()?.is_some() {
+                    return Err(self.error("expected a single tag"));
+                }
+                Ok(())
+            }
+            Shape::Base(uuid, args)
+                if (uuid == uuid::MAP || uuid == uuid::HASHTBL)
+                    && map_args(uuid, args).len() == 2
+                    && is_string(&map_args(uuid, args)[0].resolve()) =>
+            {
+                let args = map_args(uuid, args);
+                let mut pairs = vec![];
+                let mut len = 0u64;
+                while let Some(key) = map.next_key::<String>()? {
+                    let mut serializer = Serializer::new(&mut pairs);
+                    serializer
+                        .serialize_nat0(key.len() as u64)
+                        .map_err(|err| self.error::<A::Error>(&err.to_string()))?;
+                    pairs.extend_from_slice(key.as_bytes());
+                    map.next_value_seed(ShapeSeed {
+                        shape: &args[1],
+                        path: format!("{}<1>", self.path),
+                        out: &mut pairs,
+                        depth: self.depth,
+                    })?;
+                    len += 1
+                }
+                self.serializer()
+                    .serialize_nat0(len)
+                    .map_err(|err| self.error::<A::Error>(&err.to_string()))?;
+                self.out.extend_from_slice(&pairs);
+                Ok(())
+            }
+            _ => Err(self.mismatch("an object")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{to_vec, Error, HasShape, Shape};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::Serialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, BinShape)]
+    enum Side {
+        Buy,
+        Sell { limit: Option<f64> },
+        Cross(i64, String),
+    }
+
+    #[derive(Serialize, BinShape)]
+    struct Order {
+        id: u64,
+        side: Side,
+        fills: Vec<(i64, f64)>,
+        tags: BTreeMap<String, Option<Option<bool>>>,
+        nonce: Vec<u8>,
+    }
+
+    #[test]
+    fn test_json() {
+        let mut tags = BTreeMap::new();
+        tags.insert("a".to_string(), Some(None));
+        tags.insert("b".to_string(), Some(Some(true)));
+        tags.insert("c".to_string(), None);
+        let order = Order {
+            id: u64::MAX,
+            side: Side::Sell { limit: Some(0.1) },
+            fills: vec![(-1 << 40, 1e300), (3, f64::NEG_INFINITY)],
+            tags,
+            nonce: vec![],
+        };
+        let data = to_vec(&order).unwrap();
+        let json = super::to_string(&data, &Order::shape()).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":18446744073709551615,"side":{"Sell":{"limit":0.1}},"fills":[[-1099511627776,1e+300],[3,"-inf"]],"tags":{"a":[null],"b":[true],"c":null},"nonce":[]}"#
+        );
+        assert_eq!(super::from_str(&json, &Order::shape()).unwrap(), data);
+
+        let sides = [Side::Buy, Side::Cross(-2, "x".to_string())];
+        let data = to_vec(&sides).unwrap();
+        let shape = <[Side; 2]>::shape();
+        let json = super::to_string(&data, &shape).unwrap();
+        assert_eq!(json, r#"["Buy",{"Cross":[-2,"x"]}]"#);
+        assert_eq!(super::from_str(&json, &shape).unwrap(), data);
+
+        // Fields can be reordered and missing options are `None`.
+        let json = r#"{"fills":[],"nonce":[],"side":"Buy","tags":{},"id":3}"#;
+        let data = super::from_str(json, &Order::shape()).unwrap();
+        let json = super::to_string(&data, &Order::shape()).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":3,"side":"Buy","fills":[],"tags":{},"nonce":[]}"#
+        );
+        let json = r#"{"id":1,"side":{"Sell":{}},"fills":[],"tags":{},"nonce":[]}"#;
+        assert!(super::from_str(json, &Order::shape()).is_ok());
+        let err = super::from_str(r#"{"id":-1}"#, &Order::shape()).unwrap_err();
+        assert!(format!("{}", err).contains(".id: -1 out of range"));
+        let strings = Shape::list(Shape::string());
+        let json = super::to_string(&[1, 1, 0xff], &strings).unwrap();
+        assert_eq!(json, "[[255]]");
+        assert_eq!(super::from_str(&json, &strings).unwrap(), [1, 1, 0xff]);
+
+        // Maps with keys that are not valid UTF-8 are lists of pairs.
+        let map = Shape::map(Shape::string(), Shape::int());
+        let json = super::to_string(&[2, 1, b'a', 1, 1, 0xff, 3], &map).unwrap();
+        assert_eq!(json, r#"[["a",1],[[255],3]]"#);
+        assert_eq!(
+            super::from_str(&json, &map).unwrap(),
+            [2, 1, b'a', 1, 1, 0xff, 3]
+        );
+        let json = r#"{"id":1,"id":2,"side":"Buy","fills":[],"tags":{},"nonce":[]}"#;
+        let err = super::from_str(json, &Order::shape()).unwrap_err();
+        assert!(format!("{}", err).contains("duplicate field id"));
+
+        let values = super::from_str_many("1 \n[2]\n", &<Vec<i64>>::shape());
+        assert!(matches!(values, Err(Error::ParseError { line: 1, .. })));
+        let values = super::from_str_many("[1]\n[2, 3]\n", &<Vec<i64>>::shape()).unwrap();
+        assert_eq!(
+            values,
+            [
+                to_vec(&vec![1i64]).unwrap(),
+                to_vec(&vec![2i64, 3]).unwrap()
+            ]
+        );
+        assert!(super::from_str_many(" \n", &strings).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_json_keys_and_floats() {
+        // Maps with keys that are not strings are lists of pairs.
+        let mut map = BTreeMap::new();
+        map.insert(-1i64, (1.5f64, "a"));
+        map.insert(300, (0.0, "b"));
+        let data = to_vec(&map).unwrap();
+        let shape = <BTreeMap<i64, (f64, String)>>::shape();
+        let json = super::to_string(&data, &shape).unwrap();
+        assert_eq!(json, r#"[[-1,[1.5,"a"]],[300,[0.0,"b"]]]"#);
+        assert_eq!(super::from_str(&json, &shape).unwrap(), data);
+        let json = r#"{"1":[1.5,"a"]}"#;
+        assert!(super::from_str(json, &shape).is_err());
+
+        let floats = [f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -0.0];
+        let data = to_vec(&floats).unwrap();
+        let shape = <[f64; 4]>::shape();
+        let json = super::to_string(&data, &shape).unwrap();
+        assert_eq!(json, r#"["nan","inf","-inf",-0.0]"#);
+        let back = super::from_str(&json, &shape).unwrap();
+        let back: [f64; 4] = crate::from_slice(&back).unwrap();
+        assert!(back[0].is_nan());
+        assert_eq!(back[1..], floats[1..]);
+        assert!(back[3].is_sign_negative());
+        let err = super::from_str(r#"["nan","inf","infinity",0]"#, &shape).unwrap_err();
+        assert!(format!("{}", err).contains(".2: invalid float"));
+    }
+}
@@ -0,0 +1,507 @@
+//! Wire layout reports describing how a type is encoded.
+//!
+//! The report has a row per value that a type contains, in the order in
+//! which they appear on the wire: record fields, tuple components, variant
+//! constructors and their arguments, option contents, collection elements.
+//! Each row gives the bin_prot encoding of the value and the minimum and
+//! maximum number of bytes that it can take, the maximum being unbounded
+//! for strings, collections and recursive types.
+//!
+//! ```
+//! use serde_binprot::HasShape;
+//!
+//! let layout = serde_binprot::layout::layout_of::<(i32, Option<f64>)>();
+//! let sizes: Vec<_> = layout.rows.iter().map(|r| (r.path.as_str(), r.min, r.max)).collect();
+//! assert_eq!(sizes, [(".", 2, Some(18)), (".0", 1, Some(9)), (".1", 1, Some(9)), (".1?", 8, Some(8))]);
+//! ```
+use crate::shape::{encoding_class, is_sequence, map_args, uuid, HasShape, Shape};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub path: String,
+    /// The nesting depth of the value, 0 for the whole type.
+    pub depth: usize,
+    pub encoding: String,
+    pub min: usize,
+    /// The maximum size, `None` when unbounded.
+    pub max: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub rows: Vec<Row>,
+}
+
+/// Describes the layout of a type of the given shape.
+pub fn layout(shape: &Shape) -> Layout {
+    let mut builder = Builder {
+        rows: vec![],
+        stack: vec![],
+    };
+    builder.node(shape, "", 0);
+    Layout { rows: builder.rows }
+}
+
+/// Describes the layout of type `T`.
+pub fn layout_of<T: HasShape + ?Sized>() -> Layout {
+    layout(&T::shape())
+}
+
+// The sizes of the variable-length integers, depending on their range.
+const INT_MAX_BYTES: usize = 9;
+const INT32_MAX_BYTES: usize = 5;
+// The sizes larger than this are considered infinite, i.e. types without
+// finite values.
+const INFINITE: usize = usize::MAX;
+
+#[derive(Clone, Copy)]
+struct Bytes {
+    min: usize,
+    max: Option<usize>,
+}
+
+impl Bytes {
+    fn exactly(n: usize) -> Self {
+        Bytes {
+            min: n,
+            max: Some(n),
+        }
+    }
+
+    fn at_least(n: usize) -> Self {
+        Bytes { min: n, max: None }
+    }
+
+    // The sizes of a value made of the two parts.
+    fn then(self, other: Bytes) -> Self {
+        let max = match (self.max, other.max) {
+            (Some(m1), Some(m2)) => m1.checked_add(m2),
+            _ => None,
+        };
+        Bytes {
+            min: self.min.saturating_add(other.min),
+            max,
+        }
+    }
+
+    // The sizes of a value that is one of the two.
+    fn or(self, other: Bytes) -> Self {
+        let max = match (self.max, other.max) {
+            (Some(m1), Some(m2)) => Some(std::cmp::max(m1, m2)),
+            _ => None,
+        };
+        Bytes {
+            min: std::cmp::min(self.min, other.min),
+            max,
+        }
+    }
+}
+
+// The minimum size of a type, `env` gives the assumed minimum sizes of the
+// enclosing recursive types. The minimum size of a recursive type is the
+// fixpoint obtained by starting from infinite sizes.
+fn min_size(shape: &Shape, env: &mut Vec<(Shape, usize)>) -> usize {
+    let mut stripped = shape;
+    while let Shape::Annotate(_, shape) = stripped {
+        stripped = shape
+    }
+    if let Shape::Application(_, _) = stripped {
+        if let Some((_, min)) = env.iter().find(|(s, _)| s == stripped) {
+            return *min;
+        }
+        if env.len() >= MAX_NESTING {
+            return INFINITE;
+        }
+        env.push((stripped.clone(), INFINITE));
+        let mut min = INFINITE;
+        for _ in 0..MAX_ITERATIONS {
+            let new_min = min_size_unfolded(&stripped.unfold(), env);
+            if new_min == min {
+                break;
+            }
+            min = new_min;
+            env.last_mut().unwrap().1 = min
+        }
+        env.pop();
+        return min;
+    }
+    min_size_unfolded(&shape.unfold(), env)
+}
+
+fn min_size_unfolded(shape: &Shape, env: &mut Vec<(Shape, usize)>) -> usize {
+    let sum = |shapes: &mut dyn Iterator<Item = &Shape>, env: &mut Vec<(Shape, usize)>| {
+        shapes.fold(0usize, |acc, shape| {
+            acc.saturating_add(min_size(shape, env))
+        })
+    };
+    match shape {
+        Shape::Base(uuid, args) => match (encoding_class(uuid), args.as_slice()) {
+            (uuid::REF, [arg]) | (uuid::LAZY, [arg]) => min_size(arg, env),
+            (uuid::FLOAT, []) => 8,
+            _ => 1,
+        },
+        Shape::Tuple(shapes) => sum(&mut shapes.iter(), env),
+        Shape::Record(fields) => sum(&mut fields.iter().map(|(_, s)| s), env),
+        Shape::Variant(variants) => {
+            let args = variants.iter().map(|(_, args)| sum(&mut args.iter(), env));
+            args.min().unwrap_or(INFINITE).saturating_add(1)
+        }
+        Shape::PolyVariant(variants) => {
+            let args = variants.values().map(|arg| sum(&mut arg.iter(), env));
+            args.min().unwrap_or(INFINITE).saturating_add(4)
+        }
+        _ => 0,
+    }
+}
+
+// Bounds the computation of the minimum sizes of recursive types.
+const MAX_NESTING: usize = 64;
+const MAX_ITERATIONS: usize = 1024;
+// Bounds the depth of the report, nested types can otherwise be infinite.
+const MAX_DEPTH: usize = 256;
+
+struct Builder {
+    rows: Vec<Row>,
+    // The applications being described, with their rows and minimum sizes.
+    stack: Vec<(Shape, usize, usize)>,
+}
+
+impl Builder {
+    fn node(&mut self, shape: &Shape, path: &str, depth: usize) -> Bytes {
+        let row = self.rows.len();
+        self.rows.push(Row {
+            path: if path.is_empty() {
+                ".".to_string()
+            } else {
+                path.to_string()
+            },
+            depth,
+            encoding: String::new(),
+            min: 0,
+            max: None,
+        });
+        if depth >= MAX_DEPTH {
+            self.rows[row].encoding = "nested too deeply".to_string();
+            return Bytes::at_least(0);
+        }
+        // References are transparent.
+        let mut stripped = shape;
+        loop {
+            match stripped {
+                Shape::Annotate(_, shape) => stripped = shape,
+                Shape::Base(uuid, args)
+                    if (uuid == uuid::REF || uuid == uuid::LAZY) && args.len() == 1 =>
+                {
+                    stripped = &args[0]
+                }
+                _ => break,
+            }
+        }
+        let application = matches!(stripped, Shape::Application(_, _));
+        if application {
+            if let Some((_, definition, min)) = self.stack.iter().find(|(s, _, _)| s == stripped) {
+                let bytes = Bytes::at_least(*min);
+                let path = self.rows[*definition].path.clone();
+                let row = &mut self.rows[row];
+                row.encoding = format!("recursive, same as {}", path);
+                row.min = bytes.min;
+                return bytes;
+            }
+            let min = min_size(stripped, &mut vec![]);
+            self.stack.push((stripped.clone(), row, min))
+        }
+        let (encoding, bytes) = self.unfolded(&stripped.unfold(), path, depth);
+        if application {
+            self.stack.pop();
+        }
+        let row = &mut self.rows[row];
+        row.encoding = encoding;
+        row.min = bytes.min;
+        row.max = bytes.max;
+        bytes
+    }
+
+    fn unfolded(&mut self, shape: &Shape, path: &str, depth: usize) -> (String, Bytes) {
+        let depth = depth + 1;
+        match shape {
+            Shape::Base(uuid, args) => self.base(uuid, args, path, depth),
+            Shape::Tuple(shapes) => {
+                let mut bytes = Bytes::exactly(0);
+                for (index, shape) in shapes.iter().enumerate() {
+                    bytes = bytes.then(self.node(shape, &format!("{}.{}", path, index), depth))
+                }
+                (format!("tuple of {} values", shapes.len()), bytes)
+            }
+            Shape::Record(fields) => {
+                let mut bytes = Bytes::exactly(0);
+                for (name, shape) in fields.iter() {
+                    bytes = bytes.then(self.node(shape, &format!("{}.{}", path, name), depth))
+                }
+                (format!("record of {} fields", fields.len()), bytes)
+            }
+            Shape::Variant(variants) => {
+                let mut bytes = Bytes::at_least(INFINITE);
+                for (index, (name, args)) in variants.iter().enumerate() {
+                    let path = format!("{}.{}", path, name);
+                    let encoding = format!("constructor, tag byte {}", index);
+                    let args = self.constructor(encoding, 1, args, &path, depth);
+                    bytes = bytes.or(args)
+                }
+                let encoding = format!("variant of {} constructors", variants.len());
+                (encoding, Bytes::exactly(1).then(bytes))
+            }
+            Shape::PolyVariant(variants) => {
+                let mut bytes = Bytes::at_least(INFINITE);
+                for (name, arg) in variants.iter() {
+                    let path = format!("{}.`{}", path, name);
+                    let hash = crate::ser::hash_variant(name);
+                    let encoding = format!("constructor, tag hash {}", hash);
+                    let args: Vec<_> = arg.iter().cloned().collect();
+                    bytes = bytes.or(self.constructor(encoding, 4, &args, &path, depth))
+                }
+                let encoding = format!(
+                    "polymorphic variant of {} constructors, 4 bytes tag",
+                    variants.len()
+                );
+                (encoding, Bytes::exactly(4).then(bytes))
+            }
+            shape => (format!("unsupported shape {:?}", shape), Bytes::exactly(0)),
+        }
+    }
+
+    // The `tag` is the size of the constructor tag, 1 byte for variants and
+    // 4 for polymorphic variants.
+    fn constructor(
+        &mut self,
+        encoding: String,
+        tag: usize,
+        args: &[Shape],
+        path: &str,
+        depth: usize,
+    ) -> Bytes {
+        let row = self.rows.len();
+        self.rows.push(Row {
+            path: path.to_string(),
+            depth,
+            encoding,
+            min: 0,
+            max: None,
+        });
+        let mut bytes = Bytes::exactly(0);
+        if args.len() == 1 {
+            bytes = self.node(&args[0], path, depth + 1)
+        } else {
+            for (index, arg) in args.iter().enumerate() {
+                bytes = bytes.then(self.node(arg, &format!("{}.{}", path, index), depth + 1))
+            }
+        }
+        // The constructor rows include the tag.
+        let with_tag = Bytes::exactly(tag).then(bytes);
+        self.rows[row].min = with_tag.min;
+        self.rows[row].max = with_tag.max;
+        bytes
+    }
+
+    fn base(&mut self, uuid: &str, args: &[Shape], path: &str, depth: usize) -> (String, Bytes) {
+        let class = encoding_class(uuid);
+        match (class, map_args(uuid, args)) {
+            (uuid::REF, [arg]) | (uuid::LAZY, [arg]) => {
+                let bytes = self.node(arg, path, depth);
+                (format!("{}, same as its content", uuid), bytes)
+            }
+            (uuid::UNIT, []) => ("unit, 1 byte".to_string(), Bytes::exactly(1)),
+            (uuid::BOOL, []) => ("bool, 1 byte".to_string(), Bytes::exactly(1)),
+            (uuid::CHAR, []) => ("char, 1 byte".to_string(), Bytes::exactly(1)),
+            (uuid::FLOAT, []) => ("float64, little-endian".to_string(), Bytes::exactly(8)),
+            (uuid::INT, []) => {
+                let max = if uuid == uuid::INT32 {
+                    INT32_MAX_BYTES
+                } else {
+                    INT_MAX_BYTES
+                };
+                let encoding = format!("{}, variable-length int", uuid);
+                (
+                    encoding,
+                    Bytes {
+                        min: 1,
+                        max: Some(max),
+                    },
+                )
+            }
+            (uuid::NAT0, []) => {
+                let encoding = "nat0, variable-length unsigned int".to_string();
+                (
+                    encoding,
+                    Bytes {
+                        min: 1,
+                        max: Some(INT_MAX_BYTES),
+                    },
+                )
+            }
+            (uuid::STRING, []) => (
+                format!("{}, nat0 length then the bytes", uuid),
+                Bytes::at_least(1),
+            ),
+            (uuid::OPTION, [arg]) => {
+                let bytes = self.node(arg, &format!("{}?", path), depth);
+                let encoding = "option, tag byte 0 for None or 1 then the value".to_string();
+                (
+                    encoding,
+                    Bytes::exactly(1).or(Bytes::exactly(1).then(bytes)),
+                )
+            }
+            (uuid::FLOAT_ARRAY, []) => (
+                "float array, nat0 length then float64 elements".to_string(),
+                Bytes::at_least(1),
+            ),
+            (uuid::MAP, [key, value]) | (uuid::HASHTBL, [key, value]) => {
+                let path = format!("{}[]", path);
+                self.node(key, &format!("{}<0>", path), depth);
+                self.node(value, &format!("{}<1>", path), depth);
+                let encoding = format!("{}, nat0 length then key/value pairs", name(uuid));
+                (encoding, Bytes::at_least(1))
+            }
+            (class, [arg]) if is_sequence(class) => {
+                self.node(arg, &format!("{}[]", path), depth);
+                let encoding = format!("{}, nat0 length then the elements", name(uuid));
+                (encoding, Bytes::at_least(1))
+            }
+            _ => (
+                format!("unknown base type {} with {} arguments", uuid, args.len()),
+                Bytes::exactly(0),
+            ),
+        }
+    }
+}
+
+// The Core containers are identified by uuids, they are displayed by name.
+fn name(uuid: &str) -> &str {
+    match uuid {
+        uuid::MAP => "map",
+        uuid::SET => "set",
+        uuid::HASH_SET => "hash_set",
+        uuid::DEQUE => "deque",
+        uuid => uuid,
+    }
+}
+
+impl Row {
+    fn bytes(&self) -> String {
+        match self.max {
+            _ if self.min == INFINITE => "no finite value".to_string(),
+            Some(max) if max == self.min => max.to_string(),
+            Some(max) => format!("{}..{}", self.min, max),
+            None => format!("{}..", self.min),
+        }
+    }
+}
+
+impl Layout {
+    /// Renders the layout as a Markdown table.
+    pub fn to_markdown(&self) -> String {
+        let mut md = "| Path | Encoding | Min bytes | Max bytes |\n".to_string();
+        md.push_str("|------|----------|-----------|-----------|\n");
+        for row in self.rows.iter() {
+            let min = if row.min == INFINITE {
+                "-".to_string()
+            } else {
+                row.min.to_string()
+            };
+            let max = row
+                .max
+                .map_or("unbounded".to_string(), |max| max.to_string());
+            let path = format!("{}`{}`", "&nbsp;&nbsp;".repeat(row.depth), row.path);
+            md.push_str(&format!(
+                "| {} | {} | {} | {} |\n",
+                path, row.encoding, min, max
+            ))
+        }
+        md
+    }
+}
+
+/// Renders the layout as plain text, one line per row.
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = |row: &Row| format!("{}{}", "  ".repeat(row.depth), row.path);
+        let path_width = self.rows.iter().map(|r| path(r).len()).max().unwrap_or(0);
+        let bytes_width = self.rows.iter().map(|r| r.bytes().len()).max().unwrap_or(0);
+        for row in self.rows.iter() {
+            writeln!(
+                f,
+                "{:pw$}  {:>bw$}  {}",
+                path(row),
+                row.bytes(),
+                row.encoding,
+                pw = path_width,
+                bw = bytes_width
+            )?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::layout_of;
+    use crate::{HasShape, Shape};
+    use serde_binprot_derive::BinShape;
+    use std::collections::BTreeMap;
+
+    #[allow(dead_code)]
+    #[derive(BinShape)]
+    enum Tree<T> {
+        Leaf,
+        Node(Box<Tree<T>>, T, Box<Tree<T>>),
+    }
+
+    #[allow(dead_code)]
+    #[derive(BinShape)]
+    struct Order {
+        id: f64,
+        qty: i32,
+        price: Option<f64>,
+        tags: BTreeMap<String, bool>,
+        tree: Tree<u8>,
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = layout_of::<Order>();
+        let expected = [
+            ".                   12..  record of 5 fields",
+            "  .id                  8  float64, little-endian",
+            "  .qty              1..9  int, variable-length int",
+            "  .price            1..9  option, tag byte 0 for None or 1 then the value",
+            "    .price?            8  float64, little-endian",
+            "  .tags              1..  map, nat0 length then key/value pairs",
+            "    .tags[]<0>       1..  string, nat0 length then the bytes",
+            "    .tags[]<1>         1  bool, 1 byte",
+            "  .tree              1..  variant of 2 constructors",
+            "    .tree.Leaf         1  constructor, tag byte 0",
+            "    .tree.Node       4..  constructor, tag byte 1",
+            "      .tree.Node.0   1..  recursive, same as .tree",
+            "      .tree.Node.1  1..9  nat0, variable-length unsigned int",
+            "      .tree.Node.2   1..  recursive, same as .tree",
+        ];
+        assert_eq!(layout.to_string(), expected.join("\n") + "\n");
+        let md = layout.to_markdown();
+        let md: Vec<_> = md.lines().take(4).collect();
+        assert_eq!(
+            md,
+            [
+                "| Path | Encoding | Min bytes | Max bytes |",
+                "|------|----------|-----------|-----------|",
+                "| `.` | record of 5 fields | 12 | unbounded |",
+                "| &nbsp;&nbsp;`.id` | float64, little-endian | 8 | 8 |",
+            ]
+        );
+
+        // Variants nested in polymorphic variants have 1 byte tags.
+        let variants = [("A".to_string(), Some(Tree::<u8>::shape()))];
+        let poly = super::layout(&Shape::PolyVariant(variants.iter().cloned().collect()));
+        let leaf = poly.rows.iter().find(|row| row.path == ".`A.Leaf").unwrap();
+        assert_eq!((leaf.min, leaf.max), (1, Some(1)));
+    }
+}
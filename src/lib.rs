@@ -1,14 +1,55 @@
+#[cfg(feature = "json")]
+pub mod cli;
+pub mod codegen;
+pub mod compat;
+pub mod containers;
 mod de;
+pub mod diff;
+pub mod envelope;
 mod error;
+pub mod explain;
+pub mod framing;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod layout;
+pub mod ocaml;
+#[cfg(feature = "json")]
+pub mod query;
+pub mod random;
+pub mod registry;
 mod ser;
+pub mod sexp;
+pub mod sexp_conv;
+pub mod shape;
+pub mod stringable;
+mod trace;
+pub mod transcode;
+mod types;
+pub mod value;
+mod versioned;
+mod walk;
 const CODE_NEG_INT8: u8 = 0xff;
 const CODE_INT16: u8 = 0xfe;
 const CODE_INT32: u8 = 0xfd;
 const CODE_INT64: u8 = 0xfc;
 
-pub use crate::de::{from_reader, from_slice, from_str, Deserializer};
+pub use crate::de::{from_reader, from_slice, from_slice_with_shape, from_str, Deserializer};
 pub use crate::error::{Error, Result};
 pub use crate::ser::{to_vec, to_writer, Serializer};
+pub use crate::sexp::Sexp;
+pub use crate::sexp_conv::{from_sexp, from_sexp_str, to_sexp, to_sexp_string};
+pub use crate::shape::{check_shape, HasShape, Shape};
+pub use crate::stringable::{OfStringable, Stringable};
+pub use crate::trace::trace_shape;
+pub use crate::types::{Bigstring, Md5Digest, PolyVariantTag};
+pub use crate::value::{decode_value, encode_value, from_value, to_value, Value};
+pub use crate::versioned::{from_slice_version, NoPrevious, Stable, Versioned};
+#[cfg(feature = "derive")]
+pub use serde_binprot_derive::BinShape;
+
+// Allows the code generated by the derive macro to refer to this crate
+// from within the crate itself.
+extern crate self as serde_binprot;
 
 #[cfg(test)]
 mod tests {
@@ -16,6 +57,7 @@ mod tests {
     use serde_derive::Serialize;
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_roundtrip() {
         #[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Copy)]
         struct Foo {
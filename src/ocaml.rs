@@ -0,0 +1,1580 @@
+//! OCaml type declarations, for the subset of the type syntax supported by
+//! `[@@deriving bin_io]`.
+//!
+//! The type declarations are extracted from the content of a `.ml` or `.mli`
+//! file, the rest of the file is ignored. The declarations can then be
+//! converted to shapes, the types that they refer to being either declared
+//! in the same file or one of the standard types, including common Core
+//! types such as `Map.M(String).t`, `Result.t`, `Or_error.t`, `Time_ns.t`
+//! and `Date.t`.
+//!
+//! ```
+//! use serde_binprot::ocaml::Declarations;
+//! use serde_binprot::Shape;
+//!
+//! let decls = Declarations::parse(
+//!     "type side = Buy | Sell [@@deriving bin_io]
+//!      type t = { side : side; qty : int } [@@deriving bin_io]",
+//! )
+//! .unwrap();
+//! let shape = decls.shape("t").unwrap();
+//! assert_eq!(shape.eval_digest().len(), 32);
+//! ```
+use crate::error::{Error, Result};
+use crate::shape::{uuid, Shape};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeExpr {
+    Var(String),
+    // A type constructor, e.g. `int`, `'a list` or `(string, int) Map.t`.
+    Constr {
+        name: String,
+        args: Vec<TypeExpr>,
+        line: usize,
+        column: usize,
+    },
+    Tuple(Vec<TypeExpr>),
+    PolyVariant(Vec<PolyTag>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolyTag {
+    Tag(String, Option<TypeExpr>),
+    // Another polymorphic variant type included in this one.
+    Inherit(TypeExpr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub ty: TypeExpr,
+    pub mutable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConstructorArgs {
+    Tuple(Vec<TypeExpr>),
+    Record(Vec<Field>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Constructor {
+    pub name: String,
+    pub args: ConstructorArgs,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeKind {
+    Abstract,
+    Alias(TypeExpr),
+    Record(Vec<Field>),
+    Variant(Vec<Constructor>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeDecl {
+    pub name: String,
+    pub params: Vec<String>,
+    pub kind: TypeKind,
+    pub line: usize,
+    pub column: usize,
+    /// Declarations linked with `and` share the same group index.
+    pub group: usize,
+    pub nonrec: bool,
+    /// The names of the enclosing modules, outermost first.
+    pub module_path: Vec<String>,
+}
+
+/// The type declarations found in an OCaml source file.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Declarations {
+    pub decls: Vec<TypeDecl>,
+}
+
+fn parse_error(line: usize, column: usize, msg: String) -> Error {
+    Error::ParseError { line, column, msg }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Tok {
+    Ident(String),
+    TypeVar(String),
+    Tag(String),
+    Sym(String),
+    // Literals and other tokens that cannot appear in type declarations.
+    Other,
+    Eof,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    tok: Tok,
+    line: usize,
+    column: usize,
+}
+
+struct Lexer<'a> {
+    input: &'a [u8],
+    pos: usize,
+    line: usize,
+    line_start: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.input.get(self.pos + offset).copied()
+    }
+
+    fn advance(&mut self) {
+        if self.input[self.pos] == b'\n' {
+            self.line += 1;
+            self.line_start = self.pos + 1;
+        }
+        self.pos += 1
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        parse_error(self.line, self.pos - self.line_start + 1, msg.to_string())
+    }
+
+    fn skip_string(&mut self) -> Result<()> {
+        self.advance();
+        loop {
+            match self.peek_at(0) {
+                None => return Err(self.error("unterminated string literal")),
+                Some(b'"') => {
+                    self.advance();
+                    return Ok(());
+                }
+                Some(b'\\') => {
+                    self.advance();
+                    if self.peek_at(0).is_some() {
+                        self.advance()
+                    }
+                }
+                Some(_) => self.advance(),
+            }
+        }
+    }
+
+    // Skips `{id|...|id}` quoted strings, returns false if the input at the
+    // current position is not a quoted string.
+    fn skip_quoted_string(&mut self) -> Result<bool> {
+        let mut len = 1;
+        while let Some(b'a'..=b'z' | b'_') = self.peek_at(len) {
+            len += 1
+        }
+        if self.peek_at(len) != Some(b'|') {
+            return Ok(false);
+        }
+        let mut delimiter = vec![b'|'];
+        delimiter.extend_from_slice(&self.input[self.pos + 1..self.pos + len]);
+        delimiter.push(b'}');
+        for _ in 0..=len {
+            self.advance()
+        }
+        while !self.input[self.pos..].starts_with(&delimiter) {
+            if self.peek_at(0).is_none() {
+                return Err(self.error("unterminated quoted string"));
+            }
+            self.advance()
+        }
+        for _ in 0..delimiter.len() {
+            self.advance()
+        }
+        Ok(true)
+    }
+
+    fn skip_comment(&mut self) -> Result<()> {
+        self.advance();
+        self.advance();
+        let mut depth = 1;
+        while depth > 0 {
+            match (self.peek_at(0), self.peek_at(1)) {
+                (None, _) => return Err(self.error("unterminated comment")),
+                (Some(b'('), Some(b'*')) => {
+                    self.advance();
+                    depth += 1
+                }
+                (Some(b'*'), Some(b')')) => {
+                    self.advance();
+                    depth -= 1
+                }
+                (Some(b'"'), _) => {
+                    self.skip_string()?;
+                    continue;
+                }
+                _ => {}
+            }
+            self.advance()
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self) -> String {
+        let start = self.pos;
+        while let Some(b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'\'') = self.peek_at(0) {
+            self.advance()
+        }
+        String::from_utf8_lossy(&self.input[start..self.pos]).into_owned()
+    }
+
+    fn tokens(mut self) -> Result<Vec<Token>> {
+        let mut tokens = vec![];
+        loop {
+            let (line, column) = (self.line, self.pos - self.line_start + 1);
+            let c = match self.peek_at(0) {
+                None => break,
+                Some(c) => c,
+            };
+            let tok = match c {
+                c if c.is_ascii_whitespace() => {
+                    self.advance();
+                    continue;
+                }
+                b'(' if self.peek_at(1) == Some(b'*') => {
+                    self.skip_comment()?;
+                    continue;
+                }
+                b'"' => {
+                    self.skip_string()?;
+                    Tok::Other
+                }
+                b'{' if self.skip_quoted_string()? => Tok::Other,
+                b'a'..=b'z' | b'A'..=b'Z' | b'_' => Tok::Ident(self.ident()),
+                b'0'..=b'9' => {
+                    self.ident();
+                    Tok::Other
+                }
+                b'\'' => {
+                    // Either a character literal or a type variable.
+                    if self.peek_at(2) == Some(b'\'') {
+                        for _ in 0..3 {
+                            self.advance()
+                        }
+                        Tok::Other
+                    } else if self.peek_at(1) == Some(b'\\') {
+                        for _ in 0..3 {
+                            if self.peek_at(0).is_some() {
+                                self.advance()
+                            }
+                        }
+                        while !matches!(self.peek_at(0), None | Some(b'\'')) {
+                            self.advance()
+                        }
+                        if self.peek_at(0).is_some() {
+                            self.advance()
+                        }
+                        Tok::Other
+                    } else {
+                        self.advance();
+                        Tok::TypeVar(self.ident())
+                    }
+                }
+                b'`' => {
+                    self.advance();
+                    Tok::Tag(self.ident())
+                }
+                _ => {
+                    let two = [c, self.peek_at(1).unwrap_or(0)];
+                    let sym = match &two {
+                        b"->" | b".." | b";;" | b":=" | b"::" => 2,
+                        _ => 1,
+                    };
+                    let sym_str = String::from_utf8_lossy(&self.input[self.pos..self.pos + sym]);
+                    let sym_str = sym_str.into_owned();
+                    for _ in 0..sym {
+                        self.advance()
+                    }
+                    Tok::Sym(sym_str)
+                }
+            };
+            tokens.push(Token { tok, line, column })
+        }
+        tokens.push(Token {
+            tok: Tok::Eof,
+            line: self.line,
+            column: self.pos - self.line_start + 1,
+        });
+        Ok(remove_attributes(tokens))
+    }
+}
+
+// Removes the attributes `[@...]`, `[@@...]`, `[@@@...]` and the extension
+// nodes `[%...]`, `[%%...]`.
+fn remove_attributes(tokens: Vec<Token>) -> Vec<Token> {
+    let is_sym = |t: &Token, s: &str| matches!(&t.tok, Tok::Sym(sym) if sym == s);
+    let mut res = Vec::with_capacity(tokens.len());
+    let mut index = 0;
+    while index < tokens.len() {
+        let is_attribute = is_sym(&tokens[index], "[")
+            && index + 1 < tokens.len()
+            && (is_sym(&tokens[index + 1], "@") || is_sym(&tokens[index + 1], "%"));
+        if is_attribute {
+            let mut depth = 0;
+            while index < tokens.len() {
+                if is_sym(&tokens[index], "[") {
+                    depth += 1
+                } else if is_sym(&tokens[index], "]") {
+                    depth -= 1;
+                    if depth == 0 {
+                        index += 1;
+                        break;
+                    }
+                } else if tokens[index].tok == Tok::Eof {
+                    break;
+                }
+                index += 1
+            }
+        } else {
+            res.push(tokens[index].clone());
+            index += 1
+        }
+    }
+    res
+}
+
+// The OCaml keywords that can follow a type expression.
+fn is_reserved(s: &str) -> bool {
+    matches!(
+        s,
+        "and"
+            | "as"
+            | "class"
+            | "constraint"
+            | "end"
+            | "exception"
+            | "external"
+            | "in"
+            | "include"
+            | "let"
+            | "module"
+            | "of"
+            | "open"
+            | "type"
+            | "val"
+            | "with"
+    )
+}
+
+fn is_lowercase_ident(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+}
+
+fn is_uppercase_ident(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_uppercase())
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // The enclosing `struct`, `sig`, `begin` and `object` blocks, with the
+    // name of the module for the first two.
+    modules: Vec<Option<String>>,
+    pending_module: Option<String>,
+}
+
+impl Parser {
+    fn peek(&self) -> &Tok {
+        &self.tokens[self.pos].tok
+    }
+
+    fn peek_at(&self, offset: usize) -> &Tok {
+        let index = usize::min(self.pos + offset, self.tokens.len() - 1);
+        &self.tokens[index].tok
+    }
+
+    // The line and column of the next token.
+    fn position(&self) -> (usize, usize) {
+        let token = &self.tokens[self.pos];
+        (token.line, token.column)
+    }
+
+    fn next(&mut self) -> Tok {
+        let tok = self.tokens[self.pos].tok.clone();
+        if tok != Tok::Eof {
+            self.pos += 1
+        }
+        tok
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        let token = &self.tokens[self.pos];
+        let found = match &token.tok {
+            Tok::Ident(s) | Tok::Sym(s) => format!("'{}'", s),
+            Tok::TypeVar(s) => format!("'{}", s),
+            Tok::Tag(s) => format!("`{}", s),
+            Tok::Other => "a literal".to_string(),
+            Tok::Eof => "end of input".to_string(),
+        };
+        parse_error(
+            token.line,
+            token.column,
+            format!("{}, found {}", msg, found),
+        )
+    }
+
+    fn is_sym(&self, sym: &str) -> bool {
+        matches!(self.peek(), Tok::Sym(s) if s == sym)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Tok::Ident(s) if s == keyword)
+    }
+
+    fn eat_sym(&mut self, sym: &str) -> bool {
+        let is_sym = self.is_sym(sym);
+        if is_sym {
+            self.next();
+        }
+        is_sym
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let is_keyword = self.is_keyword(keyword);
+        if is_keyword {
+            self.next();
+        }
+        is_keyword
+    }
+
+    fn expect_sym(&mut self, sym: &str) -> Result<()> {
+        if !self.eat_sym(sym) {
+            return Err(self.error(&format!("expected '{}'", sym)));
+        }
+        Ok(())
+    }
+
+    fn lowercase_ident(&mut self, what: &str) -> Result<String> {
+        match self.peek() {
+            Tok::Ident(s) if is_lowercase_ident(s) => {
+                let s = s.clone();
+                self.next();
+                Ok(s)
+            }
+            _ => Err(self.error(&format!("expected {}", what))),
+        }
+    }
+
+    fn declarations(&mut self) -> Result<Vec<TypeDecl>> {
+        let mut decls = vec![];
+        let mut group = 0;
+        loop {
+            match self.peek() {
+                Tok::Eof => break,
+                Tok::Ident(s) if s == "type" => {
+                    // Skip `module type`, `with type` and `(type a)`.
+                    let skip = self.pos > 0
+                        && matches!(&self.tokens[self.pos - 1].tok,
+                            Tok::Ident(s) if s == "module" || s == "with")
+                        || matches!(&self.tokens[self.pos.saturating_sub(1)].tok, Tok::Sym(s) if s == "(");
+                    self.next();
+                    if skip {
+                        continue;
+                    }
+                    let nonrec = self.eat_keyword("nonrec");
+                    loop {
+                        decls.push(self.declaration(group, nonrec)?);
+                        if !self.eat_keyword("and") {
+                            break;
+                        }
+                    }
+                    group += 1
+                }
+                Tok::Ident(s) if s == "module" => {
+                    self.next();
+                    self.eat_keyword("rec");
+                    self.pending_module = match self.peek() {
+                        Tok::Ident(s) if is_uppercase_ident(s) => Some(s.clone()),
+                        _ => None,
+                    }
+                }
+                // For `module M : sig ... end = struct ... end`, the module
+                // name is used for both blocks.
+                Tok::Ident(s) if s == "sig" => {
+                    self.next();
+                    self.modules.push(self.pending_module.clone())
+                }
+                Tok::Ident(s) if s == "struct" => {
+                    self.next();
+                    self.modules.push(self.pending_module.take())
+                }
+                Tok::Ident(s) if s == "begin" || s == "object" => {
+                    self.next();
+                    self.modules.push(None)
+                }
+                Tok::Ident(s) if s == "end" => {
+                    self.next();
+                    self.modules.pop();
+                }
+                _ => {
+                    self.next();
+                }
+            }
+        }
+        Ok(decls)
+    }
+
+    fn type_params(&mut self) -> Result<Vec<String>> {
+        let param = |p: &mut Parser| -> Result<String> {
+            if !p.eat_sym("+") {
+                p.eat_sym("-");
+            }
+            match p.next() {
+                Tok::TypeVar(v) => Ok(v),
+                Tok::Ident(s) if s == "_" => Ok(s),
+                _ => {
+                    p.pos -= 1;
+                    Err(p.error("expected a type parameter"))
+                }
+            }
+        };
+        let mut params = vec![];
+        match self.peek() {
+            Tok::TypeVar(_) => params.push(param(self)?),
+            Tok::Sym(s) if s == "+" || s == "-" => params.push(param(self)?),
+            Tok::Sym(s) if s == "(" => {
+                self.next();
+                loop {
+                    params.push(param(self)?);
+                    if !self.eat_sym(",") {
+                        break;
+                    }
+                }
+                self.expect_sym(")")?
+            }
+            _ => {}
+        }
+        Ok(params)
+    }
+
+    fn declaration(&mut self, group: usize, nonrec: bool) -> Result<TypeDecl> {
+        let params = self.type_params()?;
+        let (line, column) = self.position();
+        let name = self.lowercase_ident("a type name")?;
+        let kind = if self.eat_sym("=") {
+            let kind = self.type_kind()?;
+            match kind {
+                // A type re-export, e.g. `type t = M.t = { ... }`.
+                TypeKind::Alias(_) if self.eat_sym("=") => self.type_kind()?,
+                kind => kind,
+            }
+        } else {
+            TypeKind::Abstract
+        };
+        if self.is_keyword("constraint") {
+            return Err(self.error("type constraints are not supported"));
+        }
+        Ok(TypeDecl {
+            name,
+            params,
+            kind,
+            line,
+            column,
+            group,
+            nonrec,
+            module_path: self.modules.iter().flatten().cloned().collect(),
+        })
+    }
+
+    fn type_kind(&mut self) -> Result<TypeKind> {
+        self.eat_keyword("private");
+        if self.is_sym("{") {
+            return Ok(TypeKind::Record(self.record_fields()?));
+        }
+        if self.is_sym("..") {
+            return Err(self.error("extensible variants are not supported"));
+        }
+        let is_variant = match self.peek() {
+            Tok::Sym(s) => s == "|",
+            Tok::Ident(s) => {
+                is_uppercase_ident(s)
+                    && !matches!(self.peek_at(1), Tok::Sym(s) if s == "." || s == "(")
+            }
+            _ => false,
+        };
+        if !is_variant {
+            return Ok(TypeKind::Alias(self.type_expr()?));
+        }
+        self.eat_sym("|");
+        let mut constructors = vec![];
+        loop {
+            constructors.push(self.constructor()?);
+            if !self.eat_sym("|") {
+                break;
+            }
+        }
+        Ok(TypeKind::Variant(constructors))
+    }
+
+    fn constructor(&mut self) -> Result<Constructor> {
+        let name = match self.peek() {
+            Tok::Ident(s) if is_uppercase_ident(s) => s.clone(),
+            // `[]` and `()` can be used as constructor names.
+            Tok::Sym(s) if s == "[" || s == "(" => {
+                let close = if s == "[" { "]" } else { ")" };
+                if !matches!(self.peek_at(1), Tok::Sym(s) if s == close) {
+                    return Err(self.error("expected a constructor"));
+                }
+                self.next();
+                format!("{}{}", if close == "]" { "[" } else { "(" }, close)
+            }
+            _ => return Err(self.error("expected a constructor")),
+        };
+        self.next();
+        if self.is_sym(":") {
+            return Err(self.error("GADT constructors are not supported"));
+        }
+        let args = if self.eat_keyword("of") {
+            if self.is_sym("{") {
+                ConstructorArgs::Record(self.record_fields()?)
+            } else {
+                ConstructorArgs::Tuple(self.tuple_components()?)
+            }
+        } else {
+            ConstructorArgs::Tuple(vec![])
+        };
+        Ok(Constructor { name, args })
+    }
+
+    fn record_fields(&mut self) -> Result<Vec<Field>> {
+        self.expect_sym("{")?;
+        let mut fields = vec![];
+        while !self.eat_sym("}") {
+            let mutable = self.eat_keyword("mutable");
+            let name = self.lowercase_ident("a field name")?;
+            self.expect_sym(":")?;
+            if matches!(self.peek(), Tok::TypeVar(_))
+                && matches!(self.peek_at(1), Tok::Sym(s) if s == ".")
+            {
+                return Err(self.error("polymorphic record fields are not supported"));
+            }
+            let ty = self.type_expr()?;
+            fields.push(Field { name, ty, mutable });
+            if !self.eat_sym(";") && !self.is_sym("}") {
+                return Err(self.error("expected ';' or '}'"));
+            }
+        }
+        if fields.is_empty() {
+            return Err(self.error("empty records are not supported"));
+        }
+        Ok(fields)
+    }
+
+    fn type_expr(&mut self) -> Result<TypeExpr> {
+        let mut components = self.tuple_components()?;
+        if self.is_sym("->") {
+            return Err(self.error("function types are not supported"));
+        }
+        if components.len() == 1 {
+            Ok(components.remove(0))
+        } else {
+            Ok(TypeExpr::Tuple(components))
+        }
+    }
+
+    fn tuple_components(&mut self) -> Result<Vec<TypeExpr>> {
+        let mut components = vec![self.app_expr()?];
+        while self.eat_sym("*") {
+            components.push(self.app_expr()?)
+        }
+        Ok(components)
+    }
+
+    // Type constructors applied in postfix position, e.g. `int list option`.
+    fn app_expr(&mut self) -> Result<TypeExpr> {
+        let mut args = if self.is_sym("(") {
+            self.next();
+            let mut args = vec![self.type_expr()?];
+            while self.eat_sym(",") {
+                args.push(self.type_expr()?)
+            }
+            self.expect_sym(")")?;
+            args
+        } else {
+            vec![self.atom_expr()?]
+        };
+        while matches!(self.peek(), Tok::Ident(s) if !is_reserved(s)) {
+            let (line, column) = self.position();
+            let name = self.type_path()?;
+            args = vec![TypeExpr::Constr {
+                name,
+                args,
+                line,
+                column,
+            }]
+        }
+        if args.len() != 1 {
+            return Err(self.error("expected a type constructor"));
+        }
+        Ok(args.remove(0))
+    }
+
+    fn atom_expr(&mut self) -> Result<TypeExpr> {
+        match self.peek().clone() {
+            Tok::TypeVar(v) => {
+                self.next();
+                Ok(TypeExpr::Var(v))
+            }
+            Tok::Ident(_) => {
+                let (line, column) = self.position();
+                let name = self.type_path()?;
+                Ok(TypeExpr::Constr {
+                    name,
+                    args: vec![],
+                    line,
+                    column,
+                })
+            }
+            Tok::Sym(s) if s == "[" => self.poly_variant(),
+            Tok::Sym(s) if s == "<" => Err(self.error("object types are not supported")),
+            _ => Err(self.error("expected a type")),
+        }
+    }
+
+    // A possibly qualified type name, e.g. `t`, `Int.Map.t` or `Map.M(String).t`.
+    fn type_path(&mut self) -> Result<String> {
+        let mut path = String::new();
+        loop {
+            match self.next() {
+                Tok::Ident(s) if is_lowercase_ident(&s) => {
+                    path.push_str(&s);
+                    return Ok(path);
+                }
+                Tok::Ident(s) => {
+                    path.push_str(&s);
+                    if self.is_sym("(") {
+                        self.next();
+                        path.push('(');
+                        let arg = self.type_path_module()?;
+                        path.push_str(&arg);
+                        self.expect_sym(")")?;
+                        path.push(')');
+                    }
+                    if !self.eat_sym(".") {
+                        return Err(self.error("expected '.'"));
+                    }
+                    path.push('.')
+                }
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("expected a type name"));
+                }
+            }
+        }
+    }
+
+    // A module path used as a functor argument, e.g. `String` or `Foo.Bar`.
+    fn type_path_module(&mut self) -> Result<String> {
+        let mut path = String::new();
+        loop {
+            match self.peek().clone() {
+                Tok::Ident(s) if is_uppercase_ident(&s) => {
+                    self.next();
+                    path.push_str(&s);
+                    if !matches!(self.peek_at(0), Tok::Sym(s) if s == ".") {
+                        return Ok(path);
+                    }
+                    self.next();
+                    path.push('.')
+                }
+                _ => return Err(self.error("expected a module name")),
+            }
+        }
+    }
+
+    fn poly_variant(&mut self) -> Result<TypeExpr> {
+        self.expect_sym("[")?;
+        let open = self.eat_sym("<") || self.eat_sym(">");
+        self.eat_sym("|");
+        let mut tags = vec![];
+        loop {
+            if self.is_sym("]") && open {
+                break;
+            }
+            match self.peek().clone() {
+                Tok::Tag(name) => {
+                    self.next();
+                    let arg = if self.eat_keyword("of") {
+                        self.eat_sym("&");
+                        Some(self.type_expr()?)
+                    } else {
+                        None
+                    };
+                    tags.push(PolyTag::Tag(name, arg))
+                }
+                _ => tags.push(PolyTag::Inherit(self.type_expr()?)),
+            }
+            if !self.eat_sym("|") {
+                break;
+            }
+        }
+        if self.eat_sym(">") {
+            // The lower bound of a `[< ... > `A ]` type, the tags are already
+            // listed in the upper bound.
+            while let Tok::Tag(_) = self.peek() {
+                self.next();
+            }
+        }
+        self.expect_sym("]")?;
+        Ok(TypeExpr::PolyVariant(tags))
+    }
+}
+
+// Strips the standard library prefixes, e.g. `Core.Int.t` becomes `Int.t`.
+pub(crate) fn strip_stdlib_prefix(name: &str) -> &str {
+    let prefixes = [
+        "Core.",
+        "Core_kernel.",
+        "Base.",
+        "Stdlib.",
+        "Bin_prot.Std.",
+        "Bin_prot.",
+    ];
+    for prefix in prefixes.iter() {
+        if let Some(name) = name.strip_prefix(prefix) {
+            return strip_stdlib_prefix(name);
+        }
+    }
+    name
+}
+
+// The shape of a type constructor from the OCaml standard library or from
+// Core, `None` if the type is unknown.
+pub(crate) fn core_type(
+    name: &str,
+    mut args: Vec<Shape>,
+) -> Option<std::result::Result<Shape, String>> {
+    let name = strip_stdlib_prefix(name);
+    let arity = |expected: usize, args: &[Shape]| {
+        if args.len() == expected {
+            Ok(())
+        } else {
+            Err(format!(
+                "type {} expects {} argument(s) but got {}",
+                name,
+                expected,
+                args.len()
+            ))
+        }
+    };
+    let base0 = |uuid: &str| Some(arity(0, &args).map(|()| Shape::base(uuid)));
+    let defined0 = |shape: fn() -> Shape| Some(arity(0, &args).map(|()| shape()));
+    let base1 = |uuid: &str, args: Vec<Shape>| {
+        Some(arity(1, &args).map(|()| Shape::Base(uuid.to_string(), args)))
+    };
+    match name {
+        "unit" | "Unit.t" => base0(uuid::UNIT),
+        "bool" | "Bool.t" => base0(uuid::BOOL),
+        "string" | "String.t" => base0(uuid::STRING),
+        "bytes" | "Bytes.t" => base0(uuid::BYTES),
+        "char" | "Char.t" => base0(uuid::CHAR),
+        "float" | "Float.t" => base0(uuid::FLOAT),
+        "int" | "Int.t" => base0(uuid::INT),
+        "int32" | "Int32.t" => base0(uuid::INT32),
+        "int64" | "Int64.t" => base0(uuid::INT64),
+        "Int63.t" => base0(uuid::INT63),
+        "nativeint" | "Nativeint.t" => base0(uuid::NATIVEINT),
+        "Nat0.t" => base0(uuid::NAT0),
+        "Md5.t" | "Md5_lib.t" => base0(uuid::STRING),
+        "bigstring" | "Bigstring.t" | "Bigstring.Stable.V1.t" => base0(uuid::BIGSTRING),
+        "option" | "Option.t" => base1(uuid::OPTION, args),
+        "list" | "List.t" | "Fqueue.t" => base1(uuid::LIST, args),
+        "array" | "Array.t" => base1(uuid::ARRAY, args),
+        "ref" | "Ref.t" => base1(uuid::REF, args),
+        "lazy_t" | "Lazy.t" => base1(uuid::LAZY, args),
+        "Deque.t" => base1(uuid::DEQUE, args),
+        "Nonempty_list.t" => Some(arity(1, &args).map(|()| {
+            let arg = args.remove(0);
+            Shape::Tuple(vec![arg.clone(), Shape::list(arg)])
+        })),
+        "Time_ns.t" | "Time_ns.Span.t" => base0(uuid::INT63),
+        "Date.t" => defined0(date_shape),
+        "Result.t" => Some(arity(2, &args).map(|()| {
+            let error = args.pop().unwrap();
+            result_shape(args.pop().unwrap(), error)
+        })),
+        "Or_error.t" => Some(arity(1, &args).map(|()| result_shape(args.remove(0), info_shape()))),
+        "Error.t" | "Info.t" => defined0(info_shape),
+        "Sexp.t" => defined0(sexp_shape),
+        _ => {
+            // Maps and sets keyed by a module, e.g. `String.Map.t` or
+            // `Map.M(String).t`.
+            let (key, container) = if let Some(rest) = name.strip_suffix(").t") {
+                let (container, key) = rest.split_once(".M(")?;
+                (format!("{}.t", key), container)
+            } else {
+                let rest = name.strip_suffix(".t")?;
+                let (key, container) = rest.rsplit_once('.')?;
+                (format!("{}.t", key), container)
+            };
+            let uuid = match container {
+                "Map" => uuid::MAP,
+                "Set" => uuid::SET,
+                "Table" | "Hashtbl" => uuid::HASHTBL,
+                "Hash_set" => uuid::HASH_SET,
+                _ => return None,
+            };
+            let key = match core_type(&key, vec![])? {
+                Ok(key) => key,
+                Err(err) => return Some(Err(err)),
+            };
+            let expected = if uuid == uuid::SET || uuid == uuid::HASH_SET {
+                0
+            } else {
+                1
+            };
+            if let Err(err) = arity(expected, &args) {
+                return Some(Err(err));
+            }
+            if uuid == uuid::MAP {
+                return Some(Ok(Shape::map(key, args.remove(0))));
+            }
+            args.insert(0, key);
+            Some(Ok(Shape::Base(uuid.to_string(), args)))
+        }
+    }
+}
+
+// The shapes of the Core types with a bin_io derived from their OCaml
+// definition, these follow the stable versions of the types.
+
+fn result_shape(ok: Shape, error: Shape) -> Shape {
+    let variants = vec![
+        ("Ok".to_string(), vec![ok]),
+        ("Error".to_string(), vec![error]),
+    ];
+    Shape::Variant(variants)
+}
+
+fn sexp_shape() -> Shape {
+    let list = Shape::list(Shape::RecApp(0, vec![]));
+    let variants = vec![
+        ("Atom".to_string(), vec![Shape::string()]),
+        ("List".to_string(), vec![list]),
+    ];
+    Shape::Application(Box::new(Shape::Variant(variants)), vec![])
+}
+
+// `Date.Stable.V1.t`, the month being a variant with one constructor per month.
+fn date_shape() -> Shape {
+    let months = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let month = Shape::Variant(months.iter().map(|m| (m.to_string(), vec![])).collect());
+    let fields = vec![
+        ("y".to_string(), Shape::int()),
+        ("m".to_string(), Shape::Application(Box::new(month), vec![])),
+        ("d".to_string(), Shape::int()),
+    ];
+    Shape::Application(Box::new(Shape::Record(fields)), vec![])
+}
+
+// `Info.Stable.V2.t`, which `Error.t` shares, exceptions being written as
+// s-expressions.
+fn info_shape() -> Shape {
+    let sexp = sexp_shape;
+    let info = || Shape::RecApp(0, vec![]);
+    let position = Shape::Record(
+        ["pos_fname", "pos_lnum", "pos_bol", "pos_cnum"]
+            .iter()
+            .map(|f| {
+                let shape = if *f == "pos_fname" {
+                    Shape::string()
+                } else {
+                    Shape::int()
+                };
+                (f.to_string(), shape)
+            })
+            .collect(),
+    );
+    let position = Shape::Application(Box::new(position), vec![]);
+    let variants = vec![
+        ("Could_not_construct", vec![sexp()]),
+        ("String", vec![Shape::string()]),
+        ("Exn", vec![sexp()]),
+        ("Sexp", vec![sexp()]),
+        (
+            "Tag_sexp",
+            vec![Shape::string(), sexp(), Shape::option(position)],
+        ),
+        ("Tag_t", vec![Shape::string(), info()]),
+        ("Tag_arg", vec![Shape::string(), sexp(), info()]),
+        (
+            "Of_list",
+            vec![Shape::option(Shape::int()), Shape::list(info())],
+        ),
+        ("With_backtrace", vec![info(), Shape::string()]),
+    ];
+    let variants = variants
+        .into_iter()
+        .map(|(n, args)| (n.to_string(), args))
+        .collect();
+    Shape::Application(Box::new(Shape::Variant(variants)), vec![])
+}
+
+struct ShapeBuilder<'a> {
+    decls: &'a Declarations,
+    // The declarations being expanded, innermost last.
+    stack: Vec<usize>,
+}
+
+impl ShapeBuilder<'_> {
+    fn decl_shape(&mut self, index: usize, args: Vec<Shape>) -> Result<Shape> {
+        if let Some(pos) = self.stack.iter().rposition(|&i| i == index) {
+            return Ok(Shape::RecApp(self.stack.len() - 1 - pos, args));
+        }
+        let decl = &self.decls.decls[index];
+        if decl.params.len() != args.len() {
+            return Err(parse_error(
+                decl.line,
+                decl.column,
+                format!(
+                    "type {} expects {} argument(s) but got {}",
+                    decl.name,
+                    decl.params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        self.stack.push(index);
+        let body = self.kind_shape(index);
+        self.stack.pop();
+        Ok(Shape::Application(Box::new(body?), args))
+    }
+
+    fn kind_shape(&mut self, index: usize) -> Result<Shape> {
+        let decl = &self.decls.decls[index];
+        match &decl.kind {
+            TypeKind::Abstract => Err(parse_error(
+                decl.line,
+                decl.column,
+                format!("type {} is abstract", decl.name),
+            )),
+            TypeKind::Alias(ty) => self.expr_shape(ty, index),
+            TypeKind::Record(fields) => self.record_shape(fields, index),
+            TypeKind::Variant(constructors) => {
+                let mut variants = vec![];
+                for constructor in constructors.iter() {
+                    let args = match &constructor.args {
+                        ConstructorArgs::Tuple(args) => args
+                            .iter()
+                            .map(|arg| self.expr_shape(arg, index))
+                            .collect::<Result<Vec<_>>>()?,
+                        ConstructorArgs::Record(fields) => vec![self.record_shape(fields, index)?],
+                    };
+                    variants.push((constructor.name.clone(), args))
+                }
+                Ok(Shape::Variant(variants))
+            }
+        }
+    }
+
+    fn record_shape(&mut self, fields: &[Field], index: usize) -> Result<Shape> {
+        let fields = fields
+            .iter()
+            .map(|field| Ok((field.name.clone(), self.expr_shape(&field.ty, index)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Shape::Record(fields))
+    }
+
+    fn expr_shape(&mut self, ty: &TypeExpr, index: usize) -> Result<Shape> {
+        let decl = &self.decls.decls[index];
+        match ty {
+            TypeExpr::Var(v) => match decl.params.iter().position(|p| p == v) {
+                Some(i) => Ok(Shape::Var(i)),
+                None => Err(parse_error(
+                    decl.line,
+                    decl.column,
+                    format!("unbound type variable '{} in type {}", v, decl.name),
+                )),
+            },
+            TypeExpr::Tuple(tys) => {
+                let shapes = tys
+                    .iter()
+                    .map(|ty| self.expr_shape(ty, index))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Shape::Tuple(shapes))
+            }
+            TypeExpr::Constr {
+                name,
+                args,
+                line,
+                column,
+            } => {
+                let args = args
+                    .iter()
+                    .map(|ty| self.expr_shape(ty, index))
+                    .collect::<Result<Vec<_>>>()?;
+                if let Some(target) = self.decls.resolve(name, index) {
+                    return self.decl_shape(target, args);
+                }
+                match core_type(name, args) {
+                    Some(shape) => shape.map_err(|msg| parse_error(*line, *column, msg)),
+                    None => Err(parse_error(
+                        *line,
+                        *column,
+                        format!("unknown type {}", name),
+                    )),
+                }
+            }
+            TypeExpr::PolyVariant(tags) => {
+                let mut variants = BTreeMap::new();
+                for tag in tags.iter() {
+                    match tag {
+                        PolyTag::Tag(name, arg) => {
+                            let arg = match arg {
+                                None => None,
+                                Some(arg) => Some(self.expr_shape(arg, index)?),
+                            };
+                            variants.insert(name.clone(), arg);
+                        }
+                        PolyTag::Inherit(ty) => {
+                            let shape = self.expr_shape(ty, index)?;
+                            variants.extend(inherited_tags(shape, ty)?)
+                        }
+                    }
+                }
+                Ok(Shape::PolyVariant(variants))
+            }
+        }
+    }
+}
+
+fn inherited_tags(shape: Shape, ty: &TypeExpr) -> Result<BTreeMap<String, Option<Shape>>> {
+    let (line, column) = match ty {
+        TypeExpr::Constr { line, column, .. } => (*line, *column),
+        _ => (0, 0),
+    };
+    let error = || {
+        parse_error(
+            line,
+            column,
+            "only non-recursive polymorphic variants can be included".to_string(),
+        )
+    };
+    match shape {
+        Shape::PolyVariant(variants) => Ok(variants),
+        Shape::Application(body, args) => {
+            if contains_rec_app(&body, 0) {
+                return Err(error());
+            }
+            inherited_tags(body.instantiate(&args), ty)
+        }
+        _ => Err(error()),
+    }
+}
+
+// Whether the shape refers to the application `depth` levels above it.
+fn contains_rec_app(shape: &Shape, depth: usize) -> bool {
+    let any = |shapes: &[Shape]| shapes.iter().any(|s| contains_rec_app(s, depth));
+    match shape {
+        Shape::Annotate(_, shape) => contains_rec_app(shape, depth),
+        Shape::Base(_, shapes) | Shape::Tuple(shapes) => any(shapes),
+        Shape::Record(fields) => fields.iter().any(|(_, s)| contains_rec_app(s, depth)),
+        Shape::Variant(variants) => variants.iter().any(|(_, shapes)| any(shapes)),
+        Shape::PolyVariant(variants) => variants
+            .values()
+            .any(|s| s.as_ref().is_some_and(|s| contains_rec_app(s, depth))),
+        Shape::Application(body, shapes) => contains_rec_app(body, depth + 1) || any(shapes),
+        Shape::RecApp(index, shapes) => *index == depth || any(shapes),
+        Shape::Var(_) => false,
+    }
+}
+
+impl Declarations {
+    /// Extracts the type declarations from the content of an OCaml file.
+    pub fn parse(src: &str) -> Result<Self> {
+        let lexer = Lexer {
+            input: src.as_bytes(),
+            pos: 0,
+            line: 1,
+            line_start: 0,
+        };
+        let tokens = lexer.tokens()?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            modules: vec![],
+            pending_module: None,
+        };
+        let decls = parser.declarations()?;
+        Ok(Declarations { decls })
+    }
+
+    /// Finds the declaration that a possibly qualified type name refers to
+    /// from the declaration at index `from`. The declarations of the same
+    /// recursive group come first, then the last visible declaration with
+    /// this name that precedes the group.
+    pub fn resolve(&self, name: &str, from: usize) -> Option<usize> {
+        let decl = &self.decls[from];
+        let (modules, name) = match name.rsplit_once('.') {
+            None => (vec![], name),
+            Some((modules, name)) => (modules.split('.').collect(), name),
+        };
+        let matches = |d: &TypeDecl| {
+            if d.name != name {
+                return false;
+            }
+            if modules.is_empty() {
+                // Unqualified names only refer to declarations from the
+                // current module or from the enclosing ones.
+                decl.module_path.starts_with(&d.module_path)
+            } else {
+                d.module_path.len() >= modules.len()
+                    && d.module_path[d.module_path.len() - modules.len()..]
+                        .iter()
+                        .zip(modules.iter())
+                        .all(|(m1, m2)| m1 == m2)
+            }
+        };
+        let group_start = self
+            .decls
+            .iter()
+            .position(|d| d.group == decl.group)
+            .unwrap_or(from);
+        if !decl.nonrec {
+            let in_group = self
+                .decls
+                .iter()
+                .position(|d| d.group == decl.group && matches(d));
+            if in_group.is_some() {
+                return in_group;
+            }
+        }
+        self.decls[..group_start].iter().rposition(matches)
+    }
+
+    /// The last declaration with the given name.
+    pub fn find(&self, name: &str) -> Option<&TypeDecl> {
+        self.decls.iter().rev().find(|d| d.name == name)
+    }
+
+    /// The shape of the last declaration with the given name, the type
+    /// parameters if any are left as variables.
+    pub fn shape(&self, name: &str) -> Result<Shape> {
+        match self.decls.iter().rposition(|d| d.name == name) {
+            Some(index) => self.decl_shape(index),
+            None => Err(Error::Message(format!("unknown type {}", name))),
+        }
+    }
+
+    /// The shape of the declaration at the given index.
+    pub fn decl_shape(&self, index: usize) -> Result<Shape> {
+        let mut builder = ShapeBuilder {
+            decls: self,
+            stack: vec![],
+        };
+        let args = (0..self.decls[index].params.len())
+            .map(Shape::Var)
+            .collect();
+        builder.decl_shape(index, args)
+    }
+}
+
+impl TypeExpr {
+    // Whether the expression has to be parenthesized when used as the
+    // argument of a type constructor or as a tuple component.
+    fn needs_parens(&self) -> bool {
+        matches!(self, TypeExpr::Tuple(_))
+    }
+}
+
+impl fmt::Display for TypeExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let atom = |f: &mut fmt::Formatter, ty: &TypeExpr| {
+            if ty.needs_parens() {
+                write!(f, "({})", ty)
+            } else {
+                write!(f, "{}", ty)
+            }
+        };
+        match self {
+            TypeExpr::Var(v) => write!(f, "'{}", v),
+            TypeExpr::Constr { name, args, .. } => {
+                match args.as_slice() {
+                    [] => {}
+                    [arg] => {
+                        atom(f, arg)?;
+                        write!(f, " ")?
+                    }
+                    args => {
+                        write!(f, "(")?;
+                        for (i, arg) in args.iter().enumerate() {
+                            if i > 0 {
+                                write!(f, ", ")?
+                            }
+                            write!(f, "{}", arg)?
+                        }
+                        write!(f, ") ")?
+                    }
+                }
+                write!(f, "{}", name)
+            }
+            TypeExpr::Tuple(tys) => {
+                for (i, ty) in tys.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " * ")?
+                    }
+                    atom(f, ty)?
+                }
+                Ok(())
+            }
+            TypeExpr::PolyVariant(tags) => {
+                write!(f, "[ ")?;
+                for (i, tag) in tags.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " | ")?
+                    }
+                    match tag {
+                        PolyTag::Tag(name, None) => write!(f, "`{}", name)?,
+                        PolyTag::Tag(name, Some(ty)) => write!(f, "`{} of {}", name, ty)?,
+                        PolyTag::Inherit(ty) => write!(f, "{}", ty)?,
+                    }
+                }
+                write!(f, " ]")
+            }
+        }
+    }
+}
+
+fn write_fields(f: &mut fmt::Formatter, fields: &[Field], indent: &str) -> fmt::Result {
+    for (i, field) in fields.iter().enumerate() {
+        let sep = if i == 0 { "{" } else { ";" };
+        let mutable = if field.mutable { "mutable " } else { "" };
+        writeln!(
+            f,
+            "{}{} {}{} : {}",
+            indent, sep, mutable, field.name, field.ty
+        )?
+    }
+    write!(f, "{}}}", indent)
+}
+
+impl fmt::Display for TypeDecl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.params.as_slice() {
+            [] => {}
+            [param] => write!(f, "'{} ", param)?,
+            params => {
+                let params: Vec<_> = params.iter().map(|p| format!("'{}", p)).collect();
+                write!(f, "({}) ", params.join(", "))?
+            }
+        }
+        write!(f, "{}", self.name)?;
+        match &self.kind {
+            TypeKind::Abstract => Ok(()),
+            TypeKind::Alias(ty) => write!(f, " = {}", ty),
+            TypeKind::Record(fields) => {
+                writeln!(f, " =")?;
+                write_fields(f, fields, "  ")
+            }
+            TypeKind::Variant(constructors) => {
+                write!(f, " =")?;
+                for constructor in constructors.iter() {
+                    write!(f, "\n  | {}", constructor.name)?;
+                    match &constructor.args {
+                        ConstructorArgs::Tuple(args) if args.is_empty() => {}
+                        ConstructorArgs::Tuple(args) => {
+                            write!(f, " of ")?;
+                            for (i, arg) in args.iter().enumerate() {
+                                if i > 0 {
+                                    write!(f, " * ")?
+                                }
+                                if arg.needs_parens() {
+                                    write!(f, "({})", arg)?
+                                } else {
+                                    write!(f, "{}", arg)?
+                                }
+                            }
+                        }
+                        ConstructorArgs::Record(fields) => {
+                            writeln!(f, " of")?;
+                            write_fields(f, fields, "      ")?
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Prints the declarations, each recursive group being followed by a
+/// `[@@deriving bin_io, sexp]` attribute.
+impl fmt::Display for Declarations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, decl) in self.decls.iter().enumerate() {
+            let first_in_group = index == 0 || self.decls[index - 1].group != decl.group;
+            if first_in_group {
+                if index > 0 {
+                    writeln!(f)?
+                }
+                let nonrec = if decl.nonrec { "nonrec " } else { "" };
+                writeln!(f, "type {}{}", nonrec, decl)?
+            } else {
+                writeln!(f, "\nand {}", decl)?
+            }
+            let last_in_group = self
+                .decls
+                .get(index + 1)
+                .is_none_or(|d| d.group != decl.group);
+            if last_in_group {
+                writeln!(f, "[@@deriving bin_io, sexp]")?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Declarations, TypeKind};
+    use crate::shape::uuid;
+    use crate::{Error, HasShape, Shape};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::Serialize;
+    use std::collections::BTreeMap;
+
+    const SRC: &str = r#"
+open! Core
+
+(* A comment with a "string (*" and a (* nested *) comment. *)
+module Side = struct
+  type t = Buy | Sell [@@deriving bin_io, sexp]
+end
+
+type side = Side.t
+
+type 'a tree =
+  | Leaf
+  | Node of 'a tree * 'a * 'a tree
+  | Nodes of { children : 'a tree list }
+[@@deriving bin_io]
+
+type order =
+  { qty : int
+  ; mutable price : float option [@default None]
+  ; tags : (string * Nat0.t) list
+  ; ids : int String.Map.t
+  ; at : Time_ns.t * Date.t
+  ; result : (int, string) Result.t
+  ; checked : unit Or_error.t
+  } [@@deriving bin_io]
+
+type color = [ `Red | `Rgb of int * int * int ] [@@deriving bin_io]
+type color2 = [ color | `Blue ] [@@deriving bin_io]
+
+let f x = x + 'a' |> ignore
+"#;
+
+    #[test]
+    fn test_parse() {
+        let decls = Declarations::parse(SRC).unwrap();
+        let names: Vec<_> = decls.decls.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["t", "side", "tree", "order", "color", "color2"]);
+        assert!(matches!(decls.decls[1].kind, TypeKind::Alias(_)));
+
+        #[derive(BinShape)]
+        #[allow(dead_code)]
+        enum Month {
+            Jan,
+            Feb,
+            Mar,
+            Apr,
+            May,
+            Jun,
+            Jul,
+            Aug,
+            Sep,
+            Oct,
+            Nov,
+            Dec,
+        }
+
+        #[derive(BinShape)]
+        #[allow(dead_code)]
+        struct Date {
+            y: i64,
+            m: Month,
+            d: i64,
+        }
+
+        #[derive(BinShape, Serialize)]
+        #[allow(dead_code)]
+        enum Tree<T> {
+            Leaf,
+            Node(Box<Tree<T>>, T, Box<Tree<T>>),
+            Nodes { children: Vec<Tree<T>> },
+        }
+        assert_eq!(
+            decls.shape("tree").unwrap(),
+            Tree::<crate::shape::ShapeVar<0>>::shape()
+        );
+
+        let mut rgb = BTreeMap::new();
+        rgb.insert("Blue".to_string(), None);
+        rgb.insert("Red".to_string(), None);
+        let ints = Shape::Tuple(vec![Shape::int(), Shape::int(), Shape::int()]);
+        rgb.insert("Rgb".to_string(), Some(ints));
+        assert_eq!(
+            decls.shape("color2").unwrap(),
+            Shape::Application(Box::new(Shape::PolyVariant(rgb)), vec![])
+        );
+
+        let order = decls.shape("order").unwrap();
+        match order {
+            Shape::Application(body, _) => match *body {
+                Shape::Record(fields) => {
+                    assert_eq!(fields[1].1, Shape::option(Shape::float()));
+                    assert_eq!(
+                        fields[2].1,
+                        Shape::list(Shape::Tuple(vec![Shape::string(), Shape::nat0()]))
+                    );
+                    assert_eq!(fields[3].1, Shape::map(Shape::string(), Shape::int()));
+                    let time_ns = Shape::base(uuid::INT63);
+                    assert_eq!(fields[4].1, Shape::Tuple(vec![time_ns, Date::shape()]));
+                    let ok = |shape| ("Ok".to_string(), vec![shape]);
+                    let error = |shape| ("Error".to_string(), vec![shape]);
+                    assert_eq!(
+                        fields[5].1,
+                        Shape::Variant(vec![ok(Shape::int()), error(Shape::string())])
+                    );
+                    match &fields[6].1 {
+                        Shape::Variant(variants) => match &variants[..] {
+                            [(_, unit), (_, error)] if unit == &[Shape::unit()] => {
+                                match &error[0] {
+                                    Shape::Application(body, _) => {
+                                        assert!(
+                                            matches!(&**body, Shape::Variant(v) if v.len() == 9)
+                                        )
+                                    }
+                                    error => panic!("unexpected shape {:?}", error),
+                                }
+                            }
+                            variants => panic!("unexpected variants {:?}", variants),
+                        },
+                        shape => panic!("unexpected shape {:?}", shape),
+                    }
+                }
+                body => panic!("unexpected shape {:?}", body),
+            },
+            order => panic!("unexpected shape {:?}", order),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let position = |src: &str| match Declarations::parse(src).and_then(|d| d.shape("t")) {
+            Err(Error::ParseError { line, column, .. }) => (line, column),
+            res => panic!("unexpected result {:?}", res),
+        };
+        assert_eq!(position("type t =\n  { a : int\n  ; b : foo\n  }"), (3, 9));
+        assert_eq!(position("\n\ntype t = int -> int"), (3, 14));
+        assert_eq!(position("type t = { a : int\n b : int }"), (2, 4));
+        assert_eq!(position("type u = int\n\nand t = 'a list"), (3, 5));
+        assert_eq!(position("type t = int Result.t option"), (1, 14));
+    }
+}
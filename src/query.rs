@@ -0,0 +1,678 @@
+//! Filtering and projecting streams of framed messages.
+//!
+//! The messages of a stream framed with size headers are decoded one at a
+//! time with a shape and converted to JSON as done in [`crate::json`]. A
+//! predicate selects the messages to keep, and the output contains either
+//! the whole messages or some of their fields, as JSON lines or CSV.
+//!
+//! Paths follow the JSON representation of the messages, e.g. `.side.Sell`
+//! for the argument of constructor `Sell`, and `.fills[0]` or `.fills.0`
+//! for the first element of a list or tuple. Predicates compare paths with
+//! literals using `==`, `!=`, `<`, `<=`, `>` and `>=`, and are combined
+//! with `and`, `or`, `not` and parentheses. A path alone is true when its
+//! value is neither `false` nor `null`, missing paths being `null`.
+//!
+//! ```
+//! use serde_binprot::query::{Format, Path, Predicate, Query};
+//! use serde_binprot::HasShape;
+//!
+//! let mut data = vec![];
+//! for order in [("AAPL", 150i64), ("MSFT", 200), ("AAPL", 20)].iter() {
+//!     let payload = serde_binprot::to_vec(order).unwrap();
+//!     serde_binprot::framing::write_frame(&mut data, &payload).unwrap();
+//! }
+//! let query = Query::new(<(String, i64)>::shape())
+//!     .filter(Predicate::parse(r#".0 == "AAPL" and .1 > 100"#).unwrap())
+//!     .select(Path::parse_list(".1").unwrap())
+//!     .format(Format::Csv);
+//! let mut out = vec![];
+//! assert_eq!(query.run(data.as_slice(), &mut out).unwrap(), 1);
+//! assert_eq!(String::from_utf8(out).unwrap(), "1\n150\n");
+//! ```
+use crate::error::{Error, Result};
+use crate::framing::{self, FrameReader};
+use crate::shape::Shape;
+use serde_json::Value as Json;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{Read, Write};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    // An object key, or an index when the value is a list.
+    Key(String),
+    Index(usize),
+}
+
+/// A path to a part of the JSON representation of a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path {
+    text: String,
+    segments: Vec<Segment>,
+}
+
+impl Path {
+    /// Parses a single path.
+    pub fn parse(s: &str) -> Result<Path> {
+        match Path::parse_list(s)?.as_slice() {
+            [path] => Ok(path.clone()),
+            _ => Err(Error::Message(format!("invalid path {}", s))),
+        }
+    }
+
+    /// Parses a comma separated list of paths.
+    pub fn parse_list(s: &str) -> Result<Vec<Path>> {
+        let mut lexer = Lexer::new(s);
+        let mut paths = vec![];
+        loop {
+            match lexer.next()? {
+                Some(Token::Path(path)) => paths.push(path),
+                _ => return Err(lexer.error("expected a path")),
+            }
+            match lexer.next()? {
+                Some(Token::Comma) => {}
+                None => return Ok(paths),
+                _ => return Err(lexer.error("expected a comma")),
+            }
+        }
+    }
+
+    /// The value at this path, if any.
+    pub fn get<'a>(&self, json: &'a Json) -> Option<&'a Json> {
+        let mut json = json;
+        for segment in self.segments.iter() {
+            json = match (segment, json) {
+                (Segment::Key(key), Json::Object(map)) => map.get(key)?,
+                (Segment::Key(key), Json::Array(list)) => list.get(key.parse::<usize>().ok()?)?,
+                (Segment::Index(index), Json::Array(list)) => list.get(*index)?,
+                _ => return None,
+            }
+        }
+        Some(json)
+    }
+
+    // The name of the path in the output, without the leading dot.
+    fn column(&self) -> &str {
+        match self.text.strip_prefix('.') {
+            Some(name) if !name.is_empty() => name,
+            _ => &self.text,
+        }
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Path(Path),
+    Literal(Json),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, Op, Operand),
+    Operand(Operand),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Path(Path),
+    Literal(Json),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+    Comma,
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    // The start of the last token, used to locate errors.
+    start: usize,
+    peeked: Option<Option<Token>>,
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '\''
+}
+
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str) -> Self {
+        Lexer {
+            src,
+            pos: 0,
+            start: 0,
+            peeked: None,
+        }
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        Error::Message(format!(
+            "{} at column {} of {}",
+            msg,
+            self.start + 1,
+            self.src
+        ))
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    // Consumes the longest prefix whose characters satisfy `f`.
+    fn take_while<F: Fn(char) -> bool>(&mut self, f: F) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    // Consumes a JSON string literal.
+    fn string(&mut self) -> Result<String> {
+        let rest = self.rest();
+        let mut escaped = false;
+        for (index, c) in rest.char_indices().skip(1) {
+            match c {
+                '\\' => escaped = !escaped,
+                '"' if !escaped => {
+                    self.pos += index + 1;
+                    return serde_json::from_str(&rest[..index + 1])
+                        .map_err(|err| self.error(&format!("invalid string, {}", err)));
+                }
+                _ => escaped = false,
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    fn path(&mut self) -> Result<Path> {
+        let start = self.pos;
+        let mut segments = vec![];
+        loop {
+            if self.rest().starts_with('.') {
+                self.pos += 1;
+                if self.rest().starts_with('"') {
+                    segments.push(Segment::Key(self.string()?))
+                } else {
+                    let key = self.take_while(is_ident);
+                    if !key.is_empty() {
+                        segments.push(Segment::Key(key.to_string()))
+                    } else if !segments.is_empty() || self.rest().starts_with('.') {
+                        return Err(self.error("empty field name in path"));
+                    }
+                }
+            } else if self.rest().starts_with('[') {
+                self.pos += 1;
+                let index = self.take_while(|c| c.is_ascii_digit()).parse();
+                match (index, self.rest().starts_with(']')) {
+                    (Ok(index), true) => segments.push(Segment::Index(index)),
+                    _ => return Err(self.error("invalid index in path")),
+                }
+                self.pos += 1
+            } else {
+                break;
+            }
+        }
+        Ok(Path {
+            text: self.src[start..self.pos].to_string(),
+            segments,
+        })
+    }
+
+    fn lex(&mut self) -> Result<Option<Token>> {
+        self.take_while(char::is_whitespace);
+        self.start = self.pos;
+        let rest = self.rest();
+        let c = match rest.chars().next() {
+            None => return Ok(None),
+            Some(c) => c,
+        };
+        let ops = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+        if let Some((s, op)) = ops.iter().find(|(s, _)| rest.starts_with(s)) {
+            self.pos += s.len();
+            return Ok(Some(Token::Op(*op)));
+        }
+        let token = match c {
+            '(' | ')' | ',' => {
+                self.pos += 1;
+                match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    _ => Token::Comma,
+                }
+            }
+            '.' => Token::Path(self.path()?),
+            '"' => Token::Literal(Json::String(self.string()?)),
+            '-' | '0'..='9' => {
+                let number = self.take_while(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+                match serde_json::from_str::<serde_json::Number>(number) {
+                    Ok(number) => Token::Literal(Json::Number(number)),
+                    Err(_) => return Err(self.error(&format!("invalid number {}", number))),
+                }
+            }
+            c if is_ident(c) => match self.take_while(is_ident) {
+                "and" => Token::And,
+                "or" => Token::Or,
+                "not" => Token::Not,
+                "true" => Token::Literal(Json::Bool(true)),
+                "false" => Token::Literal(Json::Bool(false)),
+                "null" => Token::Literal(Json::Null),
+                word => return Err(self.error(&format!("unexpected {}", word))),
+            },
+            c => return Err(self.error(&format!("unexpected character {}", c))),
+        };
+        Ok(Some(token))
+    }
+
+    fn next(&mut self) -> Result<Option<Token>> {
+        match self.peeked.take() {
+            Some(token) => Ok(token),
+            None => self.lex(),
+        }
+    }
+
+    fn peek(&mut self) -> Result<Option<&Token>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.lex()?)
+        }
+        Ok(self.peeked.as_ref().and_then(|t| t.as_ref()))
+    }
+}
+
+/// A predicate over the JSON representation of messages, e.g.
+/// `.symbol == "AAPL" and (.qty > 100 or not .side.Buy)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Predicate(Expr);
+
+impl Predicate {
+    pub fn parse(s: &str) -> Result<Predicate> {
+        let mut lexer = Lexer::new(s);
+        let expr = Predicate::or(&mut lexer)?;
+        match lexer.next()? {
+            None => Ok(Predicate(expr)),
+            Some(_) => Err(lexer.error("unexpected token")),
+        }
+    }
+
+    fn or(lexer: &mut Lexer) -> Result<Expr> {
+        let mut expr = Predicate::and(lexer)?;
+        while lexer.peek()? == Some(&Token::Or) {
+            lexer.next()?;
+            expr = Expr::Or(Box::new(expr), Box::new(Predicate::and(lexer)?))
+        }
+        Ok(expr)
+    }
+
+    fn and(lexer: &mut Lexer) -> Result<Expr> {
+        let mut expr = Predicate::not(lexer)?;
+        while lexer.peek()? == Some(&Token::And) {
+            lexer.next()?;
+            expr = Expr::And(Box::new(expr), Box::new(Predicate::not(lexer)?))
+        }
+        Ok(expr)
+    }
+
+    fn not(lexer: &mut Lexer) -> Result<Expr> {
+        match lexer.next()? {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(Predicate::not(lexer)?))),
+            Some(Token::LParen) => {
+                let expr = Predicate::or(lexer)?;
+                match lexer.next()? {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err(lexer.error("expected )")),
+                }
+            }
+            token => {
+                let left = Predicate::operand(lexer, token)?;
+                match lexer.peek()? {
+                    Some(&Token::Op(op)) => {
+                        lexer.next()?;
+                        let token = lexer.next()?;
+                        let right = Predicate::operand(lexer, token)?;
+                        Ok(Expr::Compare(left, op, right))
+                    }
+                    _ => Ok(Expr::Operand(left)),
+                }
+            }
+        }
+    }
+
+    fn operand(lexer: &Lexer, token: Option<Token>) -> Result<Operand> {
+        match token {
+            Some(Token::Path(path)) => Ok(Operand::Path(path)),
+            Some(Token::Literal(json)) => Ok(Operand::Literal(json)),
+            Some(_) => Err(lexer.error("expected a path or a literal")),
+            None => Err(lexer.error("unexpected end of predicate")),
+        }
+    }
+
+    /// Evaluates the predicate on the JSON representation of a message.
+    pub fn matches(&self, json: &Json) -> bool {
+        eval(&self.0, json)
+    }
+}
+
+fn eval(expr: &Expr, json: &Json) -> bool {
+    let operand = |operand: &Operand| match operand {
+        Operand::Path(path) => path.get(json).cloned().unwrap_or(Json::Null),
+        Operand::Literal(literal) => literal.clone(),
+    };
+    match expr {
+        Expr::Or(left, right) => eval(left, json) || eval(right, json),
+        Expr::And(left, right) => eval(left, json) && eval(right, json),
+        Expr::Not(expr) => !eval(expr, json),
+        Expr::Operand(o) => !matches!(operand(o), Json::Null | Json::Bool(false)),
+        Expr::Compare(left, op, right) => {
+            let ordering = compare(&operand(left), &operand(right));
+            match op {
+                Op::Eq => ordering == Some(Ordering::Equal),
+                Op::Ne => ordering != Some(Ordering::Equal),
+                Op::Lt => ordering == Some(Ordering::Less),
+                Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                Op::Gt => ordering == Some(Ordering::Greater),
+                Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            }
+        }
+    }
+}
+
+// Compares two values, numbers are compared exactly when both are integers.
+// Values of different kinds are not comparable, lists and objects are only
+// compared for equality.
+fn compare(left: &Json, right: &Json) -> Option<Ordering> {
+    match (left, right) {
+        (Json::Number(l), Json::Number(r)) => {
+            let as_int = |n: &serde_json::Number| {
+                (n.as_i64().map(i128::from)).or_else(|| n.as_u64().map(i128::from))
+            };
+            match (as_int(l), as_int(r)) {
+                (Some(l), Some(r)) => Some(l.cmp(&r)),
+                _ => l.as_f64()?.partial_cmp(&r.as_f64()?),
+            }
+        }
+        (Json::String(l), Json::String(r)) => Some(l.cmp(r)),
+        (Json::Bool(l), Json::Bool(r)) => Some(l.cmp(r)),
+        (Json::Null, Json::Null) => Some(Ordering::Equal),
+        (Json::Array(_), Json::Array(_)) | (Json::Object(_), Json::Object(_)) if left == right => {
+            Some(Ordering::Equal)
+        }
+        _ => None,
+    }
+}
+
+/// The output format of a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON value per line.
+    JsonLines,
+    /// A header line with the selected paths, followed by one line per
+    /// message. Strings are written as is and the other values as JSON.
+    Csv,
+}
+
+/// A query over a stream of framed messages of some shape.
+pub struct Query {
+    shape: Shape,
+    filter: Option<Predicate>,
+    select: Vec<Path>,
+    format: Format,
+}
+
+impl Query {
+    /// A query printing all the messages as JSON lines.
+    pub fn new(shape: Shape) -> Self {
+        Query {
+            shape,
+            filter: None,
+            select: vec![],
+            format: Format::JsonLines,
+        }
+    }
+
+    /// Only keeps the messages satisfying the predicate.
+    pub fn filter(mut self, predicate: Predicate) -> Self {
+        self.filter = Some(predicate);
+        self
+    }
+
+    /// Outputs the values at these paths rather than whole messages, the
+    /// JSON lines are then objects keyed by the paths.
+    pub fn select(mut self, paths: Vec<Path>) -> Self {
+        self.select = paths;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    // The CSV columns default to the fields of a record.
+    fn columns(&self) -> Result<Vec<Path>> {
+        if !self.select.is_empty() || self.format != Format::Csv {
+            return Ok(self.select.clone());
+        }
+        match self.shape.resolve() {
+            Shape::Record(fields) => Ok(fields
+                .iter()
+                .map(|(name, _)| Path {
+                    text: format!(".{}", name),
+                    segments: vec![Segment::Key(name.to_string())],
+                })
+                .collect()),
+            _ => Err(Error::Message(
+                "the CSV columns have to be selected when messages are not records".to_string(),
+            )),
+        }
+    }
+
+    /// Runs the query on a stream of framed messages and returns the number
+    /// of messages written to `out`. Messages are read one at a time.
+    pub fn run<R: Read, W: Write>(&self, reader: R, mut out: W) -> Result<usize> {
+        let columns = self.columns()?;
+        if self.format == Format::Csv {
+            let header: Vec<_> = columns.iter().map(|p| csv_field(p.column())).collect();
+            writeln!(out, "{}", header.join(","))?
+        }
+        let mut matches = 0;
+        for (index, frame) in FrameReader::new(reader).enumerate() {
+            let (offset, payload) = frame?;
+            let mut json = vec![];
+            crate::json::to_writer(&payload, &self.shape, &mut json)
+                .map_err(|err| framing::in_frame(err, index, offset))?;
+            let json: Json = serde_json::from_slice(&json)
+                .map_err(|err| Error::Message(format!("message {}: {}", index, err)))?;
+            if let Some(filter) = &self.filter {
+                if !filter.matches(&json) {
+                    continue;
+                }
+            }
+            matches += 1;
+            let get = |path: &Path| path.get(&json).unwrap_or(&Json::Null);
+            match self.format {
+                Format::JsonLines if columns.is_empty() => writeln!(out, "{}", json)?,
+                Format::JsonLines => {
+                    let object: serde_json::Map<_, _> = columns
+                        .iter()
+                        .map(|path| (path.column().to_string(), get(path).clone()))
+                        .collect();
+                    writeln!(out, "{}", Json::Object(object))?
+                }
+                Format::Csv => {
+                    let row: Vec<_> = columns
+                        .iter()
+                        .map(|path| match get(path) {
+                            Json::Null => String::new(),
+                            Json::String(s) => csv_field(s),
+                            json => csv_field(&json.to_string()),
+                        })
+                        .collect();
+                    writeln!(out, "{}", row.join(","))?
+                }
+            }
+        }
+        Ok(matches)
+    }
+}
+
+// Quotes a CSV field when it contains separators or quotes.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, Path, Predicate, Query};
+    use crate::framing::write_frame;
+    use crate::{to_vec, Error, HasShape};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::Serialize;
+
+    #[derive(Serialize, BinShape)]
+    enum Side {
+        Buy,
+        Sell { limit: Option<f64> },
+    }
+
+    #[derive(Serialize, BinShape)]
+    struct Order {
+        symbol: String,
+        qty: i64,
+        side: Side,
+        tags: Vec<String>,
+    }
+
+    fn order(symbol: &str, qty: i64, side: Side) -> Order {
+        let tags = vec![format!("{}, {}", symbol, qty)];
+        let symbol = symbol.to_string();
+        Order {
+            symbol,
+            qty,
+            side,
+            tags,
+        }
+    }
+
+    #[test]
+    fn test_predicate() {
+        let json = serde_json::json!({"a": 1, "b": "x", "c": [true, null], "d": 1.5});
+        let matches = |s: &str| Predicate::parse(s).unwrap().matches(&json);
+        assert!(matches(".a == 1 and .b == \"x\""));
+        assert!(matches(".a < .d and .d <= 1.5 and .a != 2"));
+        assert!(matches(".c[0] and .c.0 and not .c[1] and not .missing"));
+        assert!(matches(".c[1] == null and .missing == null"));
+        assert!(matches("not (.a == 2 or .b > \"y\") or false"));
+        assert!(!matches(".a == \"1\" or .a > \"0\""));
+        assert!(matches("true or .a and false"));
+        assert!(matches(".c == .c and . != null"));
+        for invalid in [
+            "", ".a ==", ".a = 1", "(.a", ".a..b", "foo", ".a 1", ".c[x]",
+        ]
+        .iter()
+        {
+            assert!(Predicate::parse(invalid).is_err(), "{}", invalid)
+        }
+        let err = Predicate::parse(".a == 1 and .b ==")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("unexpected end of predicate at column 18"),
+            "{}",
+            err
+        );
+        let paths = Path::parse_list(".a, .\"x y\"[2]").unwrap();
+        let paths: Vec<_> = paths.iter().map(|p| p.to_string()).collect();
+        assert_eq!(paths, [".a", ".\"x y\"[2]"]);
+    }
+
+    #[test]
+    fn test_query() {
+        let orders = [
+            order("AAPL", 150, Side::Buy),
+            order("MSFT", 200, Side::Sell { limit: Some(2.5) }),
+            order("AAPL", 20, Side::Sell { limit: None }),
+        ];
+        let mut data = vec![];
+        for order in orders.iter() {
+            write_frame(&mut data, &to_vec(order).unwrap()).unwrap();
+        }
+        let run = |query: Query| {
+            let mut out = vec![];
+            let matches = query.run(data.as_slice(), &mut out).unwrap();
+            (matches, String::from_utf8(out).unwrap())
+        };
+
+        let query =
+            Query::new(Order::shape()).filter(Predicate::parse(".side.Sell.limit").unwrap());
+        let (matches, out) = run(query);
+        assert_eq!(matches, 1);
+        assert_eq!(
+            out,
+            "{\"symbol\":\"MSFT\",\"qty\":200,\"side\":{\"Sell\":{\"limit\":2.5}},\"tags\":[\"MSFT, 200\"]}\n"
+        );
+
+        let query = Query::new(Order::shape())
+            .filter(Predicate::parse(".symbol == \"AAPL\"").unwrap())
+            .select(Path::parse_list(".qty,.side.Sell.limit").unwrap());
+        let (_, out) = run(query);
+        assert_eq!(
+            out,
+            "{\"qty\":150,\"side.Sell.limit\":null}\n{\"qty\":20,\"side.Sell.limit\":null}\n"
+        );
+
+        let query = Query::new(Order::shape()).format(Format::Csv);
+        let (matches, out) = run(query);
+        assert_eq!(matches, 3);
+        assert_eq!(
+            out,
+            "symbol,qty,side,tags\n\
+             AAPL,150,Buy,\"[\"\"AAPL, 150\"\"]\"\n\
+             MSFT,200,\"{\"\"Sell\"\":{\"\"limit\"\":2.5}}\",\"[\"\"MSFT, 200\"\"]\"\n\
+             AAPL,20,\"{\"\"Sell\"\":{\"\"limit\"\":null}}\",\"[\"\"AAPL, 20\"\"]\"\n"
+        );
+
+        // Decoding errors are located in the whole stream.
+        let mut data = data.clone();
+        let start = data.len() - to_vec(&orders[2]).unwrap().len();
+        data[start + 6] = 5;
+        let query = Query::new(Order::shape());
+        match query.run(data.as_slice(), &mut vec![]) {
+            Err(Error::AtPath { path, offset, msg }) => {
+                assert_eq!((path.as_str(), offset), (".side", start + 6));
+                assert_eq!(msg, "message 2: invalid constructor index 5");
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,489 @@
+//! Random generation of well-formed payloads from shapes.
+//!
+//! The generated values are biased toward the edge cases of the bin_prot
+//! encoding: integers around the thresholds where the encoding gets wider,
+//! special floats, empty and long strings, and recursive values as deep as
+//! allowed. Generation is deterministic given a seed so that failures can be
+//! reproduced, e.g. when comparing the output of a Rust type with OCaml.
+//!
+//! ```
+//! use serde_binprot::random::{check_roundtrip, Generator};
+//!
+//! let mut gen = Generator::new(42);
+//! check_roundtrip::<(i64, Vec<Option<String>>, f64)>(&mut gen, 100).unwrap();
+//! ```
+use crate::error::{Error, Result};
+use crate::ser::hash_variant;
+use crate::shape::{uuid, HasShape, Kind, Shape};
+use crate::value::Value;
+use crate::walk::MAX_DEPTH;
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use std::cmp::Ordering;
+
+// The integers where the encoding of ints and nat0 changes width.
+const BOUNDARIES: [i64; 25] = [
+    0,
+    1,
+    0x7f,
+    0x80,
+    0x7fff,
+    0x8000,
+    0xffff,
+    0x1_0000,
+    0x7fff_ffff,
+    0x8000_0000,
+    0x8000_0001,
+    0xffff_ffff,
+    0x1_0000_0000,
+    i64::MAX - 1,
+    i64::MAX,
+    -1,
+    -0x80,
+    -0x81,
+    -0x8000,
+    -0x8001,
+    -0x7fff_ffff,
+    -0x8000_0000,
+    -0x8000_0001,
+    i64::MIN + 1,
+    i64::MIN,
+];
+
+// The range of OCaml ints on 64 bits platforms.
+const OCAML_INT_MIN: i64 = -(1 << 62);
+const OCAML_INT_MAX: i64 = (1 << 62) - 1;
+
+/// A generator of random values, the generation parameters can be set with
+/// the builder methods.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    state: u64,
+    max_depth: usize,
+    max_len: usize,
+    max_string_len: usize,
+    depth: usize,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Self {
+        Generator {
+            state: seed,
+            max_depth: 16,
+            max_len: 8,
+            max_string_len: 300,
+            depth: 0,
+        }
+    }
+
+    /// The nesting depth after which the generator picks the smallest
+    /// values: no option content, empty collections, and constructors with
+    /// the fewest arguments.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// The maximum number of elements of the generated collections.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// The maximum length of the generated strings, long strings get their
+    /// length encoded on more than one byte from 128 onwards.
+    pub fn max_string_len(mut self, max_string_len: usize) -> Self {
+        self.max_string_len = max_string_len;
+        self
+    }
+
+    // splitmix64
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn pick<T: Copy>(&mut self, values: &[T]) -> T {
+        values[self.below(values.len())]
+    }
+
+    fn int(&mut self, min: i64, max: i64) -> i64 {
+        let v = match self.below(4) {
+            0 => {
+                let v = self.pick(&BOUNDARIES);
+                v.saturating_add(self.below(3) as i64 - 1)
+            }
+            1 => self.pick(&[min, max]),
+            2 => self.below(256) as i64 - 128,
+            _ => {
+                let v = (self.next_u64() >> self.below(64)) as i64;
+                if self.below(2) == 0 {
+                    v
+                } else {
+                    v.wrapping_neg()
+                }
+            }
+        };
+        v.clamp(min, max)
+    }
+
+    fn float(&mut self) -> f64 {
+        match self.below(3) {
+            0 => self.pick(&[
+                0.0,
+                -0.0,
+                1.0,
+                f64::NAN,
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+                f64::MIN_POSITIVE,
+                f64::MAX,
+                f64::MIN,
+                f64::EPSILON,
+            ]),
+            1 => (self.below(2001) as f64 - 1000.) / 8.,
+            _ => f64::from_bits(self.next_u64()),
+        }
+    }
+
+    fn len(&mut self, max: usize) -> usize {
+        if self.depth >= self.max_depth {
+            return 0;
+        }
+        match self.below(4) {
+            0 => 0,
+            1 => 1,
+            _ => self.below(max + 1),
+        }
+    }
+
+    fn string_len(&mut self) -> usize {
+        let max = self.max_string_len;
+        let len = match self.below(4) {
+            0 => 0,
+            1 => self.pick(&[127, 128, max]),
+            _ => self.below(16),
+        };
+        std::cmp::min(len, max)
+    }
+
+    fn string(&mut self) -> String {
+        let len = self.string_len();
+        let mut s = String::new();
+        while s.len() < len {
+            let c = match self.below(8) {
+                0 => self.pick(&['\0', '"', '\\', '\n', 'é', '€', '😀']),
+                _ => (b' ' + self.below(95) as u8) as char,
+            };
+            if s.len() + c.len_utf8() > len {
+                break;
+            }
+            s.push(c)
+        }
+        s
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+
+    /// Generates a random value of the given shape.
+    pub fn value(&mut self, shape: &Shape) -> Result<Value> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Error::Message(
+                "the shape has no finite values within the depth limit".to_string(),
+            ));
+        }
+        self.depth += 1;
+        let res = self.resolved(&shape.resolve());
+        self.depth -= 1;
+        res
+    }
+
+    fn values(&mut self, shapes: &[Shape]) -> Result<Vec<Value>> {
+        shapes.iter().map(|shape| self.value(shape)).collect()
+    }
+
+    /// Generates a random value of the given shape and encodes it.
+    pub fn payload(&mut self, shape: &Shape) -> Result<Vec<u8>> {
+        crate::encode_value(&self.value(shape)?, shape)
+    }
+
+    fn resolved(&mut self, shape: &Shape) -> Result<Value> {
+        let value = match shape.kind()? {
+            Kind::Unit => Value::Unit,
+            Kind::Bool => Value::Bool(self.below(2) == 0),
+            Kind::Char => Value::Char(self.next_u64() as u8 as char),
+            Kind::Float => Value::Float(self.float()),
+            Kind::Int(uuid::INT32) => Value::Int(self.int(i32::MIN.into(), i32::MAX.into())),
+            Kind::Int(uuid::INT64) | Kind::Int(uuid::NATIVEINT) => {
+                Value::Int(self.int(i64::MIN, i64::MAX))
+            }
+            Kind::Int(_) => Value::Int(self.int(OCAML_INT_MIN, OCAML_INT_MAX)),
+            Kind::Nat0 => Value::Nat0(self.int(0, OCAML_INT_MAX) as u64),
+            Kind::String(uuid::STRING) => Value::String(self.string()),
+            Kind::String(_) => {
+                let len = self.string_len();
+                Value::Bytes(self.bytes(len))
+            }
+            Kind::Option(arg) => {
+                if self.depth >= self.max_depth || self.below(3) == 0 {
+                    Value::Option(None)
+                } else {
+                    Value::Option(Some(Box::new(self.value(arg)?)))
+                }
+            }
+            Kind::Map(key, value) => {
+                let len = self.len(self.max_len);
+                let mut pairs = vec![];
+                for _ in 0..len {
+                    pairs.push((self.value(key)?, self.value(value)?))
+                }
+                pairs.sort_by(|(k1, _), (k2, _)| compare(k1, k2, key));
+                pairs.dedup_by(|(k1, _), (k2, _)| compare(k1, k2, key) == Ordering::Equal);
+                Value::Map(pairs)
+            }
+            Kind::Sequence(uuid, elt) => {
+                let len = self.len(self.max_len);
+                let mut values = (0..len)
+                    .map(|_| self.value(elt))
+                    .collect::<Result<Vec<_>>>()?;
+                if matches!(uuid, uuid::SET | uuid::HASH_SET) {
+                    values.sort_by(|v1, v2| compare(v1, v2, elt));
+                    values.dedup_by(|v1, v2| compare(v1, v2, elt) == Ordering::Equal);
+                }
+                Value::List(values)
+            }
+            Kind::Tuple(shapes) => Value::Tuple(self.values(shapes)?),
+            Kind::Record(fields) => {
+                let mut values = vec![];
+                for (name, shape) in fields.iter() {
+                    values.push((name.to_string(), self.value(shape)?))
+                }
+                Value::Record(values)
+            }
+            Kind::Variant(variants) if !variants.is_empty() => {
+                let index = if self.depth >= self.max_depth {
+                    let smallest = variants.iter().enumerate().min_by_key(|(_, v)| v.1.len());
+                    smallest.map_or(0, |(index, _)| index)
+                } else {
+                    self.below(variants.len())
+                };
+                let (name, args) = &variants[index];
+                Value::Variant(name.to_string(), self.values(args)?)
+            }
+            Kind::PolyVariant(variants) if !variants.is_empty() => {
+                let constant = variants.iter().find(|(_, arg)| arg.is_none());
+                let (name, arg) = match constant {
+                    Some(variant) if self.depth >= self.max_depth => variant,
+                    _ => variants.iter().nth(self.below(variants.len())).unwrap(),
+                };
+                let args = match arg {
+                    None => vec![],
+                    Some(arg) => vec![self.value(arg)?],
+                };
+                Value::Variant(name.to_string(), args)
+            }
+            _ => return Err(Error::Message(format!("cannot generate {:?}", shape))),
+        };
+        Ok(value)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Generates some payloads with the shape of `T`, and checks that reading
+/// each of them as a `T` and writing it back gives the same bytes. The
+/// first payload that fails is reported in the error.
+pub fn check_roundtrip<T>(gen: &mut Generator, count: usize) -> Result<()>
+where
+    T: Serialize + DeserializeOwned + HasShape,
+{
+    let shape = T::shape();
+    for index in 0..count {
+        let payload = gen.payload(&shape)?;
+        let value: T = crate::from_slice(&payload).map_err(|err| {
+            let msg = format!(
+                "payload {} {} cannot be read: {}",
+                index,
+                hex(&payload),
+                err
+            );
+            Error::Message(msg)
+        })?;
+        let output = crate::to_vec(&value)?;
+        if output != payload {
+            let offset = payload
+                .iter()
+                .zip(output.iter())
+                .position(|(b1, b2)| b1 != b2);
+            let offset = offset.unwrap_or_else(|| std::cmp::min(payload.len(), output.len()));
+            return Err(Error::Message(format!(
+                "payload {} {} is written back as {}, first difference at byte {}",
+                index,
+                hex(&payload),
+                hex(&output),
+                offset
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Orders the generated values like the keys of maps and sets are ordered:
+// structurally, with constructors ordered by their position in the type.
+fn compare(v1: &Value, v2: &Value, shape: &Shape) -> Ordering {
+    let shape = shape.resolve();
+    let kind = match shape.kind() {
+        Ok(kind) => kind,
+        Err(_) => return Ordering::Equal,
+    };
+    match (kind, v1, v2) {
+        (_, Value::Bool(b1), Value::Bool(b2)) => b1.cmp(b2),
+        (_, Value::Int(i1), Value::Int(i2)) => i1.cmp(i2),
+        (_, Value::Nat0(n1), Value::Nat0(n2)) => n1.cmp(n2),
+        (_, Value::Float(f1), Value::Float(f2)) => f1.partial_cmp(f2).unwrap_or(Ordering::Equal),
+        (_, Value::Char(c1), Value::Char(c2)) => c1.cmp(c2),
+        (_, Value::String(s1), Value::String(s2)) => s1.cmp(s2),
+        (_, Value::Bytes(b1), Value::Bytes(b2)) => b1.cmp(b2),
+        (Kind::Option(arg), Value::Option(o1), Value::Option(o2)) => match (o1, o2) {
+            (Some(v1), Some(v2)) => compare(v1, v2, arg),
+            (o1, o2) => o1.is_some().cmp(&o2.is_some()),
+        },
+        (Kind::Sequence(_, elt), Value::List(v1), Value::List(v2)) => {
+            compare_all(v1.iter().zip(v2.iter()).map(|(v1, v2)| (v1, v2, elt)))
+                .then(v1.len().cmp(&v2.len()))
+        }
+        (Kind::Tuple(shapes), Value::Tuple(v1), Value::Tuple(v2)) => compare_all(
+            v1.iter()
+                .zip(v2.iter())
+                .zip(shapes.iter())
+                .map(|((v1, v2), shape)| (v1, v2, shape)),
+        ),
+        (Kind::Record(fields), Value::Record(f1), Value::Record(f2)) => compare_all(
+            f1.iter()
+                .zip(f2.iter())
+                .zip(fields.iter())
+                .map(|(((_, v1), (_, v2)), (_, shape))| (v1, v2, shape)),
+        ),
+        (Kind::Map(key, value), Value::Map(p1), Value::Map(p2)) => {
+            let pairs = p1.iter().zip(p2.iter());
+            compare_all(pairs.flat_map(|((k1, v1), (k2, v2))| vec![(k1, k2, key), (v1, v2, value)]))
+                .then(p1.len().cmp(&p2.len()))
+        }
+        (Kind::Variant(variants), Value::Variant(n1, a1), Value::Variant(n2, a2)) => {
+            let position = |name| variants.iter().position(|(n, _)| n == name);
+            let args = variants.iter().find(|(n, _)| n == n1);
+            let args = args.map_or(&[][..], |(_, args)| args.as_slice());
+            position(n1)
+                .cmp(&position(n2))
+                .then_with(|| compare_args(a1, a2, args))
+        }
+        (Kind::PolyVariant(variants), Value::Variant(n1, a1), Value::Variant(n2, a2)) => {
+            let arg = variants.get(n1).and_then(|arg| arg.as_ref());
+            let tag = |name| hash_variant(name);
+            let args = arg.map_or(&[][..], std::slice::from_ref);
+            tag(n1)
+                .cmp(&tag(n2))
+                .then_with(|| compare_args(a1, a2, args))
+        }
+        _ => Ordering::Equal,
+    }
+}
+
+fn compare_args(a1: &[Value], a2: &[Value], args: &[Shape]) -> Ordering {
+    compare_all(
+        a1.iter()
+            .zip(a2.iter())
+            .zip(args.iter())
+            .map(|((v1, v2), shape)| (v1, v2, shape)),
+    )
+}
+
+fn compare_all<'a>(values: impl Iterator<Item = (&'a Value, &'a Value, &'a Shape)>) -> Ordering {
+    for (v1, v2, shape) in values {
+        match compare(v1, v2, shape) {
+            Ordering::Equal => {}
+            ordering => return ordering,
+        }
+    }
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_roundtrip, compare, Generator};
+    use crate::{HasShape, Shape, Value};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::{Deserialize, Serialize};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[derive(BinShape, Serialize, Deserialize)]
+    enum Tree {
+        Leaf,
+        Node(Box<Tree>, i64, Box<Tree>),
+    }
+
+    #[derive(BinShape, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+    enum Side {
+        Buy,
+        Sell(Option<String>),
+    }
+
+    #[derive(BinShape, Serialize, Deserialize)]
+    struct Order {
+        id: i64,
+        qty: u64,
+        price: f64,
+        side: Side,
+        symbol: String,
+        flags: (bool, char),
+        tags: BTreeSet<(Side, i64)>,
+        book: BTreeMap<String, Vec<Tree>>,
+    }
+
+    #[test]
+    fn test_random() {
+        let mut gen = Generator::new(0).max_depth(6);
+        check_roundtrip::<Order>(&mut gen, 500).unwrap();
+        check_roundtrip::<Tree>(&mut gen, 500).unwrap();
+
+        // Small and large integers get generated.
+        let mut sizes = BTreeSet::new();
+        for _ in 0..100 {
+            sizes.insert(gen.payload(&i64::shape()).unwrap().len());
+        }
+        assert_eq!(sizes.into_iter().collect::<Vec<_>>(), [1, 2, 3, 5, 9]);
+
+        // The same seed gives the same values.
+        let shape = Order::shape();
+        let v1 = Generator::new(7).value(&shape).unwrap();
+        let v2 = Generator::new(7).value(&shape).unwrap();
+        assert_eq!(v1, v2);
+
+        // Byte differences are reported, e.g. floats that lose precision
+        // when read as `f32`, or ints that do not fit an `i32`.
+        let mut gen = Generator::new(0);
+        let err = check_roundtrip::<f32>(&mut gen, 100).unwrap_err();
+        assert!(err.to_string().contains("first difference at byte"));
+        assert!(check_roundtrip::<i32>(&mut gen, 100).is_err());
+        let shape = Shape::list(Shape::nat0());
+        assert!(matches!(gen.value(&shape).unwrap(), Value::List(_)));
+
+        // Polymorphic variants are ordered by their signed hashes, the one
+        // of `Cancel` being negative.
+        let names = ["Buy", "Cancel"].map(|name| (name.to_string(), None));
+        let shape = Shape::PolyVariant(names.iter().cloned().collect());
+        let variant = |name: &str| Value::Variant(name.to_string(), vec![]);
+        assert!(compare(&variant("Cancel"), &variant("Buy"), &shape).is_lt());
+    }
+}
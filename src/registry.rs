@@ -0,0 +1,313 @@
+//! Dispatching messages of different types carried on a single stream.
+//!
+//! Each message is prefixed with a key identifying its type, either the
+//! digest of its shape written as a `Md5.t`, or a numeric id written as a
+//! nat0. A [`TypeRegistry`] maps the keys to handlers, it reads the prefix
+//! of each message and decodes the payload with the matching handler.
+//!
+//! ```
+//! use serde_binprot::registry::{self, TypeKey, TypeRegistry};
+//!
+//! let mut registry = TypeRegistry::new();
+//! registry.register(TypeKey::of::<i64>(), |i: i64| format!("int {}", i)).unwrap();
+//! registry.register(TypeKey::of::<String>(), |s: String| format!("string {}", s)).unwrap();
+//!
+//! let data = registry::to_vec_with_digest(&"foo".to_string()).unwrap();
+//! assert_eq!(registry.decode(&data).unwrap(), "string foo");
+//! ```
+use crate::de::{Deserializer, SliceReader};
+use crate::error::{Error, Result};
+use crate::shape::{HasShape, Shape};
+use crate::types::Md5Digest;
+use crate::value::Value;
+use serde::de::{Deserialize, DeserializeOwned};
+use serde::ser::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The key prefixed to a message to identify its type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TypeKey {
+    Digest(Md5Digest),
+    Id(u64),
+}
+
+impl TypeKey {
+    /// The digest key of a type.
+    pub fn of<T: HasShape + ?Sized>() -> TypeKey {
+        TypeKey::Digest(T::shape().eval_md5())
+    }
+
+    pub fn of_shape(shape: &Shape) -> TypeKey {
+        TypeKey::Digest(shape.eval_md5())
+    }
+}
+
+impl fmt::Display for TypeKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeKey::Digest(digest) => write!(f, "{}", digest),
+            TypeKey::Id(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+impl Serialize for TypeKey {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            TypeKey::Digest(digest) => digest.serialize(serializer),
+            TypeKey::Id(id) => serializer.serialize_u64(*id),
+        }
+    }
+}
+
+type Handler<R> = Box<dyn Fn(&[u8]) -> Result<(R, usize)>>;
+
+struct Entry<R> {
+    name: String,
+    shape: Shape,
+    handler: Handler<R>,
+}
+
+/// A set of handlers indexed by type keys, all the keys of a registry are
+/// either digests or numeric ids. Each handler turns a message of its type
+/// into a `R`.
+pub struct TypeRegistry<R> {
+    by_id: bool,
+    entries: BTreeMap<TypeKey, Entry<R>>,
+}
+
+impl<R> Default for TypeRegistry<R> {
+    fn default() -> Self {
+        TypeRegistry::new()
+    }
+}
+
+impl<R> TypeRegistry<R> {
+    /// A registry for messages prefixed with their shape digest.
+    pub fn new() -> Self {
+        TypeRegistry {
+            by_id: false,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// A registry for messages prefixed with a numeric id.
+    pub fn with_ids() -> Self {
+        TypeRegistry {
+            by_id: true,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn insert(
+        &mut self,
+        key: TypeKey,
+        name: String,
+        shape: Shape,
+        handler: Handler<R>,
+    ) -> Result<()> {
+        if matches!(key, TypeKey::Id(_)) != self.by_id {
+            let kind = if self.by_id { "ids" } else { "digests" };
+            return Err(Error::Message(format!(
+                "cannot register {} for {}, the registry uses {}",
+                key, name, kind
+            )));
+        }
+        if let Some(entry) = self.entries.get(&key) {
+            return Err(Error::Message(format!(
+                "key {} for {} is already registered for {}",
+                key, name, entry.name
+            )));
+        }
+        let entry = Entry {
+            name,
+            shape,
+            handler,
+        };
+        self.entries.insert(key, entry);
+        Ok(())
+    }
+
+    /// Registers the handler for the messages of type `T`.
+    pub fn register<T, F>(&mut self, key: TypeKey, handler: F) -> Result<()>
+    where
+        T: DeserializeOwned + HasShape,
+        F: Fn(T) -> R + 'static,
+    {
+        let handler = move |data: &[u8]| {
+            let mut de = Deserializer::new(SliceReader::new(data));
+            let value = T::deserialize(&mut de)?;
+            Ok((handler(value), de.get_ref().offset()))
+        };
+        let name = std::any::type_name::<T>().to_string();
+        self.insert(key, name, T::shape(), Box::new(handler))
+    }
+
+    /// Registers a handler for the messages of the given shape, the messages
+    /// are decoded as dynamic values.
+    pub fn register_shape<F>(&mut self, key: TypeKey, shape: Shape, handler: F) -> Result<()>
+    where
+        F: Fn(Value) -> R + 'static,
+    {
+        let shape_ = shape.clone();
+        let handler = move |data: &[u8]| {
+            let (value, len) = crate::value::decode_value_prefix(data, &shape_)?;
+            Ok((handler(value), len))
+        };
+        let name = format!("shape {}", shape.eval_digest());
+        self.insert(key, name, shape, Box::new(handler))
+    }
+
+    /// The registered keys, in increasing order.
+    pub fn keys(&self) -> impl Iterator<Item = &TypeKey> {
+        self.entries.keys()
+    }
+
+    /// The registered keys together with the name and shape of their type.
+    pub fn entries(&self) -> impl Iterator<Item = (&TypeKey, &str, &Shape)> {
+        let entries = self.entries.iter();
+        entries.map(|(key, entry)| (key, entry.name.as_str(), &entry.shape))
+    }
+
+    /// The shape registered for a key.
+    pub fn shape(&self, key: &TypeKey) -> Option<&Shape> {
+        self.entries.get(key).map(|entry| &entry.shape)
+    }
+
+    /// Reads the key prefixed to some data.
+    pub fn read_key(&self, data: &[u8]) -> Result<(TypeKey, usize)> {
+        let mut de = Deserializer::new(SliceReader::new(data));
+        let key = if self.by_id {
+            TypeKey::Id(u64::deserialize(&mut de)?)
+        } else {
+            TypeKey::Digest(Md5Digest::deserialize(&mut de)?)
+        };
+        Ok((key, de.get_ref().offset()))
+    }
+
+    /// Decodes the message at the beginning of some data and returns the
+    /// result of its handler together with the remaining data.
+    pub fn decode_next<'a>(&self, data: &'a [u8]) -> Result<(R, &'a [u8])> {
+        let (key, offset) = self.read_key(data)?;
+        let entry = match self.entries.get(&key) {
+            Some(entry) => entry,
+            None => {
+                let known = self
+                    .entries
+                    .iter()
+                    .map(|(key, entry)| format!("{} ({})", key, entry.name))
+                    .collect();
+                return Err(Error::UnknownTypeKey {
+                    key: key.to_string(),
+                    known,
+                });
+            }
+        };
+        let (result, len) = (entry.handler)(&data[offset..])?;
+        Ok((result, &data[offset + len..]))
+    }
+
+    /// Decodes some data holding exactly one message.
+    pub fn decode(&self, data: &[u8]) -> Result<R> {
+        let (result, rest) = self.decode_next(data)?;
+        if !rest.is_empty() {
+            return Err(Error::TrailingCharacters);
+        }
+        Ok(result)
+    }
+
+    /// Decodes some data holding a sequence of messages.
+    pub fn decode_all(&self, mut data: &[u8]) -> Result<Vec<R>> {
+        let mut results = vec![];
+        while !data.is_empty() {
+            let (result, rest) = self.decode_next(data)?;
+            results.push(result);
+            data = rest;
+        }
+        Ok(results)
+    }
+}
+
+/// Serializes a value prefixed with the given key.
+pub fn to_vec_prefixed<T: Serialize + ?Sized>(key: &TypeKey, value: &T) -> Result<Vec<u8>> {
+    let mut out = crate::to_vec(key)?;
+    crate::to_writer(&mut out, value)?;
+    Ok(out)
+}
+
+/// Serializes a value prefixed with the digest of its shape.
+pub fn to_vec_with_digest<T: Serialize + HasShape + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    to_vec_prefixed(&TypeKey::of::<T>(), value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_vec_prefixed, TypeKey, TypeRegistry};
+    use crate::{Error, HasShape, Value};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(BinShape, Serialize, Deserialize, Debug, PartialEq)]
+    struct Trade {
+        qty: i64,
+        price: f64,
+    }
+
+    #[derive(BinShape, Serialize, Deserialize, Debug, PartialEq)]
+    enum Status {
+        Open,
+        Closed(String),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Msg {
+        Trade(Trade),
+        Status(Status),
+        Dynamic(Value),
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = TypeRegistry::with_ids();
+        registry.register(TypeKey::Id(1), Msg::Trade).unwrap();
+        registry.register(TypeKey::Id(200), Msg::Status).unwrap();
+        registry
+            .register_shape(TypeKey::Id(3), <(i64, bool)>::shape(), Msg::Dynamic)
+            .unwrap();
+        assert!(registry.register(TypeKey::Id(1), Msg::Status).is_err());
+        assert!(registry
+            .register(TypeKey::of::<Trade>(), Msg::Trade)
+            .is_err());
+
+        let trade = Trade { qty: 2, price: 1.5 };
+        let mut data = to_vec_prefixed(&TypeKey::Id(200), &Status::Closed("x".into())).unwrap();
+        data.extend(to_vec_prefixed(&TypeKey::Id(1), &trade).unwrap());
+        data.extend(to_vec_prefixed(&TypeKey::Id(3), &(-1i64, true)).unwrap());
+        // The ids are written as nat0, 200 takes three bytes.
+        assert_eq!(data[..3], [0xfe, 200, 0]);
+        assert_eq!(registry.read_key(&data).unwrap(), (TypeKey::Id(200), 3));
+        let msgs = registry.decode_all(&data).unwrap();
+        assert_eq!(
+            msgs,
+            [
+                Msg::Status(Status::Closed("x".into())),
+                Msg::Trade(trade),
+                Msg::Dynamic(Value::Tuple(vec![Value::Int(-1), Value::Bool(true)])),
+            ]
+        );
+
+        let data = to_vec_prefixed(&TypeKey::Id(4), &Status::Open).unwrap();
+        match registry.decode(&data) {
+            Err(Error::UnknownTypeKey { key, known }) => {
+                assert_eq!(key, "4");
+                assert_eq!(known.len(), 3);
+                assert!(known[0].starts_with("1 ("));
+            }
+            _ => panic!("expected an unknown key error"),
+        }
+    }
+}
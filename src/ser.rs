@@ -19,7 +19,7 @@ where
         Serializer { writer }
     }
 
-    fn serialize_nat0(&mut self, v: u64) -> Result<()> {
+    pub(crate) fn serialize_nat0(&mut self, v: u64) -> Result<()> {
         if v < 0x000000080 {
             self.writer.write_all(&[v as u8])?;
         } else if v < 0x000010000 {
@@ -35,7 +35,7 @@ where
         Ok(())
     }
 
-    fn serialize_as_u8(&mut self, v: u32) -> Result<()> {
+    pub(crate) fn serialize_as_u8(&mut self, v: u32) -> Result<()> {
         if v < 256 {
             self.writer.write_all(&[v as u8])?;
             Ok(())
@@ -45,6 +45,26 @@ where
     }
 }
 
+/// The hash used by OCaml for polymorphic variant tags, `Btype.hash_variant`.
+pub(crate) fn hash_variant(name: &str) -> i32 {
+    let mut accu = 0u64;
+    for &c in name.as_bytes() {
+        accu = accu.wrapping_mul(223).wrapping_add(c as u64)
+    }
+    let accu = accu & ((1 << 31) - 1);
+    if accu > 0x3FFFFFFF {
+        (accu as i64 - (1 << 31)) as i32
+    } else {
+        accu as i32
+    }
+}
+
+/// The four bytes written by bin_prot for a polymorphic variant tag.
+pub(crate) fn poly_variant_tag(name: &str) -> [u8; 4] {
+    ((hash_variant(name) << 1) | 1).to_le_bytes()
+}
+
+#[allow(clippy::needless_lifetimes)]
 impl<'a, W> ser::Serializer for &'a mut Serializer<W>
 where
     W: io::Write,
@@ -266,6 +286,7 @@ where
     }
 }
 
+#[allow(clippy::needless_lifetimes)]
 impl<'a, W> ser::SerializeSeq for &'a mut Serializer<W>
 where
     W: io::Write,
@@ -285,6 +306,7 @@ where
     }
 }
 
+#[allow(clippy::needless_lifetimes)]
 impl<'a, W> ser::SerializeTuple for &'a mut Serializer<W>
 where
     W: io::Write,
@@ -304,6 +326,7 @@ where
     }
 }
 
+#[allow(clippy::needless_lifetimes)]
 impl<'a, W> ser::SerializeTupleStruct for &'a mut Serializer<W>
 where
     W: io::Write,
@@ -323,6 +346,7 @@ where
     }
 }
 
+#[allow(clippy::needless_lifetimes)]
 impl<'a, W> ser::SerializeTupleVariant for &'a mut Serializer<W>
 where
     W: io::Write,
@@ -342,6 +366,7 @@ where
     }
 }
 
+#[allow(clippy::needless_lifetimes)]
 impl<'a, W> ser::SerializeMap for &'a mut Serializer<W>
 where
     W: io::Write,
@@ -368,6 +393,7 @@ where
     }
 }
 
+#[allow(clippy::needless_lifetimes)]
 impl<'a, W> ser::SerializeStruct for &'a mut Serializer<W>
 where
     W: io::Write,
@@ -387,6 +413,7 @@ where
     }
 }
 
+#[allow(clippy::needless_lifetimes)]
 impl<'a, W> ser::SerializeStructVariant for &'a mut Serializer<W>
 where
     W: io::Write,
@@ -406,6 +433,7 @@ where
     }
 }
 
+#[allow(clippy::multiple_bound_locations)]
 pub fn to_writer<W, T: ?Sized>(writer: W, value: &T) -> Result<()>
 where
     W: io::Write,
@@ -416,6 +444,7 @@ where
     Ok(())
 }
 
+#[allow(clippy::multiple_bound_locations)]
 pub fn to_vec<T: ?Sized>(value: &T) -> Result<Vec<u8>>
 where
     T: Serialize,
@@ -431,6 +460,7 @@ mod tests {
     use serde_derive::Serialize;
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_all() {
         #[derive(Serialize, Clone)]
         struct Bar {
@@ -0,0 +1,481 @@
+//! S-expressions, compatible with the OCaml `Sexplib` textual format.
+use crate::error::{Error, Result};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Sexp {
+    /// Atoms are byte strings as in OCaml, they are escaped when printed.
+    Atom(Vec<u8>),
+    List(Vec<Sexp>),
+}
+
+impl Sexp {
+    pub fn atom(s: &str) -> Sexp {
+        Sexp::Atom(s.as_bytes().to_vec())
+    }
+
+    pub fn list(v: Vec<Sexp>) -> Sexp {
+        Sexp::List(v)
+    }
+
+    /// The atom as a string, `None` for lists and atoms that are not valid
+    /// UTF-8.
+    pub fn as_atom(&self) -> Option<&str> {
+        self.as_bytes().and_then(|s| std::str::from_utf8(s).ok())
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Sexp::Atom(s) => Some(s),
+            Sexp::List(_) => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Sexp]> {
+        match self {
+            Sexp::Atom(_) => None,
+            Sexp::List(l) => Some(l),
+        }
+    }
+
+    /// Parses a single s-expression, surrounding whitespace and comments
+    /// are allowed.
+    pub fn parse(s: &str) -> Result<Sexp> {
+        let mut parser = Parser::new(s);
+        let sexp = match parser.next_sexp()? {
+            Some(sexp) => sexp,
+            None => return Err(parser.error("empty input")),
+        };
+        if parser.next_sexp()?.is_some() {
+            return Err(parser.error("trailing characters after s-expression"));
+        }
+        Ok(sexp)
+    }
+
+    /// Parses a sequence of s-expressions.
+    pub fn parse_many(s: &str) -> Result<Vec<Sexp>> {
+        let mut parser = Parser::new(s);
+        let mut sexps = vec![];
+        while let Some(sexp) = parser.next_sexp()? {
+            sexps.push(sexp)
+        }
+        Ok(sexps)
+    }
+
+    /// The compact representation, as produced by `Sexp.to_string_mach`.
+    pub fn to_string_mach(&self) -> String {
+        let mut buf = String::new();
+        self.write_mach(&mut buf, &mut false);
+        buf
+    }
+
+    fn write_mach(&self, buf: &mut String, may_need_space: &mut bool) {
+        match self {
+            Sexp::Atom(s) => {
+                // Quoted atoms are self-delimiting, a space is only needed
+                // between two consecutive unquoted atoms.
+                let quoted = must_escape(s);
+                if *may_need_space && !quoted {
+                    buf.push(' ')
+                }
+                write_atom(buf, s);
+                *may_need_space = !quoted
+            }
+            Sexp::List(l) => {
+                buf.push('(');
+                *may_need_space = false;
+                for sexp in l.iter() {
+                    sexp.write_mach(buf, may_need_space)
+                }
+                buf.push(')');
+                *may_need_space = false
+            }
+        }
+    }
+
+    /// A human readable representation, similar to `Sexp.to_string_hum`.
+    pub fn to_string_hum(&self) -> String {
+        let mut buf = String::new();
+        self.write_hum(&mut buf, 0);
+        buf
+    }
+
+    fn write_flat(&self, buf: &mut String) {
+        match self {
+            Sexp::Atom(s) => write_atom(buf, s),
+            Sexp::List(l) => {
+                buf.push('(');
+                for (i, sexp) in l.iter().enumerate() {
+                    if i > 0 {
+                        buf.push(' ')
+                    }
+                    sexp.write_flat(buf)
+                }
+                buf.push(')');
+            }
+        }
+    }
+
+    fn write_hum(&self, buf: &mut String, indent: usize) {
+        const MAX_WIDTH: usize = 80;
+        let mut flat = String::new();
+        self.write_flat(&mut flat);
+        match self {
+            Sexp::List(l) if indent + flat.len() > MAX_WIDTH && l.len() > 1 => {
+                buf.push('(');
+                let (first, rest) = (&l[0], &l[1..]);
+                let indent = match first {
+                    Sexp::Atom(_) => {
+                        first.write_flat(buf);
+                        buf.push(' ');
+                        indent + 2
+                    }
+                    Sexp::List(_) => {
+                        first.write_hum(buf, indent + 1);
+                        buf.push('\n');
+                        buf.push_str(&" ".repeat(indent + 1));
+                        indent + 1
+                    }
+                };
+                for (i, sexp) in rest.iter().enumerate() {
+                    if i > 0 {
+                        buf.push('\n');
+                        buf.push_str(&" ".repeat(indent));
+                    }
+                    sexp.write_hum(buf, indent)
+                }
+                buf.push(')');
+            }
+            _ => buf.push_str(&flat),
+        }
+    }
+}
+
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_mach())
+    }
+}
+
+impl std::str::FromStr for Sexp {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Sexp> {
+        Sexp::parse(s)
+    }
+}
+
+// This follows `must_escape` from Sexplib0.
+fn must_escape(bytes: &[u8]) -> bool {
+    if bytes.is_empty() {
+        return true;
+    }
+    bytes.iter().enumerate().any(|(i, &c)| match c {
+        b'"' | b'(' | b')' | b';' | b'\\' => true,
+        b'|' => i > 0 && bytes[i - 1] == b'#',
+        b'#' => i > 0 && bytes[i - 1] == b'|',
+        0..=32 | 127..=255 => true,
+        _ => false,
+    })
+}
+
+fn write_atom(buf: &mut String, s: &[u8]) {
+    if !must_escape(s) {
+        // Unescaped atoms are printable ASCII.
+        buf.extend(s.iter().map(|&c| c as char));
+        return;
+    }
+    // Escape the same way as OCaml `String.escaped`.
+    buf.push('"');
+    for &c in s.iter() {
+        match c {
+            b'"' => buf.push_str("\\\""),
+            b'\\' => buf.push_str("\\\\"),
+            b'\n' => buf.push_str("\\n"),
+            b'\t' => buf.push_str("\\t"),
+            b'\r' => buf.push_str("\\r"),
+            8 => buf.push_str("\\b"),
+            b' '..=b'~' => buf.push(c as char),
+            c => buf.push_str(&format!("\\{:03}", c)),
+        }
+    }
+    buf.push('"');
+}
+
+// The maximum nesting of lists, the parser recurses on each of them.
+const MAX_NESTING: usize = 512;
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    line: usize,
+    line_start: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Parser {
+            input: input.as_bytes(),
+            pos: 0,
+            line: 1,
+            line_start: 0,
+            depth: 0,
+        }
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        Error::ParseError {
+            line: self.line,
+            column: self.pos - self.line_start + 1,
+            msg: msg.to_string(),
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn peek2(&self) -> Option<u8> {
+        self.input.get(self.pos + 1).copied()
+    }
+
+    fn advance(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == b'\n' {
+            self.line += 1;
+            self.line_start = self.pos;
+        }
+        Some(c)
+    }
+
+    fn skip_whitespace_and_comments(&mut self) -> Result<()> {
+        loop {
+            match (self.peek(), self.peek2()) {
+                (Some(c), _) if c.is_ascii_whitespace() => {
+                    self.advance();
+                }
+                (Some(b';'), _) => {
+                    while let Some(c) = self.advance() {
+                        if c == b'\n' {
+                            break;
+                        }
+                    }
+                }
+                (Some(b'#'), Some(b'|')) => self.skip_block_comment()?,
+                (Some(b'#'), Some(b';')) => {
+                    self.advance();
+                    self.advance();
+                    if self.next_sexp()?.is_none() {
+                        return Err(self.error("missing s-expression after #;"));
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn skip_block_comment(&mut self) -> Result<()> {
+        self.advance();
+        self.advance();
+        let mut depth = 1;
+        while depth > 0 {
+            match (self.peek(), self.peek2()) {
+                (None, _) => return Err(self.error("unterminated block comment")),
+                (Some(b'|'), Some(b'#')) => {
+                    self.advance();
+                    depth -= 1;
+                }
+                (Some(b'#'), Some(b'|')) => {
+                    self.advance();
+                    depth += 1;
+                }
+                (Some(b'"'), _) => {
+                    self.quoted_atom()?;
+                    continue;
+                }
+                _ => {}
+            }
+            self.advance();
+        }
+        Ok(())
+    }
+
+    fn list_items(&mut self) -> Result<Vec<Sexp>> {
+        let mut list = vec![];
+        loop {
+            self.skip_whitespace_and_comments()?;
+            match self.peek() {
+                None => return Err(self.error("unterminated list")),
+                Some(b')') => {
+                    self.advance();
+                    return Ok(list);
+                }
+                Some(_) => match self.next_sexp()? {
+                    Some(sexp) => list.push(sexp),
+                    None => return Err(self.error("unterminated list")),
+                },
+            }
+        }
+    }
+
+    fn next_sexp(&mut self) -> Result<Option<Sexp>> {
+        self.skip_whitespace_and_comments()?;
+        match self.peek() {
+            None => Ok(None),
+            Some(b')') => Err(self.error("unexpected closing parenthesis")),
+            Some(b'(') => {
+                if self.depth >= MAX_NESTING {
+                    return Err(Error::Message("sexp nested too deeply".to_string()));
+                }
+                self.advance();
+                self.depth += 1;
+                let list = self.list_items();
+                self.depth -= 1;
+                Ok(Some(Sexp::List(list?)))
+            }
+            Some(b'"') => Ok(Some(Sexp::Atom(self.quoted_atom()?))),
+            Some(_) => {
+                let start = self.pos;
+                while let Some(c) = self.peek() {
+                    let is_comment_start = c == b'#' && matches!(self.peek2(), Some(b'|' | b';'));
+                    let is_comment_end = c == b'|' && self.peek2() == Some(b'#');
+                    if c.is_ascii_whitespace()
+                        || matches!(c, b'(' | b')' | b'"' | b';')
+                        || (self.pos > start && is_comment_start)
+                        || is_comment_end
+                    {
+                        break;
+                    }
+                    self.advance();
+                }
+                if self.pos == start {
+                    return Err(self.error("unexpected character"));
+                }
+                Ok(Some(Sexp::Atom(self.input[start..self.pos].to_vec())))
+            }
+        }
+    }
+
+    fn quoted_atom(&mut self) -> Result<Vec<u8>> {
+        self.advance();
+        let mut bytes = vec![];
+        loop {
+            let c = match self.advance() {
+                None => return Err(self.error("unterminated quoted atom")),
+                Some(c) => c,
+            };
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let c = match self.advance() {
+                        None => return Err(self.error("unterminated quoted atom")),
+                        Some(c) => c,
+                    };
+                    match c {
+                        b'n' => bytes.push(b'\n'),
+                        b't' => bytes.push(b'\t'),
+                        b'r' => bytes.push(b'\r'),
+                        b'b' => bytes.push(8),
+                        b' ' => bytes.push(b' '),
+                        b'\\' | b'"' | b'\'' => bytes.push(c),
+                        b'\n' => {
+                            while matches!(self.peek(), Some(b' ' | b'\t')) {
+                                self.advance();
+                            }
+                        }
+                        b'\r' if self.peek() == Some(b'\n') => {
+                            self.advance();
+                            while matches!(self.peek(), Some(b' ' | b'\t')) {
+                                self.advance();
+                            }
+                        }
+                        b'x' => {
+                            let hex = [self.advance(), self.advance()];
+                            let hex: Option<Vec<u8>> = hex.iter().copied().collect();
+                            let v = hex
+                                .and_then(|h| String::from_utf8(h).ok())
+                                .and_then(|h| u8::from_str_radix(&h, 16).ok());
+                            match v {
+                                Some(v) => bytes.push(v),
+                                None => return Err(self.error("invalid hex escape")),
+                            }
+                        }
+                        b'0'..=b'9' => {
+                            let mut v = u32::from(c - b'0');
+                            for _ in 0..2 {
+                                match self.advance() {
+                                    Some(c @ b'0'..=b'9') => v = 10 * v + u32::from(c - b'0'),
+                                    _ => return Err(self.error("invalid decimal escape")),
+                                }
+                            }
+                            if v > 255 {
+                                return Err(self.error("invalid decimal escape"));
+                            }
+                            bytes.push(v as u8)
+                        }
+                        // Unknown escape sequences are kept verbatim.
+                        c => {
+                            bytes.push(b'\\');
+                            bytes.push(c)
+                        }
+                    }
+                }
+                c => bytes.push(c),
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sexp;
+
+    #[test]
+    fn test_sexp() {
+        let sexp = Sexp::parse("((foo 1) (bar \"hello world\") ; comment\n (baz ()))").unwrap();
+        assert_eq!(
+            sexp.to_string_mach(),
+            "((foo 1)(bar\"hello world\")(baz()))"
+        );
+        assert_eq!(
+            sexp.to_string_hum(),
+            "((foo 1) (bar \"hello world\") (baz ()))"
+        );
+        assert_eq!(Sexp::parse(&sexp.to_string_mach()).unwrap(), sexp);
+
+        let atoms = [
+            "", "a b", "x\ny", "#|", "é", "tab\t", "q\"uote", "a#b", "-1.5",
+        ];
+        for atom in atoms.iter() {
+            let sexp = Sexp::atom(atom);
+            assert_eq!(Sexp::parse(&sexp.to_string()).unwrap(), sexp);
+        }
+        assert_eq!(Sexp::atom("é").to_string(), "\"\\195\\169\"");
+        assert_eq!(Sexp::atom("a#b").to_string(), "a#b");
+        let bytes = Sexp::Atom(vec![0xff, b'a', 0]);
+        assert_eq!(bytes.to_string(), "\"\\255a\\000\"");
+        assert_eq!(Sexp::parse(&bytes.to_string()).unwrap(), bytes);
+        assert_eq!(bytes.as_atom(), None);
+        assert_eq!(
+            Sexp::parse_many("a #| block (comment |# b #; (skipped sexp) c").unwrap(),
+            vec![Sexp::atom("a"), Sexp::atom("b"), Sexp::atom("c")]
+        );
+        assert_eq!(
+            Sexp::parse("\"\\x41\\066\\n\"").unwrap(),
+            Sexp::atom("AB\n")
+        );
+
+        let err = Sexp::parse("(a\n (b c)").unwrap_err();
+        assert!(matches!(err, crate::Error::ParseError { line: 2, .. }));
+        assert!(Sexp::parse("a b").is_err());
+        assert!(Sexp::parse("a)").is_err());
+
+        let deep = format!("{}{}", "(".repeat(100), ")".repeat(100));
+        assert!(Sexp::parse(&deep).is_ok());
+        let err = crate::Shape::from_sexp_str(&"(".repeat(200000)).unwrap_err();
+        assert!(matches!(err, crate::Error::Message(_)));
+    }
+}
@@ -0,0 +1,998 @@
+//! A serde format for s-expressions, following the `ppx_sexp_conv`
+//! conventions so that Rust and OCaml types print and parse the same text.
+//!
+//! - records are `((field value) ...)`,
+//! - constructors without arguments are atoms, the other ones
+//!   `(Constructor args...)`, with `(field value)` arguments for inline
+//!   records,
+//! - options are `()` or `(x)`, see [`OptionStyle`] for record fields,
+//! - lists, arrays and tuples are plain lists, maps lists of `(key value)`,
+//! - floats are formatted as `Sexplib0.Sexp_conv.sexp_of_float` does, e.g.
+//!   `1`, `0.1` or `1E+300`.
+//!
+//! ```
+//! use serde_binprot::{from_sexp_str, to_sexp_string};
+//!
+//! let sexp = to_sexp_string(&(1.0f64, Some("foo bar"), vec![1, 2])).unwrap();
+//! assert_eq!(sexp, r#"(1("foo bar")(1 2))"#);
+//! let v: (f64, Option<String>, Vec<i64>) = from_sexp_str(&sexp).unwrap();
+//! assert_eq!(v, (1.0, Some("foo bar".to_string()), vec![1, 2]));
+//! ```
+use crate::error::{Error, Result};
+use crate::sexp::Sexp;
+use serde::de::{self, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+use std::cell::Cell;
+
+/// How the options of record fields are represented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptionStyle {
+    /// `(field ())` and `(field (x))`, as for an `option` field.
+    #[default]
+    List,
+    /// The field is omitted for `None` and is `(field x)` otherwise, as for
+    /// a `[@sexp.option]` field.
+    SexpOption,
+}
+
+/// The conversion settings, the default ones are used by [`to_sexp`] and
+/// [`from_sexp`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SexpConv {
+    option_style: OptionStyle,
+}
+
+impl SexpConv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn option_style(mut self, option_style: OptionStyle) -> Self {
+        self.option_style = option_style;
+        self
+    }
+
+    pub fn to_sexp<T: Serialize + ?Sized>(&self, value: &T) -> Result<Sexp> {
+        value.serialize(SexpSerializer {
+            option_style: self.option_style,
+            omitted: None,
+        })
+    }
+
+    /// Converts a value to a s-expression in the machine format.
+    pub fn to_string<T: Serialize + ?Sized>(&self, value: &T) -> Result<String> {
+        Ok(self.to_sexp(value)?.to_string_mach())
+    }
+
+    pub fn from_sexp<'a, T: de::Deserialize<'a>>(&self, sexp: &'a Sexp) -> Result<T> {
+        T::deserialize(SexpDeserializer {
+            sexp,
+            option_style: self.option_style,
+            field: false,
+        })
+    }
+
+    pub fn from_str<T: de::DeserializeOwned>(&self, s: &str) -> Result<T> {
+        self.from_sexp(&Sexp::parse(s)?)
+    }
+}
+
+pub fn to_sexp<T: Serialize + ?Sized>(value: &T) -> Result<Sexp> {
+    SexpConv::new().to_sexp(value)
+}
+
+pub fn to_sexp_string<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    SexpConv::new().to_string(value)
+}
+
+pub fn from_sexp<'a, T: de::Deserialize<'a>>(sexp: &'a Sexp) -> Result<T> {
+    SexpConv::new().from_sexp(sexp)
+}
+
+pub fn from_sexp_str<T: de::DeserializeOwned>(s: &str) -> Result<T> {
+    SexpConv::new().from_str(s)
+}
+
+// Same as the C `%.<precision>G` format.
+fn format_g(f: f64, precision: usize) -> String {
+    let strip_zeros = |s: &str| -> String {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_string()
+        } else {
+            s.to_string()
+        }
+    };
+    if f == 0.0 {
+        return if f.is_sign_negative() { "-0" } else { "0" }.to_string();
+    }
+    let scientific = format!("{:.*e}", precision - 1, f);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    if exponent < -4 || exponent >= precision as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}E{}{:02}", strip_zeros(mantissa), sign, exponent.abs())
+    } else {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        strip_zeros(&format!("{:.*}", decimals, f))
+    }
+}
+
+/// Formats a float as `Sexplib0.Sexp_conv.sexp_of_float` does, with `%.15G`
+/// or `%.17G` when the former doesn't round-trip, so `1.0` is `1`.
+pub fn string_of_float(f: f64) -> String {
+    if f.is_nan() {
+        return "NAN".to_string();
+    } else if f.is_infinite() {
+        return if f > 0.0 { "INF" } else { "-INF" }.to_string();
+    }
+    let s = format_g(f, 15);
+    if s.parse::<f64>() == Ok(f) {
+        s
+    } else {
+        format_g(f, 17)
+    }
+}
+
+pub(crate) fn float_of_string(s: &str) -> Option<f64> {
+    match s {
+        "nan" | "NAN" | "-nan" | "-NAN" => Some(f64::NAN),
+        "inf" | "INF" | "infinity" => Some(f64::INFINITY),
+        "-inf" | "-INF" | "-infinity" => Some(f64::NEG_INFINITY),
+        s => s.replace('_', "").parse().ok(),
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SexpSerializer<'a> {
+    option_style: OptionStyle,
+    // Set for the values of record fields, used to omit `None` fields with
+    // the `SexpOption` style.
+    omitted: Option<&'a Cell<bool>>,
+}
+
+impl<'a> SexpSerializer<'a> {
+    fn child(&self) -> SexpSerializer<'static> {
+        SexpSerializer {
+            option_style: self.option_style,
+            omitted: None,
+        }
+    }
+
+    fn atom<T: ToString>(v: T) -> Result<Sexp> {
+        Ok(Sexp::atom(&v.to_string()))
+    }
+}
+
+impl<'a> ser::Serializer for SexpSerializer<'a> {
+    type Ok = Sexp;
+    type Error = Error;
+
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeList;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeRecord;
+    type SerializeStructVariant = SerializeRecord;
+
+    fn serialize_bool(self, v: bool) -> Result<Sexp> {
+        Self::atom(v)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Sexp> {
+        Self::atom(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Sexp> {
+        Self::atom(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Sexp> {
+        Self::atom(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Sexp> {
+        Self::atom(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Sexp> {
+        Self::atom(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Sexp> {
+        Self::atom(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Sexp> {
+        Self::atom(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Sexp> {
+        Self::atom(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Sexp> {
+        Self::atom(string_of_float(v as f64))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Sexp> {
+        Self::atom(string_of_float(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Sexp> {
+        Self::atom(v)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Sexp> {
+        Self::atom(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Sexp> {
+        Ok(Sexp::Atom(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Sexp> {
+        if let (OptionStyle::SexpOption, Some(omitted)) = (self.option_style, self.omitted) {
+            omitted.set(true)
+        }
+        Ok(Sexp::List(vec![]))
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Sexp>
+    where
+        T: ?Sized + Serialize,
+    {
+        let sexp = value.serialize(self.child())?;
+        match (self.option_style, self.omitted) {
+            (OptionStyle::SexpOption, Some(_)) => Ok(sexp),
+            _ => Ok(Sexp::List(vec![sexp])),
+        }
+    }
+
+    fn serialize_unit(self) -> Result<Sexp> {
+        Ok(Sexp::List(vec![]))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Sexp> {
+        Ok(Sexp::List(vec![]))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Sexp> {
+        Self::atom(variant)
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<Sexp>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Sexp>
+    where
+        T: ?Sized + Serialize,
+    {
+        let value = value.serialize(self.child())?;
+        Ok(Sexp::List(vec![Sexp::atom(variant), value]))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SerializeList> {
+        Ok(SerializeList::new(self.child(), None))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<SerializeList> {
+        Ok(SerializeList::new(self.child(), None))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<SerializeList> {
+        Ok(SerializeList::new(self.child(), None))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeList> {
+        Ok(SerializeList::new(self.child(), Some(variant)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeMap> {
+        Ok(SerializeMap {
+            ser: self.child(),
+            pairs: vec![],
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<SerializeRecord> {
+        Ok(SerializeRecord::new(self.child(), None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeRecord> {
+        Ok(SerializeRecord::new(self.child(), Some(variant)))
+    }
+}
+
+struct SerializeList {
+    ser: SexpSerializer<'static>,
+    sexps: Vec<Sexp>,
+}
+
+impl SerializeList {
+    fn new(ser: SexpSerializer<'static>, variant: Option<&'static str>) -> Self {
+        let sexps = variant.into_iter().map(Sexp::atom).collect();
+        SerializeList { ser, sexps }
+    }
+
+    fn push<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.sexps.push(value.serialize(self.ser)?);
+        Ok(())
+    }
+}
+
+macro_rules! serialize_list {
+    ($trait:ident, $method:ident) => {
+        impl ser::$trait for SerializeList {
+            type Ok = Sexp;
+            type Error = Error;
+
+            fn $method<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+                self.push(value)
+            }
+
+            fn end(self) -> Result<Sexp> {
+                Ok(Sexp::List(self.sexps))
+            }
+        }
+    };
+}
+
+serialize_list!(SerializeSeq, serialize_element);
+serialize_list!(SerializeTuple, serialize_element);
+serialize_list!(SerializeTupleStruct, serialize_field);
+serialize_list!(SerializeTupleVariant, serialize_field);
+
+struct SerializeMap {
+    ser: SexpSerializer<'static>,
+    pairs: Vec<Sexp>,
+    key: Option<Sexp>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(key.serialize(self.ser)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return Err(Error::Message("map value without a key".to_string())),
+        };
+        self.pairs
+            .push(Sexp::List(vec![key, value.serialize(self.ser)?]));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Sexp> {
+        Ok(Sexp::List(self.pairs))
+    }
+}
+
+struct SerializeRecord {
+    ser: SexpSerializer<'static>,
+    sexps: Vec<Sexp>,
+}
+
+impl SerializeRecord {
+    fn new(ser: SexpSerializer<'static>, variant: Option<&'static str>) -> Self {
+        let sexps = variant.into_iter().map(Sexp::atom).collect();
+        SerializeRecord { ser, sexps }
+    }
+
+    fn push<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        let omitted = Cell::new(false);
+        let ser = SexpSerializer {
+            option_style: self.ser.option_style,
+            omitted: Some(&omitted),
+        };
+        let value = value.serialize(ser)?;
+        if !omitted.get() {
+            self.sexps.push(Sexp::List(vec![Sexp::atom(key), value]))
+        }
+        Ok(())
+    }
+}
+
+macro_rules! serialize_record {
+    ($trait:ident) => {
+        impl ser::$trait for SerializeRecord {
+            type Ok = Sexp;
+            type Error = Error;
+
+            fn serialize_field<T: ?Sized + Serialize>(
+                &mut self,
+                key: &'static str,
+                value: &T,
+            ) -> Result<()> {
+                self.push(key, value)
+            }
+
+            fn end(self) -> Result<Sexp> {
+                Ok(Sexp::List(self.sexps))
+            }
+        }
+    };
+}
+
+serialize_record!(SerializeStruct);
+serialize_record!(SerializeStructVariant);
+
+#[derive(Clone, Copy)]
+struct SexpDeserializer<'a> {
+    sexp: &'a Sexp,
+    option_style: OptionStyle,
+    // Whether this is the value of a record field.
+    field: bool,
+}
+
+impl<'a> SexpDeserializer<'a> {
+    fn child(&self, sexp: &'a Sexp) -> Self {
+        SexpDeserializer {
+            sexp,
+            option_style: self.option_style,
+            field: false,
+        }
+    }
+
+    fn error(&self, expected: &str) -> Error {
+        Error::Message(format!(
+            "expected {}, got {}",
+            expected,
+            self.sexp.to_string_mach()
+        ))
+    }
+
+    fn atom(&self, expected: &str) -> Result<&'a str> {
+        self.sexp.as_atom().ok_or_else(|| self.error(expected))
+    }
+
+    fn bytes(&self, expected: &str) -> Result<&'a [u8]> {
+        self.sexp.as_bytes().ok_or_else(|| self.error(expected))
+    }
+
+    fn list(&self, expected: &str) -> Result<&'a [Sexp]> {
+        match self.sexp {
+            Sexp::List(list) => Ok(list),
+            Sexp::Atom(_) => Err(self.error(expected)),
+        }
+    }
+
+    // Numbers for self-describing formats, the other atoms being strings.
+    fn number<V: Visitor<'a>>(&self, atom: &'a str, visitor: V) -> Result<V::Value> {
+        let digits = atom.strip_prefix('-').unwrap_or(atom);
+        if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+            return visitor.visit_borrowed_str(atom);
+        }
+        let number = atom.replace('_', "");
+        if let Ok(n) = number.parse::<i64>() {
+            visitor.visit_i64(n)
+        } else if let Ok(n) = number.parse::<u64>() {
+            visitor.visit_u64(n)
+        } else if let Ok(f) = number.parse::<f64>() {
+            visitor.visit_f64(f)
+        } else {
+            visitor.visit_borrowed_str(atom)
+        }
+    }
+
+    fn int<T: std::str::FromStr>(&self) -> Result<T> {
+        let atom = self.atom("an integer")?;
+        atom.replace('_', "")
+            .parse()
+            .map_err(|_| self.error("an integer"))
+    }
+
+    fn seq(&self, sexps: &'a [Sexp]) -> SeqAccess<'a> {
+        SeqAccess {
+            de: *self,
+            sexps: sexps.iter(),
+        }
+    }
+
+    fn record(&self, sexps: &'a [Sexp], fields: &'static [&'static str]) -> RecordAccess<'a> {
+        RecordAccess {
+            de: *self,
+            sexps: sexps.iter(),
+            fields,
+            value: None,
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for SexpDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.sexp {
+            Sexp::Atom(atom) => match std::str::from_utf8(atom) {
+                Ok(atom) => self.number(atom, visitor),
+                Err(_) => visitor.visit_borrowed_bytes(atom),
+            },
+            Sexp::List(sexps) => visitor.visit_seq(self.seq(sexps)),
+        }
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.atom("a boolean")? {
+            "true" => visitor.visit_bool(true),
+            "false" => visitor.visit_bool(false),
+            _ => Err(self.error("a boolean")),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i8(self.int()?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i16(self.int()?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i32(self.int()?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i64(self.int()?)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u8(self.int()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u16(self.int()?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u32(self.int()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u64(self.int()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match float_of_string(self.atom("a float")?) {
+            Some(f) => visitor.visit_f64(f),
+            None => Err(self.error("a float")),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let atom = self.atom("a char")?;
+        let mut chars = atom.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => visitor.visit_char(c),
+            _ => Err(self.error("a char")),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_borrowed_str(self.atom("a string")?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_borrowed_bytes(self.bytes("a string")?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.field && self.option_style == OptionStyle::SexpOption {
+            // Missing fields are handled by serde as `None`.
+            return visitor.visit_some(self.child(self.sexp));
+        }
+        match self.list("an option")? {
+            [] => visitor.visit_none(),
+            [sexp] => visitor.visit_some(self.child(sexp)),
+            _ => Err(self.error("an option")),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.list("()")? {
+            [] => visitor.visit_unit(),
+            _ => Err(self.error("()")),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(self.seq(self.list("a list")?))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let pairs = self.list("a map")?;
+        visitor.visit_map(MapAccess {
+            de: self,
+            pairs: pairs.iter(),
+            value: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_map(self.record(self.list("a record")?, fields))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        let (name, args) = match self.sexp {
+            Sexp::Atom(_) => (self.sexp, &[][..]),
+            Sexp::List(sexps) => match sexps.split_first() {
+                Some((name, args)) => (name, args),
+                None => return Err(self.error("a constructor")),
+            },
+        };
+        match name.as_atom() {
+            Some(name) => visitor.visit_enum(EnumAccess {
+                de: self,
+                name,
+                args,
+            }),
+            None => Err(self.error("a constructor")),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! { i128 u128 }
+}
+
+struct SeqAccess<'a> {
+    de: SexpDeserializer<'a>,
+    sexps: std::slice::Iter<'a, Sexp>,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        match self.sexps.next() {
+            None => Ok(None),
+            Some(sexp) => seed.deserialize(self.de.child(sexp)).map(Some),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.sexps.len())
+    }
+}
+
+struct MapAccess<'a> {
+    de: SexpDeserializer<'a>,
+    pairs: std::slice::Iter<'a, Sexp>,
+    value: Option<&'a Sexp>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        match self.pairs.next() {
+            None => Ok(None),
+            Some(Sexp::List(pair)) if pair.len() == 2 => {
+                self.value = Some(&pair[1]);
+                seed.deserialize(self.de.child(&pair[0])).map(Some)
+            }
+            Some(sexp) => Err(self.de.child(sexp).error("a (key value) pair")),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        match self.value.take() {
+            Some(value) => seed.deserialize(self.de.child(value)),
+            None => Err(Error::Message("map value without a key".to_string())),
+        }
+    }
+}
+
+struct RecordAccess<'a> {
+    de: SexpDeserializer<'a>,
+    sexps: std::slice::Iter<'a, Sexp>,
+    // Extra fields are rejected, as with ppx_sexp_conv.
+    fields: &'static [&'static str],
+    value: Option<&'a Sexp>,
+}
+
+impl<'de> de::MapAccess<'de> for RecordAccess<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        match self.sexps.next() {
+            None => Ok(None),
+            Some(Sexp::List(field)) if field.len() == 2 => match field[0].as_atom() {
+                Some(name) if !self.fields.contains(&name) => {
+                    Err(Error::Message(format!("unknown field {}", name)))
+                }
+                Some(name) => {
+                    self.value = Some(&field[1]);
+                    seed.deserialize(name.into_deserializer()).map(Some)
+                }
+                None => Err(self.de.child(&field[0]).error("a field name")),
+            },
+            Some(sexp) => Err(self.de.child(sexp).error("a (field value) pair")),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        match self.value.take() {
+            Some(value) => {
+                let mut de = self.de.child(value);
+                de.field = true;
+                seed.deserialize(de)
+            }
+            None => Err(Error::Message("field value without a name".to_string())),
+        }
+    }
+}
+
+struct EnumAccess<'a> {
+    de: SexpDeserializer<'a>,
+    name: &'a str,
+    args: &'a [Sexp],
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self)>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        let name = de::value::BorrowedStrDeserializer::<Error>::new(self.name);
+        Ok((seed.deserialize(name)?, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for EnumAccess<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        match (self.de.sexp, self.args) {
+            (Sexp::Atom(_), []) => Ok(()),
+            _ => Err(self.de.error("a constructor without arguments")),
+        }
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        match self.args {
+            [arg] => seed.deserialize(self.de.child(arg)),
+            _ => Err(self.de.error("a constructor with one argument")),
+        }
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_seq(self.de.seq(self.args))
+    }
+
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_map(self.de.record(self.args, fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_sexp_str, string_of_float, to_sexp_string, OptionStyle, SexpConv};
+    use serde_derive::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Side {
+        Buy,
+        Sell { limit: f64 },
+        Cross(i64, i64),
+        Peg(Option<i64>),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Order {
+        id: i64,
+        symbol: String,
+        sides: Vec<Side>,
+        price: Option<f64>,
+        note: Option<String>,
+        attrs: BTreeMap<String, bool>,
+    }
+
+    #[test]
+    fn test_sexp_conv() {
+        let floats = [
+            1.0,
+            -0.0,
+            0.1,
+            100.0,
+            1e300,
+            1.5e-7,
+            0.1 + 0.2,
+            123456789.125,
+        ];
+        let floats: Vec<_> = floats.iter().map(|&f| string_of_float(f)).collect();
+        assert_eq!(
+            floats,
+            [
+                "1",
+                "-0",
+                "0.1",
+                "100",
+                "1E+300",
+                "1.5E-07",
+                "0.30000000000000004",
+                "123456789.125"
+            ]
+        );
+
+        let mut attrs = BTreeMap::new();
+        attrs.insert("ioc".to_string(), true);
+        let order = Order {
+            id: -12,
+            symbol: "BRK B".to_string(),
+            sides: vec![
+                Side::Buy,
+                Side::Sell { limit: 2.5 },
+                Side::Cross(1, 2),
+                Side::Peg(None),
+            ],
+            price: Some(3.0),
+            note: None,
+            attrs,
+        };
+        let sexp = to_sexp_string(&order).unwrap();
+        assert_eq!(
+            sexp,
+            r#"((id -12)(symbol"BRK B")(sides(Buy(Sell(limit 2.5))(Cross 1 2)(Peg())))(price(3))(note())(attrs((ioc true))))"#
+        );
+        assert_eq!(from_sexp_str::<Order>(&sexp).unwrap(), order);
+
+        let conv = SexpConv::new().option_style(OptionStyle::SexpOption);
+        let sexp = conv.to_string(&order).unwrap();
+        assert_eq!(
+            sexp,
+            r#"((id -12)(symbol"BRK B")(sides(Buy(Sell(limit 2.5))(Cross 1 2)(Peg())))(price 3)(attrs((ioc true))))"#
+        );
+        assert_eq!(conv.from_str::<Order>(&sexp).unwrap(), order);
+        let order: Order =
+            from_sexp_str("((id 1_000) (symbol x) (sides ()) (price ()) (note (\"\")) (attrs ()))")
+                .unwrap();
+        assert_eq!((order.id, order.note), (1000, Some(String::new())));
+        assert!(from_sexp_str::<Order>(
+            "((id 1) (symbol x) (sides ()) (price ()) (note ()) (attrs ()) (extra 1))"
+        )
+        .is_err());
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        #[serde(untagged)]
+        enum Number {
+            Int(i64),
+            Float(f64),
+            Name(String),
+        }
+        let numbers: Vec<Number> = from_sexp_str("(-3 1.5 1E+300 x)").unwrap();
+        assert_eq!(
+            numbers,
+            [
+                Number::Int(-3),
+                Number::Float(1.5),
+                Number::Float(1e300),
+                Number::Name("x".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,1019 @@
+//! Type shapes, mirroring OCaml `Bin_shape`.
+//!
+//! A shape describes the structure of a bin_io type. Two types with the same
+//! shape have the same bin_prot layout, and the digest of a shape can be used
+//! to check that two peers agree on the type of the messages they exchange.
+//! The shapes used here are in the canonical form that OCaml gets after
+//! evaluating a `Bin_shape.t`.
+use crate::containers::{Deque, Fqueue, Hashtbl, Map, Set};
+use crate::error::{Error, Result};
+use crate::sexp::Sexp;
+use crate::types::Md5Digest;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// The uuids used by `Bin_prot` for its base types.
+pub mod uuid {
+    pub const UNIT: &str = "unit";
+    pub const BOOL: &str = "bool";
+    pub const STRING: &str = "string";
+    pub const BYTES: &str = "bytes";
+    pub const CHAR: &str = "char";
+    pub const FLOAT: &str = "float";
+    pub const INT: &str = "int";
+    pub const INT32: &str = "int32";
+    pub const INT63: &str = "int63";
+    pub const INT64: &str = "int64";
+    pub const NATIVEINT: &str = "nativeint";
+    pub const NAT0: &str = "899e2f4a-490a-11e6-b68f-bbd62472516c";
+    pub const FLOAT_ARRAY: &str = "float_array";
+    pub const REF: &str = "ref";
+    pub const LAZY: &str = "lazy";
+    pub const OPTION: &str = "option";
+    pub const LIST: &str = "list";
+    pub const ARRAY: &str = "array";
+    pub const HASHTBL: &str = "hashtbl";
+    pub const BIGSTRING: &str = "bigstring";
+    // The identities of the Core containers, these take the shape of their
+    // elements as only argument, a pair of the key and data for maps.
+    pub const MAP: &str = "dfb300f8-4992-11e6-9c15-73a2ac6b815c";
+    pub const SET: &str = "8989278e-4992-11e6-8f4a-6b89776b1e53";
+    pub const HASH_SET: &str = "ad381672-4992-11e6-9e36-b76dc8cd466f";
+    pub const DEQUE: &str = "34c1e9ca-4992-11e6-a686-8b4bd4f87796";
+}
+
+// Whether the base type is encoded as a length followed by the elements,
+// which all have the shape of the only type argument.
+pub(crate) fn is_sequence(uuid: &str) -> bool {
+    matches!(
+        uuid,
+        uuid::LIST | uuid::ARRAY | uuid::SET | uuid::HASH_SET | uuid::DEQUE
+    )
+}
+
+// The number of type arguments of a base type.
+pub(crate) fn arity(class: &str) -> usize {
+    match class {
+        uuid::OPTION => 1,
+        uuid::HASHTBL => 2,
+        uuid::MAP => 1,
+        class if is_sequence(class) => 1,
+        _ => 0,
+    }
+}
+
+// The arguments of a base type, the key and data of maps rather than the
+// pair that makes up their elements.
+pub(crate) fn map_args<'s>(uuid: &str, args: &'s [Shape]) -> &'s [Shape] {
+    match args {
+        [Shape::Tuple(pair)] if uuid == uuid::MAP && pair.len() == 2 => pair,
+        args => args,
+    }
+}
+
+// The base type with the same encoding, e.g. all the integer types use the
+// OCaml int encoding.
+pub(crate) fn encoding_class(uuid: &str) -> &str {
+    match uuid {
+        uuid::INT | uuid::INT32 | uuid::INT63 | uuid::INT64 | uuid::NATIVEINT => uuid::INT,
+        uuid::STRING | uuid::BYTES | uuid::BIGSTRING => uuid::STRING,
+        uuid::LIST | uuid::ARRAY => uuid::LIST,
+        uuid => uuid,
+    }
+}
+
+/// How the values of a shape are laid out, see [`Shape::kind`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Kind<'s> {
+    Unit,
+    Bool,
+    Char,
+    Float,
+    /// The integer types, with their uuid as the range depends on it.
+    Int(&'s str),
+    Nat0,
+    /// Strings and the types encoded as strings, with their uuid.
+    String(&'s str),
+    Option(&'s Shape),
+    /// A length followed by the elements, e.g. lists or sets, with the uuid
+    /// of the collection and the shape of the elements.
+    Sequence(&'s str, &'s Shape),
+    /// A length followed by the key-value pairs.
+    Map(&'s Shape, &'s Shape),
+    Tuple(&'s [Shape]),
+    Record(&'s [(String, Shape)]),
+    Variant(&'s [(String, Vec<Shape>)]),
+    PolyVariant(&'s BTreeMap<String, Option<Shape>>),
+}
+
+// The elements of a float array.
+fn float_shape() -> &'static Shape {
+    static FLOAT: std::sync::OnceLock<Shape> = std::sync::OnceLock::new();
+    FLOAT.get_or_init(Shape::float)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Shape {
+    Annotate(String, Box<Shape>),
+    Base(String, Vec<Shape>),
+    Tuple(Vec<Shape>),
+    Record(Vec<(String, Shape)>),
+    Variant(Vec<(String, Vec<Shape>)>),
+    // Polymorphic variants are insensitive to the order the constructors
+    // are listed in.
+    PolyVariant(BTreeMap<String, Option<Shape>>),
+    // The left-hand side of an application is a potentially recursive
+    // definition with its own scope of type variables: [Var i] refers to the
+    // i-th argument of the application and [RecApp (i, _)] refers to the i-th
+    // enclosing definition, 0 being the innermost one.
+    Application(Box<Shape>, Vec<Shape>),
+    RecApp(usize, Vec<Shape>),
+    Var(usize),
+}
+
+impl Shape {
+    pub fn base(uuid: &str) -> Shape {
+        Shape::Base(uuid.to_string(), vec![])
+    }
+
+    pub fn unit() -> Shape {
+        Shape::base(uuid::UNIT)
+    }
+
+    pub fn bool() -> Shape {
+        Shape::base(uuid::BOOL)
+    }
+
+    pub fn int() -> Shape {
+        Shape::base(uuid::INT)
+    }
+
+    pub fn nat0() -> Shape {
+        Shape::base(uuid::NAT0)
+    }
+
+    pub fn float() -> Shape {
+        Shape::base(uuid::FLOAT)
+    }
+
+    pub fn char() -> Shape {
+        Shape::base(uuid::CHAR)
+    }
+
+    pub fn string() -> Shape {
+        Shape::base(uuid::STRING)
+    }
+
+    pub fn option(shape: Shape) -> Shape {
+        Shape::Base(uuid::OPTION.to_string(), vec![shape])
+    }
+
+    pub fn list(shape: Shape) -> Shape {
+        Shape::Base(uuid::LIST.to_string(), vec![shape])
+    }
+
+    pub fn array(shape: Shape) -> Shape {
+        Shape::Base(uuid::ARRAY.to_string(), vec![shape])
+    }
+
+    /// The shape of a Core map, with the pairs of keys and data as elements.
+    pub fn map(key: Shape, data: Shape) -> Shape {
+        Shape::Base(uuid::MAP.to_string(), vec![Shape::Tuple(vec![key, data])])
+    }
+
+    /// The md5 digest of the shape, this matches the digest computed by
+    /// `Bin_shape.eval_to_digest` on the OCaml side.
+    pub fn eval_md5(&self) -> Md5Digest {
+        Md5Digest(self.digest().0)
+    }
+
+    /// The digest of the shape as an hexadecimal string, this matches
+    /// `Bin_shape.eval_to_digest_string`.
+    pub fn eval_digest(&self) -> String {
+        self.eval_md5().to_hex()
+    }
+
+    /// Removes the applications, annotations, references and lazy values at
+    /// the top of a closed shape, references and lazy values being encoded
+    /// as their content.
+    pub fn resolve(&self) -> Shape {
+        let mut shape = self.unfold();
+        while let Shape::Base(uuid, args) = &shape {
+            if (uuid != uuid::REF && uuid != uuid::LAZY) || args.len() != 1 {
+                break;
+            }
+            shape = args[0].unfold();
+        }
+        shape
+    }
+
+    // The layout of the values of a resolved shape, this is where the base
+    // types are told apart.
+    pub(crate) fn kind(&self) -> Result<Kind<'_>> {
+        let (uuid, args) = match self {
+            Shape::Base(uuid, args) => (uuid.as_str(), args.as_slice()),
+            Shape::Tuple(shapes) => return Ok(Kind::Tuple(shapes)),
+            Shape::Record(fields) => return Ok(Kind::Record(fields)),
+            Shape::Variant(variants) => return Ok(Kind::Variant(variants)),
+            Shape::PolyVariant(variants) => return Ok(Kind::PolyVariant(variants)),
+            shape => return Err(Error::Message(format!("unexpected shape {:?}", shape))),
+        };
+        let class = encoding_class(uuid);
+        if args.len() != arity(class) {
+            return Err(Error::Message(format!("invalid base type {}", uuid)));
+        }
+        let kind = match class {
+            uuid::UNIT => Kind::Unit,
+            uuid::BOOL => Kind::Bool,
+            uuid::CHAR => Kind::Char,
+            uuid::FLOAT => Kind::Float,
+            uuid::INT => Kind::Int(uuid),
+            uuid::NAT0 => Kind::Nat0,
+            uuid::STRING => Kind::String(uuid),
+            uuid::FLOAT_ARRAY => Kind::Sequence(uuid, float_shape()),
+            uuid::OPTION => Kind::Option(&args[0]),
+            uuid::MAP | uuid::HASHTBL => match map_args(uuid, args) {
+                [key, data] => Kind::Map(key, data),
+                _ => return Err(Error::Message(format!("invalid base type {}", uuid))),
+            },
+            class if is_sequence(class) => Kind::Sequence(uuid, &args[0]),
+            _ => return Err(Error::Message(format!("unknown base type {}", uuid))),
+        };
+        Ok(kind)
+    }
+
+    /// Replaces the type variables with the given arguments. The bodies of
+    /// nested applications have their own scope and are left untouched.
+    pub fn instantiate(&self, args: &[Shape]) -> Shape {
+        let all = |shapes: &[Shape]| shapes.iter().map(|s| s.instantiate(args)).collect();
+        match self {
+            Shape::Annotate(uuid, shape) => {
+                Shape::Annotate(uuid.clone(), Box::new(shape.instantiate(args)))
+            }
+            Shape::Base(uuid, shapes) => Shape::Base(uuid.clone(), all(shapes)),
+            Shape::Tuple(shapes) => Shape::Tuple(all(shapes)),
+            Shape::Record(fields) => Shape::Record(
+                fields
+                    .iter()
+                    .map(|(name, shape)| (name.clone(), shape.instantiate(args)))
+                    .collect(),
+            ),
+            Shape::Variant(variants) => Shape::Variant(
+                variants
+                    .iter()
+                    .map(|(name, shapes)| (name.clone(), all(shapes)))
+                    .collect(),
+            ),
+            Shape::PolyVariant(variants) => Shape::PolyVariant(
+                variants
+                    .iter()
+                    .map(|(name, shape)| {
+                        (name.clone(), shape.as_ref().map(|s| s.instantiate(args)))
+                    })
+                    .collect(),
+            ),
+            Shape::Application(body, shapes) => Shape::Application(body.clone(), all(shapes)),
+            Shape::RecApp(index, shapes) => Shape::RecApp(*index, all(shapes)),
+            Shape::Var(index) => args.get(*index).cloned().unwrap_or(Shape::Var(*index)),
+        }
+    }
+
+    /// Removes the applications and annotations at the top of a closed
+    /// shape, the recursive references to an unfolded application are
+    /// replaced with copies of it. The result is never an application.
+    pub fn unfold(&self) -> Shape {
+        match self {
+            Shape::Annotate(_, shape) => shape.unfold(),
+            Shape::Application(body, args) => {
+                let mut shape = (**body).clone();
+                shape.substitute(0, body, args);
+                shape.unfold()
+            }
+            shape => shape.clone(),
+        }
+    }
+
+    // Replaces the variables and recursive references of the application
+    // `depth` levels above.
+    fn substitute(&mut self, depth: usize, body: &Shape, args: &[Shape]) {
+        let all = |shapes: &mut Vec<Shape>| {
+            for shape in shapes.iter_mut() {
+                shape.substitute(depth, body, args)
+            }
+        };
+        match self {
+            Shape::Annotate(_, shape) => shape.substitute(depth, body, args),
+            Shape::Base(_, shapes) | Shape::Tuple(shapes) => all(shapes),
+            Shape::Record(fields) => {
+                for (_, shape) in fields.iter_mut() {
+                    shape.substitute(depth, body, args)
+                }
+            }
+            Shape::Variant(variants) => {
+                for (_, shapes) in variants.iter_mut() {
+                    all(shapes)
+                }
+            }
+            Shape::PolyVariant(variants) => {
+                for shape in variants.values_mut().flatten() {
+                    shape.substitute(depth, body, args)
+                }
+            }
+            Shape::Application(inner, shapes) => {
+                inner.substitute(depth + 1, body, args);
+                all(shapes)
+            }
+            Shape::RecApp(index, shapes) => {
+                all(shapes);
+                if *index == depth {
+                    *self = Shape::Application(Box::new(body.clone()), std::mem::take(shapes))
+                }
+            }
+            Shape::Var(index) => {
+                if depth == 0 {
+                    if let Some(arg) = args.get(*index) {
+                        *self = arg.clone()
+                    }
+                }
+            }
+        }
+    }
+
+    fn digest(&self) -> md5::Digest {
+        match self {
+            Shape::Annotate(uuid, shape) => {
+                constructor("annotate", &[string(uuid), shape.digest()])
+            }
+            Shape::Base(uuid, args) => constructor("base", &[string(uuid), list_of(args)]),
+            Shape::Tuple(args) => constructor("tuple", &[list_of(args)]),
+            Shape::Record(fields) => {
+                let fields: Vec<_> = fields
+                    .iter()
+                    .map(|(name, shape)| pair(string(name), shape.digest()))
+                    .collect();
+                constructor("record", &[list(&fields)])
+            }
+            Shape::Variant(variants) => {
+                let variants: Vec<_> = variants
+                    .iter()
+                    .map(|(name, args)| pair(string(name), list_of(args)))
+                    .collect();
+                constructor("variant", &[list(&variants)])
+            }
+            Shape::PolyVariant(variants) => {
+                let variants: Vec<_> = variants
+                    .iter()
+                    .map(|(name, arg)| {
+                        let arg = match arg {
+                            None => constructor("none", &[]),
+                            Some(arg) => constructor("some", &[arg.digest()]),
+                        };
+                        pair(string(name), arg)
+                    })
+                    .collect();
+                constructor("poly_variant", &[list(&variants)])
+            }
+            Shape::Application(shape, args) => {
+                constructor("application", &[shape.digest(), list_of(args)])
+            }
+            Shape::RecApp(index, args) => constructor("rec_app", &[int(*index), list_of(args)]),
+            Shape::Var(index) => constructor("var", &[int(*index)]),
+        }
+    }
+}
+
+// The digest combinators from `Bin_shape.Canonical_digest`: strings are
+// hashed directly, lists and pairs are the md5 of the concatenation of the
+// binary digests of their elements, and constructors the md5 of their name
+// followed by the binary digest of the list of their arguments.
+fn constructor(name: &str, args: &[md5::Digest]) -> md5::Digest {
+    let mut context = md5::Context::new();
+    context.consume(name);
+    context.consume(list(args).0);
+    context.compute()
+}
+
+fn list(ds: &[md5::Digest]) -> md5::Digest {
+    let mut context = md5::Context::new();
+    for d in ds {
+        context.consume(d.0);
+    }
+    context.compute()
+}
+
+fn list_of(shapes: &[Shape]) -> md5::Digest {
+    let ds: Vec<_> = shapes.iter().map(|s| s.digest()).collect();
+    list(&ds)
+}
+
+fn pair(d1: md5::Digest, d2: md5::Digest) -> md5::Digest {
+    list(&[d1, d2])
+}
+
+fn string(s: &str) -> md5::Digest {
+    md5::compute(s)
+}
+
+fn int(n: usize) -> md5::Digest {
+    string(&n.to_string())
+}
+
+// The sexp representation follows the one of `Bin_shape.Canonical.t`, each
+// node being wrapped in an `Exp` constructor.
+impl Shape {
+    /// Converts the shape to the s-expression printed on the OCaml side by
+    /// `[%sexp_of: Bin_shape.Canonical.t]`.
+    pub fn to_sexp(&self) -> Sexp {
+        let tagged = |tag: &str, mut args: Vec<Sexp>| {
+            args.insert(0, Sexp::atom(tag));
+            Sexp::List(args)
+        };
+        let shapes = |shapes: &[Shape]| Sexp::List(shapes.iter().map(|s| s.to_sexp()).collect());
+        let exp = match self {
+            Shape::Annotate(uuid, shape) => {
+                tagged("Annotate", vec![Sexp::atom(uuid), shape.to_sexp()])
+            }
+            Shape::Base(uuid, args) => tagged("Base", vec![Sexp::atom(uuid), shapes(args)]),
+            Shape::Tuple(args) => tagged("Tuple", vec![shapes(args)]),
+            Shape::Record(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(name, shape)| Sexp::List(vec![Sexp::atom(name), shape.to_sexp()]))
+                    .collect();
+                tagged("Record", vec![Sexp::List(fields)])
+            }
+            Shape::Variant(variants) => {
+                let variants = variants
+                    .iter()
+                    .map(|(name, args)| Sexp::List(vec![Sexp::atom(name), shapes(args)]))
+                    .collect();
+                tagged("Variant", vec![Sexp::List(variants)])
+            }
+            Shape::PolyVariant(variants) => {
+                let variants = variants
+                    .iter()
+                    .map(|(name, arg)| {
+                        let arg = arg.iter().map(|arg| arg.to_sexp()).collect();
+                        Sexp::List(vec![Sexp::atom(name), Sexp::List(arg)])
+                    })
+                    .collect();
+                let sorted = Sexp::List(vec![Sexp::atom("sorted"), Sexp::List(variants)]);
+                tagged("Poly_variant", vec![Sexp::List(vec![sorted])])
+            }
+            Shape::Application(shape, args) => {
+                tagged("Application", vec![shape.to_sexp(), shapes(args)])
+            }
+            Shape::RecApp(index, args) => tagged(
+                "Rec_app",
+                vec![Sexp::atom(&index.to_string()), shapes(args)],
+            ),
+            Shape::Var(index) => tagged("Var", vec![Sexp::atom(&index.to_string())]),
+        };
+        Sexp::List(vec![Sexp::atom("Exp"), exp])
+    }
+
+    /// Converts an s-expression produced by `[%sexp_of: Bin_shape.Canonical.t]`
+    /// back to a shape. The `Exp` wrappers are optional.
+    pub fn from_sexp(sexp: &Sexp) -> Result<Shape> {
+        let invalid = |msg: &str| Error::Message(format!("invalid shape sexp, {}: {}", msg, sexp));
+        let list = sexp.as_list().ok_or_else(|| invalid("expected a list"))?;
+        let tag = list.first().and_then(|tag| tag.as_atom());
+        let tag = tag.ok_or_else(|| invalid("expected a constructor"))?;
+        let args = &list[1..];
+        let atom = |index: usize| -> Result<&str> {
+            args.get(index)
+                .and_then(|a| a.as_atom())
+                .ok_or_else(|| invalid("expected an atom"))
+        };
+        let sub_list = |index: usize| -> Result<&[Sexp]> {
+            args.get(index)
+                .and_then(|a| a.as_list())
+                .ok_or_else(|| invalid("expected a list"))
+        };
+        let shapes = |index: usize| -> Result<Vec<Shape>> {
+            sub_list(index)?.iter().map(Shape::from_sexp).collect()
+        };
+        let shape = |index: usize| -> Result<Shape> {
+            args.get(index)
+                .ok_or_else(|| invalid("missing argument"))
+                .and_then(Shape::from_sexp)
+        };
+        let index = |index: usize| -> Result<usize> {
+            atom(index)?
+                .parse()
+                .map_err(|_| invalid("expected an integer"))
+        };
+        let pair = |sexp: &Sexp| -> Result<(String, Sexp)> {
+            match sexp.as_list() {
+                Some([name, value]) => match name.as_atom() {
+                    Some(name) => Ok((name.to_string(), value.clone())),
+                    None => Err(invalid("expected a pair")),
+                },
+                _ => Err(invalid("expected a pair")),
+            }
+        };
+        let arity = match tag {
+            "Exp" | "Tuple" | "Record" | "Variant" | "Poly_variant" | "Var" => 1,
+            "Annotate" | "Base" | "Application" | "Rec_app" => 2,
+            _ => return Err(invalid(&format!("unknown constructor {}", tag))),
+        };
+        if args.len() != arity {
+            return Err(invalid(&format!("{} expects {} argument(s)", tag, arity)));
+        }
+        let shape = match tag {
+            "Exp" => shape(0)?,
+            "Annotate" => Shape::Annotate(atom(0)?.to_string(), Box::new(shape(1)?)),
+            "Base" => Shape::Base(atom(0)?.to_string(), shapes(1)?),
+            "Tuple" => Shape::Tuple(shapes(0)?),
+            "Record" => {
+                let fields: Result<Vec<_>> = sub_list(0)?
+                    .iter()
+                    .map(|field| {
+                        let (name, shape) = pair(field)?;
+                        Ok((name, Shape::from_sexp(&shape)?))
+                    })
+                    .collect();
+                Shape::Record(fields?)
+            }
+            "Variant" => {
+                let variants: Result<Vec<_>> = sub_list(0)?
+                    .iter()
+                    .map(|variant| {
+                        let (name, args) = pair(variant)?;
+                        let args = args.as_list().ok_or_else(|| invalid("expected a list"))?;
+                        let args: Result<Vec<_>> = args.iter().map(Shape::from_sexp).collect();
+                        Ok((name, args?))
+                    })
+                    .collect();
+                Shape::Variant(variants?)
+            }
+            "Poly_variant" => {
+                // The table can either be given as a record with a single
+                // [sorted] field or directly as a list of constructors.
+                let mut table = sub_list(0)?;
+                if let [Sexp::List(sorted)] = table {
+                    if let [field, Sexp::List(inner)] = sorted.as_slice() {
+                        if field.as_atom() == Some("sorted") {
+                            table = inner
+                        }
+                    }
+                }
+                let mut variants = BTreeMap::new();
+                for variant in table.iter() {
+                    let (name, arg) = pair(variant)?;
+                    let arg = match arg.as_list() {
+                        Some([]) => None,
+                        Some([arg]) => Some(Shape::from_sexp(arg)?),
+                        _ => return Err(invalid("expected an optional argument")),
+                    };
+                    variants.insert(name, arg);
+                }
+                Shape::PolyVariant(variants)
+            }
+            "Application" => Shape::Application(Box::new(shape(0)?), shapes(1)?),
+            "Rec_app" => Shape::RecApp(index(0)?, shapes(1)?),
+            "Var" => Shape::Var(index(0)?),
+            _ => unreachable!(),
+        };
+        Ok(shape)
+    }
+
+    /// Parses a shape from its textual s-expression representation.
+    pub fn from_sexp_str(s: &str) -> Result<Shape> {
+        Shape::from_sexp(&Sexp::parse(s)?)
+    }
+}
+
+impl std::str::FromStr for Shape {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Shape> {
+        Shape::from_sexp_str(s)
+    }
+}
+
+/// Checks that a shape published by a peer, e.g. parsed from the OCaml
+/// `Bin_shape` sexp output, has the same digest as the shape of `T`.
+pub fn check_shape<T: HasShape + ?Sized>(published: &Shape) -> Result<()> {
+    let expected = published.eval_digest();
+    let actual = T::shape().eval_digest();
+    if expected != actual {
+        return Err(Error::ShapeMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+/// Types with a bin_prot shape. The shape describes the layout produced by
+/// this crate `Serializer` for the type.
+pub trait HasShape {
+    fn shape() -> Shape;
+}
+
+macro_rules! base_shape {
+    ($uuid:expr, $($ty:ty),*) => {
+        $(
+            impl HasShape for $ty {
+                fn shape() -> Shape {
+                    Shape::base($uuid)
+                }
+            }
+        )*
+    };
+}
+
+// Signed integers use the OCaml int encoding whereas unsigned integers use
+// the Nat0.t one.
+base_shape!(uuid::UNIT, ());
+base_shape!(uuid::BOOL, bool);
+base_shape!(uuid::INT, i8, i16, i32, i64, isize);
+base_shape!(uuid::NAT0, u8, u16, u32, u64, usize);
+base_shape!(uuid::FLOAT, f32, f64);
+base_shape!(uuid::CHAR, char);
+base_shape!(uuid::STRING, str, String);
+base_shape!(uuid::STRING, Md5Digest);
+base_shape!(uuid::BIGSTRING, crate::types::Bigstring);
+
+macro_rules! base_shape1 {
+    ($uuid:expr, $($ty:ident),*) => {
+        $(
+            impl<T: HasShape> HasShape for $ty<T> {
+                fn shape() -> Shape {
+                    Shape::Base($uuid.to_string(), vec![T::shape()])
+                }
+            }
+        )*
+    };
+}
+
+base_shape1!(uuid::OPTION, Option);
+base_shape1!(uuid::LIST, Vec, VecDeque, Fqueue);
+base_shape1!(uuid::SET, BTreeSet, Set);
+base_shape1!(uuid::HASH_SET, HashSet);
+base_shape1!(uuid::DEQUE, Deque);
+
+impl<T: HasShape> HasShape for [T] {
+    fn shape() -> Shape {
+        Shape::list(T::shape())
+    }
+}
+
+macro_rules! base_shape2 {
+    ($uuid:expr, $($ty:ident),*) => {
+        $(
+            impl<K: HasShape, V: HasShape> HasShape for $ty<K, V> {
+                fn shape() -> Shape {
+                    Shape::Base($uuid.to_string(), vec![K::shape(), V::shape()])
+                }
+            }
+        )*
+    };
+}
+
+base_shape2!(uuid::HASHTBL, HashMap, Hashtbl);
+
+// Core maps are iterable binables of the key-data pairs.
+macro_rules! map_shape {
+    ($($ty:ident),*) => {
+        $(
+            impl<K: HasShape, V: HasShape> HasShape for $ty<K, V> {
+                fn shape() -> Shape {
+                    Shape::map(K::shape(), V::shape())
+                }
+            }
+        )*
+    };
+}
+
+map_shape!(BTreeMap, Map);
+
+macro_rules! transparent_shape {
+    ($($ty:ident),*) => {
+        $(
+            impl<T: HasShape + ?Sized> HasShape for $ty<T> {
+                fn shape() -> Shape {
+                    T::shape()
+                }
+            }
+        )*
+    };
+}
+
+transparent_shape!(Box, Rc, Arc);
+
+impl<T: HasShape + ?Sized> HasShape for &T {
+    fn shape() -> Shape {
+        T::shape()
+    }
+}
+
+impl<T: HasShape, const N: usize> HasShape for [T; N] {
+    fn shape() -> Shape {
+        Shape::Tuple(vec![T::shape(); N])
+    }
+}
+
+// The following types are used by the derive macro to represent the type
+// parameters and the recursive occurrences of the type being defined.
+#[doc(hidden)]
+pub struct ShapeVar<const N: usize>;
+
+impl<const N: usize> HasShape for ShapeVar<N> {
+    fn shape() -> Shape {
+        Shape::Var(N)
+    }
+}
+
+#[doc(hidden)]
+pub struct ShapeRecApp<T>(std::marker::PhantomData<T>);
+
+impl<T: ShapeArgs> HasShape for ShapeRecApp<T> {
+    fn shape() -> Shape {
+        Shape::RecApp(0, T::shapes())
+    }
+}
+
+#[doc(hidden)]
+pub trait ShapeArgs {
+    fn shapes() -> Vec<Shape>;
+}
+
+impl ShapeArgs for () {
+    fn shapes() -> Vec<Shape> {
+        vec![]
+    }
+}
+
+macro_rules! tuple_shape {
+    ($($ty:ident),*) => {
+        impl<$($ty: HasShape),*> HasShape for ($($ty,)*) {
+            fn shape() -> Shape {
+                Shape::Tuple(vec![$($ty::shape()),*])
+            }
+        }
+
+        impl<$($ty: HasShape),*> ShapeArgs for ($($ty,)*) {
+            fn shapes() -> Vec<Shape> {
+                vec![$($ty::shape()),*]
+            }
+        }
+    };
+}
+
+tuple_shape!(T1);
+tuple_shape!(T1, T2);
+tuple_shape!(T1, T2, T3);
+tuple_shape!(T1, T2, T3, T4);
+tuple_shape!(T1, T2, T3, T4, T5);
+tuple_shape!(T1, T2, T3, T4, T5, T6);
+tuple_shape!(T1, T2, T3, T4, T5, T6, T7);
+tuple_shape!(T1, T2, T3, T4, T5, T6, T7, T8);
+
+impl<T: HasShape> HasShape for crate::containers::NonemptyList<T> {
+    fn shape() -> Shape {
+        Shape::Tuple(vec![T::shape(), Shape::list(T::shape())])
+    }
+}
+
+impl<T: crate::stringable::Stringable> HasShape for crate::stringable::OfStringable<T> {
+    fn shape() -> Shape {
+        Shape::string()
+    }
+}
+
+impl<T: HasShape> HasShape for crate::versioned::Versioned<T> {
+    fn shape() -> Shape {
+        Shape::Tuple(vec![Shape::nat0(), T::shape()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_shape, HasShape, Shape};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::Serialize;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_digest() {
+        assert_eq!(i32::shape(), i64::shape());
+        assert_eq!(i64::shape().eval_digest(), i32::shape().eval_digest());
+        assert_ne!(i64::shape().eval_digest(), u64::shape().eval_digest());
+        assert_eq!(i64::shape().eval_digest().len(), 32);
+        assert_eq!(<Box<Vec<String>>>::shape(), Shape::list(Shape::string()));
+        assert_eq!(<[f64; 2]>::shape(), <(f64, f64)>::shape());
+
+        let record = |x: &str| Shape::Record(vec![(x.to_string(), i64::shape())]);
+        assert_ne!(record("x").eval_digest(), record("y").eval_digest());
+        assert_ne!(
+            Shape::Tuple(vec![i64::shape()]).eval_digest(),
+            Shape::Tuple(vec![Shape::list(i64::shape())]).eval_digest()
+        );
+
+        let poly = |xs: &[&str]| {
+            let xs: BTreeMap<_, _> = xs.iter().map(|x| (x.to_string(), None)).collect();
+            Shape::PolyVariant(xs)
+        };
+        assert_eq!(
+            poly(&["A", "B"]).eval_digest(),
+            poly(&["B", "A"]).eval_digest()
+        );
+        let variant =
+            |xs: &[&str]| Shape::Variant(xs.iter().map(|x| (x.to_string(), vec![])).collect());
+        assert_ne!(
+            variant(&["A", "B"]).eval_digest(),
+            variant(&["B", "A"]).eval_digest()
+        );
+    }
+
+    #[test]
+    fn test_canonical_digest() {
+        // `Sexp.t`, the OCaml definition is recursive.
+        let sexp = Shape::Variant(vec![
+            ("Atom".to_string(), vec![Shape::string()]),
+            (
+                "List".to_string(),
+                vec![Shape::list(Shape::RecApp(0, vec![]))],
+            ),
+        ]);
+
+        // The digests printed by `Bin_shape.eval_to_digest_string` for the
+        // corresponding OCaml types.
+        let digests = [
+            Shape::int(),
+            Shape::string(),
+            Shape::float(),
+            Shape::bool(),
+            Shape::unit(),
+            i64::shape(),
+            Shape::list(Shape::string()),
+            Shape::Application(Box::new(sexp), vec![]),
+        ]
+        .iter()
+        .map(Shape::eval_digest)
+        .collect::<Vec<_>>();
+        assert_eq!(
+            digests,
+            [
+                "698cfa4093fe5e51523842d37b92aeac",
+                "d9a8da25d5656b016fb4dbdc2e4197fb",
+                "1fd923acb2dd9c5d401ad5b08b1d40cd",
+                "a25306e4c5d30d35adbb5b0462a6b1b3",
+                "86ba5df747eec837f0b391dd49f33f9e",
+                "698cfa4093fe5e51523842d37b92aeac",
+                "296be80010ace497614f92952e5510c4",
+                "832b40ae394f2851da8ba67b3339b429",
+            ]
+        );
+
+        // Digests computed with the same combinators, these ones have not
+        // been compared with OCaml.
+        let mut side = BTreeMap::new();
+        side.insert("Buy".to_string(), None);
+        side.insert("Sell".to_string(), Some(Shape::int()));
+        let digests = [
+            Shape::Record(vec![
+                ("x".to_string(), Shape::int()),
+                ("y".to_string(), Shape::string()),
+            ]),
+            Shape::Variant(vec![
+                ("A".to_string(), vec![]),
+                ("B".to_string(), vec![Shape::int()]),
+            ]),
+            Shape::PolyVariant(side),
+            <BTreeMap<String, i64>>::shape(),
+        ]
+        .iter()
+        .map(Shape::eval_digest)
+        .collect::<Vec<_>>();
+        assert_eq!(
+            digests,
+            [
+                "1aaa04eb4c47ca4e16c04e9bc36a6f19",
+                "ff702ab7cf7fe733347cb38d030c7f7a",
+                "f15ab572e763f32c522b4f290d99b355",
+                "c7aef8e2dd2006f0d63876ac3e028187",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sexp() {
+        let sexp = "(Exp (Application (Exp (Record ((qty (Exp (Base int ()))) \
+            (side (Exp (Poly_variant ((sorted ((Buy ()) (Sell ((Exp (Base int ()))))))))))))) ()))";
+        let shape = Shape::from_sexp_str(sexp).unwrap();
+        let mut side = BTreeMap::new();
+        side.insert("Buy".to_string(), None);
+        side.insert("Sell".to_string(), Some(Shape::int()));
+        let record = Shape::Record(vec![
+            ("qty".to_string(), Shape::int()),
+            ("side".to_string(), Shape::PolyVariant(side)),
+        ]);
+        assert_eq!(shape, Shape::Application(Box::new(record), vec![]));
+        assert_eq!(Shape::from_sexp(&shape.to_sexp()).unwrap(), shape);
+        assert_eq!(
+            Shape::from_sexp_str("(Base list ((Var 0)))").unwrap(),
+            Shape::list(Shape::Var(0))
+        );
+        assert!(Shape::from_sexp_str("(Exp (Unknown 1))").is_err());
+        assert!(Shape::from_sexp_str("(Exp (Base int ())").is_err());
+
+        let published = Shape::from_sexp_str("(Exp (Base list ((Exp (Base int ())))))").unwrap();
+        assert_eq!(published.eval_digest(), "4cd553520709511864846bda25c448d0");
+        assert!(check_shape::<Vec<i64>>(&published).is_ok());
+        assert!(matches!(
+            check_shape::<Vec<u64>>(&published),
+            Err(crate::Error::ShapeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_derive() {
+        #[derive(BinShape, Serialize)]
+        #[allow(dead_code)]
+        struct Foo {
+            foo_i64: i64,
+            #[serde(rename = "type")]
+            foo_type: Option<String>,
+        }
+
+        let record = Shape::Record(vec![
+            ("foo_i64".to_string(), Shape::int()),
+            ("type".to_string(), Shape::option(Shape::string())),
+        ]);
+        assert_eq!(Foo::shape(), Shape::Application(Box::new(record), vec![]));
+
+        #[derive(BinShape)]
+        #[allow(dead_code)]
+        enum Tree<T> {
+            Leaf,
+            Node(Box<Tree<T>>, T, Box<Tree<T>>),
+            Nodes { children: Vec<Self> },
+        }
+
+        let rec_app = Shape::RecApp(0, vec![Shape::Var(0)]);
+        let variant = Shape::Variant(vec![
+            ("Leaf".to_string(), vec![]),
+            (
+                "Node".to_string(),
+                vec![rec_app.clone(), Shape::Var(0), rec_app.clone()],
+            ),
+            (
+                "Nodes".to_string(),
+                vec![Shape::Record(vec![(
+                    "children".to_string(),
+                    Shape::list(rec_app),
+                )])],
+            ),
+        ]);
+        assert_eq!(
+            <Tree<u8>>::shape(),
+            Shape::Application(Box::new(variant), vec![Shape::nat0()])
+        );
+
+        #[derive(BinShape, Serialize)]
+        #[allow(dead_code)]
+        #[serde(rename_all = "snake_case", rename_all_fields = "camelCase")]
+        enum Side {
+            Buy,
+            ShortSell {
+                limit_price: f64,
+            },
+            #[serde(skip)]
+            Unknown,
+        }
+
+        let variant = Shape::Variant(vec![
+            ("buy".to_string(), vec![]),
+            (
+                "short_sell".to_string(),
+                vec![Shape::Record(vec![(
+                    "limitPrice".to_string(),
+                    Shape::float(),
+                )])],
+            ),
+        ]);
+        assert_eq!(Side::shape(), Shape::Application(Box::new(variant), vec![]));
+        let data = crate::to_vec(&Side::ShortSell { limit_price: 1.5 }).unwrap();
+        assert_eq!(data[0], 1);
+
+        #[derive(BinShape)]
+        #[bin_shape(uuid = "e7b7fc4a-0ffe-11e6-9d5a-57d6b3d0b1e4")]
+        #[allow(dead_code)]
+        struct Id(i64);
+
+        let annotated = Shape::Annotate(
+            "e7b7fc4a-0ffe-11e6-9d5a-57d6b3d0b1e4".to_string(),
+            Box::new(Shape::int()),
+        );
+        assert_eq!(Id::shape(), Shape::Application(Box::new(annotated), vec![]));
+
+        #[derive(BinShape)]
+        #[bin_shape(basetype = "my-uuid")]
+        #[allow(dead_code)]
+        struct Opaque<T>(Vec<T>);
+
+        let base = Shape::Base("my-uuid".to_string(), vec![Shape::float()]);
+        assert_eq!(<Opaque<f64>>::shape(), base);
+    }
+}
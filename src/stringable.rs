@@ -0,0 +1,191 @@
+//! Types serialized through their string representation, this mirrors OCaml
+//! `Binable.Of_stringable`: the value is converted to a string which is then
+//! written as a bin_prot string.
+//!
+//! The functions in this module can be used as a serde adapter:
+//!
+//! ```
+//! use serde_derive::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Peer {
+//!     #[serde(with = "serde_binprot::stringable")]
+//!     addr: std::net::IpAddr,
+//!     port: i32,
+//! }
+//! ```
+use crate::error::{Error, Result};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Conversion to and from the string used on the wire.
+pub trait Stringable: Sized {
+    fn to_wire_string(&self) -> String;
+    fn from_wire_str(s: &str) -> Result<Self>;
+}
+
+pub fn serialize<T, S>(value: &T, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    T: Stringable,
+    S: Serializer,
+{
+    serializer.serialize_str(&value.to_wire_string())
+}
+
+pub fn deserialize<'de, T, D>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    T: Stringable,
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    T::from_wire_str(&s).map_err(|err| match err {
+        Error::Message(msg) => de::Error::custom(msg),
+        err => de::Error::custom(err),
+    })
+}
+
+/// A wrapper using the string representation of the inner value, this is
+/// useful when the stringable value is nested, e.g. `Vec<OfStringable<IpAddr>>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct OfStringable<T>(pub T);
+
+impl<T: Stringable> Serialize for OfStringable<T> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serialize(&self.0, serializer)
+    }
+}
+
+impl<'de, T: Stringable> Deserialize<'de> for OfStringable<T> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserialize(deserializer).map(OfStringable)
+    }
+}
+
+// Parses an address, the error is returned as a message since it has to go
+// through `de::Error::custom` anyway.
+fn parse_addr<T>(s: &str) -> Result<T>
+where
+    T: std::str::FromStr<Err = std::net::AddrParseError>,
+{
+    s.parse()
+        .map_err(|err| Error::Message(format!("{}: {:?}", err, s)))
+}
+
+// The OCaml side uses [Unix.string_of_inet_addr] and [Unix.inet_addr_of_string]
+// for [Unix.Inet_addr.t], these use the usual textual representations.
+impl Stringable for IpAddr {
+    fn to_wire_string(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_wire_str(s: &str) -> Result<Self> {
+        parse_addr(s)
+    }
+}
+
+impl Stringable for Ipv4Addr {
+    fn to_wire_string(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_wire_str(s: &str) -> Result<Self> {
+        parse_addr(s)
+    }
+}
+
+impl Stringable for Ipv6Addr {
+    fn to_wire_string(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_wire_str(s: &str) -> Result<Self> {
+        parse_addr(s)
+    }
+}
+
+impl Stringable for SocketAddr {
+    fn to_wire_string(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_wire_str(s: &str) -> Result<Self> {
+        parse_addr(s)
+    }
+}
+
+impl Stringable for String {
+    fn to_wire_string(&self) -> String {
+        self.clone()
+    }
+
+    fn from_wire_str(s: &str) -> Result<Self> {
+        Ok(s.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OfStringable, Stringable};
+    use crate::error::{Error, Result};
+    use crate::{from_slice, to_vec};
+    use serde_derive::{Deserialize, Serialize};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    #[derive(Debug, PartialEq)]
+    struct Symbol(String);
+
+    impl Stringable for Symbol {
+        fn to_wire_string(&self) -> String {
+            self.0.clone()
+        }
+
+        fn from_wire_str(s: &str) -> Result<Self> {
+            if s.is_empty() {
+                Err(Error::Message("empty symbol".to_string()))
+            } else {
+                Ok(Symbol(s.to_string()))
+            }
+        }
+    }
+
+    #[test]
+    fn test_stringable() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Peer {
+            #[serde(with = "crate::stringable")]
+            symbol: Symbol,
+            #[serde(with = "crate::stringable")]
+            addr: IpAddr,
+            peers: Vec<OfStringable<SocketAddr>>,
+        }
+
+        let peer = Peer {
+            symbol: Symbol("AAPL".to_string()),
+            addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            peers: vec![OfStringable("[::1]:8080".parse().unwrap())],
+        };
+        let ser = to_vec(&peer).unwrap();
+        assert_eq!(ser[..5], [4, b'A', b'A', b'P', b'L']);
+        assert_eq!(ser[5..15], *b"\x09127.0.0.1");
+        assert_eq!(from_slice::<Peer>(&ser).unwrap(), peer);
+        assert_eq!(
+            to_vec(&"127.0.0.1").unwrap(),
+            to_vec(&OfStringable(peer.addr)).unwrap()
+        );
+
+        let ser = to_vec(&"not-an-ip").unwrap();
+        match from_slice::<OfStringable<IpAddr>>(&ser) {
+            Err(Error::Message(msg)) => assert_eq!(msg, "invalid IP address syntax: \"not-an-ip\""),
+            res => panic!("unexpected result {:?}", res),
+        }
+        let ser = to_vec(&"").unwrap();
+        assert!(from_slice::<OfStringable<Symbol>>(&ser).is_err());
+    }
+}
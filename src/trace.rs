@@ -0,0 +1,763 @@
+//! Infer shapes by tracing `Deserialize` implementations.
+//!
+//! This is useful for types that come from other crates and cannot derive
+//! `HasShape`. The type `Deserialize` implementation is run against a
+//! recording deserializer: structs and enums provide their field and variant
+//! names, the other calls give the primitive kinds. As only one variant of
+//! an enum can be explored at a time, the tracing is run multiple times until
+//! all the variants have been seen.
+//!
+//! Some limitations apply: types are identified by the name that they pass
+//! to serde so two instantiations of the same generic type are considered
+//! to be recursive occurrences of each other, and `deserialize_any` is not
+//! supported. Serde does not tell a `HashMap` from a `BTreeMap` so both are
+//! traced as a Core `Map.t`, use `containers::Hashtbl` to get a `Hashtbl.t`.
+use crate::containers::HASHTBL_MARKER;
+use crate::error::{Error, Result};
+use crate::shape::{map_args, uuid, HasShape, Shape};
+use crate::types::{Bigstring, Md5Digest, BIGSTRING_MARKER, MD5_DIGEST_MARKER};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use std::collections::{BTreeMap, BTreeSet};
+
+const MAX_PASSES: usize = 10_000;
+const MAX_DEPTH: usize = 512;
+
+// Named types are referred to by this placeholder until all the passes
+// have completed.
+const NAMED: &str = "\u{0}named:";
+
+fn named(name: &str) -> Shape {
+    Shape::Base(format!("{}{}", NAMED, name), vec![])
+}
+
+// The variant names of an enum together with the shapes of their arguments,
+// if already traced.
+type Variants = Vec<(&'static str, Option<Vec<Shape>>)>;
+
+#[derive(Default)]
+struct TraceState {
+    structs: BTreeMap<&'static str, Shape>,
+    enums: BTreeMap<&'static str, Variants>,
+    // The enum variants for which a value could be produced without going
+    // through a recursive occurrence of a named type.
+    leaves: BTreeSet<(&'static str, u32)>,
+}
+
+impl TraceState {
+    fn n_traced_variants(&self) -> usize {
+        let variants = self.enums.values().flat_map(|v| v.iter());
+        variants.filter(|(_, shapes)| shapes.is_some()).count()
+    }
+
+    fn is_complete(&self) -> bool {
+        let mut variants = self.enums.values().flat_map(|v| v.iter());
+        variants.all(|(_, shapes)| shapes.is_some())
+    }
+
+    fn resolve_all(&self, shapes: &[Shape], stack: &mut Vec<String>) -> Result<Vec<Shape>> {
+        shapes.iter().map(|s| self.resolve(s, stack)).collect()
+    }
+
+    // Replace the placeholders by the definitions of the named types.
+    fn resolve(&self, shape: &Shape, stack: &mut Vec<String>) -> Result<Shape> {
+        let shape = match shape {
+            Shape::Base(uuid, _) if uuid.starts_with(NAMED) => {
+                let name = &uuid[NAMED.len()..];
+                if let Some(index) = stack.iter().position(|n| n == name) {
+                    return Ok(Shape::RecApp(stack.len() - 1 - index, vec![]));
+                }
+                let body = if let Some(body) = self.structs.get(name) {
+                    body.clone()
+                } else if let Some(variants) = self.enums.get(name) {
+                    let mut vs = vec![];
+                    for (variant, shapes) in variants.iter() {
+                        match shapes {
+                            Some(shapes) => vs.push((variant.to_string(), shapes.clone())),
+                            None => {
+                                let msg = format!("variant {}::{} was not traced", name, variant);
+                                return Err(Error::Message(msg));
+                            }
+                        }
+                    }
+                    Shape::Variant(vs)
+                } else {
+                    return Err(Error::Message(format!("type {} was not traced", name)));
+                };
+                stack.push(name.to_string());
+                let body = self.resolve(&body, stack);
+                stack.pop();
+                Shape::Application(Box::new(body?), vec![])
+            }
+            Shape::Annotate(uuid, shape) => {
+                Shape::Annotate(uuid.clone(), Box::new(self.resolve(shape, stack)?))
+            }
+            Shape::Base(uuid, args) => Shape::Base(uuid.clone(), self.resolve_all(args, stack)?),
+            Shape::Tuple(args) => Shape::Tuple(self.resolve_all(args, stack)?),
+            Shape::Record(fields) => {
+                let mut fs = vec![];
+                for (name, shape) in fields.iter() {
+                    fs.push((name.clone(), self.resolve(shape, stack)?))
+                }
+                Shape::Record(fs)
+            }
+            Shape::Variant(variants) => {
+                let mut vs = vec![];
+                for (name, args) in variants.iter() {
+                    vs.push((name.clone(), self.resolve_all(args, stack)?))
+                }
+                Shape::Variant(vs)
+            }
+            Shape::PolyVariant(variants) => {
+                let mut vs = BTreeMap::new();
+                for (name, arg) in variants.iter() {
+                    let arg = match arg {
+                        None => None,
+                        Some(arg) => Some(self.resolve(arg, stack)?),
+                    };
+                    vs.insert(name.clone(), arg);
+                }
+                Shape::PolyVariant(vs)
+            }
+            Shape::Application(shape, args) => {
+                let shape = self.resolve(shape, stack)?;
+                Shape::Application(Box::new(shape), self.resolve_all(args, stack)?)
+            }
+            Shape::RecApp(index, args) => Shape::RecApp(*index, self.resolve_all(args, stack)?),
+            Shape::Var(index) => Shape::Var(*index),
+        };
+        Ok(shape)
+    }
+}
+
+/// Returns the shape of a type by tracing its `Deserialize` implementation.
+pub fn trace_shape<T: DeserializeOwned>() -> Result<Shape> {
+    let mut state = TraceState::default();
+    let mut root = None;
+    for _pass in 0..MAX_PASSES {
+        let n_traced_variants = state.n_traced_variants();
+        let mut tracer = Tracer::new(&mut state);
+        T::deserialize(&mut tracer)?;
+        root = tracer.shapes.pop().and_then(|mut shapes| shapes.pop());
+        if state.is_complete() {
+            break;
+        }
+        if state.n_traced_variants() == n_traced_variants {
+            return Err(Error::Message(
+                "no progress while tracing enum variants".to_string(),
+            ));
+        }
+    }
+    match root {
+        None => Err(Error::Message("no value was traced".to_string())),
+        Some(root) => state.resolve(&root, &mut vec![]),
+    }
+}
+
+struct Tracer<'s> {
+    state: &'s mut TraceState,
+    // The named types being traced, with the variant index for enums.
+    stack: Vec<(&'static str, Option<u32>)>,
+    // The shapes of the children of the nodes being traced.
+    shapes: Vec<Vec<Shape>>,
+    // When positive, a recursive occurrence of a named type is being traced.
+    // A minimal value is produced and no shape gets recorded.
+    recursive: usize,
+    // The number of recursive occurrences that have been traced so far.
+    recursive_occurrences: usize,
+}
+
+impl<'s> Tracer<'s> {
+    fn new(state: &'s mut TraceState) -> Self {
+        Tracer {
+            state,
+            stack: vec![],
+            shapes: vec![vec![]],
+            recursive: 0,
+            recursive_occurrences: 0,
+        }
+    }
+
+    fn push_shape(&mut self, shape: Shape) {
+        if let Some(shapes) = self.shapes.last_mut() {
+            shapes.push(shape)
+        }
+    }
+
+    fn visit_children<F, T>(&mut self, f: F) -> Result<(T, Vec<Shape>)>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        self.shapes.push(vec![]);
+        let value = f(self);
+        let shapes = self.shapes.pop().unwrap_or_default();
+        Ok((value?, shapes))
+    }
+
+    fn visit_named<F, T>(&mut self, name: &'static str, variant: Option<u32>, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<(T, Shape)>,
+    {
+        if self.stack.len() > MAX_DEPTH {
+            return Err(Error::Message(format!(
+                "max depth reached when tracing {}",
+                name
+            )));
+        }
+        let recursive = self.stack.iter().any(|(n, _)| *n == name);
+        let record = self.recursive == 0 && !recursive;
+        let recursive_occurrences = self.recursive_occurrences;
+        if recursive {
+            self.recursive += 1;
+            self.recursive_occurrences += 1;
+        }
+        self.stack.push((name, variant));
+        let value = self.visit_children(f);
+        self.stack.pop();
+        if recursive {
+            self.recursive -= 1
+        }
+        let ((value, body), _) = value?;
+        if let Some(index) = variant {
+            if self.recursive_occurrences == recursive_occurrences {
+                self.state.leaves.insert((name, index));
+            }
+        }
+        if record {
+            match variant {
+                None => {
+                    self.state.structs.insert(name, body);
+                }
+                Some(index) => {
+                    if let (Some(variants), Shape::Tuple(args)) =
+                        (self.state.enums.get_mut(name), body)
+                    {
+                        variants[index as usize].1 = Some(args)
+                    }
+                }
+            }
+        }
+        self.push_shape(named(name));
+        Ok(value)
+    }
+
+    fn choose_variant(
+        &mut self,
+        name: &'static str,
+        variants: &'static [&'static str],
+    ) -> Result<u32> {
+        let recursive = self.stack.iter().any(|(n, _)| *n == name);
+        if self.recursive == 0 && !recursive {
+            let traced = self
+                .state
+                .enums
+                .entry(name)
+                .or_insert_with(|| variants.iter().map(|v| (*v, None)).collect());
+            let index = traced.iter().position(|(_, shapes)| shapes.is_none());
+            return Ok(index.unwrap_or(0) as u32);
+        }
+        // Pick a variant that is known not to recurse, or failing that a
+        // variant that is not already being traced.
+        let n_variants = variants.len() as u32;
+        if let Some(index) = (0..n_variants).find(|i| self.state.leaves.contains(&(name, *i))) {
+            return Ok(index);
+        }
+        let in_progress = |i: u32| self.stack.iter().any(|(n, v)| *n == name && *v == Some(i));
+        match (0..n_variants).find(|i| !in_progress(*i)) {
+            Some(index) => Ok(index),
+            None => Err(Error::Message(format!(
+                "cannot find a non-recursive variant for {}",
+                name
+            ))),
+        }
+    }
+}
+
+fn single(shapes: Vec<Shape>) -> Shape {
+    shapes.into_iter().next().unwrap_or_else(Shape::unit)
+}
+
+impl<'de> de::Deserializer<'de> for &mut Tracer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::CannotDeserializeAny)
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::bool());
+        visitor.visit_bool(false)
+    }
+
+    fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::int());
+        visitor.visit_i8(1)
+    }
+
+    fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::int());
+        visitor.visit_i16(1)
+    }
+
+    fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::int());
+        visitor.visit_i32(1)
+    }
+
+    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::int());
+        visitor.visit_i64(1)
+    }
+
+    fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::nat0());
+        visitor.visit_u8(1)
+    }
+
+    fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::nat0());
+        visitor.visit_u16(1)
+    }
+
+    fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::nat0());
+        visitor.visit_u32(1)
+    }
+
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::nat0());
+        visitor.visit_u64(1)
+    }
+
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::float());
+        visitor.visit_f32(0.)
+    }
+
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::float());
+        visitor.visit_f64(0.)
+    }
+
+    fn deserialize_char<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::char());
+        visitor.visit_char('a')
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::string());
+        visitor.visit_string(String::new())
+    }
+
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::base(uuid::BYTES));
+        // Use 16 bytes so that md5 digests can be traced.
+        visitor.visit_byte_buf(vec![0u8; 16])
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if self.recursive > 0 {
+            self.push_shape(Shape::unit());
+            return visitor.visit_none();
+        }
+        let (value, shapes) = self.visit_children(|t| visitor.visit_some(t))?;
+        self.push_shape(Shape::option(single(shapes)));
+        Ok(value)
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.push_shape(Shape::unit());
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.visit_named(name, None, |_| {
+            let value = visitor.visit_unit::<Error>()?;
+            Ok((value, Shape::unit()))
+        })
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        // The types of this crate that share their serde representation with
+        // other types pass a marker name, this tells them apart.
+        let marker = match name {
+            MD5_DIGEST_MARKER => Md5Digest::shape(),
+            BIGSTRING_MARKER => Bigstring::shape(),
+            HASHTBL_MARKER => Shape::Base(uuid::HASHTBL.to_string(), vec![]),
+            _ => {
+                return self.visit_named(name, None, |t| {
+                    let (value, shapes) = t.visit_children(|t| visitor.visit_newtype_struct(t))?;
+                    Ok((value, single(shapes)))
+                })
+            }
+        };
+        let (value, shapes) = self.visit_children(|t| visitor.visit_newtype_struct(t))?;
+        let shape = match (marker, single(shapes)) {
+            // The key and data come from the traced map.
+            (Shape::Base(hashtbl, _), Shape::Base(map, args)) if hashtbl == uuid::HASHTBL => {
+                Shape::Base(hashtbl, map_args(&map, &args).to_vec())
+            }
+            (marker, _) => marker,
+        };
+        self.push_shape(shape);
+        Ok(value)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let len = if self.recursive > 0 { 0 } else { 1 };
+        let (value, shapes) = self.visit_children(|t| visitor.visit_seq(TraceSeq::new(t, len)))?;
+        self.push_shape(Shape::list(single(shapes)));
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let (value, shapes) = self.visit_children(|t| visitor.visit_seq(TraceSeq::new(t, len)))?;
+        self.push_shape(Shape::Tuple(shapes));
+        Ok(value)
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.visit_named(name, None, |t| {
+            let (value, shapes) = t.visit_children(|t| visitor.visit_seq(TraceSeq::new(t, len)))?;
+            Ok((value, Shape::Tuple(shapes)))
+        })
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let len = if self.recursive > 0 { 0 } else { 1 };
+        let (value, mut shapes) =
+            self.visit_children(|t| visitor.visit_map(TraceSeq::new(t, len)))?;
+        shapes.resize(2, Shape::unit());
+        let data = shapes.pop().unwrap();
+        let key = shapes.pop().unwrap();
+        self.push_shape(Shape::map(key, data));
+        Ok(value)
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.visit_named(name, None, |t| {
+            let (value, shapes) =
+                t.visit_children(|t| visitor.visit_seq(TraceSeq::new(t, fields.len())))?;
+            let fields = fields.iter().map(|f| f.to_string());
+            Ok((value, Shape::Record(fields.zip(shapes).collect())))
+        })
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let index = self.choose_variant(name, variants)?;
+        self.visit_named(name, Some(index), |t| {
+            let (value, shapes) =
+                t.visit_children(|t| visitor.visit_enum(TraceEnum { tracer: t, index }))?;
+            Ok((value, Shape::Tuple(shapes)))
+        })
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_any(visitor)
+    }
+}
+
+struct TraceSeq<'a, 's> {
+    tracer: &'a mut Tracer<'s>,
+    len: usize,
+}
+
+impl<'a, 's> TraceSeq<'a, 's> {
+    fn new(tracer: &'a mut Tracer<'s>, len: usize) -> Self {
+        TraceSeq { tracer, len }
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for TraceSeq<'_, '_> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.tracer).map(Some)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for TraceSeq<'_, '_> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.tracer).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.tracer)
+    }
+}
+
+struct TraceEnum<'a, 's> {
+    tracer: &'a mut Tracer<'s>,
+    index: u32,
+}
+
+impl<'de, 'a, 's> de::EnumAccess<'de> for TraceEnum<'a, 's> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self)>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        let index: de::value::U32Deserializer<Error> = self.index.into_deserializer();
+        let value = seed.deserialize(index)?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for TraceEnum<'_, '_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(self.tracer)
+    }
+
+    fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_seq(TraceSeq::new(self.tracer, len))
+    }
+
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let tracer = self.tracer;
+        let (value, shapes) =
+            tracer.visit_children(|t| visitor.visit_seq(TraceSeq::new(t, fields.len())))?;
+        let fields = fields.iter().map(|f| f.to_string());
+        tracer.push_shape(Shape::Record(fields.zip(shapes).collect()));
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::trace_shape;
+    use crate::containers::{Hashtbl, Map};
+    use crate::{Bigstring, HasShape, Md5Digest, Shape};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::Deserialize;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn test_trace() {
+        #[derive(Deserialize, BinShape)]
+        #[allow(dead_code)]
+        struct Leg {
+            symbol: String,
+            qty: i64,
+            price: Option<f64>,
+        }
+
+        #[derive(Deserialize, BinShape)]
+        #[allow(dead_code)]
+        enum Side {
+            Buy,
+            Sell,
+            Other(u32),
+            Legs { legs: Vec<Leg>, tag: (char, bool) },
+        }
+
+        assert_eq!(trace_shape::<Leg>().unwrap(), Leg::shape());
+        assert_eq!(trace_shape::<Side>().unwrap(), Side::shape());
+        assert_eq!(
+            trace_shape::<Vec<Option<i32>>>().unwrap(),
+            <Vec<Option<i32>>>::shape()
+        );
+        assert_eq!(
+            trace_shape::<Map<String, u64>>().unwrap(),
+            <Map<String, u64>>::shape()
+        );
+        assert_eq!(trace_shape::<Md5Digest>().unwrap(), Md5Digest::shape());
+        assert_eq!(trace_shape::<Bigstring>().unwrap(), Bigstring::shape());
+        assert_eq!(
+            trace_shape::<Hashtbl<String, u64>>().unwrap(),
+            <Hashtbl<String, u64>>::shape()
+        );
+        assert_eq!(
+            trace_shape::<HashMap<String, u64>>().unwrap(),
+            <BTreeMap<String, u64>>::shape()
+        );
+
+        #[derive(Deserialize, BinShape)]
+        #[allow(dead_code)]
+        enum Expr {
+            Add(Box<Expr>, Box<Expr>),
+            Neg(Box<Expr>),
+            Lit(i64),
+        }
+        assert_eq!(trace_shape::<Expr>().unwrap(), Expr::shape());
+
+        #[derive(Deserialize, BinShape)]
+        #[allow(dead_code)]
+        struct List {
+            head: i64,
+            tail: Option<Box<List>>,
+        }
+        assert_eq!(trace_shape::<List>().unwrap(), List::shape());
+        let rec_app = Shape::RecApp(0, vec![]);
+        assert_eq!(
+            trace_shape::<List>().unwrap(),
+            Shape::Application(
+                Box::new(Shape::Record(vec![
+                    ("head".to_string(), Shape::int()),
+                    ("tail".to_string(), Shape::option(rec_app)),
+                ])),
+                vec![]
+            )
+        );
+    }
+
+    #[test]
+    fn test_trace_markers() {
+        type Nested = BTreeMap<String, Hashtbl<String, u64>>;
+        assert_eq!(trace_shape::<Nested>().unwrap(), Nested::shape());
+        type Outer = Hashtbl<String, BTreeMap<String, u64>>;
+        assert_eq!(trace_shape::<Outer>().unwrap(), Outer::shape());
+        type Digests = Map<String, Md5Digest>;
+        assert_eq!(trace_shape::<Digests>().unwrap(), Digests::shape());
+        type Bigstrings = Hashtbl<i64, Vec<Bigstring>>;
+        assert_eq!(trace_shape::<Bigstrings>().unwrap(), Bigstrings::shape());
+    }
+}
@@ -0,0 +1,552 @@
+//! Rewrite bin_prot data from one layout to another.
+//!
+//! The migration is driven by the shapes of the source and target types so
+//! no Rust type is needed for either version. Values are matched by name:
+//! record fields are reordered as needed and variant constructors are
+//! re-indexed. The changes that cannot be inferred from the shapes are given
+//! as [`Rules`].
+//!
+//! Rules refer to values with paths using the same syntax as
+//! [`crate::compat`]: `.name` for a record field or a variant constructor,
+//! ``.`tag`` for a polymorphic variant tag, `.0` for a tuple component, `?`
+//! for the content of an option and `[]` for the elements of a collection,
+//! e.g. `.items[].price`. The path of a value is built with the target names.
+//!
+//! ```
+//! use serde_binprot::shape::Shape;
+//! use serde_binprot::transcode::{transcode, Rules};
+//!
+//! let old = Shape::Record(vec![("id".to_string(), Shape::int())]);
+//! let new = Shape::Record(vec![
+//!     ("id".to_string(), Shape::int()),
+//!     ("price".to_string(), Shape::float()),
+//! ]);
+//! let rules = Rules::new().default_value(".price", &1.5f64).unwrap();
+//! let data = transcode(&serde_binprot::to_vec(&42i64).unwrap(), &old, &new, &rules).unwrap();
+//! assert_eq!(data, serde_binprot::to_vec(&(42i64, 1.5f64)).unwrap());
+//! ```
+use crate::de::{Deserializer, SliceReader};
+use crate::error::{Error, Result};
+use crate::ser::{hash_variant, poly_variant_tag, Serializer};
+use crate::shape::{arity, encoding_class, is_sequence, map_args, uuid, Shape};
+use crate::walk::MAX_DEPTH;
+use serde::ser::Serializer as _;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+
+/// The changes between two layouts that cannot be inferred by matching
+/// names.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    defaults: BTreeMap<String, Vec<u8>>,
+    drops: BTreeSet<String>,
+    renames: BTreeMap<String, String>,
+}
+
+impl Rules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value to use for a field that only exists in the target layout.
+    /// Fields of option or collection types default to `None` or to an
+    /// empty collection.
+    pub fn default_value<T: Serialize + ?Sized>(self, path: &str, value: &T) -> Result<Self> {
+        Ok(self.default_bytes(path, crate::to_vec(value)?))
+    }
+
+    /// Same as [`Rules::default_value`] with an already encoded value.
+    pub fn default_bytes(mut self, path: &str, bytes: Vec<u8>) -> Self {
+        self.defaults.insert(path.to_string(), bytes);
+        self
+    }
+
+    /// Allows a field that only exists in the source layout to be dropped.
+    pub fn drop(mut self, path: &str) -> Self {
+        self.drops.insert(path.to_string());
+        self
+    }
+
+    /// Renames a record field, a variant constructor or a polymorphic
+    /// variant tag, `path` ends with the source name. Renaming a removed
+    /// constructor to an existing one maps the former to the latter.
+    pub fn rename(mut self, path: &str, new_name: &str) -> Self {
+        self.renames.insert(path.to_string(), new_name.to_string());
+        self
+    }
+}
+
+/// Rewrites `data`, encoded with the `source` layout, to the `target`
+/// layout.
+pub fn transcode(data: &[u8], source: &Shape, target: &Shape, rules: &Rules) -> Result<Vec<u8>> {
+    let mut transcoder = Transcoder {
+        de: Deserializer::new(SliceReader::new(data)),
+        rules,
+        skipping: 0,
+        depth: 0,
+    };
+    let mut out = vec![];
+    transcoder.value(source, target, "", &mut out)?;
+    if transcoder.de.get_ref().remaining() > 0 {
+        return Err(Error::TrailingCharacters);
+    }
+    Ok(out)
+}
+
+fn write_nat0(out: &mut Vec<u8>, v: u64) -> Result<()> {
+    Serializer::new(out).serialize_nat0(v)
+}
+
+fn write_int(out: &mut Vec<u8>, v: i64) -> Result<()> {
+    (&mut Serializer::new(out)).serialize_i64(v)
+}
+
+// A conversion between two base types, of the value at `path` starting at
+// `offset`.
+struct BaseConversion<'s> {
+    src: (&'s str, &'s [Shape]),
+    dst: (&'s str, &'s [Shape]),
+    path: &'s str,
+    offset: usize,
+}
+
+struct Transcoder<'a, 'b> {
+    de: Deserializer<SliceReader<'a>>,
+    rules: &'b Rules,
+    // When positive, the current value is being skipped and the rules do
+    // not apply.
+    skipping: usize,
+    depth: usize,
+}
+
+impl<'a, 'b> Transcoder<'a, 'b> {
+    fn error(&self, path: &str, offset: usize, msg: String) -> Error {
+        Error::Message(msg).at_path(path, offset)
+    }
+
+    fn renamed<'n>(&self, path: &str, name: &'n str) -> std::borrow::Cow<'n, str> {
+        match self.rules.renames.get(path) {
+            Some(new_name) if self.skipping == 0 => new_name.clone().into(),
+            _ => name.into(),
+        }
+    }
+
+    fn skip(&mut self, shape: &Shape, path: &str) -> Result<()> {
+        self.skipping += 1;
+        let res = self.value(shape, shape, path, &mut vec![]);
+        self.skipping -= 1;
+        res
+    }
+
+    fn value(&mut self, src: &Shape, dst: &Shape, path: &str, out: &mut Vec<u8>) -> Result<()> {
+        let offset = self.de.get_ref().offset();
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error(path, offset, "value nested too deeply".to_string()));
+        }
+        self.depth += 1;
+        let res = self.unfolded(&src.unfold(), &dst.unfold(), path, offset, out);
+        self.depth -= 1;
+        res.map_err(|err| err.at_path(path, offset))
+    }
+
+    fn unfolded(
+        &mut self,
+        src: &Shape,
+        dst: &Shape,
+        path: &str,
+        offset: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<()> {
+        match (src, dst) {
+            // References and lazy values are encoded as their content.
+            (Shape::Base(uuid, args), dst)
+                if (uuid == uuid::REF || uuid == uuid::LAZY) && args.len() == 1 =>
+            {
+                self.value(&args[0], dst, path, out)
+            }
+            (src, Shape::Base(uuid, args))
+                if (uuid == uuid::REF || uuid == uuid::LAZY) && args.len() == 1 =>
+            {
+                self.value(src, &args[0], path, out)
+            }
+            (Shape::Base(uuid1, args1), Shape::Base(uuid2, args2)) => {
+                let conversion = BaseConversion {
+                    src: (uuid1, args1),
+                    dst: (uuid2, args2),
+                    path,
+                    offset,
+                };
+                self.base(conversion, out)
+            }
+            (Shape::Tuple(shapes1), Shape::Tuple(shapes2)) if shapes1.len() == shapes2.len() => {
+                for (index, (shape1, shape2)) in shapes1.iter().zip(shapes2.iter()).enumerate() {
+                    self.value(shape1, shape2, &format!("{}.{}", path, index), out)?
+                }
+                Ok(())
+            }
+            (Shape::Record(fields), Shape::Tuple(shapes))
+            | (Shape::Tuple(shapes), Shape::Record(fields))
+                if fields.len() == shapes.len() =>
+            {
+                for (index, ((_, shape1), shape2)) in fields.iter().zip(shapes.iter()).enumerate() {
+                    let (shape1, shape2) = match src {
+                        Shape::Record(_) => (shape1, shape2),
+                        _ => (shape2, shape1),
+                    };
+                    self.value(shape1, shape2, &format!("{}.{}", path, index), out)?
+                }
+                Ok(())
+            }
+            (Shape::Record(fields1), Shape::Record(fields2)) => {
+                self.record(fields1, fields2, path, out)
+            }
+            (Shape::Variant(variants1), Shape::Variant(variants2)) => {
+                let index = self.de.read_byte()?;
+                let (name, args1) = match variants1.get(index as usize) {
+                    Some(variant) => variant,
+                    None => {
+                        let msg = format!("invalid constructor index {}", index);
+                        return Err(self.error(path, offset, msg));
+                    }
+                };
+                let new_name = self.renamed(&format!("{}.{}", path, name), name);
+                let path = format!("{}.{}", path, new_name);
+                let new_index = variants2.iter().position(|(n, _)| *n == new_name);
+                let args2 = match new_index {
+                    Some(new_index) => {
+                        let tag = u8::try_from(new_index).map_err(|_| {
+                            let msg =
+                                format!("constructor index {} does not fit a byte", new_index);
+                            self.error(&path, offset, msg)
+                        })?;
+                        out.push(tag);
+                        &variants2[new_index].1
+                    }
+                    None => {
+                        let msg = format!("constructor {} is not in the target layout", name);
+                        return Err(self.error(&path, offset, msg));
+                    }
+                };
+                if args1.len() != args2.len() {
+                    let msg = format!("arguments of constructor {} changed", name);
+                    return Err(self.error(&path, offset, msg));
+                }
+                if args1.len() == 1 {
+                    return self.value(&args1[0], &args2[0], &path, out);
+                }
+                for (index, (arg1, arg2)) in args1.iter().zip(args2.iter()).enumerate() {
+                    self.value(arg1, arg2, &format!("{}.{}", path, index), out)?
+                }
+                Ok(())
+            }
+            (Shape::PolyVariant(variants1), Shape::PolyVariant(variants2)) => {
+                let hash = self.de.read_i32()? >> 1;
+                let (name, arg1) = match variants1.iter().find(|(n, _)| hash_variant(n) == hash) {
+                    Some(variant) => variant,
+                    None => {
+                        let msg = format!("invalid polymorphic variant hash {}", hash);
+                        return Err(self.error(path, offset, msg));
+                    }
+                };
+                let new_name = self.renamed(&format!("{}.`{}", path, name), name);
+                let path = format!("{}.`{}", path, new_name);
+                match (arg1, variants2.get(new_name.as_ref())) {
+                    (None, Some(None)) => {
+                        out.extend_from_slice(&poly_variant_tag(&new_name));
+                        Ok(())
+                    }
+                    (Some(arg1), Some(Some(arg2))) => {
+                        out.extend_from_slice(&poly_variant_tag(&new_name));
+                        self.value(arg1, arg2, &path, out)
+                    }
+                    (_, None) => {
+                        let msg = format!("tag `{} is not in the target layout", name);
+                        Err(self.error(&path, offset, msg))
+                    }
+                    (_, Some(_)) => {
+                        let msg = format!("argument of tag `{} changed", name);
+                        Err(self.error(&path, offset, msg))
+                    }
+                }
+            }
+            (src, dst) => Err(self.error(
+                path,
+                offset,
+                format!("cannot convert {:?} to {:?}", src, dst),
+            )),
+        }
+    }
+
+    fn record(
+        &mut self,
+        fields1: &[(String, Shape)],
+        fields2: &[(String, Shape)],
+        path: &str,
+        out: &mut Vec<u8>,
+    ) -> Result<()> {
+        // The fields may be reordered, so they are converted first and
+        // written in the target order afterwards.
+        let mut values = BTreeMap::new();
+        for (name, shape1) in fields1.iter() {
+            let field_path = format!("{}.{}", path, name);
+            let new_name = self.renamed(&field_path, name);
+            match fields2.iter().find(|(n, _)| *n == new_name) {
+                Some((new_name, shape2)) => {
+                    let offset = self.de.get_ref().offset();
+                    let mut buf = vec![];
+                    self.value(shape1, shape2, &format!("{}.{}", path, new_name), &mut buf)?;
+                    if let Some((previous, _)) = values.insert(new_name.as_str(), (name, buf)) {
+                        let msg = format!(
+                            "the rules map both fields {} and {} to {}",
+                            previous, name, new_name
+                        );
+                        return Err(self.error(&field_path, offset, msg));
+                    }
+                }
+                None if self.skipping > 0 || self.rules.drops.contains(&field_path) => {
+                    self.skip(shape1, &field_path)?
+                }
+                None => {
+                    let offset = self.de.get_ref().offset();
+                    let msg = format!("field {} is not in the target layout", name);
+                    return Err(self.error(&field_path, offset, msg));
+                }
+            }
+        }
+        for (name, shape2) in fields2.iter() {
+            match values.remove(name.as_str()) {
+                Some((_, buf)) => out.extend_from_slice(&buf),
+                None => {
+                    let field_path = format!("{}.{}", path, name);
+                    match self.rules.defaults.get(&field_path) {
+                        Some(bytes) => out.extend_from_slice(bytes),
+                        None => match shape2.unfold() {
+                            Shape::Base(uuid, _)
+                                if uuid == uuid::OPTION
+                                    || uuid == uuid::MAP
+                                    || uuid == uuid::HASHTBL
+                                    || uuid == uuid::FLOAT_ARRAY
+                                    || is_sequence(&uuid) =>
+                            {
+                                out.push(0)
+                            }
+                            _ => {
+                                let offset = self.de.get_ref().offset();
+                                let msg = format!("field {} has no default value", name);
+                                return Err(self.error(&field_path, offset, msg));
+                            }
+                        },
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn base(&mut self, conversion: BaseConversion, out: &mut Vec<u8>) -> Result<()> {
+        let BaseConversion {
+            src: (uuid1, args1),
+            dst: (uuid2, args2),
+            path,
+            offset,
+        } = conversion;
+        let class = encoding_class(uuid1);
+        let same_class = class == encoding_class(uuid2)
+            && args1.len() == arity(class)
+            && args2.len() == arity(class);
+        match (class, encoding_class(uuid2)) {
+            (uuid::INT, uuid::INT) | (uuid::NAT0, uuid::INT) => {
+                let v = if class == uuid::INT {
+                    self.de.read_signed()?
+                } else {
+                    i64::try_from(self.de.read_nat0()?)?
+                };
+                if uuid2 == uuid::INT32 {
+                    i32::try_from(v)?;
+                }
+                write_int(out, v)
+            }
+            (uuid::INT, uuid::NAT0) | (uuid::NAT0, uuid::NAT0) => {
+                let v = if class == uuid::INT {
+                    u64::try_from(self.de.read_signed()?)?
+                } else {
+                    self.de.read_nat0()?
+                };
+                write_nat0(out, v)
+            }
+            _ if !same_class => {
+                let msg = format!("cannot convert {} to {}", uuid1, uuid2);
+                Err(self.error(path, offset, msg))
+            }
+            (uuid::UNIT, _) | (uuid::BOOL, _) | (uuid::CHAR, _) => {
+                out.push(self.de.read_byte()?);
+                Ok(())
+            }
+            (uuid::FLOAT, _) => {
+                out.extend_from_slice(&self.de.read_float()?.to_le_bytes());
+                Ok(())
+            }
+            (uuid::STRING, _) => {
+                let len = self.de.read_nat0()?;
+                write_nat0(out, len)?;
+                out.extend_from_slice(&self.de.read_bytes(len)?);
+                Ok(())
+            }
+            (uuid::FLOAT_ARRAY, _) => {
+                let len = self.de.read_nat0()?;
+                write_nat0(out, len)?;
+                out.extend_from_slice(&self.de.read_bytes(len.saturating_mul(8))?);
+                Ok(())
+            }
+            (uuid::OPTION, _) => match self.de.read_byte()? {
+                0 => {
+                    out.push(0);
+                    Ok(())
+                }
+                1 => {
+                    out.push(1);
+                    self.value(&args1[0], &args2[0], &format!("{}?", path), out)
+                }
+                _ => Err(Error::ExpectedOption),
+            },
+            (uuid::MAP, _) | (uuid::HASHTBL, _) => {
+                let (args1, args2) = (map_args(uuid1, args1), map_args(uuid2, args2));
+                if args1.len() != 2 || args2.len() != 2 {
+                    return Err(self.error(path, offset, format!("invalid base type {}", uuid1)));
+                }
+                let len = self.de.read_nat0()?;
+                write_nat0(out, len)?;
+                for _ in 0..len {
+                    self.value(&args1[0], &args2[0], &format!("{}<0>", path), out)?;
+                    self.value(&args1[1], &args2[1], &format!("{}<1>", path), out)?;
+                }
+                Ok(())
+            }
+            (class, _) if is_sequence(class) => {
+                let len = self.de.read_nat0()?;
+                write_nat0(out, len)?;
+                for _ in 0..len {
+                    self.value(&args1[0], &args2[0], &format!("{}[]", path), out)?
+                }
+                Ok(())
+            }
+            _ => Err(self.error(path, offset, format!("unknown base type {}", uuid1))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{transcode, Rules};
+    use crate::{to_vec, Error, HasShape, Shape};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::Serialize;
+
+    #[allow(dead_code)]
+    #[derive(Serialize, BinShape)]
+    enum KindV1 {
+        Limit(f64),
+        Market,
+        Stop,
+    }
+
+    #[derive(Serialize, BinShape)]
+    struct OrderV1 {
+        id: i64,
+        trader: String,
+        kind: KindV1,
+        qty: u32,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Serialize, BinShape)]
+    enum KindV2 {
+        Market,
+        Limit(f64),
+        StopMarket,
+    }
+
+    #[derive(Serialize, BinShape)]
+    struct OrderV2 {
+        qty: i64,
+        id: i64,
+        kind: KindV2,
+        tags: Vec<String>,
+        venue: String,
+    }
+
+    #[test]
+    fn test_transcode() {
+        let rules = Rules::new()
+            .drop(".trader")
+            .rename(".kind.Stop", "StopMarket")
+            .default_value(".venue", "XNYS")
+            .unwrap();
+        let v1 = OrderV1 {
+            id: 3,
+            trader: "bob".to_string(),
+            kind: KindV1::Stop,
+            qty: 1000,
+        };
+        let v2 = OrderV2 {
+            qty: 1000,
+            id: 3,
+            kind: KindV2::StopMarket,
+            tags: vec![],
+            venue: "XNYS".to_string(),
+        };
+        let data = to_vec(&v1).unwrap();
+        let (old, new) = (OrderV1::shape(), OrderV2::shape());
+        assert_eq!(
+            transcode(&data, &old, &new, &rules).unwrap(),
+            to_vec(&v2).unwrap()
+        );
+        let v1 = OrderV1 {
+            kind: KindV1::Limit(1.5),
+            ..v1
+        };
+        let v2 = OrderV2 {
+            kind: KindV2::Limit(1.5),
+            ..v2
+        };
+        let data = to_vec(&v1).unwrap();
+        assert_eq!(
+            transcode(&data, &old, &new, &rules).unwrap(),
+            to_vec(&v2).unwrap()
+        );
+
+        match transcode(&data, &old, &new, &Rules::new()) {
+            Err(Error::AtPath { path, offset, .. }) => {
+                assert_eq!(path, ".trader");
+                assert_eq!(offset, 1)
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        let rules = rules.rename(".id", "qty");
+        match transcode(&data, &old, &new, &rules) {
+            Err(Error::AtPath { path, msg, .. }) => {
+                assert_eq!(path, ".qty");
+                assert_eq!(msg, "the rules map both fields id and qty to qty")
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        let rules = rules.rename(".id", "id");
+        let variant =
+            |n: usize| Shape::Variant((0..n).map(|i| (format!("C{}", i), vec![])).collect());
+        let to_last = Rules::new().rename(".C0", "C299");
+        match transcode(&[0], &variant(1), &variant(300), &to_last) {
+            Err(Error::AtPath { msg, .. }) => {
+                assert_eq!(msg, "constructor index 299 does not fit a byte")
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        match transcode(&data[..12], &old, &new, &rules) {
+            Err(Error::AtPath { path, offset, .. }) => {
+                assert_eq!(path, ".kind.Limit");
+                assert_eq!(offset, 6)
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Types matching some of the encodings provided by `Bin_prot.Std`.
+use crate::ser::hash_variant;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+use std::fmt;
+
+/// A md5 digest, this uses the same representation as OCaml `Md5.t`,
+/// i.e. the 16 raw bytes of the digest with a length prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Md5Digest(pub [u8; 16]);
+
+impl Md5Digest {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut digest = [0u8; 16];
+        if bytes.len() != digest.len() {
+            return None;
+        }
+        digest.copy_from_slice(bytes);
+        Some(Md5Digest(digest))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn from_hex(s: &str) -> Option<Self> {
+        if s.len() != 32 || !s.is_ascii() {
+            return None;
+        }
+        let mut digest = [0u8; 16];
+        for (i, b) in digest.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(Md5Digest(digest))
+    }
+}
+
+impl fmt::Display for Md5Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl Serialize for Md5Digest {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+// The names passed to `deserialize_newtype_struct` so that deserializers can
+// tell these types apart from plain bytes, this is used when tracing shapes.
+pub(crate) const MD5_DIGEST_MARKER: &str = "$serde_binprot::Md5Digest";
+pub(crate) const BIGSTRING_MARKER: &str = "$serde_binprot::Bigstring";
+
+struct Md5DigestVisitor;
+
+impl<'de> Visitor<'de> for Md5DigestVisitor {
+    type Value = Md5Digest;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a md5 digest of 16 bytes")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<Md5Digest, E>
+    where
+        E: de::Error,
+    {
+        Md5Digest::from_bytes(v).ok_or_else(|| E::invalid_length(v.len(), &self))
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> std::result::Result<Md5Digest, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_byte_buf(self)
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> std::result::Result<Md5Digest, E>
+    where
+        E: de::Error,
+    {
+        self.visit_bytes(&v)
+    }
+}
+
+impl<'de> Deserialize<'de> for Md5Digest {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_newtype_struct(MD5_DIGEST_MARKER, Md5DigestVisitor)
+    }
+}
+
+/// A bigstring, this is encoded as a length prefix followed by the raw bytes.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Bigstring(pub Vec<u8>);
+
+impl From<Vec<u8>> for Bigstring {
+    fn from(v: Vec<u8>) -> Self {
+        Bigstring(v)
+    }
+}
+
+impl From<Bigstring> for Vec<u8> {
+    fn from(v: Bigstring) -> Self {
+        v.0
+    }
+}
+
+impl std::ops::Deref for Bigstring {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for Bigstring {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Serialize for Bigstring {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+struct BigstringVisitor;
+
+impl<'de> Visitor<'de> for BigstringVisitor {
+    type Value = Bigstring;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a bigstring")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<Bigstring, E>
+    where
+        E: de::Error,
+    {
+        Ok(Bigstring(v.to_vec()))
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> std::result::Result<Bigstring, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_byte_buf(self)
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> std::result::Result<Bigstring, E>
+    where
+        E: de::Error,
+    {
+        Ok(Bigstring(v))
+    }
+}
+
+impl<'de> Deserialize<'de> for Bigstring {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_newtype_struct(BIGSTRING_MARKER, BigstringVisitor)
+    }
+}
+
+/// The tag of a polymorphic variant, i.e. the hash of its constructor name.
+/// This is encoded on 4 bytes, as done by bin_prot, and is used by the code
+/// that `codegen` generates for polymorphic variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PolyVariantTag(pub i32);
+
+impl PolyVariantTag {
+    pub fn new(name: &str) -> Self {
+        PolyVariantTag(hash_variant(name))
+    }
+}
+
+impl Serialize for PolyVariantTag {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // Chars are the only values written as a single raw byte.
+        let mut tuple = serializer.serialize_tuple(4)?;
+        for b in ((self.0 << 1) | 1).to_le_bytes().iter() {
+            tuple.serialize_element(&char::from(*b))?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for PolyVariantTag {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let chars = <[char; 4]>::deserialize(deserializer)?;
+        let mut bytes = [0u8; 4];
+        for (b, c) in bytes.iter_mut().zip(chars.iter()) {
+            *b = std::convert::TryFrom::try_from(*c).map_err(de::Error::custom)?;
+        }
+        Ok(PolyVariantTag(i32::from_le_bytes(bytes) >> 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bigstring, Md5Digest, PolyVariantTag};
+    use crate::{from_slice, to_vec};
+
+    #[test]
+    fn test_md5_digest() {
+        let digest = Md5Digest::from_hex("698cfa4093fe5e51523842d37b92aeac").unwrap();
+        assert_eq!(digest.to_string(), "698cfa4093fe5e51523842d37b92aeac");
+        let ser = to_vec(&digest).unwrap();
+        assert_eq!(ser.len(), 17);
+        assert_eq!(ser[0], 16);
+        assert_eq!(&ser[1..], digest.as_bytes());
+        let de: Md5Digest = from_slice(&ser).unwrap();
+        assert_eq!(de, digest);
+
+        let ser = to_vec(&Bigstring(vec![1, 2, 3])).unwrap();
+        assert!(from_slice::<Md5Digest>(&ser).is_err());
+        assert!(Md5Digest::from_hex("698cfa40").is_none());
+    }
+
+    #[test]
+    fn test_bigstring() {
+        let bigstring = Bigstring(vec![42u8; 300]);
+        let ser = to_vec(&bigstring).unwrap();
+        assert_eq!(&ser[..3], [0xfe, 44, 1]);
+        assert_eq!(ser.len(), 303);
+        let de: Bigstring = from_slice(&ser).unwrap();
+        assert_eq!(de, bigstring);
+    }
+
+    #[test]
+    fn test_poly_variant_tag() {
+        let tag = PolyVariantTag::new("Buy");
+        let ser = to_vec(&tag).unwrap();
+        assert_eq!(ser, [0x4d, 0xf6, 0x64, 0x00]);
+        assert_eq!(from_slice::<PolyVariantTag>(&ser).unwrap(), tag);
+        let tag = PolyVariantTag::new("Cancel");
+        assert_eq!(tag.0, -322412134);
+        assert_eq!(
+            from_slice::<PolyVariantTag>(&to_vec(&tag).unwrap()).unwrap(),
+            tag
+        );
+    }
+}
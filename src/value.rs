@@ -0,0 +1,1202 @@
+//! A dynamic representation of bin_prot values.
+//!
+//! The bin_prot format is not self-describing, a [`Value`] can only be
+//! decoded from some data given the shape of the type that was used to
+//! encode it. Record fields and variant constructors are named after the
+//! shape so the result can be inspected, converted to a typed value with
+//! [`from_value`], or encoded back with [`encode_value`].
+//!
+//! ```
+//! use serde_binprot::value::{decode_value, Value};
+//! use serde_binprot::HasShape;
+//!
+//! let data = serde_binprot::to_vec(&(1i64, Some("foo".to_string()))).unwrap();
+//! let value = decode_value(&data, &<(i64, Option<String>)>::shape()).unwrap();
+//! let foo = Some(Box::new(Value::String("foo".to_string())));
+//! assert_eq!(value, Value::Tuple(vec![Value::Int(1), Value::Option(foo)]));
+//! ```
+use crate::de::{Deserializer, SliceReader};
+use crate::error::{Error, Result};
+use crate::ser::{poly_variant_tag, Serializer};
+use crate::sexp::Sexp;
+use crate::shape::{encoding_class, is_sequence, map_args, uuid, Kind, Shape};
+use crate::walk::{Node, Walker, MAX_DEPTH};
+use serde::de::{self, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize, Serializer as _};
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Unit,
+    Bool(bool),
+    /// The OCaml integer types, `int`, `int32`, `int64`...
+    Int(i64),
+    Nat0(u64),
+    Float(f64),
+    Char(char),
+    String(String),
+    /// Bytes, bigstrings and strings that are not valid UTF-8.
+    Bytes(Vec<u8>),
+    Option(Option<Box<Value>>),
+    /// Lists, arrays, sets and the other sequences.
+    List(Vec<Value>),
+    Tuple(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Record(Vec<(String, Value)>),
+    /// A variant or polymorphic variant constructor with its arguments, a
+    /// constructor with an inline record has a single record argument.
+    Variant(String, Vec<Value>),
+}
+
+impl Value {
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Value::Unit => "unit",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Nat0(_) => "nat0",
+            Value::Float(_) => "float",
+            Value::Char(_) => "char",
+            Value::String(_) => "string",
+            Value::Bytes(_) => "bytes",
+            Value::Option(_) => "option",
+            Value::List(_) => "list",
+            Value::Tuple(_) => "tuple",
+            Value::Map(_) => "map",
+            Value::Record(_) => "record",
+            Value::Variant(_, _) => "variant",
+        }
+    }
+
+    /// The s-expression of the value, following the `ppx_sexp_conv`
+    /// conventions.
+    pub fn to_sexp(&self) -> Sexp {
+        let all = |values: &[Value]| Sexp::List(values.iter().map(Value::to_sexp).collect());
+        match self {
+            Value::Unit => Sexp::List(vec![]),
+            Value::Bool(b) => Sexp::atom(&b.to_string()),
+            Value::Int(i) => Sexp::atom(&i.to_string()),
+            Value::Nat0(n) => Sexp::atom(&n.to_string()),
+            Value::Float(f) => Sexp::atom(&crate::sexp_conv::string_of_float(*f)),
+            // OCaml chars are single bytes.
+            Value::Char(c) => Sexp::Atom(vec![*c as u8]),
+            Value::String(s) => Sexp::atom(s),
+            Value::Bytes(b) => Sexp::Atom(b.clone()),
+            Value::Option(None) => Sexp::List(vec![]),
+            Value::Option(Some(v)) => Sexp::List(vec![v.to_sexp()]),
+            Value::List(values) | Value::Tuple(values) => all(values),
+            Value::Map(pairs) => Sexp::List(
+                pairs
+                    .iter()
+                    .map(|(k, v)| Sexp::List(vec![k.to_sexp(), v.to_sexp()]))
+                    .collect(),
+            ),
+            Value::Record(fields) => Sexp::List(
+                fields
+                    .iter()
+                    .map(|(name, v)| Sexp::List(vec![Sexp::atom(name), v.to_sexp()]))
+                    .collect(),
+            ),
+            Value::Variant(name, args) if args.is_empty() => Sexp::atom(name),
+            // Inline records are spliced, `(Constructor (field value)..)`.
+            Value::Variant(name, args) if matches!(args.as_slice(), [Value::Record(_)]) => {
+                let mut sexps = vec![Sexp::atom(name)];
+                if let Sexp::List(fields) = args[0].to_sexp() {
+                    sexps.extend(fields)
+                }
+                Sexp::List(sexps)
+            }
+            Value::Variant(name, args) => {
+                let mut sexps = vec![Sexp::atom(name)];
+                sexps.extend(args.iter().map(Value::to_sexp));
+                Sexp::List(sexps)
+            }
+        }
+    }
+
+    /// Converts an s-expression as produced by [`Value::to_sexp`] to a value
+    /// of the given shape. Missing record fields of option type are `None`,
+    /// and inline records can also be given as `(Constructor (field value)..)`.
+    pub fn from_sexp(sexp: &Sexp, shape: &Shape) -> Result<Value> {
+        value_of_sexp(sexp, shape, "", 0)
+    }
+}
+
+fn value_of_sexp(sexp: &Sexp, shape: &Shape, path: &str, depth: usize) -> Result<Value> {
+    let error = |msg: String| {
+        let path = if path.is_empty() { "." } else { path };
+        Error::Message(format!("{}: {}", path, msg))
+    };
+    let unexpected = || error(format!("unexpected s-expression {}", sexp));
+    if depth >= MAX_DEPTH {
+        return Err(error("value nested too deeply".to_string()));
+    }
+    let of_sexp = |sexp, shape, path: String| value_of_sexp(sexp, shape, &path, depth + 1);
+    let of_list = |sexps: &[Sexp], shape, path: String| -> Result<Vec<Value>> {
+        let of_sexp = |sexp| value_of_sexp(sexp, shape, &path, depth + 1);
+        sexps.iter().map(of_sexp).collect()
+    };
+    let shape = shape.unfold();
+    let value = match (&shape, sexp) {
+        (Shape::Base(uuid, args), sexp) => {
+            let class = encoding_class(uuid);
+            let atom = sexp.as_atom();
+            let value = match (class, map_args(uuid, args), sexp.as_list()) {
+                (uuid::REF, [arg], _) | (uuid::LAZY, [arg], _) => {
+                    return value_of_sexp(sexp, arg, path, depth + 1)
+                }
+                (uuid::UNIT, [], Some([])) => Some(Value::Unit),
+                (uuid::BOOL, [], _) => match atom {
+                    Some("true") => Some(Value::Bool(true)),
+                    Some("false") => Some(Value::Bool(false)),
+                    _ => None,
+                },
+                (uuid::CHAR, [], _) => {
+                    let mut chars = atom.map(|a| a.chars()).into_iter().flatten();
+                    match (chars.next(), chars.next(), sexp.as_bytes()) {
+                        (Some(c), None, _) if (c as u32) < 256 => Some(Value::Char(c)),
+                        (_, _, Some(&[c])) => Some(Value::Char(c as char)),
+                        _ => None,
+                    }
+                }
+                (uuid::FLOAT, [], _) => {
+                    let f = atom.and_then(crate::sexp_conv::float_of_string);
+                    f.map(Value::Float)
+                }
+                (uuid::INT, [], _) => {
+                    let i = atom.and_then(|a| a.replace('_', "").parse().ok());
+                    i.map(Value::Int)
+                }
+                (uuid::NAT0, [], _) => {
+                    let n = atom.and_then(|a| a.replace('_', "").parse().ok());
+                    n.map(Value::Nat0)
+                }
+                (uuid::STRING, [], _) if uuid == uuid::STRING && atom.is_some() => {
+                    atom.map(|a| Value::String(a.to_string()))
+                }
+                (uuid::STRING, [], _) => sexp.as_bytes().map(|b| Value::Bytes(b.to_vec())),
+                (uuid::OPTION, [arg], Some(sexps)) => match sexps {
+                    [] => Some(Value::Option(None)),
+                    [sexp] => {
+                        let value = of_sexp(sexp, arg, format!("{}?", path))?;
+                        Some(Value::Option(Some(Box::new(value))))
+                    }
+                    _ => None,
+                },
+                (uuid::FLOAT_ARRAY, [], Some(sexps)) => {
+                    let path = format!("{}[]", path);
+                    Some(Value::List(of_list(sexps, &Shape::float(), path)?))
+                }
+                (uuid::MAP, [key, value], Some(sexps))
+                | (uuid::HASHTBL, [key, value], Some(sexps)) => {
+                    let mut pairs = vec![];
+                    for sexp in sexps.iter() {
+                        match sexp.as_list() {
+                            Some([k, v]) => pairs.push((
+                                of_sexp(k, key, format!("{}<0>", path))?,
+                                of_sexp(v, value, format!("{}<1>", path))?,
+                            )),
+                            _ => return Err(unexpected()),
+                        }
+                    }
+                    Some(Value::Map(pairs))
+                }
+                (class, [arg], Some(sexps)) if is_sequence(class) => {
+                    Some(Value::List(of_list(sexps, arg, format!("{}[]", path))?))
+                }
+                _ => None,
+            };
+            value.ok_or_else(unexpected)?
+        }
+        (Shape::Tuple(shapes), Sexp::List(sexps)) if shapes.len() == sexps.len() => {
+            let values = shapes.iter().zip(sexps.iter()).enumerate();
+            let values: Result<_> = values
+                .map(|(index, (shape, sexp))| of_sexp(sexp, shape, format!("{}.{}", path, index)))
+                .collect();
+            Value::Tuple(values?)
+        }
+        (Shape::Record(fields), Sexp::List(sexps)) => record_of_sexp(sexps, fields, path, depth)?,
+        (Shape::Variant(variants), sexp) => {
+            let (name, sexps) = constructor(sexp).ok_or_else(unexpected)?;
+            let args = match variants.iter().find(|(n, _)| n == name) {
+                Some((_, args)) => args,
+                None => return Err(error(format!("unknown constructor {}", name))),
+            };
+            let path = format!("{}.{}", path, name);
+            let inline_record = match args.as_slice() {
+                [arg] => match arg.unfold() {
+                    Shape::Record(fields) => Some(fields),
+                    _ => None,
+                },
+                _ => None,
+            };
+            let values = match (args.as_slice(), sexps) {
+                ([arg], [sexp]) => match (of_sexp(sexp, arg, path.clone()), inline_record) {
+                    (Ok(value), _) => vec![value],
+                    (Err(err), None) => return Err(err),
+                    (Err(_), Some(fields)) => vec![record_of_sexp(sexps, &fields, &path, depth)?],
+                },
+                (_, sexps) if args.len() == sexps.len() => {
+                    let args = args.iter().zip(sexps.iter()).enumerate();
+                    let values: Result<_> = args
+                        .map(|(i, (arg, sexp))| of_sexp(sexp, arg, format!("{}.{}", path, i)))
+                        .collect();
+                    values?
+                }
+                (_, sexps) => match inline_record {
+                    Some(fields) => vec![record_of_sexp(sexps, &fields, &path, depth)?],
+                    None => return Err(unexpected()),
+                },
+            };
+            Value::Variant(name.to_string(), values)
+        }
+        (Shape::PolyVariant(variants), sexp) => {
+            let (name, sexps) = constructor(sexp).ok_or_else(unexpected)?;
+            let values = match (variants.get(name), sexps) {
+                (Some(None), []) => vec![],
+                (Some(Some(arg)), [sexp]) => {
+                    vec![of_sexp(sexp, arg, format!("{}.`{}", path, name))?]
+                }
+                (Some(_), _) => return Err(unexpected()),
+                (None, _) => return Err(error(format!("unknown constructor `{}", name))),
+            };
+            Value::Variant(name.to_string(), values)
+        }
+        _ => return Err(unexpected()),
+    };
+    Ok(value)
+}
+
+// The name and arguments of a constructor, `Name` or `(Name args..)`.
+fn constructor(sexp: &Sexp) -> Option<(&str, &[Sexp])> {
+    match sexp {
+        Sexp::Atom(_) => Some((sexp.as_atom()?, &[])),
+        Sexp::List(sexps) => {
+            let (name, args) = sexps.split_first()?;
+            Some((name.as_atom()?, args))
+        }
+    }
+}
+
+fn record_of_sexp(
+    sexps: &[Sexp],
+    fields: &[(String, Shape)],
+    path: &str,
+    depth: usize,
+) -> Result<Value> {
+    let error = |msg: String| {
+        let path = if path.is_empty() { "." } else { path };
+        Error::Message(format!("{}: {}", path, msg))
+    };
+    let mut values: Vec<Option<Value>> = vec![None; fields.len()];
+    for sexp in sexps.iter() {
+        let (name, value) = match sexp.as_list() {
+            Some([name, value]) => (name.as_atom(), value),
+            _ => (None, sexp),
+        };
+        let name = match name {
+            Some(name) => name,
+            None => return Err(error(format!("unexpected record field {}", sexp))),
+        };
+        match fields.iter().position(|(n, _)| n == name) {
+            Some(index) => {
+                let path = format!("{}.{}", path, name);
+                values[index] = Some(value_of_sexp(value, &fields[index].1, &path, depth + 1)?)
+            }
+            None => return Err(error(format!("unknown field {}", name))),
+        }
+    }
+    let mut record = vec![];
+    for ((name, shape), value) in fields.iter().zip(values) {
+        let value = match (value, shape.unfold()) {
+            (Some(value), _) => value,
+            (None, Shape::Base(uuid, _)) if uuid == uuid::OPTION => Value::Option(None),
+            (None, _) => return Err(error(format!("missing field {}", name))),
+        };
+        record.push((name.to_string(), value))
+    }
+    Ok(Value::Record(record))
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_sexp())
+    }
+}
+
+/// Decodes some bin_prot data encoded with a type of the given shape.
+pub fn decode_value(data: &[u8], shape: &Shape) -> Result<Value> {
+    let (value, len) = decode_value_prefix(data, shape)?;
+    if len < data.len() {
+        return Err(Error::TrailingCharacters);
+    }
+    Ok(value)
+}
+
+// Decodes a value from the beginning of some data, and returns it together
+// with the number of bytes that it used.
+pub(crate) fn decode_value_prefix(data: &[u8], shape: &Shape) -> Result<(Value, usize)> {
+    let mut decoder = Decoder {
+        de: Deserializer::new(SliceReader::new(data)),
+        depth: 0,
+    };
+    let value = decoder.value(shape, "")?;
+    Ok((value, decoder.de.get_ref().offset()))
+}
+
+/// Encodes a value as a type of the given shape.
+pub fn encode_value(value: &Value, shape: &Shape) -> Result<Vec<u8>> {
+    let mut encoder = Encoder {
+        out: vec![],
+        depth: 0,
+    };
+    encoder.value(value, shape, "")?;
+    Ok(encoder.out)
+}
+
+struct Decoder<'a> {
+    de: Deserializer<SliceReader<'a>>,
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn values(&mut self, len: u64, shape: &Shape, path: &str) -> Result<Vec<Value>> {
+        let capacity = std::cmp::min(len, self.de.get_ref().remaining() as u64);
+        let mut values = Vec::with_capacity(capacity as usize);
+        for _ in 0..len {
+            values.push(self.value(shape, path)?)
+        }
+        Ok(values)
+    }
+
+    fn args(&mut self, args: &[Shape], path: &str) -> Result<Vec<Value>> {
+        if args.len() == 1 {
+            return Ok(vec![self.value(&args[0], path)?]);
+        }
+        let args = args.iter().enumerate();
+        args.map(|(index, arg)| self.value(arg, &format!("{}.{}", path, index)))
+            .collect()
+    }
+}
+
+impl<'a> Walker<'a> for Decoder<'a> {
+    type Output = Value;
+
+    fn de(&mut self) -> &mut Deserializer<SliceReader<'a>> {
+        &mut self.de
+    }
+
+    fn depth(&mut self) -> &mut usize {
+        &mut self.depth
+    }
+
+    fn node(&mut self, node: Node, _offset: usize, path: &str) -> Result<Value> {
+        let value = match node {
+            Node::Scalar(value) => value,
+            Node::Option(None) => Value::Option(None),
+            Node::Option(Some(arg)) => {
+                let value = self.value(arg, &format!("{}?", path))?;
+                Value::Option(Some(Box::new(value)))
+            }
+            Node::Sequence(len, elt) => {
+                Value::List(self.values(len, elt, &format!("{}[]", path))?)
+            }
+            Node::Map(len, key, value) => {
+                let mut pairs = vec![];
+                for _ in 0..len {
+                    let key = self.value(key, &format!("{}<0>", path))?;
+                    let value = self.value(value, &format!("{}<1>", path))?;
+                    pairs.push((key, value))
+                }
+                Value::Map(pairs)
+            }
+            Node::Tuple(shapes) => {
+                let shapes = shapes.iter().enumerate();
+                let values: Result<_> = shapes
+                    .map(|(index, shape)| self.value(shape, &format!("{}.{}", path, index)))
+                    .collect();
+                Value::Tuple(values?)
+            }
+            Node::Record(fields) => {
+                let mut values = vec![];
+                for (name, shape) in fields.iter() {
+                    let value = self.value(shape, &format!("{}.{}", path, name))?;
+                    values.push((name.to_string(), value))
+                }
+                Value::Record(values)
+            }
+            Node::Constructor { name, args, poly } => {
+                let path = if poly {
+                    format!("{}.`{}", path, name)
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                Value::Variant(name.to_string(), self.args(args, &path)?)
+            }
+        };
+        Ok(value)
+    }
+}
+
+struct Encoder {
+    out: Vec<u8>,
+    depth: usize,
+}
+
+impl Encoder {
+    fn value(&mut self, value: &Value, shape: &Shape, path: &str) -> Result<()> {
+        let offset = self.out.len();
+        if self.depth >= MAX_DEPTH {
+            return Err(Error::Message("value nested too deeply".to_string()).at_path(path, offset));
+        }
+        self.depth += 1;
+        let res = self.resolved(value, &shape.resolve(), path);
+        self.depth -= 1;
+        res.map_err(|err| err.at_path(path, offset))
+    }
+
+    fn args(&mut self, values: &[Value], args: &[Shape], path: &str) -> Result<()> {
+        if values.len() != args.len() {
+            return Err(Error::Message(format!(
+                "expected {} arguments, got {}",
+                args.len(),
+                values.len()
+            )));
+        }
+        if args.len() == 1 {
+            return self.value(&values[0], &args[0], path);
+        }
+        for (index, (value, arg)) in values.iter().zip(args.iter()).enumerate() {
+            self.value(value, arg, &format!("{}.{}", path, index))?
+        }
+        Ok(())
+    }
+
+    fn serializer(&mut self) -> Serializer<&mut Vec<u8>> {
+        Serializer::new(&mut self.out)
+    }
+
+    fn resolved(&mut self, value: &Value, shape: &Shape, path: &str) -> Result<()> {
+        match (shape.kind()?, value) {
+            (Kind::Unit, Value::Unit) => self.out.push(0),
+            (Kind::Bool, Value::Bool(b)) => self.out.push(*b as u8),
+            (Kind::Char, Value::Char(c)) => self.serializer().serialize_char(*c)?,
+            (Kind::Float, Value::Float(f)) => self.out.extend_from_slice(&f.to_le_bytes()),
+            (Kind::Int(uuid), Value::Int(_)) | (Kind::Int(uuid), Value::Nat0(_)) => {
+                let v = match value {
+                    Value::Int(v) => *v,
+                    Value::Nat0(v) => i64::try_from(*v)?,
+                    _ => unreachable!(),
+                };
+                if uuid == uuid::INT32 {
+                    i32::try_from(v)?;
+                }
+                self.serializer().serialize_i64(v)?
+            }
+            (Kind::Nat0, Value::Int(v)) => self.serializer().serialize_nat0(u64::try_from(*v)?)?,
+            (Kind::Nat0, Value::Nat0(v)) => self.serializer().serialize_nat0(*v)?,
+            (Kind::String(_), Value::String(s)) => self.serializer().serialize_str(s)?,
+            (Kind::String(_), Value::Bytes(b)) => self.serializer().serialize_bytes(b)?,
+            (Kind::Option(_), Value::Option(None)) => self.out.push(0),
+            (Kind::Option(arg), Value::Option(Some(value))) => {
+                self.out.push(1);
+                self.value(value, arg, &format!("{}?", path))?
+            }
+            (Kind::Map(key_shape, value_shape), Value::Map(pairs)) => {
+                self.serializer().serialize_nat0(pairs.len() as u64)?;
+                for (key, value) in pairs.iter() {
+                    self.value(key, key_shape, &format!("{}<0>", path))?;
+                    self.value(value, value_shape, &format!("{}<1>", path))?
+                }
+            }
+            (Kind::Sequence(_, elt), Value::List(values)) => {
+                self.serializer().serialize_nat0(values.len() as u64)?;
+                for value in values.iter() {
+                    self.value(value, elt, &format!("{}[]", path))?
+                }
+            }
+            (Kind::Tuple(shapes), Value::Tuple(values)) if values.len() == shapes.len() => {
+                for (index, (value, shape)) in values.iter().zip(shapes.iter()).enumerate() {
+                    self.value(value, shape, &format!("{}.{}", path, index))?
+                }
+            }
+            (Kind::Record(fields), Value::Tuple(values)) if values.len() == fields.len() => {
+                for ((name, shape), value) in fields.iter().zip(values.iter()) {
+                    self.value(value, shape, &format!("{}.{}", path, name))?
+                }
+            }
+            (Kind::Record(fields), Value::Record(values)) => {
+                if let Some((name, _)) = values
+                    .iter()
+                    .find(|(name, _)| fields.iter().all(|(n, _)| n != name))
+                {
+                    return Err(Error::Message(format!("unknown field {}", name)));
+                }
+                for (name, shape) in fields.iter() {
+                    match values.iter().find(|(n, _)| n == name) {
+                        Some((_, value)) => {
+                            self.value(value, shape, &format!("{}.{}", path, name))?
+                        }
+                        None => return Err(Error::Message(format!("missing field {}", name))),
+                    }
+                }
+            }
+            (Kind::Variant(variants), Value::Variant(name, values)) => {
+                match variants.iter().position(|(n, _)| n == name) {
+                    Some(index) => {
+                        self.serializer().serialize_as_u8(index as u32)?;
+                        self.args(values, &variants[index].1, &format!("{}.{}", path, name))?
+                    }
+                    None => return Err(Error::Message(format!("unknown constructor {}", name))),
+                }
+            }
+            (Kind::PolyVariant(variants), Value::Variant(name, values)) => {
+                let path = format!("{}.`{}", path, name);
+                match (values.as_slice(), variants.get(name)) {
+                    ([], Some(None)) => self.out.extend_from_slice(&poly_variant_tag(name)),
+                    ([value], Some(Some(arg))) => {
+                        self.out.extend_from_slice(&poly_variant_tag(name));
+                        self.value(value, arg, &path)?
+                    }
+                    (_, Some(_)) => {
+                        return Err(Error::Message(format!("invalid arguments for `{}", name)))
+                    }
+                    (_, None) => return Err(Error::Message(format!("unknown tag `{}", name))),
+                }
+            }
+            (_, value) => {
+                return Err(Error::Message(format!(
+                    "cannot encode a {} as {:?}",
+                    value.kind(),
+                    shape
+                )))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Converts any serializable value to a [`Value`]. Signed integers become
+/// [`Value::Int`] and unsigned ones [`Value::Nat0`], following their shapes.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value> {
+    value.serialize(ValueSerializer)
+}
+
+/// Converts a [`Value`] to a typed value.
+pub fn from_value<T: de::DeserializeOwned>(value: Value) -> Result<T> {
+    T::deserialize(value)
+}
+
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = Error;
+
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeVec;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeRecord;
+    type SerializeStructVariant = SerializeRecord;
+
+    fn serialize_bool(self, v: bool) -> Result<Value> {
+        Ok(Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value> {
+        Ok(Value::Int(v as i64))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value> {
+        Ok(Value::Int(v as i64))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value> {
+        Ok(Value::Int(v as i64))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value> {
+        Ok(Value::Int(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value> {
+        Ok(Value::Nat0(v as u64))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value> {
+        Ok(Value::Nat0(v as u64))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value> {
+        Ok(Value::Nat0(v as u64))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value> {
+        Ok(Value::Nat0(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value> {
+        Ok(Value::Float(v as f64))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value> {
+        Ok(Value::Float(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value> {
+        Ok(Value::Char(v))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value> {
+        Ok(Value::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Value> {
+        Ok(Value::Option(None))
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Value>
+    where
+        T: ?Sized + Serialize,
+    {
+        Ok(Value::Option(Some(Box::new(value.serialize(self)?))))
+    }
+
+    fn serialize_unit(self) -> Result<Value> {
+        Ok(Value::Unit)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value> {
+        Ok(Value::Unit)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value> {
+        Ok(Value::Variant(variant.to_string(), vec![]))
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<Value>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value>
+    where
+        T: ?Sized + Serialize,
+    {
+        Ok(Value::Variant(
+            variant.to_string(),
+            vec![value.serialize(self)?],
+        ))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SerializeVec> {
+        Ok(SerializeVec::new(None, false))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<SerializeVec> {
+        Ok(SerializeVec::new(None, true))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<SerializeVec> {
+        Ok(SerializeVec::new(None, true))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeVec> {
+        Ok(SerializeVec::new(Some(variant), true))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeMap> {
+        Ok(SerializeMap {
+            pairs: vec![],
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<SerializeRecord> {
+        Ok(SerializeRecord {
+            variant: None,
+            fields: vec![],
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeRecord> {
+        Ok(SerializeRecord {
+            variant: Some(variant),
+            fields: vec![],
+        })
+    }
+}
+
+struct SerializeVec {
+    variant: Option<&'static str>,
+    tuple: bool,
+    values: Vec<Value>,
+}
+
+impl SerializeVec {
+    fn new(variant: Option<&'static str>, tuple: bool) -> Self {
+        SerializeVec {
+            variant,
+            tuple,
+            values: vec![],
+        }
+    }
+
+    fn push<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.values.push(value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value> {
+        let value = match self.variant {
+            Some(variant) => Value::Variant(variant.to_string(), self.values),
+            None if self.tuple => Value::Tuple(self.values),
+            None => Value::List(self.values),
+        };
+        Ok(value)
+    }
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value> {
+        SerializeVec::end(self)
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value> {
+        SerializeVec::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value> {
+        SerializeVec::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeVec {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value> {
+        SerializeVec::end(self)
+    }
+}
+
+struct SerializeMap {
+    pairs: Vec<(Value, Value)>,
+    key: Option<Value>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(key.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return Err(Error::Message("map value without a key".to_string())),
+        };
+        self.pairs.push((key, value.serialize(ValueSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value> {
+        Ok(Value::Map(self.pairs))
+    }
+}
+
+struct SerializeRecord {
+    variant: Option<&'static str>,
+    fields: Vec<(String, Value)>,
+}
+
+impl SerializeRecord {
+    fn push<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        self.fields
+            .push((key.to_string(), value.serialize(ValueSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value> {
+        let record = Value::Record(self.fields);
+        let value = match self.variant {
+            Some(variant) => Value::Variant(variant.to_string(), vec![record]),
+            None => record,
+        };
+        Ok(value)
+    }
+}
+
+impl ser::SerializeStruct for SerializeRecord {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        SerializeRecord::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeRecord {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        SerializeRecord::end(self)
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Value {
+    type Deserializer = Value;
+
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            Value::Unit => visitor.visit_unit(),
+            Value::Bool(b) => visitor.visit_bool(b),
+            Value::Int(v) => visitor.visit_i64(v),
+            Value::Nat0(v) => visitor.visit_u64(v),
+            Value::Float(f) => visitor.visit_f64(f),
+            Value::Char(c) => visitor.visit_char(c),
+            Value::String(s) => visitor.visit_string(s),
+            Value::Bytes(b) => visitor.visit_byte_buf(b),
+            Value::Option(None) => visitor.visit_none(),
+            Value::Option(Some(value)) => visitor.visit_some(*value),
+            Value::List(values) | Value::Tuple(values) => {
+                visitor.visit_seq(de::value::SeqDeserializer::new(values.into_iter()))
+            }
+            Value::Map(pairs) => {
+                visitor.visit_map(de::value::MapDeserializer::new(pairs.into_iter()))
+            }
+            Value::Record(fields) => {
+                let fields = fields.into_iter().map(|(k, v)| (Value::String(k), v));
+                visitor.visit_map(de::value::MapDeserializer::new(fields))
+            }
+            // Variants are presented as self-describing formats do, the
+            // constructor name or a map with a single entry.
+            Value::Variant(name, args) if args.is_empty() => visitor.visit_string(name),
+            Value::Variant(name, mut args) => {
+                let arg = match args.len() {
+                    1 => args.remove(0),
+                    _ => Value::Tuple(args),
+                };
+                let entry = std::iter::once((Value::String(name), arg));
+                visitor.visit_map(de::value::MapDeserializer::new(entry))
+            }
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            Value::Option(None) => visitor.visit_none(),
+            Value::Option(Some(value)) => visitor.visit_some(*value),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            Value::Variant(name, args) => visitor.visit_enum(EnumDeserializer { name, args }),
+            Value::String(name) => visitor.visit_enum(EnumDeserializer { name, args: vec![] }),
+            value => Err(Error::Message(format!(
+                "expected a variant, got a {}",
+                value.kind()
+            ))),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct EnumDeserializer {
+    name: String,
+    args: Vec<Value>,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self)>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        let name = seed.deserialize(Value::String(self.name.clone()))?;
+        Ok((name, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for EnumDeserializer {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        if self.args.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Message(format!(
+                "unexpected arguments for {}",
+                self.name
+            )))
+        }
+    }
+
+    fn newtype_variant_seed<T>(mut self, seed: T) -> Result<T::Value>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        if self.args.len() == 1 {
+            seed.deserialize(self.args.remove(0))
+        } else {
+            seed.deserialize(Value::Tuple(self.args))
+        }
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_any(Value::Tuple(self.args), visitor)
+    }
+
+    fn struct_variant<V>(mut self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let value = match self.args.len() {
+            1 => self.args.remove(0),
+            _ => Value::Tuple(self.args),
+        };
+        de::Deserializer::deserialize_any(value, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_value, encode_value, from_value, to_value, Value};
+    use crate::{to_vec, Error, HasShape, Sexp};
+    use serde_binprot_derive::BinShape;
+    use serde_derive::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, BinShape)]
+    enum Side {
+        Buy,
+        Sell { price: f64 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, BinShape)]
+    struct Order {
+        id: u32,
+        symbol: String,
+        side: Side,
+        fills: Vec<(i64, Option<char>)>,
+        tags: BTreeMap<String, bool>,
+    }
+
+    #[test]
+    fn test_value() {
+        let mut tags = BTreeMap::new();
+        tags.insert("ioc".to_string(), true);
+        let order = Order {
+            id: 1234,
+            symbol: "AAPL".to_string(),
+            side: Side::Sell { price: 0.5 },
+            fills: vec![(-3, Some('x')), (1 << 40, None)],
+            tags,
+        };
+        let data = to_vec(&order).unwrap();
+        let value = decode_value(&data, &Order::shape()).unwrap();
+        assert_eq!(value, to_value(&order).unwrap());
+        match &value {
+            Value::Record(fields) => {
+                assert_eq!(
+                    fields[1],
+                    ("symbol".to_string(), Value::String("AAPL".to_string()))
+                );
+                let price = Value::Record(vec![("price".to_string(), Value::Float(0.5))]);
+                assert_eq!(fields[2].1, Value::Variant("Sell".to_string(), vec![price]))
+            }
+            value => panic!("unexpected value {:?}", value),
+        }
+        assert_eq!(encode_value(&value, &Order::shape()).unwrap(), data);
+        assert_eq!(from_value::<Order>(value).unwrap(), order);
+        assert_eq!(
+            from_value::<Side>(to_value(&Side::Buy).unwrap()).unwrap(),
+            Side::Buy
+        );
+
+        match decode_value(&data[..12], &Order::shape()) {
+            Err(Error::AtPath { path, offset, .. }) => {
+                assert_eq!(path, ".side.Sell.price");
+                assert_eq!(offset, 9)
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        let value = Value::Tuple(vec![Value::Int(1), Value::String("foo".to_string())]);
+        match encode_value(&value, &<(i64, i64)>::shape()) {
+            Err(Error::AtPath { path, offset, .. }) => {
+                assert_eq!(path, ".1");
+                assert_eq!(offset, 1)
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_sexp() {
+        let mut tags = BTreeMap::new();
+        tags.insert("ioc".to_string(), true);
+        let order = Order {
+            id: 7,
+            symbol: "A B".to_string(),
+            side: Side::Sell { price: 1.5 },
+            fills: vec![(-3, Some('x')), (2, None)],
+            tags,
+        };
+        let value = to_value(&order).unwrap();
+        let sexp = value.to_sexp();
+        assert_eq!(
+            sexp.to_string_mach(),
+            r#"((id 7)(symbol"A B")(side(Sell(price 1.5)))(fills((-3(x))(2())))(tags((ioc true))))"#
+        );
+        assert_eq!(Value::from_sexp(&sexp, &Order::shape()).unwrap(), value);
+
+        // Inline records can also be written as with ppx_sexp_conv.
+        let side = Sexp::parse("(Sell (price 2.))").unwrap();
+        let side = Value::from_sexp(&side, &Side::shape()).unwrap();
+        assert_eq!(from_value::<Side>(side).unwrap(), Side::Sell { price: 2. });
+
+        // Bytes are escaped as by Sexplib, without losing the invalid UTF-8.
+        let bytes = Value::Bytes(vec![0xff, b'a', 0]);
+        let sexp = bytes.to_sexp();
+        assert_eq!(sexp.to_string_mach(), r#""\255a\000""#);
+        let shape = crate::types::Bigstring::shape();
+        assert_eq!(Value::from_sexp(&sexp, &shape).unwrap(), bytes);
+
+        let shape = <Vec<(i64, Option<bool>)>>::shape();
+        let sexp = Sexp::parse("((1_000 ()) (2 (true)))").unwrap();
+        let value = Value::from_sexp(&sexp, &shape).unwrap();
+        assert_eq!(
+            from_value::<Vec<(i64, Option<bool>)>>(value).unwrap(),
+            [(1000, None), (2, Some(true))]
+        );
+        let sexp = Sexp::parse("((1 ()) (2 (yes)))").unwrap();
+        let err = Value::from_sexp(&sexp, &shape).unwrap_err().to_string();
+        assert!(
+            err.contains("[].1?: unexpected s-expression yes"),
+            "{}",
+            err
+        );
+    }
+}
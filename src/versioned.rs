@@ -0,0 +1,273 @@
+//! Versioned stable types, similar to the Jane Street `Stable.V1`, `Stable.V2`
+//! modules.
+//!
+//! Each version of a type implements [`Stable`] and points at the previous
+//! version, the chain ends with [`NoPrevious`]. A value encoded with any
+//! version of the chain can then be read and upgraded to the latest version,
+//! either given the version number out-of-band via [`from_slice_version`] or
+//! using a version-prefixed payload via [`Versioned`].
+//!
+//! ```
+//! use serde_derive::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct OrderV1 {
+//!     qty: i64,
+//! }
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct OrderV2 {
+//!     qty: i64,
+//!     price: f64,
+//! }
+//!
+//! impl From<OrderV1> for OrderV2 {
+//!     fn from(v1: OrderV1) -> Self {
+//!         OrderV2 { qty: v1.qty, price: 0.0 }
+//!     }
+//! }
+//!
+//! serde_binprot::stable_versions!(OrderV1 = 1, OrderV2 = 2);
+//!
+//! let bytes = serde_binprot::to_vec(&OrderV1 { qty: 42 }).unwrap();
+//! let order: OrderV2 = serde_binprot::from_slice_version(1, &bytes).unwrap();
+//! assert_eq!(order.qty, 42);
+//! ```
+use crate::error::{Error, Result};
+use serde::de::{self, DeserializeOwned, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+
+/// A version of a stable type.
+pub trait Stable: DeserializeOwned + From<Self::Previous> {
+    const VERSION: u64;
+
+    /// The previous version of this type, [`NoPrevious`] for the first one.
+    type Previous: Stable;
+
+    /// Deserializes a value encoded with the given version and upgrades it
+    /// to this version.
+    fn from_slice_version(version: u64, v: &[u8]) -> Result<Self> {
+        if version == Self::VERSION {
+            crate::from_slice(v)
+        } else {
+            Self::Previous::from_slice_version(version, v).map(Self::from)
+        }
+    }
+
+    #[doc(hidden)]
+    fn next_element_version<'de, A>(
+        version: u64,
+        seq: &mut A,
+    ) -> std::result::Result<Self, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        if version == Self::VERSION {
+            seq.next_element()?
+                .ok_or_else(|| de::Error::invalid_length(1, &"a versioned value"))
+        } else {
+            Self::Previous::next_element_version(version, seq).map(Self::from)
+        }
+    }
+}
+
+/// The end of a version chain, this type has no value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoPrevious {}
+
+impl<'de> de::Deserialize<'de> for NoPrevious {
+    fn deserialize<D>(_deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Err(de::Error::custom("NoPrevious cannot be deserialized"))
+    }
+}
+
+impl Stable for NoPrevious {
+    const VERSION: u64 = 0;
+    type Previous = NoPrevious;
+
+    fn from_slice_version(version: u64, _v: &[u8]) -> Result<Self> {
+        Err(Error::UnknownVersion(version))
+    }
+
+    fn next_element_version<'de, A>(
+        version: u64,
+        _seq: &mut A,
+    ) -> std::result::Result<Self, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        Err(de::Error::custom(format!("unknown version {}", version)))
+    }
+}
+
+/// Deserializes a value encoded with the given version of `T` or of one of
+/// its previous versions, and upgrades it to `T`.
+pub fn from_slice_version<T: Stable>(version: u64, v: &[u8]) -> Result<T> {
+    T::from_slice_version(version, v)
+}
+
+/// Implements [`Stable`] for a chain of versions, oldest first. The
+/// conversions from each version to the next one have to be provided
+/// via `From`.
+#[macro_export]
+macro_rules! stable_versions {
+    ($first:ty = $first_version:expr $(, $ty:ty = $version:expr)* $(,)?) => {
+        impl ::std::convert::From<$crate::NoPrevious> for $first {
+            fn from(v: $crate::NoPrevious) -> Self {
+                match v {}
+            }
+        }
+
+        impl $crate::Stable for $first {
+            const VERSION: u64 = $first_version;
+            type Previous = $crate::NoPrevious;
+        }
+
+        $crate::stable_versions!(@chain $first $(, $ty = $version)*);
+    };
+    (@chain $prev:ty, $ty:ty = $version:expr $(, $rest:ty = $rest_version:expr)*) => {
+        impl $crate::Stable for $ty {
+            const VERSION: u64 = $version;
+            type Previous = $prev;
+        }
+
+        $crate::stable_versions!(@chain $ty $(, $rest = $rest_version)*);
+    };
+    (@chain $prev:ty) => {};
+}
+
+/// A value prefixed with its version number, encoded as a `Nat0.t`. When
+/// deserializing, any version from the chain of `T` is accepted and upgraded
+/// to `T`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Versioned<T>(pub T);
+
+impl<T: Stable + Serialize> Serialize for Versioned<T> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&T::VERSION)?;
+        tuple.serialize_element(&self.0)?;
+        tuple.end()
+    }
+}
+
+struct VersionedVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Stable> Visitor<'de> for VersionedVisitor<T> {
+    type Value = Versioned<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a version number followed by a value")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Versioned<T>, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let version: u64 = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        T::next_element_version(version, &mut seq).map(Versioned)
+    }
+}
+
+impl<'de, T: Stable> de::Deserialize<'de> for Versioned<T> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_tuple(2, VersionedVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_slice_version, Versioned};
+    use crate::{from_slice, to_vec, Error};
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct V1 {
+        qty: i64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct V2 {
+        qty: i64,
+        symbol: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct V3 {
+        qty: i64,
+        symbol: String,
+        price: Option<f64>,
+    }
+
+    impl From<V1> for V2 {
+        fn from(v: V1) -> Self {
+            V2 {
+                qty: v.qty,
+                symbol: String::new(),
+            }
+        }
+    }
+
+    impl From<V2> for V3 {
+        fn from(v: V2) -> Self {
+            V3 {
+                qty: v.qty,
+                symbol: v.symbol,
+                price: None,
+            }
+        }
+    }
+
+    crate::stable_versions!(V1 = 1, V2 = 2, V3 = 3);
+
+    #[test]
+    fn test_versioned() {
+        let v1 = to_vec(&V1 { qty: 12 }).unwrap();
+        let v2 = to_vec(&V2 {
+            qty: 42,
+            symbol: "AAPL".to_string(),
+        })
+        .unwrap();
+        let v3: V3 = from_slice_version(1, &v1).unwrap();
+        assert_eq!(v3.qty, 12);
+        assert_eq!(v3.symbol, "");
+        let v3: V3 = from_slice_version(2, &v2).unwrap();
+        assert_eq!(v3.symbol, "AAPL");
+        let v2: V2 = from_slice_version(1, &v1).unwrap();
+        assert_eq!(v2.qty, 12);
+        assert!(matches!(
+            from_slice_version::<V3>(4, &v1),
+            Err(Error::UnknownVersion(4))
+        ));
+
+        let prefixed = to_vec(&Versioned(V2 {
+            qty: 1,
+            symbol: "A".to_string(),
+        }))
+        .unwrap();
+        assert_eq!(prefixed, [2, 1, 1, b'A']);
+        let Versioned(v3) = from_slice::<Versioned<V3>>(&prefixed).unwrap();
+        assert_eq!(
+            v3,
+            V3 {
+                qty: 1,
+                symbol: "A".to_string(),
+                price: None
+            }
+        );
+        assert!(from_slice::<Versioned<V3>>(&[7, 1]).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+//! A traversal of some bin_prot data driven by a shape.
+//!
+//! The walker reads the tags, lengths and scalars of each value and hands a
+//! `Node` to the implementation, which recurses on the components with
+//! `Walker::value`. The depth limit and the location of errors are handled
+//! here so that the decoders built on top only deal with their output.
+use crate::de::{Deserializer, SliceReader};
+use crate::error::{Error, Result};
+use crate::ser::hash_variant;
+use crate::shape::{uuid, Kind, Shape};
+use crate::value::Value;
+use std::collections::BTreeMap;
+use std::io;
+
+// The maximum nesting of values, recursive types where a value can contain
+// itself without consuming any data would otherwise loop forever.
+pub(crate) const MAX_DEPTH: usize = 1024;
+
+// A value whose tag, length or content has been read.
+pub(crate) enum Node<'s> {
+    // The values without components: integers, strings...
+    Scalar(Value),
+    Option(Option<&'s Shape>),
+    // The number of elements of a collection, and their shape.
+    Sequence(u64, &'s Shape),
+    Map(u64, &'s Shape, &'s Shape),
+    Tuple(&'s [Shape]),
+    Record(&'s [(String, Shape)]),
+    // A variant constructor with its arguments, `poly` for polymorphic
+    // variants.
+    Constructor {
+        name: &'s str,
+        args: &'s [Shape],
+        poly: bool,
+    },
+}
+
+// Reads the constructor index of a variant.
+pub(crate) fn read_constructor<'s, R: io::Read>(
+    de: &mut Deserializer<R>,
+    variants: &'s [(String, Vec<Shape>)],
+) -> Result<&'s (String, Vec<Shape>)> {
+    let index = de.read_byte()?;
+    variants
+        .get(index as usize)
+        .ok_or_else(|| Error::Message(format!("invalid constructor index {}", index)))
+}
+
+// Reads the hash of a polymorphic variant.
+pub(crate) fn read_poly_tag<'s, R: io::Read>(
+    de: &mut Deserializer<R>,
+    variants: &'s BTreeMap<String, Option<Shape>>,
+) -> Result<(&'s String, &'s Option<Shape>)> {
+    let hash = de.read_i32()? >> 1;
+    variants
+        .iter()
+        .find(|(name, _)| hash_variant(name) == hash)
+        .ok_or_else(|| Error::Message(format!("invalid polymorphic variant hash {}", hash)))
+}
+
+// Reads the beginning of a value of a resolved shape.
+pub(crate) fn read_node<'s, R: io::Read>(
+    de: &mut Deserializer<R>,
+    shape: &'s Shape,
+) -> Result<Node<'s>> {
+    let scalar = match shape.kind()? {
+        Kind::Unit => {
+            de.read_byte()?;
+            Value::Unit
+        }
+        Kind::Bool => match de.read_byte()? {
+            0 => Value::Bool(false),
+            1 => Value::Bool(true),
+            _ => return Err(Error::ExpectedBoolean),
+        },
+        Kind::Char => Value::Char(de.read_byte()? as char),
+        Kind::Float => Value::Float(de.read_float()?),
+        Kind::Int(_) => Value::Int(de.read_signed()?),
+        Kind::Nat0 => Value::Nat0(de.read_nat0()?),
+        Kind::String(uuid) => {
+            let len = de.read_nat0()?;
+            let bytes = de.read_bytes(len)?;
+            match String::from_utf8(bytes) {
+                Ok(string) if uuid == uuid::STRING => Value::String(string),
+                Ok(string) => Value::Bytes(string.into_bytes()),
+                Err(err) => Value::Bytes(err.into_bytes()),
+            }
+        }
+        Kind::Option(arg) => match de.read_byte()? {
+            0 => return Ok(Node::Option(None)),
+            1 => return Ok(Node::Option(Some(arg))),
+            _ => return Err(Error::ExpectedOption),
+        },
+        Kind::Sequence(_, elt) => return Ok(Node::Sequence(de.read_nat0()?, elt)),
+        Kind::Map(key, value) => return Ok(Node::Map(de.read_nat0()?, key, value)),
+        Kind::Tuple(shapes) => return Ok(Node::Tuple(shapes)),
+        Kind::Record(fields) => return Ok(Node::Record(fields)),
+        Kind::Variant(variants) => {
+            let (name, args) = read_constructor(de, variants)?;
+            return Ok(Node::Constructor {
+                name,
+                args,
+                poly: false,
+            });
+        }
+        Kind::PolyVariant(variants) => {
+            let (name, arg) = read_poly_tag(de, variants)?;
+            return Ok(Node::Constructor {
+                name,
+                args: arg.as_slice(),
+                poly: true,
+            });
+        }
+    };
+    Ok(Node::Scalar(scalar))
+}
+
+pub(crate) trait Walker<'a> {
+    type Output;
+
+    fn de(&mut self) -> &mut Deserializer<SliceReader<'a>>;
+
+    fn depth(&mut self) -> &mut usize;
+
+    // Processes a value once its node has been read, `offset` is where the
+    // value starts.
+    fn node(&mut self, node: Node, offset: usize, path: &str) -> Result<Self::Output>;
+
+    fn offset(&mut self) -> usize {
+        self.de().get_ref().offset()
+    }
+
+    fn value(&mut self, shape: &Shape, path: &str) -> Result<Self::Output> {
+        let offset = self.offset();
+        if *self.depth() >= MAX_DEPTH {
+            return Err(Error::Message("value nested too deeply".to_string()).at_path(path, offset));
+        }
+        *self.depth() += 1;
+        let shape = shape.resolve();
+        let res = read_node(self.de(), &shape).and_then(|node| self.node(node, offset, path));
+        *self.depth() -= 1;
+        res.map_err(|err| err.at_path(path, offset))
+    }
+}